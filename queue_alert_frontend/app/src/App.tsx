@@ -13,7 +13,8 @@ import Home from './components/Home';
 import Park from './components/Park';
 import { QaClientProvider } from './components/qaUrlStore';
 import { ConfigProvider } from './components/ConfigStore';
-import { AlertConfig, getConfigFromSW } from './api/alertConfig';
+import { AlertConfig, getConfigFromSW, sendConfigToSW } from './api/alertConfig';
+import QueueAlertAccess from './api/queueAlertAccess';
 import { useEffect, useState } from 'react';
 import { ToastContainer } from 'react-toastify';
 import 'react-toastify/dist/ReactToastify.css';
@@ -43,8 +44,16 @@ export default function App() {
     })
   })
 
+  //Get the right URL for the server
+  let qaUrl: string
+  if (!process.env.NODE_ENV || process.env.NODE_ENV === 'development') {
+    qaUrl = "http://localhost:8080"
+  } else {
+    qaUrl = "https://qalert.ealovega.dev"
+  }
+
   //Load persisted config on app load
-  const initalConfig = useSWConfig()
+  const initalConfig = useSWConfig(qaUrl)
 
   //Media query to enable disable hamburger to un-collapse the sidebar
   const isLargeScreen = useMediaQuery({ query: "(min-width: 1200px)" })
@@ -64,14 +73,6 @@ export default function App() {
       : <> </>
   }
 
-  //Get the right URL for the server
-  let qaUrl: string
-  if (!process.env.NODE_ENV || process.env.NODE_ENV === 'development') {
-    qaUrl = "http://localhost:8080"
-  } else {
-    qaUrl = "https://qalert.ealovega.dev"
-  }
-
   return (
     <QaClientProvider url={qaUrl}>
       <ConfigProvider oldConfig={initalConfig ?? undefined}>
@@ -109,17 +110,26 @@ export default function App() {
 }
 
 /**
- * Attempts to load the previously set config from the SW. null if never set.
- * @returns The last stored config in the SW, if set.
+ * Attempts to load the previously set config from the SW, reconciling it with the servers copy. null if never set.
+ * @param qaUrl Url of the backend to reconcile with.
+ * @returns The servers copy of the config if registered, else the last stored config in the SW, if set.
  */
-function useSWConfig() {
+function useSWConfig(qaUrl: string) {
   //Attempt to load from SW
   let [initalConfig, setInitalConfig] = useState<AlertConfig | null>(null)
   useEffect(() => {
-    getConfigFromSW().then((config) => {
-      setInitalConfig(config)
+    getConfigFromSW().then(async (config) => {
+      //The server is the source of truth for what we will be alerted on
+      const res = await new QueueAlertAccess(qaUrl).getRegistrationFromBackend()
+
+      if (res.ok) {
+        await sendConfigToSW(res.val.config)
+        setInitalConfig(res.val.config)
+      } else {
+        setInitalConfig(config)
+      }
     })
-  }, [])
+  }, [qaUrl])
 
   return initalConfig
 }
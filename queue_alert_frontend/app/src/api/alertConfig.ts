@@ -15,9 +15,18 @@ export type AlertConfig = [string, rideConfig[]]
 export type alertOption = "Open" | "Closed" | { wait: number }
 
 /**
- * An alert config for one ride.
+ * How often a ride alerts once its condition is met. Servers default to repeat.
+ *
+ * once - alert the first time, then disarm.
+ * repeat - alert every time the server checks.
+ * on_change - alert each time the condition goes from unmet to met.
  */
-export type rideConfig = { rideName: string, alertOn: alertOption }
+export type alertMode = "once" | "repeat" | "on_change"
+
+/**
+ * An alert config for one ride. lastAlerted is an ISO date set by the server, and is ignored when registering.
+ */
+export type rideConfig = { rideName: string, alertOn: alertOption, mode?: alertMode, lastAlerted?: string | null }
 
 /**
  * Message format for communicating with SW. Set generic to discriminated union of possible message types.
@@ -7,6 +7,7 @@
  */
 
 import {Err, Ok, Result} from "ts-results";
+import * as localforage from 'localforage'
 import {AlertConfig} from "./alertConfig";
 
 /**
@@ -19,6 +20,84 @@ export type parkMap = Map<string, string>
  */
 export type rideTime = { name: string, status: "Open" | "Closed" | { Wait: number } }
 
+/**
+ * The servers copy of a registration. Dates are ISO strings.
+ */
+export type registration = { config: AlertConfig, expiresAt: string | null, snoozedUntil: string | null, vapidKey: string | null }
+
+/**
+ * A button to show on an alert notification. When pressed, the SW posts to route on the backend.
+ * minutes is the snooze length, or until expiry if unset. perRide actions only apply to the notifications ride.
+ */
+export type pushAction = { action: string, title: string, route: string, minutes?: number, perRide: boolean }
+
+/**
+ * A group of rides that met its rule, with the rides in it that met their condition.
+ */
+export type groupAlert = { name: string, rides: rideTime[] }
+
+/**
+ * How busy a park is. Waits are in minutes, and are null if no ride posts a wait, as is level.
+ */
+export type parkCrowd = { rides: number, open: number, openShare: number, averageWait: number | null, medianWait: number | null, level: "Low" | "Moderate" | "High" | "VeryHigh" | null }
+
+/**
+ * Content of a push from the server. Older servers send a bare rideTime array instead.
+ *
+ * alert - rides that met their alert condition, groups that met their rule, the parks crowd if it dropped below the level alerted on, and the actions to show on their notifications.
+ * expired - the registration has expired, and will receive no more alerts.
+ * test - sent by the servers operator to check that notifications work.
+ * resubscribe - we subscribed with a VAPID key the server has retired, and should resubscribe with key.
+ */
+export type pushPayload = { type: "alert", rides: rideTime[], groups?: groupAlert[], crowd?: parkCrowd, actions?: pushAction[], token?: string } | { type: "expired" } | { type: "test" } | { type: "resubscribe", key: string }
+
+/**
+ * Gets the headers for a JSON request to the backend, including the token proving we own our registration if we have one.
+ * The token is kept in IndexedDB so the SW can use it as well.
+ */
+export async function backendHeaders(): Promise<Record<string, string>> {
+    const token = await localforage.getItem<string>('token')
+
+    const headers: Record<string, string> = {'Content-type': 'application/json'}
+    if (token) {
+        headers['Authorization'] = `Bearer ${token}`
+    }
+
+    return headers
+}
+
+/**
+ * Converts a base64 encoded url safe byte array into a Uint8Array.
+ * @param base64String base64 encoded array.
+ */
+export function urlBase64ToUint8Array(base64String: string) {
+    const padding = '='.repeat((4 - base64String.length % 4) % 4);
+    const base64 = (base64String + padding)
+        .replace(/-/g, '+')
+        .replace(/_/g, '/');
+
+    const rawData = atob(base64);
+    const outputArray = new Uint8Array(rawData.length);
+
+    for (let i = 0; i < rawData.length; ++i) {
+        outputArray[i] = rawData.charCodeAt(i);
+    }
+    return outputArray;
+}
+
+/**
+ * Gets the base64 url safe VAPID public key a push subscription was made with, which the server needs to sign pushes to it.
+ */
+export function subscriptionKey(sub: PushSubscription): string | undefined {
+    const key = sub.options.applicationServerKey
+    if (!key) return undefined
+
+    return btoa(String.fromCharCode(...Array.from(new Uint8Array(key))))
+        .replace(/\+/g, '-')
+        .replace(/\//g, '_')
+        .replace(/=+$/, '')
+}
+
 /**
  * Provides access to the queue alert backend. Cannot be used in a serviceWorker.
  */
@@ -116,7 +195,7 @@ export default class QueueAlertAccess {
         if (!response.ok) return Err(new Error(`vapidPublicKey returned ${response.status}`))
 
         const vapidPublicKey = await response.text();
-        const convertedVapidKey = this.urlBase64ToUint8Array(vapidPublicKey);//Convert from base64 for chrome compatibility
+        const convertedVapidKey = urlBase64ToUint8Array(vapidPublicKey);//Convert from base64 for chrome compatibility
 
         const subscribeOptions = {
             userVisibleOnly: true,
@@ -159,20 +238,26 @@ export default class QueueAlertAccess {
             return Err(1)
         }
 
-        const body = JSON.stringify({sub: this.sub, config: config});
+        const body = JSON.stringify({sub: this.sub, config: config, vapidKey: subscriptionKey(this.sub)});
         console.debug(`attempting to register with: ${JSON.stringify(body)}`)
 
         try {
             const res = await fetch(this.url + '/register', {
                 method: 'post',
-                headers: {
-                    'Content-type': 'application/json'
-                },
+                headers: await backendHeaders(),
                 body: body,
             });
 
             if (res.ok) {
                 console.debug("registered with server");
+
+                //New registrations are given a token, which we need to update or remove them later
+                const resBody = await res.text()
+                if (resBody) {
+                    const {token} = JSON.parse(resBody) as { token: string }
+                    await localforage.setItem('token', token)
+                }
+
                 this.isRegistered = true
                 return Ok(null)
             } else {
@@ -183,6 +268,35 @@ export default class QueueAlertAccess {
         }
     }
 
+    /**
+     * Gets the servers copy of this users registration, which can be used to reconcile a local config with the server.
+     * @return registration if Ok, Err if any other http code, returns that other number. Err(1) if not subbed to push.
+     */
+    async getRegistrationFromBackend(): Promise<Result<registration, number>> {
+        const worker = await navigator.serviceWorker.getRegistration('/')
+        const sub = await worker?.pushManager.getSubscription()
+
+        if (sub == null) {
+            return Err(1)
+        }
+
+        try {
+            const res = await fetch(this.url + '/registration/lookup', {
+                method: 'post',
+                headers: await backendHeaders(),
+                body: JSON.stringify({endpoint: sub.endpoint}),
+            });
+
+            if (res.ok) {
+                return Ok(await res.json() as registration)
+            } else {
+                return Err(res.status)
+            }
+        } catch (e) {
+            return Err(400)
+        }
+    }
+
     /**
      * Unregisters this user with the queue alert backend, causing the server to stop notifications.
      * @param subscription The subscription object associated with this user.
@@ -205,13 +319,12 @@ export default class QueueAlertAccess {
 
         const res = await fetch(this.url + '/unregister', {
             method: 'post',
-            headers: {
-                'Content-type': 'application/json'
-            },
+            headers: await backendHeaders(),
             body: JSON.stringify(this.sub),
         });
 
         if (res.ok) {
+            await localforage.removeItem('token')
 
             console.debug("unregistered with server");
             this.isRegistered = false
@@ -220,23 +333,4 @@ export default class QueueAlertAccess {
             return Err(res.status)
         }
     }
-
-    /**
-     * Converts a base64 encoded url safe byte array into a Uint8Array.
-     * @param base64String base64 encoded array.
-     */
-    urlBase64ToUint8Array(base64String: string) {
-        const padding = '='.repeat((4 - base64String.length % 4) % 4);
-        const base64 = (base64String + padding)
-            .replace(/-/g, '+')
-            .replace(/_/g, '/');
-
-        const rawData = window.atob(base64);
-        const outputArray = new Uint8Array(rawData.length);
-
-        for (let i = 0; i < rawData.length; ++i) {
-            outputArray[i] = rawData.charCodeAt(i);
-        }
-        return outputArray;
-    }
 }
\ No newline at end of file
@@ -108,11 +108,14 @@ const ConfigTable: React.FC<props> = ({ onSave }) => {
 
 
 function getProperAlertOnString(rideConfig: rideConfig) {
+    //One shot alerts are done once they have fired
+    const fired = rideConfig.mode === 'once' && rideConfig.lastAlerted ? ' (already alerted)' : ''
+
     if (typeof rideConfig.alertOn === 'string') {
-        return rideConfig.alertOn
+        return rideConfig.alertOn + fired
     }
     else {
-        return `wait under ${rideConfig.alertOn.wait} minutes`
+        return `wait under ${rideConfig.alertOn.wait} minutes` + fired
     }
 }
 
@@ -18,9 +18,9 @@ import {createHandlerBoundToURL, precacheAndRoute} from 'workbox-precaching';
 import {registerRoute} from 'workbox-routing';
 import {CacheFirst, StaleWhileRevalidate} from 'workbox-strategies';
 
-import {AlertConfig, alertConfigMessageType, rideConfig, swMessage} from "./api/alertConfig";
+import {AlertConfig, alertConfigMessageType, swMessage} from "./api/alertConfig";
 import {Mutex} from "async-mutex";
-import {rideTime} from "./api/queueAlertAccess";
+import {backendHeaders, groupAlert, parkCrowd, pushAction, pushPayload, rideTime, urlBase64ToUint8Array} from "./api/queueAlertAccess";
 import * as localforage from 'localforage'
 import {toByteArray} from 'base64-js'
 import {decompressSync, strFromU8} from "fflate";
@@ -103,6 +103,9 @@ self.addEventListener('message', (event) => {
 
 /** Actual Logic **/
 
+/** Url of the queue alert backend. Must match the url used in App.tsx. */
+const qaUrl = (!process.env.NODE_ENV || process.env.NODE_ENV === 'development') ? "http://localhost:8080" : "https://qalert.ealovega.dev"
+
 /**
  * Locks write access to `config` when config is being read.
  *
@@ -120,94 +123,142 @@ function loadConfig() {
     })
 }
 
-function handlePush(payload: rideTime[], config: AlertConfig | null) {
+//Use our own badges and tag each ride to avoid reporting the same ride more than once.
+const notificationConfig = {
+    icon: "/icons/queueLogo@0,33x.png",
+    badge: "/icons/apple-icon-72x72.png",
+}
+
+/**
+ * Notifies the user that the server has removed their expired registration.
+ */
+function handleExpired() {
+    return configMutex.runExclusive(async () => {
+        //Clear config so the app shows that nothing is being alerted on
+        await localforage.removeItem('config')
+
+        await (self as any).registration.showNotification('Alerts Ended', {
+            body: 'Your ride alerts have expired. Open Queue Alert to set up new ones.',
+            ...notificationConfig
+        })
+    })
+}
 
-    //Use our own badges and tag each ride to avoid reporting the same ride more than once.
-    const notificationConfig = {
-        icon: "/icons/queueLogo@0,33x.png",
-        badge: "/icons/apple-icon-72x72.png",
+/**
+ * Shows the notification an operator sends to check that pushes reach this device.
+ */
+function handleTest() {
+    return (self as any).registration.showNotification('Test Notification', {
+        body: 'Queue Alert can send you notifications.',
+        ...notificationConfig
+    })
+}
+
+/**
+ * Moves our push subscription to the servers new VAPID key, re-registering our current config with it.
+ * Subscriptions are bound to a key, so the server can only keep pushing to us on its retired key for so long.
+ */
+async function handleResubscribe(key: string) {
+    const old = await self.registration.pushManager.getSubscription()
+    const config = await loadConfig()
+    if (old == null || config == null) return
+
+    //Remove the registration for the old subscription, as it will stop working
+    await fetch(qaUrl + '/unregister', {
+        method: 'post',
+        headers: await backendHeaders(),
+        body: JSON.stringify(old),
+    })
+    await localforage.removeItem('token')
+    await old.unsubscribe()
+
+    const sub = await self.registration.pushManager.subscribe({
+        userVisibleOnly: true,
+        applicationServerKey: urlBase64ToUint8Array(key),
+    })
+
+    const res = await fetch(qaUrl + '/register', {
+        method: 'post',
+        headers: await backendHeaders(),
+        body: JSON.stringify({sub: sub, config: config, vapidKey: key}),
+    })
+
+    if (res.ok) {
+        const {token} = await res.json() as { token: string }
+        await localforage.setItem('token', token)
+    } else {
+        console.error(`resubscribe failed with ${res.status}`)
     }
+}
 
-    return configMutex.runExclusive(async () => {
-        console.debug("Starting handler")
-        console.debug(`Config: ${JSON.stringify(config)}`)
-        console.debug(`Payload: ${JSON.stringify(payload)}`)
+async function handlePush(payload: rideTime[], groups: groupAlert[], crowd: parkCrowd | undefined, actions: pushAction[]) {
+    //Buttons and the data needed to handle them, for a rides notification
+    const actionConfig = (rideName: string) => ({
+        actions: actions.map(a => ({action: a.action, title: a.title})),
+        data: {rideName: rideName, actions: actions},
+    })
+
+    console.debug("Starting handler")
+    console.debug(`Payload: ${JSON.stringify(payload)}`)
 
-        let notified = false
+    let notified = false
 
-        let rideConfigs: null | rideConfig[]
-        if (config == null) {
-            rideConfigs = []
+    //The server decides which rides alert, and only sends those, so notify for every one
+    for (const ride of payload) {
+        let body: string
+        if (typeof ride.status !== "string") {
+            body = `${ride.name}'s wait is ${ride.status.Wait} minutes!`
+        } else if (ride.status === "Closed") {
+            body = `${ride.name} is Closed!`
         } else {
-            rideConfigs = config[1]
+            body = `${ride.name} is Open!`
         }
 
-        //Check times for all rides we're waiting on. The server will send a ride only if it will alert
-        for (const rideConf of rideConfigs) {
-            console.debug(`Checking ride ${rideConf.rideName} for ${rideConf.alertOn}`)
-
-            //Attempt to find same ride from server
-            let serverRide = payload.find(r => r.name === rideConf.rideName)
-            console.debug(`server equivalent: ${serverRide?.status}`)
-
-            //Skip if server didn't send ride/park
-            if (serverRide == null) continue
-
-            //Handle alert conditions
-            switch (rideConf.alertOn) {
-                case "Open":
-                    console.debug(`handling open case`)
-                    if (serverRide.status !== "Closed") {
-                        //Output current wait if we can
-                        if (typeof serverRide.status !== "string") {
-                            await (self as any).registration.showNotification('Ride Alert', {
-                                body: `${rideConf.rideName} is Open with a wait of ${serverRide.status.Wait} minutes!`,
-                                ...notificationConfig,
-                                tag: `${rideConf.rideName}`
-                            })
-                            notified = true
-                        } else {
-                            await (self as any).registration.showNotification('Ride Alert', {
-                                body: `${rideConf.rideName} is Open!`,
-                                ...notificationConfig,
-                                tag: `${rideConf.rideName}`
-                            })
-                            notified = true
-                        }
-                    }
-                    break;
-                case "Closed":
-                    console.debug(`handling closed case`)
-                    if (serverRide.status === "Closed") {
-                        await (self as any).registration.showNotification('Ride Alert', {
-                            body: `${rideConf.rideName} is Closed!`,
-                            ...notificationConfig,
-                            tag: `${rideConf.rideName}`
-                        })
-                        notified = true
-                    }
-                    break;
-                default: //Configured for a time
-                    console.debug(`handling waittime case`)
-                    if (typeof serverRide.status !== "string" && serverRide.status.Wait <= rideConf.alertOn.wait) {
-                        await (self as any).registration.showNotification('Ride Alert', {
-                            body: `${rideConf.rideName}'s wait is ${serverRide.status.Wait} minutes!`,
-                            ...notificationConfig,
-                            tag: `${rideConf.rideName}`
-                        })
-                        notified = true
-                    }
-                    break;
-            }
-        }
+        await (self as any).registration.showNotification('Ride Alert', {
+            body: body,
+            ...notificationConfig,
+            ...actionConfig(ride.name),
+            tag: `${ride.name}`
+        })
+        notified = true
+    }
 
-        if (!notified) {
-            await (self as any).registration.showNotification('Oops', {
-                body: 'Somehow you managed to get a config desynced from the server! Heres a notification so Apple devices wont disable the app. Theres no need for you to do anything, this should resolve itself.',
-                ...notificationConfig
-            })
-        }
-    });
+    //Groups are only sent when they alert, so notify for every one. Per ride actions don't apply to a group
+    for (const group of groups) {
+        const groupActions = actions.filter(a => !a.perRide)
+        const rides = group.rides.map(r => typeof r.status !== "string" ? `${r.name} (${r.status.Wait} min)` : `${r.name} (${r.status})`)
+
+        await (self as any).registration.showNotification('Group Alert', {
+            body: `${group.name}: ${rides.join(', ')}`,
+            ...notificationConfig,
+            actions: groupActions.map(a => ({action: a.action, title: a.title})),
+            data: {rideName: group.name, actions: groupActions},
+            tag: `group ${group.name}`
+        })
+        notified = true
+    }
+
+    //Crowd alerts are only sent when they alert too
+    if (crowd) {
+        const crowdActions = actions.filter(a => !a.perRide)
+        const level = crowd.level === "VeryHigh" ? "very high" : (crowd.level ?? "Low").toLowerCase()
+
+        await (self as any).registration.showNotification('Crowd Alert', {
+            body: `Crowds are ${level}, with an average wait of ${Math.round(crowd.averageWait ?? 0)} minutes`,
+            ...notificationConfig,
+            actions: crowdActions.map(a => ({action: a.action, title: a.title})),
+            data: {actions: crowdActions},
+            tag: 'crowd'
+        })
+        notified = true
+    }
+
+    if (!notified) {
+        await (self as any).registration.showNotification('Oops', {
+            body: 'Somehow you managed to get a config desynced from the server! Heres a notification so Apple devices wont disable the app. Theres no need for you to do anything, this should resolve itself.',
+            ...notificationConfig
+        })
+    }
 }
 
 /**
@@ -218,12 +269,31 @@ function handlePush(payload: rideTime[], config: AlertConfig | null) {
     const unBase64 = toByteArray(event.data.text())
     const raw = decompressSync(unBase64)
 
-    //Recreate and parse the JSON
-    const payload = JSON.parse(strFromU8(raw)) as rideTime[]
+    //Recreate and parse the JSON. Older servers send just the ride array.
+    const parsed = JSON.parse(strFromU8(raw)) as pushPayload | rideTime[]
+    const payload: pushPayload = Array.isArray(parsed) ? {type: "alert", rides: parsed} : parsed
 
-    console.debug("received push from server")
+    console.debug(`received ${payload.type} push from server`)
 
-    event.waitUntil(loadConfig().then(config => handlePush(payload, config)))
+    switch (payload.type) {
+        case "alert":
+            //Registrations made before tokens existed are sent one with their next alert
+            if (payload.token) {
+                event.waitUntil(localforage.setItem('token', payload.token))
+            }
+
+            event.waitUntil(handlePush(payload.rides, payload.groups ?? [], payload.crowd, payload.actions ?? []))
+            break
+        case "expired":
+            event.waitUntil(handleExpired())
+            break
+        case "test":
+            event.waitUntil(handleTest())
+            break
+        case "resubscribe":
+            event.waitUntil(handleResubscribe(payload.key))
+            break
+    }
 });
 
 /**
@@ -264,12 +334,42 @@ self.addEventListener('message', async (event) => {
 })
 
 /**
- * Open the app when a notification is clicked, and remove notification.
+ * Posts a notification action to the backend, such as snoozing alerts.
+ */
+async function handleAction(action: pushAction, rideName: string) {
+    const sub = await self.registration.pushManager.getSubscription()
+    if (sub == null) return
+
+    const res = await fetch(qaUrl + action.route, {
+        method: 'post',
+        headers: await backendHeaders(),
+        body: JSON.stringify({
+            endpoint: sub.endpoint,
+            minutes: action.minutes,
+            rideName: action.perRide ? rideName : undefined,
+        }),
+    })
+
+    if (!res.ok) console.error(`${action.action} failed with ${res.status}`)
+}
+
+/**
+ * Open the app when a notification is clicked, and remove notification. If an action button was clicked, handle it instead.
  */
 self.addEventListener('notificationclick', async (event) => {
     const clickedNotification = event.notification;
     clickedNotification.close();
 
+    if (event.action) {
+        const data = clickedNotification.data as { rideName: string, actions: pushAction[] } | null
+        const action = data?.actions.find(a => a.action === event.action)
+
+        if (action) {
+            event.waitUntil(handleAction(action, data!.rideName))
+        }
+        return
+    }
+
     const urlToOpen = new URL('/', self.location.origin).href;
 
     const promiseChain = self.clients.matchAll({
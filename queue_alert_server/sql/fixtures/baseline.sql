@@ -0,0 +1,36 @@
+-- A db created by the server before migrations were versioned, with a couple of registrations.
+CREATE TABLE IF NOT EXISTS REGISTRATIONS
+(
+    endpoint          TEXT NOT NULL,
+    subscription_info TEXT NOT NULL,
+    created_at        DATE NOT NULL,
+    PRIMARY KEY (endpoint)
+);
+
+CREATE TABLE IF NOT EXISTS CONFIGS
+(
+    endpoint TEXT NOT NULL,
+    park     TEXT NOT NULL,
+    PRIMARY KEY (endpoint),
+    FOREIGN KEY (endpoint) REFERENCES REGISTRATIONS (endpoint) ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+CREATE TABLE IF NOT EXISTS RIDEALERTS
+(
+    endpoint TEXT NOT NULL,
+    ridename TEXT NOT NULL,
+    alerton  TEXT NOT NULL CHECK ( alerton in ('open', 'closed', 'wait') ),
+    -- Null if alerton is not wait, set otherwise
+    wait     INTEGER CHECK ( wait is null AND alerton not in ('wait') OR wait is not null AND alerton in ('wait')),
+    PRIMARY KEY (endpoint, ridename),
+    FOREIGN KEY (endpoint) REFERENCES CONFIGS (endpoint) ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+INSERT INTO REGISTRATIONS VALUES ('https://push.example.com/a', '{"endpoint":"https://push.example.com/a","keys":{"p256dh":"p256dh","auth":"auth"}}', '2023-07-01');
+INSERT INTO CONFIGS VALUES ('https://push.example.com/a', 'Cedar Point');
+INSERT INTO RIDEALERTS VALUES ('https://push.example.com/a', 'Maverick', 'wait', 30);
+INSERT INTO RIDEALERTS VALUES ('https://push.example.com/a', 'Steel Vengeance', 'open', NULL);
+
+INSERT INTO REGISTRATIONS VALUES ('https://push.example.com/b', '{"endpoint":"https://push.example.com/b","keys":{"p256dh":"p256dh","auth":"auth"}}', '2023-07-02');
+INSERT INTO CONFIGS VALUES ('https://push.example.com/b', 'Kings Island');
+INSERT INTO RIDEALERTS VALUES ('https://push.example.com/b', 'The Beast', 'closed', NULL);
@@ -0,0 +1,24 @@
+-- Synthetic registrations for benchmarking loading, run against a migrated db. Each has three rides, and every other
+-- registration has an alert window on the whole config and on one ride.
+WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 50000)
+INSERT INTO REGISTRATIONS (endpoint, subscription_info, created_at, expires_at, vapid_key)
+SELECT 'https://push.example.com/' || i,
+       json_object('endpoint', 'https://push.example.com/' || i, 'keys', json_object('p256dh', 'p256dh', 'auth', 'auth')),
+       date(), datetime('now', '+3 days'), 'key'
+FROM n;
+
+INSERT INTO CONFIGS SELECT endpoint, 'Cedar Point' FROM REGISTRATIONS;
+
+INSERT INTO RIDEALERTS (endpoint, ridename, alerton, wait, mode)
+SELECT endpoint, 'Maverick', 'wait', 30, 'once' FROM REGISTRATIONS;
+INSERT INTO RIDEALERTS (endpoint, ridename, alerton, wait, mode)
+SELECT endpoint, 'Steel Vengeance', 'open', NULL, 'repeat' FROM REGISTRATIONS;
+INSERT INTO RIDEALERTS (endpoint, ridename, alerton, wait, mode)
+SELECT endpoint, 'Millennium Force', 'closed', NULL, 'on_change' FROM REGISTRATIONS;
+
+INSERT INTO CONFIGWINDOWS
+SELECT endpoint, '10:00:00', '22:00:00', 'America/New_York', 'Sat,Sun', NULL, NULL
+FROM REGISTRATIONS WHERE rowid % 2 = 0;
+INSERT INTO RIDEWINDOWS
+SELECT endpoint, 'Maverick', '18:00:00', '01:00:00', 'America/New_York', '', NULL, NULL
+FROM REGISTRATIONS WHERE rowid % 2 = 0;
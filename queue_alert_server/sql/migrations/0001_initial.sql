@@ -1,3 +1,4 @@
+-- Schema from before migrations were versioned. Existing tables are kept as is.
 CREATE TABLE IF NOT EXISTS REGISTRATIONS
 (
     endpoint          TEXT NOT NULL,
@@ -23,4 +24,4 @@ CREATE TABLE IF NOT EXISTS RIDEALERTS
     wait     INTEGER CHECK ( wait is null AND alerton not in ('wait') OR wait is not null AND alerton in ('wait')),
     PRIMARY KEY (endpoint, ridename),
     FOREIGN KEY (endpoint) REFERENCES CONFIGS (endpoint) ON DELETE CASCADE ON UPDATE CASCADE
-)
\ No newline at end of file
+);
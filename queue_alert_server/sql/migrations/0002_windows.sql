@@ -0,0 +1,27 @@
+CREATE TABLE CONFIGWINDOWS
+(
+    endpoint   TEXT NOT NULL,
+    start_time TEXT NOT NULL,
+    end_time   TEXT NOT NULL,
+    timezone   TEXT NOT NULL,
+    -- Comma separated weekdays, eg. 'Sat,Sun'. Empty for every day
+    days       TEXT NOT NULL,
+    start_date TEXT,
+    end_date   TEXT,
+    PRIMARY KEY (endpoint),
+    FOREIGN KEY (endpoint) REFERENCES CONFIGS (endpoint) ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+CREATE TABLE RIDEWINDOWS
+(
+    endpoint   TEXT NOT NULL,
+    ridename   TEXT NOT NULL,
+    start_time TEXT NOT NULL,
+    end_time   TEXT NOT NULL,
+    timezone   TEXT NOT NULL,
+    days       TEXT NOT NULL,
+    start_date TEXT,
+    end_date   TEXT,
+    PRIMARY KEY (endpoint, ridename),
+    FOREIGN KEY (endpoint, ridename) REFERENCES RIDEALERTS (endpoint, ridename) ON DELETE CASCADE ON UPDATE CASCADE
+);
@@ -0,0 +1,4 @@
+ALTER TABLE REGISTRATIONS ADD COLUMN expires_at TEXT;
+-- Registrations made before expiry existed get the default three days from now, rather than from when they were
+-- created, so upgrading does not expire every existing registration at once
+UPDATE REGISTRATIONS SET expires_at = datetime('now', '+3 days');
@@ -0,0 +1,3 @@
+-- Null if not snoozed
+ALTER TABLE REGISTRATIONS ADD COLUMN snoozed_until TEXT;
+ALTER TABLE RIDEALERTS ADD COLUMN snoozed_until TEXT;
@@ -0,0 +1,3 @@
+ALTER TABLE RIDEALERTS ADD COLUMN mode TEXT NOT NULL DEFAULT 'repeat' CHECK ( mode in ('once', 'repeat', 'on_change') );
+-- Null if never alerted
+ALTER TABLE RIDEALERTS ADD COLUMN last_alerted TEXT;
@@ -0,0 +1,2 @@
+-- Hash of the token proving ownership. Null for registrations made before tokens existed, until one is delivered
+ALTER TABLE REGISTRATIONS ADD COLUMN token_hash TEXT;
@@ -0,0 +1,2 @@
+-- Base64URL VAPID public key the client subscribed with
+ALTER TABLE REGISTRATIONS ADD COLUMN vapid_key TEXT;
@@ -0,0 +1,27 @@
+-- Alerts on several rides at once, when enough of them meet their conditions
+CREATE TABLE RIDEGROUPS
+(
+    endpoint     TEXT    NOT NULL,
+    name         TEXT    NOT NULL,
+    require      TEXT    NOT NULL CHECK ( require in ('any', 'all', 'at_least') ),
+    -- Null if require is not at_least, set otherwise
+    count        INTEGER CHECK ( count is null AND require not in ('at_least') OR count is not null AND require in ('at_least')),
+    mode         TEXT    NOT NULL DEFAULT 'repeat' CHECK ( mode in ('once', 'repeat', 'on_change') ),
+    -- Null if never alerted
+    last_alerted TEXT,
+    matched      INTEGER NOT NULL DEFAULT 0,
+    PRIMARY KEY (endpoint, name),
+    FOREIGN KEY (endpoint) REFERENCES CONFIGS (endpoint) ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+CREATE TABLE GROUPRIDES
+(
+    endpoint  TEXT NOT NULL,
+    groupname TEXT NOT NULL,
+    ridename  TEXT NOT NULL,
+    alerton   TEXT NOT NULL CHECK ( alerton in ('open', 'closed', 'wait') ),
+    -- Null if alerton is not wait, set otherwise
+    wait      INTEGER CHECK ( wait is null AND alerton not in ('wait') OR wait is not null AND alerton in ('wait')),
+    PRIMARY KEY (endpoint, groupname, ridename),
+    FOREIGN KEY (endpoint, groupname) REFERENCES RIDEGROUPS (endpoint, name) ON DELETE CASCADE ON UPDATE CASCADE
+);
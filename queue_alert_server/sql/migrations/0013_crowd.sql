@@ -0,0 +1,12 @@
+-- Alerts when the crowd at a park drops below a level
+CREATE TABLE CROWDALERTS
+(
+    endpoint     TEXT    NOT NULL,
+    below        TEXT    NOT NULL CHECK ( below in ('moderate', 'high', 'very_high') ),
+    mode         TEXT    NOT NULL DEFAULT 'repeat' CHECK ( mode in ('once', 'repeat', 'on_change') ),
+    -- Null if never alerted
+    last_alerted TEXT,
+    matched      INTEGER NOT NULL DEFAULT 0,
+    PRIMARY KEY (endpoint),
+    FOREIGN KEY (endpoint) REFERENCES CONFIGS (endpoint) ON DELETE CASCADE ON UPDATE CASCADE
+);
@@ -0,0 +1,69 @@
+-- Full schema as of SQLite migration 0007. Postgres support was added after versioning, so it starts here.
+CREATE TABLE REGISTRATIONS
+(
+    endpoint          TEXT NOT NULL,
+    subscription_info TEXT NOT NULL,
+    created_at        DATE NOT NULL DEFAULT CURRENT_DATE,
+    -- Always set by the server, including for imported registrations without one
+    expires_at        TIMESTAMPTZ,
+    -- Null if not snoozed
+    snoozed_until     TIMESTAMPTZ,
+    -- Hash of the token proving ownership. Null for registrations made before tokens existed, until one is delivered
+    token_hash        TEXT,
+    -- Base64URL VAPID public key the client subscribed with
+    vapid_key         TEXT,
+    PRIMARY KEY (endpoint)
+);
+
+CREATE TABLE CONFIGS
+(
+    endpoint TEXT NOT NULL,
+    park     TEXT NOT NULL,
+    PRIMARY KEY (endpoint),
+    FOREIGN KEY (endpoint) REFERENCES REGISTRATIONS (endpoint) ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+CREATE TABLE RIDEALERTS
+(
+    endpoint      TEXT    NOT NULL,
+    ridename      TEXT    NOT NULL,
+    -- Index of the ride in the clients config, as Postgres does not keep insertion order
+    position      INTEGER NOT NULL,
+    alerton       TEXT    NOT NULL CHECK ( alerton in ('open', 'closed', 'wait') ),
+    -- Null if alerton is not wait, set otherwise
+    wait          INTEGER CHECK ( wait is null AND alerton not in ('wait') OR wait is not null AND alerton in ('wait')),
+    snoozed_until TIMESTAMPTZ,
+    mode          TEXT    NOT NULL DEFAULT 'repeat' CHECK ( mode in ('once', 'repeat', 'on_change') ),
+    -- Null if never alerted
+    last_alerted  TIMESTAMPTZ,
+    PRIMARY KEY (endpoint, ridename),
+    FOREIGN KEY (endpoint) REFERENCES CONFIGS (endpoint) ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+CREATE TABLE CONFIGWINDOWS
+(
+    endpoint   TEXT NOT NULL,
+    start_time TIME NOT NULL,
+    end_time   TIME NOT NULL,
+    timezone   TEXT NOT NULL,
+    -- Comma separated weekdays, eg. 'Sat,Sun'. Empty for every day
+    days       TEXT NOT NULL,
+    start_date DATE,
+    end_date   DATE,
+    PRIMARY KEY (endpoint),
+    FOREIGN KEY (endpoint) REFERENCES CONFIGS (endpoint) ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+CREATE TABLE RIDEWINDOWS
+(
+    endpoint   TEXT NOT NULL,
+    ridename   TEXT NOT NULL,
+    start_time TIME NOT NULL,
+    end_time   TIME NOT NULL,
+    timezone   TEXT NOT NULL,
+    days       TEXT NOT NULL,
+    start_date DATE,
+    end_date   DATE,
+    PRIMARY KEY (endpoint, ridename),
+    FOREIGN KEY (endpoint, ridename) REFERENCES RIDEALERTS (endpoint, ridename) ON DELETE CASCADE ON UPDATE CASCADE
+);
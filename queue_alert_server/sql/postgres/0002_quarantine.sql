@@ -0,0 +1,9 @@
+-- Registrations that could not be loaded, moved out of the way so the server can still start
+CREATE TABLE QUARANTINE
+(
+    endpoint       TEXT        NOT NULL,
+    reason         TEXT        NOT NULL,
+    -- JSON of the registration, park and ride alerts as they were stored
+    data           TEXT        NOT NULL,
+    quarantined_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
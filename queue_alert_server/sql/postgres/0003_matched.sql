@@ -0,0 +1,2 @@
+-- If an on_change alert's condition was met when last checked. Saved on shutdown, so restarts do not repeat alerts
+ALTER TABLE RIDEALERTS ADD COLUMN matched BOOLEAN NOT NULL DEFAULT false;
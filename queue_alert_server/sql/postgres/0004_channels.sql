@@ -0,0 +1,2 @@
+-- JSON of the channel alerts are delivered over. Null for Web Push
+ALTER TABLE REGISTRATIONS ADD COLUMN channel TEXT;
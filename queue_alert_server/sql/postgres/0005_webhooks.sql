@@ -0,0 +1,15 @@
+-- Secret that webhook deliveries are signed with. Null for other channels
+ALTER TABLE REGISTRATIONS ADD COLUMN signing_secret TEXT;
+
+-- Webhook deliveries, for debugging. Not removed with their registration, so removed webhooks can still be debugged
+CREATE TABLE WEBHOOKDELIVERIES
+(
+    id           BIGSERIAL   PRIMARY KEY,
+    endpoint     TEXT        NOT NULL,
+    delivered_at TIMESTAMPTZ NOT NULL,
+    -- Type of payload delivered, eg. 'alert'
+    payload      TEXT        NOT NULL,
+    -- Null if delivered
+    error        TEXT
+);
+CREATE INDEX WEBHOOKDELIVERIES_ENDPOINT ON WEBHOOKDELIVERIES (endpoint);
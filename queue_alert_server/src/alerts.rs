@@ -0,0 +1,862 @@
+//! Alert rules.
+//!
+//! [`evaluate`] decides which rides, groups and crowd alerts alert, given a config, the latest wait times and the
+//! [`State`] left by earlier evaluations. It is pure, leaving callers to deliver the alerts and record the events it
+//! gives. Every way alerts are delivered evaluates through here, so they all follow the same rules.
+
+use crate::models::{
+    AlertMode, CrowdAlert, GroupConfig, GroupRequire, Registration, RideConfig, RideStatus,
+};
+use chrono::{DateTime, Utc};
+use queue_times::model::{ParkCrowd, RideTime};
+use std::collections::HashMap;
+
+/// The rules of a config.
+#[derive(Copy, Clone, Debug)]
+pub struct Rules<'c> {
+    pub rides: &'c [RideConfig],
+    pub groups: &'c [GroupConfig],
+    pub crowd: Option<&'c CrowdAlert>,
+}
+
+impl<'c> Rules<'c> {
+    /// Gets the rules of a registration.
+    pub fn of(reg: &'c Registration) -> Self {
+        Self {
+            rides: &reg.config.1,
+            groups: &reg.groups,
+            crowd: reg.crowd.as_ref(),
+        }
+    }
+
+    /// Rules of a config with only single rides.
+    pub fn rides(rides: &'c [RideConfig]) -> Self {
+        Self {
+            rides,
+            groups: &[],
+            crowd: None,
+        }
+    }
+}
+
+/// A park's wait times at some moment.
+#[derive(Copy, Clone, Debug)]
+pub struct Snapshot<'a> {
+    pub rides: &'a [RideTime],
+    /// Time the rides are evaluated at, which decides if windows and snoozes are active.
+    pub at: DateTime<Utc>,
+}
+
+/// What is remembered about a ride, group or crowd alert between evaluations.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct RuleState {
+    /// If the rule was met the last time it was evaluated.
+    pub matched: bool,
+    /// Last time an alert for the rule was delivered.
+    pub last_alerted: Option<DateTime<Utc>>,
+}
+
+/// State of the rides and groups in a config by name, and of its crowd alert. Those without any have never been
+/// evaluated.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct State {
+    rides: HashMap<String, RuleState>,
+    groups: HashMap<String, RuleState>,
+    crowd: RuleState,
+}
+
+impl State {
+    /// Reads the state kept on each ride, group and crowd alert of a registration's config.
+    pub fn of(rules: Rules) -> Self {
+        Self {
+            rides: rules
+                .rides
+                .iter()
+                .map(|rc| {
+                    (
+                        rc.ride_name.clone(),
+                        RuleState {
+                            matched: rc.matched,
+                            last_alerted: rc.last_alerted,
+                        },
+                    )
+                })
+                .collect(),
+            groups: rules
+                .groups
+                .iter()
+                .map(|group| {
+                    (
+                        group.name.clone(),
+                        RuleState {
+                            matched: group.matched,
+                            last_alerted: group.last_alerted,
+                        },
+                    )
+                })
+                .collect(),
+            crowd: rules
+                .crowd
+                .map(|crowd| RuleState {
+                    matched: crowd.matched,
+                    last_alerted: crowd.last_alerted,
+                })
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Gets the state of a ride.
+    pub fn ride(&self, ride_name: &str) -> RuleState {
+        self.rides.get(ride_name).copied().unwrap_or_default()
+    }
+
+    /// Gets the state of a group.
+    pub fn group(&self, name: &str) -> RuleState {
+        self.groups.get(name).copied().unwrap_or_default()
+    }
+
+    /// Gets the state of the crowd alert.
+    pub fn crowd(&self) -> RuleState {
+        self.crowd
+    }
+
+    /// Records the events of an evaluation, as if its alerts were delivered at `at`.
+    pub fn apply(&mut self, events: &[AlertEvent], at: DateTime<Utc>) {
+        for event in events {
+            match event {
+                AlertEvent::Alert(ride) => {
+                    self.rides
+                        .entry(ride.name.clone())
+                        .or_default()
+                        .last_alerted = Some(at);
+                }
+                AlertEvent::Changed { ride, matched } => {
+                    self.rides.entry(ride.to_string()).or_default().matched = *matched;
+                }
+                AlertEvent::GroupAlert { group, .. } => {
+                    self.groups.entry(group.clone()).or_default().last_alerted = Some(at);
+                }
+                AlertEvent::GroupChanged { group, matched } => {
+                    self.groups.entry(group.clone()).or_default().matched = *matched;
+                }
+                AlertEvent::CrowdAlert(_) => self.crowd.last_alerted = Some(at),
+                AlertEvent::CrowdChanged { matched } => self.crowd.matched = *matched,
+            }
+        }
+    }
+}
+
+/// Something that happened to a ride, group or crowd alert during an evaluation.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AlertEvent<'a> {
+    /// The ride should alert, with its current status.
+    Alert(&'a RideTime),
+    /// An on change ride's condition is now `matched`, having been the opposite last time. This must be recorded for
+    /// the next evaluation to alert correctly.
+    Changed { ride: &'a str, matched: bool },
+    /// The group met its rule and should alert, with the rides in it that met their conditions.
+    GroupAlert {
+        group: String,
+        rides: Vec<&'a RideTime>,
+    },
+    /// An on change group's rule is now `matched`, having been the opposite last time.
+    GroupChanged { group: String, matched: bool },
+    /// The park's crowd dropped below the level alerted on, and should alert with the park's current crowd.
+    CrowdAlert(ParkCrowd),
+    /// An on change crowd alert's condition is now `matched`, having been the opposite last time.
+    CrowdChanged { matched: bool },
+}
+
+/// Checks if a ride with `status` meets a condition of `alert_on`.
+pub fn is_met(alert_on: RideStatus, status: queue_times::model::RideStatus) -> bool {
+    match alert_on {
+        RideStatus::Open => !matches!(status, queue_times::model::RideStatus::Closed),
+        RideStatus::Closed => matches!(status, queue_times::model::RideStatus::Closed),
+        RideStatus::Wait(conf_t) => {
+            matches!(status, queue_times::model::RideStatus::Wait(stat_t) if conf_t >= stat_t)
+        }
+    }
+}
+
+/// Checks if a group of `total` rides meets `require`, when `met` of them meet their condition.
+pub fn is_group_met(require: GroupRequire, met: usize, total: usize) -> bool {
+    match require {
+        GroupRequire::Any => met > 0,
+        GroupRequire::All => total > 0 && met == total,
+        GroupRequire::AtLeast(count) => met >= (count as usize).max(1),
+    }
+}
+
+/// Checks if a park with `crowd` is below the level a crowd alert is `below`. Parks with no posted waits never are.
+pub fn is_crowd_met(below: queue_times::model::CrowdLevel, crowd: &ParkCrowd) -> bool {
+    crowd.level.is_some_and(|level| level < below)
+}
+
+/// Checks if a rule can still alert. `Once` alerts disarm after they are delivered.
+pub fn is_armed(mode: AlertMode, state: RuleState) -> bool {
+    !(mode == AlertMode::Once && state.last_alerted.is_some())
+}
+
+/// Checks if a rule should alert, given if it is `matched` now. On change alerts only alert if their rule was not met
+/// the last time it was evaluated.
+fn should_alert(mode: AlertMode, state: RuleState, matched: bool) -> bool {
+    matched && is_armed(mode, state) && (mode != AlertMode::OnChange || !state.matched)
+}
+
+/// Evaluates each ride in the rules that is active at the snapshot's time against its status in the snapshot, then
+/// each group, then the crowd alert. Ride events are in the order of the snapshot's rides, and group events in the order
+/// of the groups.
+pub fn evaluate<'a>(rules: Rules, snapshot: Snapshot<'a>, previous: &State) -> Vec<AlertEvent<'a>> {
+    let mut events = Vec::new();
+
+    for ride in snapshot.rides {
+        let Some(rc) = rules
+            .rides
+            .iter()
+            .find(|rc| rc.ride_name == ride.name && rc.is_active(snapshot.at))
+        else {
+            continue;
+        };
+        let state = previous.ride(&rc.ride_name);
+        let matched = is_met(rc.alert_on, ride.status);
+
+        if rc.mode == AlertMode::OnChange && state.matched != matched {
+            events.push(AlertEvent::Changed {
+                ride: &ride.name,
+                matched,
+            });
+        }
+        if should_alert(rc.mode, state, matched) {
+            events.push(AlertEvent::Alert(ride));
+        }
+    }
+
+    for group in rules.groups {
+        // Rides missing from the snapshot can't meet their condition
+        let met: Vec<_> = group
+            .rides
+            .iter()
+            .filter_map(|gr| {
+                snapshot
+                    .rides
+                    .iter()
+                    .find(|r| r.name == gr.ride_name && is_met(gr.alert_on, r.status))
+            })
+            .collect();
+
+        let state = previous.group(&group.name);
+        let matched = is_group_met(group.require, met.len(), group.rides.len());
+
+        if group.mode == AlertMode::OnChange && state.matched != matched {
+            events.push(AlertEvent::GroupChanged {
+                group: group.name.clone(),
+                matched,
+            });
+        }
+        if should_alert(group.mode, state, matched) {
+            events.push(AlertEvent::GroupAlert {
+                group: group.name.clone(),
+                rides: met,
+            });
+        }
+    }
+
+    if let Some(crowd_alert) = rules.crowd {
+        let crowd = ParkCrowd::new(snapshot.rides);
+        let state = previous.crowd();
+        let matched = is_crowd_met(crowd_alert.below, &crowd);
+
+        if crowd_alert.mode == AlertMode::OnChange && state.matched != matched {
+            events.push(AlertEvent::CrowdChanged { matched });
+        }
+        if should_alert(crowd_alert.mode, state, matched) {
+            events.push(AlertEvent::CrowdAlert(crowd));
+        }
+    }
+
+    events
+}
+
+/// Gets the rides that alert out of `events`.
+pub fn alerting<'a>(events: &[AlertEvent<'a>]) -> Vec<&'a RideTime> {
+    events
+        .iter()
+        .filter_map(|event| match event {
+            AlertEvent::Alert(ride) => Some(*ride),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Gets the groups that alert out of `events`, with the rides that met their rule.
+pub fn alerting_groups<'e, 'a>(events: &'e [AlertEvent<'a>]) -> Vec<(&'e str, &'e [&'a RideTime])> {
+    events
+        .iter()
+        .filter_map(|event| match event {
+            AlertEvent::GroupAlert { group, rides } => Some((group.as_str(), rides.as_slice())),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Gets the park's crowd out of `events`, if the crowd alert alerts.
+pub fn alerting_crowd<'e>(events: &'e [AlertEvent]) -> Option<&'e ParkCrowd> {
+    events.iter().find_map(|event| match event {
+        AlertEvent::CrowdAlert(crowd) => Some(crowd),
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::models::GroupRide;
+    use chrono::TimeZone;
+    use proptest::prelude::*;
+    use queue_times::model::CrowdLevel;
+    use queue_times::model::RideStatus as Status;
+
+    fn config(name: &str, alert_on: RideStatus, mode: AlertMode) -> RideConfig {
+        RideConfig {
+            ride_name: name.to_string(),
+            alert_on,
+            mode,
+            ..Default::default()
+        }
+    }
+
+    fn at() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2023, 7, 1, 16, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn test_is_met() {
+        let cases = [
+            (RideStatus::Open, Status::Open, true),
+            (RideStatus::Open, Status::Wait(45), true),
+            (RideStatus::Open, Status::Closed, false),
+            (RideStatus::Closed, Status::Open, false),
+            (RideStatus::Closed, Status::Wait(45), false),
+            (RideStatus::Closed, Status::Closed, true),
+            (RideStatus::Wait(30), Status::Wait(29), true),
+            (RideStatus::Wait(30), Status::Wait(30), true),
+            (RideStatus::Wait(30), Status::Wait(31), false),
+            (RideStatus::Wait(30), Status::Open, false),
+            (RideStatus::Wait(30), Status::Closed, false),
+        ];
+
+        for (alert_on, status, met) in cases {
+            assert_eq!(
+                is_met(alert_on, status),
+                met,
+                "{:?} on {:?}",
+                alert_on,
+                status
+            );
+        }
+    }
+
+    #[test]
+    fn test_once_disarms() {
+        let mut state = RuleState::default();
+        assert!(is_armed(AlertMode::Once, state));
+
+        state.last_alerted = Some(at());
+        assert!(!is_armed(AlertMode::Once, state));
+        assert!(is_armed(AlertMode::Repeat, state));
+        assert!(is_armed(AlertMode::OnChange, state));
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let config = [
+            config("Maverick", RideStatus::Wait(30), AlertMode::Repeat),
+            config("Steel Vengeance", RideStatus::Open, AlertMode::Once),
+            config("Magnum XL-200", RideStatus::Closed, AlertMode::Repeat),
+        ];
+        let rides = [
+            RideTime::new("Magnum XL-200", Status::Open),
+            RideTime::new("Maverick", Status::Wait(20)),
+            RideTime::new("Millennium Force", Status::Wait(5)),
+            RideTime::new("Steel Vengeance", Status::Wait(60)),
+        ];
+        let snapshot = Snapshot {
+            rides: &rides,
+            at: at(),
+        };
+
+        assert_eq!(
+            evaluate(Rules::rides(&config), snapshot, &State::default()),
+            vec![AlertEvent::Alert(&rides[1]), AlertEvent::Alert(&rides[3])]
+        );
+    }
+
+    #[test]
+    fn test_evaluate_once() {
+        let config = [config("Maverick", RideStatus::Open, AlertMode::Once)];
+        let rides = [RideTime::new("Maverick", Status::Open)];
+        let snapshot = Snapshot {
+            rides: &rides,
+            at: at(),
+        };
+        let mut state = State::default();
+
+        let events = evaluate(Rules::rides(&config), snapshot, &state);
+        assert_eq!(events, vec![AlertEvent::Alert(&rides[0])]);
+
+        state.apply(&events, at());
+        assert!(evaluate(Rules::rides(&config), snapshot, &state).is_empty());
+    }
+
+    #[test]
+    fn test_evaluate_on_change() {
+        let config = [config(
+            "Maverick",
+            RideStatus::Wait(30),
+            AlertMode::OnChange,
+        )];
+        let under = [RideTime::new("Maverick", Status::Wait(20))];
+        let over = [RideTime::new("Maverick", Status::Wait(45))];
+        let mut state = State::default();
+
+        // Becoming met alerts once
+        let events = evaluate(
+            Rules::rides(&config),
+            Snapshot {
+                rides: &under,
+                at: at(),
+            },
+            &state,
+        );
+        assert_eq!(
+            events,
+            vec![
+                AlertEvent::Changed {
+                    ride: "Maverick",
+                    matched: true
+                },
+                AlertEvent::Alert(&under[0])
+            ]
+        );
+        state.apply(&events, at());
+        assert!(evaluate(
+            Rules::rides(&config),
+            Snapshot {
+                rides: &under,
+                at: at()
+            },
+            &state
+        )
+        .is_empty());
+
+        // Becoming unmet is only recorded, and rearms the alert
+        let events = evaluate(
+            Rules::rides(&config),
+            Snapshot {
+                rides: &over,
+                at: at(),
+            },
+            &state,
+        );
+        assert_eq!(
+            events,
+            vec![AlertEvent::Changed {
+                ride: "Maverick",
+                matched: false
+            }]
+        );
+        state.apply(&events, at());
+        assert_eq!(
+            alerting(&evaluate(
+                Rules::rides(&config),
+                Snapshot {
+                    rides: &under,
+                    at: at()
+                },
+                &state
+            )),
+            vec![&under[0]]
+        );
+    }
+
+    #[test]
+    fn test_evaluate_snoozed() {
+        let mut config = [config("Maverick", RideStatus::Open, AlertMode::Repeat)];
+        config[0].snoozed_until = Some(at() + chrono::Duration::minutes(30));
+        let rides = [RideTime::new("Maverick", Status::Open)];
+
+        assert!(evaluate(
+            Rules::rides(&config),
+            Snapshot {
+                rides: &rides,
+                at: at()
+            },
+            &State::default()
+        )
+        .is_empty());
+        assert_eq!(
+            evaluate(
+                Rules::rides(&config),
+                Snapshot {
+                    rides: &rides,
+                    at: at() + chrono::Duration::minutes(30)
+                },
+                &State::default()
+            ),
+            vec![AlertEvent::Alert(&rides[0])]
+        );
+    }
+
+    #[test]
+    fn test_state_of() {
+        let mut ride = config("Maverick", RideStatus::Open, AlertMode::OnChange);
+        ride.matched = true;
+        ride.last_alerted = Some(at());
+
+        let state = State::of(Rules::rides(&[ride]));
+        assert_eq!(
+            state.ride("Maverick"),
+            RuleState {
+                matched: true,
+                last_alerted: Some(at())
+            }
+        );
+        assert_eq!(state.ride("Steel Vengeance"), RuleState::default());
+    }
+
+    fn group(name: &str, rides: &[(&str, RideStatus)], require: GroupRequire) -> GroupConfig {
+        GroupConfig {
+            name: name.to_string(),
+            rides: rides
+                .iter()
+                .map(|(ride_name, alert_on)| GroupRide {
+                    ride_name: ride_name.to_string(),
+                    alert_on: *alert_on,
+                })
+                .collect(),
+            require,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_is_group_met() {
+        let cases = [
+            (GroupRequire::Any, 0, 3, false),
+            (GroupRequire::Any, 1, 3, true),
+            (GroupRequire::All, 2, 3, false),
+            (GroupRequire::All, 3, 3, true),
+            (GroupRequire::All, 0, 0, false),
+            (GroupRequire::AtLeast(2), 1, 3, false),
+            (GroupRequire::AtLeast(2), 2, 3, true),
+            (GroupRequire::AtLeast(0), 0, 3, false),
+        ];
+
+        for (require, met, total, group_met) in cases {
+            assert_eq!(
+                is_group_met(require, met, total),
+                group_met,
+                "{:?} with {} of {}",
+                require,
+                met,
+                total
+            );
+        }
+    }
+
+    #[test]
+    fn test_evaluate_groups() {
+        let groups = [
+            group(
+                "Coasters",
+                &[
+                    ("Maverick", RideStatus::Wait(30)),
+                    ("Millennium Force", RideStatus::Wait(30)),
+                    ("Steel Vengeance", RideStatus::Wait(30)),
+                ],
+                GroupRequire::AtLeast(2),
+            ),
+            group(
+                "Both open",
+                &[
+                    ("Maverick", RideStatus::Open),
+                    ("Top Thrill 2", RideStatus::Open),
+                ],
+                GroupRequire::All,
+            ),
+        ];
+        let rules = Rules {
+            rides: &[],
+            groups: &groups,
+            crowd: None,
+        };
+        let rides = [
+            RideTime::new("Maverick", Status::Wait(20)),
+            RideTime::new("Millennium Force", Status::Wait(45)),
+            RideTime::new("Steel Vengeance", Status::Wait(10)),
+        ];
+        let snapshot = Snapshot {
+            rides: &rides,
+            at: at(),
+        };
+
+        // Top Thrill 2 is missing from the snapshot, so can't count towards its group
+        assert_eq!(
+            evaluate(rules, snapshot, &State::default()),
+            vec![AlertEvent::GroupAlert {
+                group: "Coasters".to_string(),
+                rides: vec![&rides[0], &rides[2]]
+            }]
+        );
+    }
+
+    #[test]
+    fn test_evaluate_groups_on_change() {
+        let mut groups = [group(
+            "Coasters",
+            &[
+                ("Maverick", RideStatus::Wait(30)),
+                ("Steel Vengeance", RideStatus::Wait(30)),
+            ],
+            GroupRequire::Any,
+        )];
+        groups[0].mode = AlertMode::OnChange;
+        let rules = Rules {
+            rides: &[],
+            groups: &groups,
+            crowd: None,
+        };
+        let rides = [
+            RideTime::new("Maverick", Status::Wait(20)),
+            RideTime::new("Steel Vengeance", Status::Wait(45)),
+        ];
+        let snapshot = Snapshot {
+            rides: &rides,
+            at: at(),
+        };
+        let mut state = State::default();
+
+        let events = evaluate(rules, snapshot, &state);
+        assert_eq!(
+            events,
+            vec![
+                AlertEvent::GroupChanged {
+                    group: "Coasters".to_string(),
+                    matched: true
+                },
+                AlertEvent::GroupAlert {
+                    group: "Coasters".to_string(),
+                    rides: vec![&rides[0]]
+                }
+            ]
+        );
+
+        state.apply(&events, at());
+        assert_eq!(
+            state.group("Coasters"),
+            RuleState {
+                matched: true,
+                last_alerted: Some(at())
+            }
+        );
+        assert!(evaluate(rules, snapshot, &state).is_empty());
+    }
+
+    #[test]
+    fn test_evaluate_crowd() {
+        let crowd = CrowdAlert {
+            below: CrowdLevel::Moderate,
+            mode: AlertMode::OnChange,
+            ..Default::default()
+        };
+        let rules = Rules {
+            rides: &[],
+            groups: &[],
+            crowd: Some(&crowd),
+        };
+        let quiet = [
+            RideTime::new("Maverick", Status::Wait(20)),
+            RideTime::new("Millennium Force", Status::Wait(5)),
+            RideTime::new("Top Thrill 2", Status::Closed),
+        ];
+        let busy = [
+            RideTime::new("Maverick", Status::Wait(60)),
+            RideTime::new("Millennium Force", Status::Wait(5)),
+        ];
+        let mut state = State::default();
+
+        let events = evaluate(
+            rules,
+            Snapshot {
+                rides: &quiet,
+                at: at(),
+            },
+            &state,
+        );
+        assert_eq!(
+            events,
+            vec![
+                AlertEvent::CrowdChanged { matched: true },
+                AlertEvent::CrowdAlert(ParkCrowd::new(&quiet))
+            ]
+        );
+        assert_eq!(
+            alerting_crowd(&events).and_then(|c| c.level),
+            Some(CrowdLevel::Low)
+        );
+
+        state.apply(&events, at());
+        assert_eq!(
+            state.crowd(),
+            RuleState {
+                matched: true,
+                last_alerted: Some(at())
+            }
+        );
+        let busy_events = evaluate(
+            rules,
+            Snapshot {
+                rides: &busy,
+                at: at(),
+            },
+            &state,
+        );
+        assert_eq!(
+            busy_events,
+            vec![AlertEvent::CrowdChanged { matched: false }]
+        );
+
+        // A closed park isn't quiet, it has no crowd at all
+        let closed = [RideTime::new("Maverick", Status::Closed)];
+        assert!(!is_crowd_met(
+            CrowdLevel::VeryHigh,
+            &ParkCrowd::new(&closed)
+        ));
+    }
+
+    fn alert_on() -> impl Strategy<Value = RideStatus> {
+        prop_oneof![
+            Just(RideStatus::Open),
+            Just(RideStatus::Closed),
+            (0..120u16).prop_map(RideStatus::Wait),
+        ]
+    }
+
+    fn status() -> impl Strategy<Value = Status> {
+        prop_oneof![
+            Just(Status::Open),
+            Just(Status::Closed),
+            (0..120u16).prop_map(Status::Wait),
+        ]
+    }
+
+    fn mode() -> impl Strategy<Value = AlertMode> {
+        prop_oneof![
+            Just(AlertMode::Once),
+            Just(AlertMode::Repeat),
+            Just(AlertMode::OnChange),
+        ]
+    }
+
+    /// Names from a small pool, so configs and snapshots overlap.
+    fn name() -> impl Strategy<Value = String> {
+        (0..6u8).prop_map(|n| format!("Ride {}", n))
+    }
+
+    fn configs() -> impl Strategy<Value = Vec<RideConfig>> {
+        prop::collection::vec(
+            (name(), alert_on(), mode())
+                .prop_map(|(name, alert_on, mode)| config(&name, alert_on, mode)),
+            0..6,
+        )
+    }
+
+    fn rides() -> impl Strategy<Value = Vec<RideTime>> {
+        prop::collection::btree_map(name(), status(), 0..6).prop_map(|rides| {
+            rides
+                .into_iter()
+                .map(|(name, status)| RideTime { name, status })
+                .collect()
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn prop_alerts_are_configured_and_met(config in configs(), rides in rides()) {
+            let events = evaluate(Rules::rides(&config), Snapshot { rides: &rides, at: at() }, &State::default());
+
+            for ride in alerting(&events) {
+                let rc = config.iter().find(|rc| rc.ride_name == ride.name).unwrap();
+                prop_assert!(is_met(rc.alert_on, ride.status));
+            }
+        }
+
+        #[test]
+        fn prop_rides_alert_at_most_once(config in configs(), rides in rides()) {
+            let events = evaluate(Rules::rides(&config), Snapshot { rides: &rides, at: at() }, &State::default());
+            let alerting = alerting(&events);
+
+            for ride in &alerting {
+                prop_assert_eq!(alerting.iter().filter(|r| r.name == ride.name).count(), 1);
+            }
+        }
+
+        #[test]
+        fn prop_repeat_alerts_every_time(config in configs(), rides in rides()) {
+            let config: Vec<_> = config.into_iter().map(|rc| RideConfig { mode: AlertMode::Repeat, ..rc }).collect();
+            let snapshot = Snapshot { rides: &rides, at: at() };
+            let mut state = State::default();
+
+            let events = evaluate(Rules::rides(&config), snapshot, &state);
+            state.apply(&events, at());
+            prop_assert_eq!(evaluate(Rules::rides(&config), snapshot, &state), events);
+        }
+
+        #[test]
+        fn prop_unchanged_snapshot_only_repeats(config in configs(), rides in rides()) {
+            let snapshot = Snapshot { rides: &rides, at: at() };
+            let mut state = State::default();
+
+            let events = evaluate(Rules::rides(&config), snapshot, &state);
+            state.apply(&events, at());
+
+            // Once alerts have disarmed and on change alerts have nothing new, so only repeat alerts are left
+            for event in evaluate(Rules::rides(&config), snapshot, &state) {
+                let AlertEvent::Alert(ride) = event else {
+                    return Err(TestCaseError::fail(format!("state changed again: {:?}", event)));
+                };
+                let rc = config.iter().find(|rc| rc.ride_name == ride.name).unwrap();
+                prop_assert_eq!(rc.mode, AlertMode::Repeat);
+            }
+        }
+
+        #[test]
+        fn prop_group_alerts_name_met_rides(rides in rides(), members in prop::collection::vec((name(), alert_on()), 1..6), count in 1..6u16) {
+            let members: Vec<_> = members.iter().map(|(name, alert_on)| (name.as_str(), *alert_on)).collect();
+            let groups = [
+                group("Any", &members, GroupRequire::Any),
+                group("All", &members, GroupRequire::All),
+                group("At least", &members, GroupRequire::AtLeast(count)),
+            ];
+            let rules = Rules { rides: &[], groups: &groups, crowd: None };
+            let events = evaluate(rules, Snapshot { rides: &rides, at: at() }, &State::default());
+
+            for (name, met) in alerting_groups(&events) {
+                let group = groups.iter().find(|g| g.name == name).unwrap();
+                prop_assert!(!met.is_empty());
+                prop_assert!(is_group_met(group.require, met.len(), group.rides.len()));
+                for ride in met {
+                    prop_assert!(group.rides.iter().any(|gr| gr.ride_name == ride.name && is_met(gr.alert_on, ride.status)));
+                }
+            }
+        }
+
+        #[test]
+        fn prop_higher_wait_threshold_still_met(limit in 0..120u16, extra in 0..120u16, status in status()) {
+            if is_met(RideStatus::Wait(limit), status) {
+                prop_assert!(is_met(RideStatus::Wait(limit + extra), status));
+                prop_assert!(is_met(RideStatus::Open, status));
+            }
+        }
+    }
+}
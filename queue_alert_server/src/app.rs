@@ -1,11 +1,86 @@
-use crate::models::{Keys, RideStatus};
+use crate::alerts::{self, AlertEvent, Rules, Snapshot, State};
+use crate::channel::{Channels, SendError};
+use crate::config::Config;
+use crate::history::WaitHistory;
+use crate::metrics::{MeasuredClient, Metrics};
+use crate::models::{Channel, GroupAlert, PushPayload, Registration, VapidKeys, ALERT_ACTIONS};
 use crate::registration::RegistrationRepository;
-use flate2::write::GzEncoder;
-use flate2::Compression;
-use queue_times::client::QueueTimesClient;
-use std::io::Write;
+use crate::store::Delivery;
+use crate::token;
+use chrono::{DateTime, Utc};
+use dashmap::DashSet;
+use queue_times::api::ApiClient;
+use queue_times::client::{CachedClient, QueueTimesClient};
+use queue_times::model::{ParkCrowd, RideTime};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
-use web_push::{ContentEncoding, WebPushClient, WebPushError, WebPushMessageBuilder};
+use tokio::sync::watch;
+use tokio::task::{JoinHandle, JoinSet};
+use web_push::WebPushError;
+
+/// Days that webhook deliveries are kept in the delivery log.
+pub const DELIVERY_LOG_DAYS: i64 = 7;
+
+/// Shared caching queue times client, with its calls to the site measured.
+pub type QueueClient = CachedClient<MeasuredClient<ApiClient>>;
+
+/// An alert the push loop decided to send, owning what it sends so it can be delivered after the tick.
+struct PendingAlert {
+    reg: Registration,
+    rides: Vec<RideTime>,
+    /// Names of the groups that alert, and the rides in each that met their condition
+    groups: Vec<(String, Vec<RideTime>)>,
+    crowd: Option<ParkCrowd>,
+    /// Rides whose on change condition is newly met, recorded only once delivered
+    matched: Vec<String>,
+    /// Groups whose on change rule is newly met, recorded only once delivered
+    groups_matched: Vec<String>,
+    /// If the on change crowd alert is newly met, recorded only once delivered
+    crowd_matched: bool,
+    /// Token for a registration from before tokens existed
+    new_token: Option<String>,
+}
+
+/// Changes to alert state made by sending alerts, recorded together once they are sent.
+#[derive(Default)]
+struct AlertLog {
+    //Subs to remove after a send fails. Endpoints are unique, so they are used as an ID.
+    to_remove: Vec<String>,
+    //Endpoints and the rides they were alerted for
+    alerted: Vec<(String, Vec<String>)>,
+    //Endpoints and rides whose on change condition flipped
+    matches: Vec<(String, String, bool)>,
+    //Endpoints and the groups they were alerted for
+    groups_alerted: Vec<(String, Vec<String>)>,
+    //Endpoints and groups whose on change rule flipped
+    group_matches: Vec<(String, String, bool)>,
+    //Endpoints that were alerted about their park's crowd
+    crowds_alerted: Vec<String>,
+    //Endpoints whose on change crowd alert flipped
+    crowd_matches: Vec<(String, bool)>,
+    //Endpoints and the hash of the token delivered to them
+    tokens: Vec<(String, String)>,
+}
+
+/// Marks a webhook as having a delivery running until dropped, so deliveries that panic or are cancelled don't stop
+/// it being alerted again.
+struct InFlight {
+    app: Arc<Application>,
+    endpoint: String,
+}
+
+impl InFlight {
+    fn new(app: Arc<Application>, endpoint: String) -> Self {
+        app.webhooks_in_flight.insert(endpoint.clone());
+        Self { app, endpoint }
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.app.webhooks_in_flight.remove(&self.endpoint);
+    }
+}
 
 /// Application state.
 ///
@@ -14,41 +89,212 @@ pub struct Application {
     /// All client registrations
     pub subs: RegistrationRepository,
     /// Queue times scraper
-    pub queue_client: queue_times::client::CachedClient<queue_times::api::ApiClient>,
-    /// Web push client
-    pub push_client: Box<dyn WebPushClient + Send + Sync>,
+    pub queue_client: QueueClient,
+    /// Channels that alerts are delivered over
+    pub channels: Channels,
     /// ECDH keys used for vapid
-    pub keys: Keys,
+    pub keys: Arc<VapidKeys>,
+    /// Server configuration
+    pub config: Config,
+    /// Prometheus metrics
+    pub metrics: Metrics,
+    /// Typical wait times, learned from the cache
+    pub history: WaitHistory,
+    /// When the application was created
+    pub started_at: DateTime<Utc>,
+    /// When the push loop last finished a tick
+    last_tick: Mutex<Option<DateTime<Utc>>>,
+    /// Webhook deliveries running in the background
+    deliveries: Mutex<JoinSet<()>>,
+    /// Endpoints of the webhooks with a delivery running
+    webhooks_in_flight: DashSet<String>,
+    /// Set to true once the server starts shutting down
+    shutdown: watch::Sender<bool>,
 }
 
 impl Application {
     pub fn new(
         subs: RegistrationRepository,
-        queue_client: queue_times::client::CachedClient<queue_times::api::ApiClient>,
-        push_client: Box<dyn WebPushClient + Send + Sync>,
-        keys: Keys,
+        queue_client: QueueClient,
+        channels: Channels,
+        keys: Arc<VapidKeys>,
+        config: Config,
+        metrics: Metrics,
     ) -> Self {
         Self {
             subs,
             queue_client,
-            push_client,
+            channels,
             keys,
+            config,
+            metrics,
+            history: WaitHistory::new(),
+            started_at: Utc::now(),
+            last_tick: Mutex::new(None),
+            deliveries: Mutex::new(JoinSet::new()),
+            webhooks_in_flight: DashSet::new(),
+            shutdown: watch::Sender::new(false),
         }
     }
 
-    /// Spins infinitely, sending push notifications to registered clients if their ride is ready.
-    pub async fn push_loop(&self) {
-        let mut timer = tokio::time::interval(Duration::from_secs(60));
+    /// Starts the push, cleanup and history loops. They run until [`Self::shutdown`].
+    pub fn spawn_loops(self: &Arc<Self>) -> Vec<JoinHandle<()>> {
+        let push_app = self.clone();
+        let cleanup_app = self.clone();
+        let history_app = self.clone();
 
-        loop {
-            timer.tick().await;
+        vec![
+            // Check client configs and send push notifications on a timer
+            tokio::spawn(async move { push_app.push_loop().await }),
+            // Remove expired registrations, and move clients off of retired keys
+            tokio::spawn(async move { cleanup_app.cleanup_loop().await }),
+            // Learn typical wait times
+            tokio::spawn(async move { history_app.history_loop().await }),
+        ]
+    }
+
+    /// Stops the loops started by [`Self::spawn_loops`], then saves any state kept in memory and closes the store.
+    ///
+    /// Loops stop sending once they finish the push in progress, and record what they have done so far. Webhook
+    /// deliveries already started are finished. Anything still running after `deadline` is cancelled.
+    pub async fn shutdown(&self, loops: Vec<JoinHandle<()>>, deadline: Duration) {
+        self.begin_shutdown();
+
+        let until = tokio::time::Instant::now() + deadline;
+        for mut handle in loops {
+            if tokio::time::timeout_at(until, &mut handle).await.is_err() {
+                log::warn!("A background task did not stop in time, cancelling it");
+                handle.abort();
+            }
+        }
+        if tokio::time::timeout_at(until, self.finish_deliveries())
+            .await
+            .is_err()
+        {
+            log::warn!("Webhook deliveries did not finish in time, cancelling them");
+        }
 
+        if let Err(why) = self.subs.close().await {
+            log::error!("Couldn't save registrations while shutting down: {}", why);
+        }
+    }
+
+    /// Tells the loops and live streams to stop, without waiting for them. [`Self::shutdown`] does this too.
+    pub fn begin_shutdown(&self) {
+        self.shutdown.send_replace(true);
+    }
+
+    /// True once [`Self::shutdown`] is called.
+    fn shutting_down(&self) -> bool {
+        *self.shutdown.borrow()
+    }
+
+    /// Watches for [`Self::shutdown`], for tasks started outside [`Self::spawn_loops`].
+    pub fn subscribe_shutdown(&self) -> watch::Receiver<bool> {
+        self.shutdown.subscribe()
+    }
+
+    /// Waits for the next tick of `timer`. Returns false instead if the server starts shutting down first.
+    async fn tick(&self, timer: &mut tokio::time::Interval) -> bool {
+        let mut shutdown = self.shutdown.subscribe();
+
+        tokio::select! {
+            _ = timer.tick() => !self.shutting_down(),
+            _ = shutdown.wait_for(|down| *down) => false,
+        }
+    }
+
+    /// Spins until shutdown, sending push notifications to registered clients if their ride is ready.
+    pub async fn push_loop(self: &Arc<Self>) {
+        let mut timer = tokio::time::interval(self.config.push_interval());
+
+        while self.tick(&mut timer).await {
+            let tick_timer = self.metrics.tick_duration.start_timer();
+            self.refresh_wait_times().await;
             self.push_to_clients().await;
+            tick_timer.observe_duration();
+
+            *self.last_tick.lock().unwrap() = Some(Utc::now());
+        }
+    }
+
+    /// When the push loop last finished a tick, if it has yet.
+    pub fn last_tick(&self) -> Option<DateTime<Utc>> {
+        *self.last_tick.lock().unwrap()
+    }
+
+    /// Has the cache refresh wait times once they are stale, even if no registrations need them. Otherwise wait times
+    /// only refresh when asked for, and an idle server would look like it could not reach the queue times site.
+    async fn refresh_wait_times(&self) {
+        let parks = match self.queue_client.get_park_urls().await {
+            Ok(parks) => parks,
+            Err(why) => {
+                log::debug!("While refreshing parks: {}", why);
+                return;
+            }
+        };
+
+        // Any park will do, as the cache refreshes every park at once
+        if let Some(url) = parks.into_values().next() {
+            if let Err(why) = self.queue_client.get_ride_times(url).await {
+                log::debug!("While refreshing rides: {}", why);
+            }
+        }
+    }
+
+    /// Records the wait times of every park after each refresh of the cache, until shutdown.
+    pub async fn history_loop(&self) {
+        let mut refreshed = self.queue_client.subscribe();
+        let mut shutdown = self.shutdown.subscribe();
+
+        // The cache may have filled before we started watching it
+        let filled = refreshed.borrow_and_update().is_some();
+        if filled {
+            self.record_history().await;
+        }
+
+        loop {
+            let changed = tokio::select! {
+                changed = refreshed.changed() => changed.is_ok(),
+                _ = shutdown.wait_for(|down| *down) => false,
+            };
+            if !changed {
+                break;
+            }
+
+            self.record_history().await;
+        }
+    }
+
+    /// Records the cached wait times of every park in [`Self::history`].
+    async fn record_history(&self) {
+        let parks = match self.queue_client.get_park_urls().await {
+            Ok(parks) => parks,
+            Err(why) => {
+                log::debug!("While recording wait history: {}", why);
+                return;
+            }
+        };
+
+        let now = Utc::now();
+        for (park, url) in parks {
+            match self.queue_client.get_ride_times(url).await {
+                Ok(rides) => self.history.record(&park, &rides, now),
+                Err(why) => log::debug!("While recording wait history for {}: {}", park, why),
+            }
         }
     }
 
     /// Sends notifications to clients if their ride is ready.
-    async fn push_to_clients(&self) {
+    ///
+    /// Web pushes are sent during the tick. Webhooks may retry for a while, so each is delivered by a task of its own,
+    /// and records what it delivered when done. Webhooks still delivering an earlier alert are skipped.
+    async fn push_to_clients(self: &Arc<Self>) {
+        //Only one server sharing a store sends alerts
+        if !self.subs.lead().await {
+            return;
+        }
+
         //Skip if no subscribers
         if self.subs.get_current_user_count() == 0 {
             return;
@@ -62,17 +308,33 @@ impl Application {
         }
         let parks = parks.unwrap();
 
-        let subs = &self.subs;
-        //Subs to remove after a send fails. Endpoints are unique, so they are used as an ID.
-        let mut subs_to_remove: Vec<String> = Vec::new();
+        let mut log = AlertLog::default();
 
         log::info!(
             "checking if we should push to {} clients",
-            subs.get_current_user_count()
+            self.subs.get_current_user_count()
         );
 
+        let now = Utc::now();
+
+        // Clone out of the cache so we don't hold its lock while sending. Skip clients that expired or are outside
+        // their alert window.
+        let active: Vec<_> = self
+            .subs
+            .cache
+            .iter()
+            .filter(|r| r.is_active(now) && !self.webhooks_in_flight.contains(r.key()))
+            .map(|r| r.clone())
+            .collect();
+
         //Push to all clients, if they have a ride ready
-        for sub in subs.cache.iter() {
+        for sub in active {
+            // Stop sending, but still record what was sent so far
+            if self.shutting_down() {
+                log::info!("Stopping alerts early to shut down");
+                break;
+            }
+
             let url = parks.get(&sub.config.0);
             if url.is_none() {
                 log::error!("Submitted invalid park {}", sub.config.0);
@@ -91,79 +353,787 @@ impl Application {
             }
             let rides = rides.unwrap();
 
+            // Check every ride, group and crowd alert the client has configured against the current statuses
+            let snapshot = Snapshot {
+                rides: &rides,
+                at: now,
+            };
+            let rules = Rules::of(&sub);
+            for rc in rules.rides {
+                if let Some(ride) = rides.iter().find(|r| r.name == rc.ride_name) {
+                    log::debug!("config: {:?} server_time: {:?}", rc.alert_on, ride.status);
+                }
+            }
+            let events = alerts::evaluate(rules, snapshot, &State::of(rules));
+
             // Get all rides to send, which are all rides the client will alert on. This is done so we dont send a push where the client will not notify.
-            let rides = rides.iter()
-                .filter_map(|r| sub.config.1.iter().find(|rc| rc.ride_name == r.name).map(|rc| (rc, r)))
-                .filter(|(ride_conf, ride_stat)| {
-                    log::debug!("config: {:?} server_time: {:?}", ride_conf.alert_on, ride_stat.status);
-                    // Only keep rides the user will alert on
-                    match ride_conf.alert_on {
-                        RideStatus::Open => !matches!(ride_stat.status, queue_times::model::RideStatus::Closed),
-                        RideStatus::Closed => matches!(ride_stat.status, queue_times::model::RideStatus::Closed),
-                        RideStatus::Wait(conf_t) => matches!(ride_stat.status, queue_times::model::RideStatus::Wait(stat_t) if conf_t >= stat_t)
+            let mut alert = PendingAlert {
+                rides: alerts::alerting(&events).into_iter().cloned().collect(),
+                groups: alerts::alerting_groups(&events)
+                    .into_iter()
+                    .map(|(name, rides)| {
+                        (name.to_string(), rides.iter().map(|&r| r.clone()).collect())
+                    })
+                    .collect(),
+                crowd: alerts::alerting_crowd(&events).cloned(),
+                matched: Vec::new(),
+                groups_matched: Vec::new(),
+                crowd_matched: false,
+                // Registrations from before tokens existed get one with their next alert
+                new_token: sub.token_hash.is_none().then(token::generate),
+                reg: sub,
+            };
+
+            // On change alerts need to know if their condition was met last time. A newly met condition only counts
+            // once its alert is delivered, so a failed send is tried again next tick.
+            let endpoint = &alert.reg.sub.endpoint;
+            for event in &events {
+                match *event {
+                    AlertEvent::Changed {
+                        ride,
+                        matched: false,
+                    } => log
+                        .matches
+                        .push((endpoint.clone(), ride.to_string(), false)),
+                    AlertEvent::Changed {
+                        ride,
+                        matched: true,
+                    } if alert.rides.iter().any(|r| r.name == ride) => {
+                        alert.matched.push(ride.to_string())
+                    }
+                    AlertEvent::GroupChanged {
+                        ref group,
+                        matched: false,
+                    } => log
+                        .group_matches
+                        .push((endpoint.clone(), group.clone(), false)),
+                    AlertEvent::GroupChanged {
+                        ref group,
+                        matched: true,
+                    } if alert.groups.iter().any(|(name, _)| name == group) => {
+                        alert.groups_matched.push(group.clone())
+                    }
+                    AlertEvent::CrowdChanged { matched: false } => {
+                        log.crowd_matches.push((endpoint.clone(), false))
                     }
-                })
-                // Reduce back to the ride-statuses we want to send to the client
-                .map(|(_, rs)| rs)
-                .collect::<Vec<_>>();
+                    AlertEvent::CrowdChanged { matched: true } => {
+                        alert.crowd_matched = alert.crowd.is_some()
+                    }
+                    _ => {}
+                }
+            }
 
             // If nothing to send to client, continue.
-            if rides.is_empty() {
+            if alert.rides.is_empty() && alert.groups.is_empty() && alert.crowd.is_none() {
                 continue;
             }
 
-            let mut builder = WebPushMessageBuilder::new(&sub.sub);
-
-            let content = serde_json::to_string(&rides).unwrap();
+            if let Channel::Webhook { .. } = alert.reg.channel {
+                self.spawn_webhook_delivery(alert, now);
+            } else {
+                self.deliver(&alert, &mut log).await;
+            }
+        }
 
-            //Compress JSON with gzip
-            let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
-            encoder.write_all(content.as_bytes()).unwrap();
-            let content = encoder.finish().unwrap();
+        self.record(log, now).await;
+    }
 
-            //Web push will reject non text payloads, so base64 encode
-            let content = base64::encode(&content);
+    /// Delivers an alert to a webhook in the background, then records what was delivered.
+    fn spawn_webhook_delivery(self: &Arc<Self>, alert: PendingAlert, at: DateTime<Utc>) {
+        let in_flight = InFlight::new(self.clone(), alert.reg.sub.endpoint.clone());
 
-            log::debug!("Content size: {} bytes", content.len());
+        let mut deliveries = self.deliveries.lock().unwrap();
+        // Forget deliveries that have finished
+        while deliveries.try_join_next().is_some() {}
+        deliveries.spawn(async move {
+            let app = &in_flight.app;
+            let mut log = AlertLog::default();
+            app.deliver(&alert, &mut log).await;
+            app.record(log, at).await;
+        });
+    }
 
-            //*Must* set vapid signature, else the push will be rejected
-            builder
-                .set_vapid_signature(self.keys.2.clone().add_sub_info(&sub.sub).build().unwrap());
-            builder.set_payload(ContentEncoding::Aes128Gcm, content.as_bytes());
+    /// Waits for background webhook deliveries to finish and record what they delivered.
+    pub async fn finish_deliveries(&self) {
+        let mut deliveries = std::mem::take(&mut *self.deliveries.lock().unwrap());
+        while deliveries.join_next().await.is_some() {}
+    }
 
-            let message = builder.build();
+    /// Sends an alert, adding what was delivered to `log`, or the registration if it should be removed.
+    async fn deliver(&self, alert: &PendingAlert, log: &mut AlertLog) {
+        let sub = &alert.reg;
+        let groups: Vec<_> = alert
+            .groups
+            .iter()
+            .map(|(name, rides)| GroupAlert {
+                name,
+                rides: rides.iter().collect(),
+            })
+            .collect();
 
-            //Message will fail if too large.
-            if let Err(why) = message {
-                if why == WebPushError::PayloadTooLarge {
-                    log::error!("Payload for message was too large!");
+        //Send the message
+        if let Err(why) = self
+            .send(
+                sub,
+                &PushPayload::Alert {
+                    rides: alert.rides.iter().collect(),
+                    groups,
+                    crowd: alert.crowd.as_ref(),
+                    actions: &ALERT_ACTIONS,
+                    token: alert.new_token.as_deref(),
+                },
+            )
+            .await
+        {
+            match why {
+                //Add expired endpoints to removal list
+                why if why.is_gone() => {
+                    log.to_remove.push(sub.sub.endpoint.clone());
+                    log::info!("Added expired endpoint for removal.");
                 }
-            }
-            //Send the message
-            else if let Err(why) = self.push_client.send(message.unwrap()).await {
-                match why {
-                    //Add expired endpoints to removal list
-                    WebPushError::EndpointNotValid => {
-                        let endpoint = sub.sub.endpoint.clone();
-                        subs_to_remove.push(endpoint);
-                        log::info!("Added expired endpoint for removal.");
-                    }
-                    WebPushError::EndpointNotFound => {
-                        let endpoint = sub.sub.endpoint.clone();
-                        subs_to_remove.push(endpoint);
-                        log::info!("Added expired endpoint for removal.");
+                SendError::WebPush(WebPushError::PayloadTooLarge) => {
+                    log::error!("Payload for message was too large!")
+                }
+                _ => {
+                    log::error!(
+                        "When sending {} alert to client: {}",
+                        sub.channel.name(),
+                        why
+                    );
+
+                    // Webhooks that keep failing are unlikely to start working again
+                    if self.keeps_failing(sub).await {
+                        log.to_remove.push(sub.sub.endpoint.clone());
+                        log::warn!(
+                            "Removing webhook {} after {} failed deliveries in a row",
+                            sub.sub.endpoint,
+                            self.config.webhook_max_failures
+                        );
                     }
-                    _ => log::error!("When sending webpush to client: {}", why),
                 }
             }
+        } else {
+            if let Some(new_token) = &alert.new_token {
+                log.tokens
+                    .push((sub.sub.endpoint.clone(), token::hash(new_token)));
+            }
+
+            if !alert.rides.is_empty() {
+                log.alerted.push((
+                    sub.sub.endpoint.clone(),
+                    alert.rides.iter().map(|r| r.name.clone()).collect(),
+                ));
+            }
+            if !alert.groups.is_empty() {
+                log.groups_alerted.push((
+                    sub.sub.endpoint.clone(),
+                    alert.groups.iter().map(|(name, _)| name.clone()).collect(),
+                ));
+            }
+            if alert.crowd.is_some() {
+                log.crowds_alerted.push(sub.sub.endpoint.clone());
+            }
+
+            let endpoint = &sub.sub.endpoint;
+            log.matches.extend(
+                alert
+                    .matched
+                    .iter()
+                    .map(|ride| (endpoint.clone(), ride.clone(), true)),
+            );
+            log.group_matches.extend(
+                alert
+                    .groups_matched
+                    .iter()
+                    .map(|group| (endpoint.clone(), group.clone(), true)),
+            );
+            if alert.crowd_matched {
+                log.crowd_matches.push((endpoint.clone(), true));
+            }
+        }
+    }
+
+    /// Records the alert state changes in `log`, as of alerts delivered at `at`.
+    async fn record(&self, log: AlertLog, at: DateTime<Utc>) {
+        let subs = &self.subs;
+
+        // Only require tokens once they have been delivered
+        for (endpoint, hash) in log.tokens {
+            if let Err(err) = subs.set_token_hash(&endpoint, hash).await {
+                log::error!("Error: {} when setting token for {}", err, endpoint);
+            }
+        }
+
+        // Record changes to alert state
+        for (endpoint, ride, matched) in log.matches {
+            subs.set_matched(&endpoint, &ride, matched);
+        }
+        if !log.alerted.is_empty() {
+            if let Err(err) = subs.mark_alerted(&log.alerted, at).await {
+                log::error!("Error: {} when recording delivered alerts", err);
+            }
+        }
+        for (endpoint, group, matched) in log.group_matches {
+            subs.set_group_matched(&endpoint, &group, matched);
+        }
+        if !log.groups_alerted.is_empty() {
+            if let Err(err) = subs.mark_groups_alerted(&log.groups_alerted, at).await {
+                log::error!("Error: {} when recording delivered group alerts", err);
+            }
+        }
+        for (endpoint, matched) in log.crowd_matches {
+            subs.set_crowd_matched(&endpoint, matched);
+        }
+        if !log.crowds_alerted.is_empty() {
+            if let Err(err) = subs.mark_crowd_alerted(&log.crowds_alerted, at).await {
+                log::error!("Error: {} when recording delivered crowd alerts", err);
+            }
         }
 
         // Remove bad endpoints
-        for rm_sub in subs_to_remove {
+        for rm_sub in log.to_remove {
             match subs.remove_registration(&rm_sub).await {
                 Ok(_) => log::info!("Removed stale endpoint {}", rm_sub),
                 Err(err) => log::error!("Error: {} when removing endpoints", err),
             }
         }
     }
+
+    /// Spins until shutdown, removing expired registrations and asking clients on retired keys to resubscribe.
+    pub async fn cleanup_loop(&self) {
+        let mut timer = tokio::time::interval(Duration::from_secs(60 * 10));
+
+        while self.tick(&mut timer).await {
+            self.remove_expired().await;
+            self.nudge_old_keys().await;
+            self.prune_deliveries().await;
+        }
+    }
+
+    /// Removes webhook deliveries older than [`DELIVERY_LOG_DAYS`] from the delivery log.
+    async fn prune_deliveries(&self) {
+        let before = Utc::now() - chrono::Duration::days(DELIVERY_LOG_DAYS);
+
+        match self.subs.prune_deliveries(before).await {
+            Ok(0) => {}
+            Ok(pruned) => log::info!("Pruned {} old webhook deliveries", pruned),
+            Err(why) => log::error!("Error: {} when pruning webhook deliveries", why),
+        }
+    }
+
+    /// Removes all expired registrations, sending each a final notification that their alerts have ended.
+    async fn remove_expired(&self) {
+        if !self.subs.lead().await {
+            return;
+        }
+
+        let expired = self.subs.expired_endpoints(Utc::now());
+
+        if expired.is_empty() {
+            return;
+        }
+
+        log::info!("Removing {} expired registrations", expired.len());
+
+        for endpoint in expired {
+            if self.shutting_down() {
+                break;
+            }
+
+            // Clone out of the cache so we don't hold its lock while sending
+            let reg = match self.subs.cache.get(&endpoint) {
+                Some(reg) => reg.clone(),
+                None => continue,
+            };
+
+            if let Err(why) = self.send(&reg, &PushPayload::Expired).await {
+                log::info!("Could not send expiry notice to {}: {}", endpoint, why);
+            }
+
+            match self.subs.remove_registration(&endpoint).await {
+                Ok(_) => log::info!("Removed expired endpoint {}", endpoint),
+                Err(err) => log::error!("Error: {} when removing expired endpoint", err),
+            }
+        }
+    }
+
+    /// Asks every client that subscribed with a retired VAPID key to resubscribe with the current one. Clients that are
+    /// gone are removed.
+    pub async fn nudge_old_keys(&self) {
+        if !self.subs.lead().await {
+            return;
+        }
+
+        let current = self.keys.current_public();
+
+        // Clone out of the cache so we don't hold its lock while sending. Only Web Push uses keys.
+        let old: Vec<_> = self
+            .subs
+            .cache
+            .iter()
+            .filter(|r| r.channel == Channel::WebPush && r.vapid_key.as_deref() != Some(current))
+            .map(|r| r.clone())
+            .collect();
+
+        if old.is_empty() {
+            return;
+        }
+
+        log::info!("Asking {} clients on old keys to resubscribe", old.len());
+
+        for reg in old {
+            if self.shutting_down() {
+                break;
+            }
+
+            match self
+                .send(&reg, &PushPayload::Resubscribe { key: current })
+                .await
+            {
+                Ok(()) => {}
+                Err(why) if why.is_gone() => {
+                    match self.subs.remove_registration(&reg.sub.endpoint).await {
+                        Ok(_) => log::info!("Removed stale endpoint {}", reg.sub.endpoint),
+                        Err(err) => log::error!("Error: {} when removing endpoints", err),
+                    }
+                }
+                Err(why) => {
+                    log::info!("Could not ask {} to resubscribe: {}", reg.sub.endpoint, why)
+                }
+            }
+        }
+    }
+
+    /// Sends a notification with the passed payload to a client, over the channel it registered with.
+    pub async fn send(
+        &self,
+        reg: &Registration,
+        payload: &PushPayload<'_>,
+    ) -> Result<(), SendError> {
+        let res = self.channels.send(reg, payload).await;
+        self.metrics.record_push(&res);
+
+        if let Channel::Webhook { .. } = reg.channel {
+            let delivery = Delivery {
+                endpoint: reg.sub.endpoint.clone(),
+                delivered_at: Utc::now(),
+                payload: payload.kind().to_string(),
+                error: res.as_ref().err().map(|why| why.to_string()),
+            };
+            if let Err(why) = self.subs.log_delivery(&delivery).await {
+                log::error!(
+                    "Error: {} when logging delivery to {}",
+                    why,
+                    delivery.endpoint
+                );
+            }
+        }
+
+        res
+    }
+
+    /// Checks if the last `webhook_max_failures` deliveries to a webhook registration all failed.
+    async fn keeps_failing(&self, reg: &Registration) -> bool {
+        if !matches!(reg.channel, Channel::Webhook { .. }) {
+            return false;
+        }
+
+        let max_failures = self.config.webhook_max_failures.max(1) as usize;
+        match self
+            .subs
+            .deliveries(&reg.sub.endpoint, max_failures as i64)
+            .await
+        {
+            Ok(deliveries) => {
+                deliveries.len() == max_failures && deliveries.iter().all(|d| d.error.is_some())
+            }
+            Err(why) => {
+                log::error!(
+                    "Error: {} when reading deliveries to {}",
+                    why,
+                    reg.sub.endpoint
+                );
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use crate::channel::web_push::test as web_push_test;
+    use crate::models::AlertMode;
+    use crate::store::sqlite::test::TempDb;
+    use crate::store::sqlite::SqliteStore;
+    use crate::store::test::{registration, webhook_registration};
+    use crate::store::RegistrationStore;
+    use actix_web::{web, App, HttpResponse, HttpServer};
+    use async_trait::async_trait;
+    use tokio::sync::Notify;
+    use url::Url;
+    use web_push::{HyperWebPushClient, WebPushClient, WebPushMessage};
+
+    /// Push service that takes a while to reject every push, as if the endpoint expired.
+    struct SlowExpiredClient {
+        sending: Arc<Notify>,
+    }
+
+    #[async_trait]
+    impl WebPushClient for SlowExpiredClient {
+        async fn send(&self, _message: WebPushMessage) -> Result<(), WebPushError> {
+            self.sending.notify_one();
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            Err(WebPushError::EndpointNotValid)
+        }
+    }
+
+    /// Serves Cedar Point with Steel Vengeance open, in place of the queue times site.
+    pub fn stand_in_upstream() -> Url {
+        let server = HttpServer::new(|| {
+            App::new()
+                .route(
+                    "/en-US/parks.json",
+                    web::get().to(|| async {
+                        HttpResponse::Ok().json(serde_json::json!([
+                            {"name": "Cedar Fair", "parks": [{"id": 1, "name": "Cedar Point"}]}
+                        ]))
+                    }),
+                )
+                .route(
+                    "/en-US/parks/1/queue_times.json",
+                    web::get().to(|| async {
+                        HttpResponse::Ok().json(serde_json::json!({
+                            "lands": [],
+                            "rides": [{"name": "Steel Vengeance", "is_open": true, "wait_time": 10}]
+                        }))
+                    }),
+                )
+        })
+        .workers(1)
+        .disable_signals()
+        .bind("127.0.0.1:0")
+        .unwrap();
+
+        let url = Url::parse(&format!("http://{}/", server.addrs()[0])).unwrap();
+        tokio::spawn(server.run());
+        url
+    }
+
+    /// Generates a VAPID key, with no previous keys.
+    fn vapid_keys() -> Arc<VapidKeys> {
+        Arc::new(VapidKeys {
+            current: web_push_test::keys(),
+            previous: Vec::new(),
+        })
+    }
+
+    /// Builds an app with no registrations, storing them in `file`.
+    pub async fn stand_in_app(file: &TempDb, config: Config) -> Application {
+        stand_in_app_with(
+            file,
+            config,
+            Box::new(HyperWebPushClient::new()),
+            vapid_keys(),
+        )
+        .await
+    }
+
+    /// Builds an app like [`stand_in_app`], that sends web pushes with `push_client` signed by `keys`.
+    async fn stand_in_app_with(
+        file: &TempDb,
+        config: Config,
+        push_client: Box<dyn WebPushClient + Send + Sync>,
+        keys: Arc<VapidKeys>,
+    ) -> Application {
+        let metrics = Metrics::new();
+        let subs = RegistrationRepository::new(
+            metrics.measure_store(Box::new(SqliteStore::open(&file.0).await.unwrap())),
+        )
+        .await
+        .unwrap();
+        let queue_client = CachedClient::with_ttl(
+            metrics.measure_client(ApiClient::with_base_url(config.upstream_url.clone())),
+            config.cache_ttl(),
+        );
+        let channels = Channels::new(push_client, keys.clone(), &config).unwrap();
+
+        Application::new(subs, queue_client, channels, keys, config, metrics)
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_finishes_tick() {
+        let file = TempDb::new("shutdown");
+        let keys = vapid_keys();
+        let config = Config {
+            upstream_url: stand_in_upstream(),
+            ..Default::default()
+        };
+        let sending = Arc::new(Notify::new());
+        let client = SlowExpiredClient {
+            sending: sending.clone(),
+        };
+        let app = Arc::new(stand_in_app_with(&file, config, Box::new(client), keys.clone()).await);
+
+        // Pushes are encrypted for the client before sending, so it needs real keys
+        let reg = web_push_test::Browser::new()
+            .registration("https://push.example.com/a", Some(keys.current_public()));
+        app.subs.add_registration(reg).await.unwrap();
+
+        let loops = app.spawn_loops();
+
+        // Simulate a signal arriving while an alert is being sent
+        sending.notified().await;
+        app.shutdown(loops, Duration::from_secs(5)).await;
+
+        // The push finished, and the expired endpoint was removed before the store closed
+        assert!(app
+            .metrics
+            .render(&Default::default(), Default::default())
+            .contains("queue_alert_push_failures_total{error=\"endpoint_not_valid\"} 1"));
+        assert!(app.subs.ping().await.is_err());
+
+        let store = SqliteStore::open(&file.0).await.unwrap();
+        assert!(store
+            .load("https://push.example.com/a")
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    /// Builds an app with a single webhook registration, delivering to a stand in server that responds with
+    /// `status`.
+    async fn webhook_app(
+        file: &TempDb,
+        status: u16,
+        config: Config,
+    ) -> (
+        Arc<Application>,
+        Arc<Mutex<Vec<crate::channel::test::Received>>>,
+    ) {
+        let (hook_url, received) = crate::channel::test::recorder(status);
+        let config = Config {
+            upstream_url: stand_in_upstream(),
+            ..config
+        };
+        let app = stand_in_app(file, config).await;
+        app.subs
+            .add_registration(webhook_registration(&hook_url))
+            .await
+            .unwrap();
+
+        (Arc::new(app), received)
+    }
+
+    #[tokio::test]
+    async fn test_history_loop_records_refreshes() {
+        let file = TempDb::new("history");
+        let app = Arc::new(
+            stand_in_app(
+                &file,
+                Config {
+                    upstream_url: stand_in_upstream(),
+                    ..Default::default()
+                },
+            )
+            .await,
+        );
+        let loops = app.spawn_loops();
+
+        // The push loop refreshes the cache on its first tick
+        let mut refreshed = app.queue_client.subscribe();
+        refreshed.wait_for(|at| at.is_some()).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while app
+                .history
+                .typical("Cedar Point", "Steel Vengeance", Utc::now())
+                .is_none()
+            {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(
+            app.history
+                .typical("Cedar Point", "Steel Vengeance", Utc::now()),
+            Some(10.0)
+        );
+        app.shutdown(loops, Duration::from_secs(5)).await;
+    }
+
+    #[tokio::test]
+    async fn test_gone_webhook_is_removed() {
+        let file = TempDb::new("webhook_gone");
+        let (app, received) = webhook_app(&file, 410, Config::default()).await;
+
+        app.push_to_clients().await;
+        app.finish_deliveries().await;
+
+        assert_eq!(received.lock().unwrap().len(), 1);
+        assert_eq!(app.subs.get_current_user_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_failing_webhook_is_removed() {
+        let file = TempDb::new("webhook_failing");
+        let config = Config {
+            webhook_max_attempts: 2,
+            webhook_backoff_ms: 0,
+            webhook_max_failures: 2,
+            ..Default::default()
+        };
+        let (app, received) = webhook_app(&file, 500, config).await;
+        let endpoint = app.subs.cache.iter().next().unwrap().key().clone();
+
+        app.push_to_clients().await;
+        app.finish_deliveries().await;
+        assert_eq!(app.subs.get_current_user_count(), 1);
+        app.push_to_clients().await;
+        app.finish_deliveries().await;
+        assert_eq!(app.subs.get_current_user_count(), 0);
+
+        // Each delivery was retried once, and logged
+        assert_eq!(received.lock().unwrap().len(), 4);
+        let deliveries = app.subs.deliveries(&endpoint, 10).await.unwrap();
+        assert_eq!(deliveries.len(), 2);
+        assert_eq!(
+            deliveries[0].error.as_deref(),
+            Some("failed after 2 attempts: rejected with status 500 Internal Server Error")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_slow_webhook_does_not_hold_up_tick() {
+        let file = TempDb::new("webhook_slow");
+        let config = Config {
+            webhook_max_attempts: 3,
+            webhook_backoff_ms: 500,
+            ..Default::default()
+        };
+        let (app, received) = webhook_app(&file, 500, config).await;
+
+        // Retries take at least 1.5s, but the tick only starts the delivery
+        tokio::time::timeout(Duration::from_millis(500), app.push_to_clients())
+            .await
+            .unwrap();
+
+        // Registrations can change while the webhook is retrying
+        tokio::time::timeout(
+            Duration::from_millis(500),
+            app.subs
+                .add_registration(registration("https://push.example.com/other")),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+
+        // A webhook still delivering isn't sent another alert
+        let before = received.lock().unwrap().len();
+        app.push_to_clients().await;
+        app.finish_deliveries().await;
+        assert!(before < 3);
+        assert_eq!(received.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_webhook_is_alerted_again() {
+        let file = TempDb::new("webhook_cancelled");
+        let config = Config {
+            webhook_max_attempts: 3,
+            webhook_backoff_ms: 500,
+            ..Default::default()
+        };
+        let (app, received) = webhook_app(&file, 500, config).await;
+
+        app.push_to_clients().await;
+        assert_eq!(app.webhooks_in_flight.len(), 1);
+        app.deliveries.lock().unwrap().abort_all();
+        app.finish_deliveries().await;
+        assert!(app.webhooks_in_flight.is_empty());
+
+        // The next tick starts a new delivery
+        let before = received.lock().unwrap().len();
+        app.push_to_clients().await;
+        assert_eq!(app.webhooks_in_flight.len(), 1);
+        app.finish_deliveries().await;
+        assert_eq!(received.lock().unwrap().len(), before + 3);
+    }
+
+    /// Gets if the on change ride and crowd alerts of the only registration in `app` are matched.
+    fn on_change_matched(app: &Application) -> (bool, bool) {
+        let reg = app.subs.cache.iter().next().unwrap();
+        (reg.config.1[1].matched, reg.crowd.as_ref().unwrap().matched)
+    }
+
+    #[tokio::test]
+    async fn test_matched_only_after_delivery() {
+        for (status, delivered) in [(500, false), (200, true)] {
+            let file = TempDb::new(&format!("webhook_matched_{}", status));
+            let config = Config {
+                webhook_max_attempts: 1,
+                ..Default::default()
+            };
+            let (app, received) = webhook_app(&file, status, config).await;
+            app.subs.cache.iter_mut().next().unwrap().config.1[1].mode = AlertMode::OnChange;
+
+            app.push_to_clients().await;
+            app.finish_deliveries().await;
+            assert_eq!(received.lock().unwrap().len(), 1);
+            assert_eq!(on_change_matched(&app), (delivered, delivered));
+
+            // A failed alert is sent again, a delivered one isn't
+            app.push_to_clients().await;
+            app.finish_deliveries().await;
+            assert_eq!(
+                received.lock().unwrap().len(),
+                if delivered { 1 } else { 2 }
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_nudge_old_keys() {
+        let file = TempDb::new("nudge");
+        let keys = Arc::new(VapidKeys {
+            current: web_push_test::keys(),
+            previous: vec![web_push_test::keys()],
+        });
+        let current = keys.current_public().to_string();
+        let previous = keys.previous[0].0 .0.clone();
+        let old = "https://push.example.com/old";
+        let gone = "https://push.example.com/gone";
+        let client = web_push_test::RecordingClient::new(&[gone]);
+        let sent = client.sent.clone();
+        let app = stand_in_app_with(&file, Config::default(), Box::new(client), keys).await;
+
+        let browser = web_push_test::Browser::new();
+        for (endpoint, key) in [
+            (old, &previous),
+            (gone, &previous),
+            ("https://push.example.com/new", &current),
+        ] {
+            app.subs
+                .add_registration(browser.registration(endpoint, Some(key)))
+                .await
+                .unwrap();
+        }
+
+        app.nudge_old_keys().await;
+
+        // Only clients on the old key are asked to resubscribe, signed with the key they have
+        let sent = std::mem::take(&mut *sent.lock().unwrap());
+        assert_eq!(sent.len(), 2);
+        let message = sent.iter().find(|m| m.endpoint == old).unwrap();
+        assert_eq!(web_push_test::signed_with(message), previous);
+        assert_eq!(
+            browser.read(message),
+            serde_json::json!({"type": "resubscribe", "key": current})
+        );
+
+        assert!(app.subs.endpoint_is_registered(old));
+        assert!(!app.subs.endpoint_is_registered(gone));
+        assert_eq!(app.subs.get_current_user_count(), 2);
+    }
 }
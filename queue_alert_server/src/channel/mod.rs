@@ -0,0 +1,397 @@
+//! Channels that alerts are delivered over.
+//!
+//! Web Push is the default. Registrations can instead choose a webhook, an ntfy style topic or email, which lets
+//! kiosks and scripts get alerts without a browser.
+
+pub mod email;
+pub mod ntfy;
+pub mod web_push;
+pub mod webhook;
+
+use crate::config::Config;
+use crate::error::Error;
+use crate::models::{Channel, PushPayload, Registration, VapidKeys};
+use ::web_push::{WebPushClient, WebPushError};
+use async_trait::async_trait;
+use lettre::Address;
+use reqwest::StatusCode;
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use std::time::Duration;
+use url::Host;
+
+/// Delivers notifications to registrations.
+#[async_trait]
+pub trait NotificationChannel: Send + Sync {
+    /// Sends `payload` to the address of `reg`.
+    async fn send(&self, reg: &Registration, payload: &PushPayload<'_>) -> Result<(), SendError>;
+}
+
+/// Why a notification could not be delivered.
+#[derive(Debug, thiserror::Error)]
+pub enum SendError {
+    #[error(transparent)]
+    WebPush(#[from] WebPushError),
+    #[error("request failed: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("rejected with status {0}")]
+    Rejected(StatusCode),
+    #[error("invalid email: {0}")]
+    EmailMessage(#[from] lettre::error::Error),
+    #[error("smtp error: {0}")]
+    Smtp(#[from] lettre::transport::smtp::Error),
+    #[error("{0} alerts are not enabled on this server")]
+    Disabled(&'static str),
+    #[error("registration is on the {0} channel")]
+    WrongChannel(&'static str),
+    #[error("failed after {0} attempts: {1}")]
+    Retried(u32, Box<SendError>),
+}
+
+impl SendError {
+    /// True if the address will never accept notifications again, so its registration should be removed.
+    pub fn is_gone(&self) -> bool {
+        match self {
+            SendError::WebPush(WebPushError::EndpointNotValid)
+            | SendError::WebPush(WebPushError::EndpointNotFound) => true,
+            SendError::Rejected(status) => {
+                *status == StatusCode::NOT_FOUND || *status == StatusCode::GONE
+            }
+            // Such as a mailbox that does not exist
+            SendError::Smtp(why) => why.is_permanent(),
+            SendError::Retried(_, why) => why.is_gone(),
+            _ => false,
+        }
+    }
+
+    /// Short name of the error, for metrics.
+    pub fn short_description(&self) -> &'static str {
+        match self {
+            SendError::WebPush(why) => why.short_description(),
+            SendError::Http(_) => "request_failed",
+            SendError::Rejected(_) => "rejected",
+            SendError::EmailMessage(_) => "invalid_email",
+            SendError::Smtp(_) => "smtp_error",
+            SendError::Disabled(_) => "channel_disabled",
+            SendError::WrongChannel(_) => "wrong_channel",
+            SendError::Retried(_, why) => why.short_description(),
+        }
+    }
+}
+
+/// Every channel the server can deliver over.
+pub struct Channels {
+    pub web_push: web_push::WebPushChannel,
+    pub webhook: webhook::WebhookChannel,
+    pub ntfy: ntfy::NtfyChannel,
+    /// Unset unless SMTP is configured.
+    pub email: Option<email::EmailChannel>,
+}
+
+impl Channels {
+    /// Sets up every channel enabled by `config`. Web pushes are sent with `push_client`, signed by `keys`.
+    pub fn new(
+        push_client: Box<dyn WebPushClient + Send + Sync>,
+        keys: Arc<VapidKeys>,
+        config: &Config,
+    ) -> Result<Self, Error> {
+        let http = http_client();
+
+        Ok(Self {
+            web_push: web_push::WebPushChannel::new(push_client, keys),
+            webhook: webhook::WebhookChannel::new(
+                http.clone(),
+                config.webhook_max_attempts,
+                config.webhook_backoff(),
+            ),
+            ntfy: ntfy::NtfyChannel::new(http),
+            email: config
+                .smtp_url
+                .as_deref()
+                .map(|url| email::EmailChannel::new(url, &config.smtp_from))
+                .transpose()?,
+        })
+    }
+
+    /// Gets the channel that delivers to `channel`, if it is enabled.
+    pub fn get(&self, channel: &Channel) -> Option<&dyn NotificationChannel> {
+        match channel {
+            Channel::WebPush => Some(&self.web_push),
+            Channel::Webhook { .. } => Some(&self.webhook),
+            Channel::Ntfy { .. } => Some(&self.ntfy),
+            Channel::Email { .. } => self
+                .email
+                .as_ref()
+                .map(|email| email as &dyn NotificationChannel),
+        }
+    }
+
+    /// Sends `payload` to `reg` over the channel it chose.
+    pub async fn send(
+        &self,
+        reg: &Registration,
+        payload: &PushPayload<'_>,
+    ) -> Result<(), SendError> {
+        match self.get(&reg.channel) {
+            Some(channel) => channel.send(reg, payload).await,
+            None => Err(SendError::Disabled(reg.channel.name())),
+        }
+    }
+}
+
+/// Builds the client webhook and ntfy deliveries are sent with.
+///
+/// Redirects are not followed, as a public host could redirect to a private one that [`check_address`] rejects.
+/// They are rejected deliveries instead.
+fn http_client() -> reqwest::Client {
+    reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap()
+}
+
+/// Checks that `channel` may deliver to its address. Webhook and ntfy urls must use https, and must not be on this
+/// machine or a private network, unless `config` allows it. Email must be to an address `config` allows.
+///
+/// Only hosts written as addresses or `localhost` are caught, as names are not resolved.
+pub fn check_address(channel: &Channel, config: &Config) -> Result<(), String> {
+    let (url, kind) = match channel {
+        Channel::Webhook { url } => (url, "Webhooks"),
+        Channel::Ntfy { url } => (url, "Ntfy topics"),
+        Channel::Email { to } => return check_email(to, config),
+        Channel::WebPush => return Ok(()),
+    };
+
+    match url.scheme() {
+        "https" => {}
+        "http" if config.webhook_allow_http => {}
+        _ => return Err(format!("{} must use https", kind)),
+    }
+
+    match url.host() {
+        None => Err(format!("{} need a host", kind)),
+        Some(host) if is_private(&host) && !config.webhook_allow_private => {
+            Err(format!("{} can't be on a private network", kind))
+        }
+        Some(_) => Ok(()),
+    }
+}
+
+/// Checks that email may be sent to `to`, which must be one of the addresses or domains `config` allows.
+fn check_email(to: &Address, config: &Config) -> Result<(), String> {
+    let allowed = config
+        .smtp_allowed_to
+        .iter()
+        .any(|allowed| match allowed.strip_prefix('@') {
+            Some(domain) => to.domain().eq_ignore_ascii_case(domain),
+            None => to.to_string().eq_ignore_ascii_case(allowed),
+        });
+
+    if allowed {
+        Ok(())
+    } else {
+        Err("Email alerts can't be sent to this address".to_string())
+    }
+}
+
+/// True if `host` is loopback, private, link-local or otherwise not on the public internet.
+fn is_private(host: &Host<&str>) -> bool {
+    match host {
+        Host::Domain(name) => {
+            let name = name.trim_end_matches('.').to_ascii_lowercase();
+            name == "localhost" || name.ends_with(".localhost")
+        }
+        Host::Ipv4(ip) => is_private_v4(*ip),
+        Host::Ipv6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_private_v4(ip),
+            None => {
+                ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_unique_local()
+                    || ip.is_unicast_link_local()
+            }
+        },
+    }
+}
+
+/// True if `ip` is not on the public internet. Includes carrier grade NAT, which is often used for internal networks.
+fn is_private_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || (a == 100 && (64..128).contains(&b))
+}
+
+/// Stand in servers for channel tests.
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+    use std::sync::{Arc, Mutex};
+    use url::Url;
+
+    /// A request received by [`recorder`].
+    #[derive(Debug, Clone)]
+    pub struct Received {
+        pub path: String,
+        pub headers: Vec<(String, String)>,
+        pub body: String,
+    }
+
+    impl Received {
+        /// Gets the value of a header, if it was sent.
+        pub fn header(&self, name: &str) -> Option<&str> {
+            self.headers
+                .iter()
+                .find(|(n, _)| n.eq_ignore_ascii_case(name))
+                .map(|(_, v)| v.as_str())
+        }
+    }
+
+    #[test]
+    fn test_check_url() {
+        let strict = Config::default();
+        let local = Config {
+            webhook_allow_http: true,
+            webhook_allow_private: true,
+            ..Default::default()
+        };
+        let webhook = |url: &str| Channel::Webhook {
+            url: Url::parse(url).unwrap(),
+        };
+
+        assert!(check_address(&webhook("https://hooks.example.com/alert"), &strict).is_ok());
+        assert!(check_address(&Channel::WebPush, &strict).is_ok());
+        assert_eq!(
+            check_address(
+                &Channel::Ntfy {
+                    url: Url::parse("http://ntfy.sh/rides").unwrap()
+                },
+                &strict
+            ),
+            Err("Ntfy topics must use https".to_string())
+        );
+
+        for private in [
+            "https://127.0.0.1/",
+            "https://localhost:8080/",
+            "https://api.localhost./",
+            "https://169.254.169.254/latest/meta-data",
+            "https://10.0.0.5/",
+            "https://192.168.1.1/",
+            "https://172.16.0.1/",
+            "https://100.64.0.1/",
+            "https://0.0.0.0/",
+            "https://2130706433/",
+            "https://[::1]/",
+            "https://[fe80::1]/",
+            "https://[fd00::1]/",
+            "https://[::ffff:127.0.0.1]/",
+        ] {
+            assert_eq!(
+                check_address(&webhook(private), &strict),
+                Err("Webhooks can't be on a private network".to_string()),
+                "{}",
+                private
+            );
+            assert!(
+                check_address(&webhook(private), &local).is_ok(),
+                "{}",
+                private
+            );
+        }
+        assert!(check_address(&webhook("http://127.0.0.1:8096/"), &local).is_ok());
+    }
+
+    #[test]
+    fn test_check_email() {
+        let config = Config {
+            smtp_allowed_to: vec!["Rider@Example.com".to_string(), "@park.example".to_string()],
+            ..Default::default()
+        };
+        let email = |to: &str| Channel::Email {
+            to: to.parse().unwrap(),
+        };
+
+        assert!(check_address(&email("rider@example.com"), &config).is_ok());
+        assert!(check_address(&email("anyone@park.example"), &config).is_ok());
+        for denied in ["other@example.com", "rider@sub.park.example"] {
+            assert_eq!(
+                check_address(&email(denied), &config),
+                Err("Email alerts can't be sent to this address".to_string())
+            );
+        }
+        assert!(check_address(&email("rider@example.com"), &Config::default()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_redirects_are_rejected() {
+        let (target, received) = recorder(200);
+        let redirect = HttpServer::new(move || {
+            let target = target.clone();
+            App::new().default_service(web::post().to(move || {
+                let target = target.clone();
+                async move {
+                    HttpResponse::Found()
+                        .insert_header(("Location", target.as_str()))
+                        .finish()
+                }
+            }))
+        })
+        .workers(1)
+        .disable_signals()
+        .bind("127.0.0.1:0")
+        .unwrap();
+        let url = Url::parse(&format!("http://{}/", redirect.addrs()[0])).unwrap();
+        tokio::spawn(redirect.run());
+
+        let reg = crate::store::test::webhook_registration(&url);
+        let channel = webhook::WebhookChannel::new(http_client(), 1, Duration::ZERO);
+        let res = channel.send(&reg, &PushPayload::Test).await;
+
+        assert!(matches!(res, Err(SendError::Rejected(StatusCode::FOUND))));
+        assert!(!res.unwrap_err().is_gone());
+        assert!(received.lock().unwrap().is_empty());
+    }
+
+    /// Starts an HTTP server that records every POST to it, responding with `status`. Returns its root url.
+    pub fn recorder(status: u16) -> (Url, Arc<Mutex<Vec<Received>>>) {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let server_received = received.clone();
+
+        let server = HttpServer::new(move || {
+            let received = server_received.clone();
+
+            App::new().default_service(web::post().to(move |req: HttpRequest, body: String| {
+                let received = received.clone();
+
+                async move {
+                    received.lock().unwrap().push(Received {
+                        path: req.path().to_string(),
+                        headers: req
+                            .headers()
+                            .iter()
+                            .map(|(n, v)| (n.to_string(), v.to_str().unwrap_or("").to_string()))
+                            .collect(),
+                        body,
+                    });
+
+                    HttpResponse::build(actix_web::http::StatusCode::from_u16(status).unwrap())
+                        .finish()
+                }
+            }))
+        })
+        .workers(1)
+        .disable_signals()
+        .bind("127.0.0.1:0")
+        .unwrap();
+
+        let url = Url::parse(&format!("http://{}/", server.addrs()[0])).unwrap();
+        tokio::spawn(server.run());
+        (url, received)
+    }
+}
@@ -0,0 +1,95 @@
+//! Delivers alerts by publishing to an ntfy style HTTP topic.
+
+use super::{NotificationChannel, SendError};
+use crate::models::{Channel, PushPayload, Registration};
+use async_trait::async_trait;
+
+/// Publishes each payload as a plain text message to the registrations topic url.
+///
+/// The title is sent in the `Title` header, which ntfy shows as the notification title.
+pub struct NtfyChannel {
+    http: reqwest::Client,
+}
+
+impl NtfyChannel {
+    pub fn new(http: reqwest::Client) -> Self {
+        Self { http }
+    }
+}
+
+#[async_trait]
+impl NotificationChannel for NtfyChannel {
+    async fn send(&self, reg: &Registration, payload: &PushPayload<'_>) -> Result<(), SendError> {
+        let Channel::Ntfy { url } = &reg.channel else {
+            return Err(SendError::WrongChannel(reg.channel.name()));
+        };
+
+        let res = self
+            .http
+            .post(url.clone())
+            .header("Title", payload.title())
+            .body(payload.text())
+            .send()
+            .await?;
+
+        if !res.status().is_success() {
+            return Err(SendError::Rejected(res.status()));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::channel::test::recorder;
+    use crate::models::GroupAlert;
+    use crate::store::test::registration;
+    use queue_times::model::{ParkCrowd, RideStatus, RideTime};
+
+    #[tokio::test]
+    async fn test_ntfy() {
+        let (url, received) = recorder(200);
+        let mut reg = registration("https://push.example.com/a");
+        reg.channel = Channel::Ntfy {
+            url: url.join("queue-alerts").unwrap(),
+        };
+
+        let rides = [
+            RideTime {
+                name: "Maverick".to_string(),
+                status: RideStatus::Wait(15),
+            },
+            RideTime {
+                name: "Millennium Force".to_string(),
+                status: RideStatus::Open,
+            },
+        ];
+        let channel = NtfyChannel::new(reqwest::Client::new());
+        channel
+            .send(
+                &reg,
+                &PushPayload::Alert {
+                    rides: rides.iter().collect(),
+                    groups: vec![GroupAlert {
+                        name: "Coasters",
+                        rides: rides.iter().collect(),
+                    }],
+                    crowd: Some(&ParkCrowd::new(&rides)),
+                    actions: &[],
+                    token: Some("token"),
+                },
+            )
+            .await
+            .unwrap();
+
+        let received = received.lock().unwrap();
+        assert_eq!(received[0].path, "/queue-alerts");
+        assert_eq!(received[0].header("title"), Some("Ride Alert"));
+        assert_eq!(
+            received[0].body,
+            "Maverick's wait is 15 minutes!\nMillennium Force is Open!\nCoasters: Maverick (15 min), Millennium Force (Open)\nCrowds are moderate, with an average wait of 15 minutes\nKeep this token to change or remove your alerts: token"
+        );
+    }
+}
@@ -0,0 +1,234 @@
+//! Delivers alerts with the Push API, to the browser that registered.
+
+use super::{NotificationChannel, SendError};
+use crate::models::{PushPayload, Registration, VapidKeys};
+use async_trait::async_trait;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::io::Write;
+use std::sync::Arc;
+use web_push::{ContentEncoding, WebPushClient, WebPushError, WebPushMessageBuilder};
+
+/// Sends push notifications, signed with the VAPID key each client subscribed with.
+pub struct WebPushChannel {
+    client: Box<dyn WebPushClient + Send + Sync>,
+    keys: Arc<VapidKeys>,
+}
+
+impl WebPushChannel {
+    pub fn new(client: Box<dyn WebPushClient + Send + Sync>, keys: Arc<VapidKeys>) -> Self {
+        Self { client, keys }
+    }
+}
+
+#[async_trait]
+impl NotificationChannel for WebPushChannel {
+    /// Pushes the payload to the registrations subscription. The current key is used if it has no `vapid_key`.
+    async fn send(&self, reg: &Registration, payload: &PushPayload<'_>) -> Result<(), SendError> {
+        let sub = &reg.sub;
+        let keys = match reg.vapid_key.as_deref() {
+            Some(public) => self.keys.get(public).ok_or_else(|| {
+                log::error!("{} subscribed with a key we no longer have", sub.endpoint);
+                WebPushError::InvalidCryptoKeys
+            })?,
+            None => &self.keys.current,
+        };
+
+        let mut builder = WebPushMessageBuilder::new(sub);
+
+        let content = serde_json::to_string(payload).unwrap();
+
+        //Compress JSON with gzip
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+        encoder.write_all(content.as_bytes()).unwrap();
+        let content = encoder.finish().unwrap();
+
+        //Web push will reject non text payloads, so base64 encode
+        let content = base64::encode(content);
+
+        log::debug!("Content size: {} bytes", content.len());
+
+        //*Must* set vapid signature, else the push will be rejected
+        builder.set_vapid_signature(keys.1.clone().add_sub_info(sub).build()?);
+        builder.set_payload(ContentEncoding::Aes128Gcm, content.as_bytes());
+
+        //Message will fail if too large.
+        let message = builder.build()?;
+
+        Ok(self.client.send(message).await?)
+    }
+}
+
+/// Stand in browsers and push services for tests.
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use crate::models::Keys;
+    use crate::store::test::registration;
+    use flate2::read::GzDecoder;
+    use p256::elliptic_curve::sec1::ToEncodedPoint;
+    use p256::pkcs8::{EncodePrivateKey, LineEnding};
+    use p256::SecretKey;
+    use std::io::Read;
+    use std::sync::Mutex;
+    use web_push::WebPushMessage;
+
+    /// Generates a VAPID key.
+    pub fn keys() -> Keys {
+        let key_path = std::env::temp_dir().join(format!("vapid_{}.pem", rand::random::<u64>()));
+        let key = SecretKey::random(&mut rand::rngs::OsRng);
+        std::fs::write(
+            &key_path,
+            key.to_pkcs8_pem(LineEnding::LF).unwrap().as_bytes(),
+        )
+        .unwrap();
+        let keys = crate::load_private_key(&key_path).unwrap();
+        std::fs::remove_file(&key_path).unwrap();
+        keys
+    }
+
+    /// A browser's push subscription, which can read the pushes sent to it.
+    pub struct Browser {
+        key: SecretKey,
+        auth: [u8; 16],
+    }
+
+    impl Browser {
+        pub fn new() -> Self {
+            Self {
+                key: SecretKey::random(&mut rand::rngs::OsRng),
+                auth: rand::random(),
+            }
+        }
+
+        /// A registration for this browser, subscribed at `endpoint` with the VAPID key `vapid_key`.
+        pub fn registration(&self, endpoint: &str, vapid_key: Option<&str>) -> Registration {
+            let mut reg = registration(endpoint);
+            reg.sub.keys.p256dh = base64::encode_config(self.public_key(), base64::URL_SAFE_NO_PAD);
+            reg.sub.keys.auth = base64::encode_config(self.auth, base64::URL_SAFE_NO_PAD);
+            reg.vapid_key = vapid_key.map(str::to_string);
+            reg
+        }
+
+        fn public_key(&self) -> Vec<u8> {
+            self.key
+                .public_key()
+                .to_encoded_point(false)
+                .as_bytes()
+                .to_vec()
+        }
+
+        /// Decrypts a push sent to this browser, giving its payload.
+        pub fn read(&self, message: &WebPushMessage) -> serde_json::Value {
+            let components =
+                ece::EcKeyComponents::new(self.key.to_bytes().to_vec(), self.public_key());
+            let content = ece::decrypt(
+                &components,
+                &self.auth,
+                &message.payload.as_ref().unwrap().content,
+            )
+            .unwrap();
+
+            let mut json = String::new();
+            GzDecoder::new(&*base64::decode(content).unwrap())
+                .read_to_string(&mut json)
+                .unwrap();
+            serde_json::from_str(&json).unwrap()
+        }
+    }
+
+    /// Gets the public VAPID key a push was signed with.
+    pub fn signed_with(message: &WebPushMessage) -> String {
+        let (_, auth) = message
+            .payload
+            .as_ref()
+            .unwrap()
+            .crypto_headers
+            .iter()
+            .find(|(name, _)| *name == "Authorization")
+            .unwrap();
+        auth.split("k=").nth(1).unwrap().to_string()
+    }
+
+    /// Push service that records every push, rejecting those to `gone` endpoints as no longer valid.
+    pub struct RecordingClient {
+        pub sent: Arc<Mutex<Vec<WebPushMessage>>>,
+        gone: Vec<String>,
+    }
+
+    impl RecordingClient {
+        pub fn new(gone: &[&str]) -> Self {
+            Self {
+                sent: Default::default(),
+                gone: gone.iter().map(|e| e.to_string()).collect(),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl WebPushClient for RecordingClient {
+        async fn send(&self, message: WebPushMessage) -> Result<(), WebPushError> {
+            let gone = self.gone.contains(&message.endpoint.to_string());
+            self.sent.lock().unwrap().push(message);
+            if gone {
+                Err(WebPushError::EndpointNotValid)
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_signs_with_subscribed_key() {
+        let keys = Arc::new(VapidKeys {
+            current: keys(),
+            previous: vec![keys()],
+        });
+        let current = keys.current_public().to_string();
+        let previous = keys.previous[0].0 .0.clone();
+        let client = RecordingClient::new(&[]);
+        let sent = client.sent.clone();
+        let channel = WebPushChannel::new(Box::new(client), keys);
+        let browser = Browser::new();
+
+        for (vapid_key, signed) in [
+            (Some(current.as_str()), &current),
+            (Some(previous.as_str()), &previous),
+            // Registrations from before keys were recorded subscribed with the current key
+            (None, &current),
+        ] {
+            channel
+                .send(
+                    &browser.registration("https://push.example.com/a", vapid_key),
+                    &PushPayload::Expired,
+                )
+                .await
+                .unwrap();
+
+            let sent = sent.lock().unwrap();
+            let message = sent.last().unwrap();
+            assert_eq!(&signed_with(message), signed);
+            assert_eq!(browser.read(message)["type"], "expired");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unknown_key_is_not_sent() {
+        let client = RecordingClient::new(&[]);
+        let sent = client.sent.clone();
+        let channel = WebPushChannel::new(
+            Box::new(client),
+            Arc::new(VapidKeys {
+                current: keys(),
+                previous: Vec::new(),
+            }),
+        );
+
+        let reg = Browser::new().registration("https://push.example.com/a", Some("retired"));
+        assert!(matches!(
+            channel.send(&reg, &PushPayload::Expired).await,
+            Err(SendError::WebPush(WebPushError::InvalidCryptoKeys))
+        ));
+        assert!(sent.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,226 @@
+//! Delivers alerts by POSTing JSON to a url.
+//!
+//! Each POST is signed with the registrations secret, so receivers can check it came from us. The signature is
+//! `sha256=` followed by the hex HMAC-SHA256 of `<timestamp>.<body>`, sent in [`SIGNATURE_HEADER`], where timestamp is
+//! the unix time sent in [`TIMESTAMP_HEADER`].
+
+use super::{NotificationChannel, SendError};
+use crate::models::{Channel, PushPayload, Registration};
+use async_trait::async_trait;
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+use std::time::Duration;
+use url::Url;
+
+/// Header holding the signature of a delivery.
+pub const SIGNATURE_HEADER: &str = "X-Queue-Alert-Signature";
+/// Header holding the unix time a delivery was signed at.
+pub const TIMESTAMP_HEADER: &str = "X-Queue-Alert-Timestamp";
+
+/// Signs a delivery body sent at `timestamp` with `secret`.
+pub fn sign(secret: &str, timestamp: i64, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body);
+
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// Body POSTed to webhooks. This is the same JSON that is pushed to browsers, along with the park it is for.
+#[derive(Serialize)]
+struct WebhookBody<'a> {
+    park: &'a str,
+    #[serde(flatten)]
+    payload: &'a PushPayload<'a>,
+}
+
+/// POSTs each payload as JSON to the registrations url, retrying failures that may be temporary.
+pub struct WebhookChannel {
+    http: reqwest::Client,
+    /// Times to try each delivery
+    max_attempts: u32,
+    /// Wait before the first retry, doubling after each
+    backoff: Duration,
+}
+
+impl WebhookChannel {
+    pub fn new(http: reqwest::Client, max_attempts: u32, backoff: Duration) -> Self {
+        Self {
+            http,
+            max_attempts: max_attempts.max(1),
+            backoff,
+        }
+    }
+
+    /// Makes a single attempt at a delivery.
+    async fn post(
+        &self,
+        url: &Url,
+        body: &[u8],
+        timestamp: i64,
+        signature: Option<&str>,
+    ) -> Result<(), SendError> {
+        let mut req = self
+            .http
+            .post(url.clone())
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(TIMESTAMP_HEADER, timestamp)
+            .body(body.to_vec());
+        if let Some(signature) = signature {
+            req = req.header(SIGNATURE_HEADER, signature);
+        }
+
+        let res = req.send().await?;
+
+        if !res.status().is_success() {
+            return Err(SendError::Rejected(res.status()));
+        }
+
+        Ok(())
+    }
+}
+
+/// Checks if a failed delivery might succeed if tried again.
+fn is_retryable(why: &SendError) -> bool {
+    match why {
+        SendError::Http(_) => true,
+        SendError::Rejected(status) => {
+            status.is_server_error() || *status == reqwest::StatusCode::TOO_MANY_REQUESTS
+        }
+        _ => false,
+    }
+}
+
+#[async_trait]
+impl NotificationChannel for WebhookChannel {
+    async fn send(&self, reg: &Registration, payload: &PushPayload<'_>) -> Result<(), SendError> {
+        let Channel::Webhook { url } = &reg.channel else {
+            return Err(SendError::WrongChannel(reg.channel.name()));
+        };
+
+        let body = serde_json::to_vec(&WebhookBody {
+            park: &reg.config.0,
+            payload,
+        })
+        .unwrap();
+        let timestamp = Utc::now().timestamp();
+        let signature = reg
+            .signing_secret
+            .as_deref()
+            .map(|secret| sign(secret, timestamp, &body));
+
+        let mut backoff = self.backoff;
+        for attempt in 1.. {
+            match self.post(url, &body, timestamp, signature.as_deref()).await {
+                Ok(()) => return Ok(()),
+                Err(why) if attempt < self.max_attempts && is_retryable(&why) => {
+                    log::debug!(
+                        "Delivery to {} failed, retrying in {:?}: {}",
+                        url,
+                        backoff,
+                        why
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                }
+                Err(why) if attempt > 1 => return Err(SendError::Retried(attempt, Box::new(why))),
+                Err(why) => return Err(why),
+            }
+        }
+
+        unreachable!("deliveries return once out of attempts")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::channel::test::recorder;
+    use crate::store::test::webhook_registration;
+    use queue_times::model::{RideStatus, RideTime};
+
+    #[tokio::test]
+    async fn test_webhook() {
+        let (url, received) = recorder(200);
+        let reg = webhook_registration(&url.join("hooks/alerts").unwrap());
+
+        let ride = RideTime {
+            name: "Maverick".to_string(),
+            status: RideStatus::Wait(15),
+        };
+        let channel = WebhookChannel::new(reqwest::Client::new(), 1, Duration::ZERO);
+        channel
+            .send(
+                &reg,
+                &PushPayload::Alert {
+                    rides: vec![&ride],
+                    groups: vec![],
+                    crowd: None,
+                    actions: &[],
+                    token: None,
+                },
+            )
+            .await
+            .unwrap();
+
+        let received = received.lock().unwrap();
+        assert_eq!(received[0].path, "/hooks/alerts");
+        assert_eq!(received[0].header("content-type"), Some("application/json"));
+
+        let body: serde_json::Value = serde_json::from_str(&received[0].body).unwrap();
+        assert_eq!(body["type"], "alert");
+        assert_eq!(body["park"], "Cedar Point");
+        assert_eq!(body["rides"][0]["name"], "Maverick");
+    }
+
+    #[tokio::test]
+    async fn test_webhook_gone() {
+        let (url, _) = recorder(410);
+        let reg = webhook_registration(&url);
+
+        let channel = WebhookChannel::new(reqwest::Client::new(), 3, Duration::ZERO);
+        let err = channel.send(&reg, &PushPayload::Test).await.unwrap_err();
+
+        assert!(err.is_gone());
+    }
+
+    #[tokio::test]
+    async fn test_webhook_signed() {
+        let (url, received) = recorder(200);
+        let mut reg = webhook_registration(&url);
+        reg.signing_secret = Some("secret".to_string());
+
+        let channel = WebhookChannel::new(reqwest::Client::new(), 1, Duration::ZERO);
+        channel.send(&reg, &PushPayload::Test).await.unwrap();
+
+        let received = &received.lock().unwrap()[0];
+        let timestamp: i64 = received.header(TIMESTAMP_HEADER).unwrap().parse().unwrap();
+        assert_eq!(
+            received.header(SIGNATURE_HEADER),
+            Some(sign("secret", timestamp, received.body.as_bytes()).as_str())
+        );
+        assert_ne!(
+            received.header(SIGNATURE_HEADER),
+            Some(sign("other", timestamp, received.body.as_bytes()).as_str())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_webhook_retries() {
+        let (url, received) = recorder(503);
+        let reg = webhook_registration(&url);
+
+        let channel = WebhookChannel::new(reqwest::Client::new(), 3, Duration::from_millis(10));
+        let err = channel.send(&reg, &PushPayload::Test).await.unwrap_err();
+
+        assert_eq!(received.lock().unwrap().len(), 3);
+        assert_eq!(
+            err.to_string(),
+            "failed after 3 attempts: rejected with status 503 Service Unavailable"
+        );
+        assert!(!err.is_gone());
+    }
+}
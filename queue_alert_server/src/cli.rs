@@ -0,0 +1,324 @@
+//! Management subcommands, for operators to inspect and maintain a deployment without touching the db by hand.
+
+use crate::app::Application;
+use crate::channel::{self, Channels};
+use crate::config::Config;
+use crate::metrics::Metrics;
+use crate::models::PushPayload;
+use crate::registration::{RegistrationDump, RegistrationRepository};
+use crate::store::{self, RegistrationStore};
+use chrono::Utc;
+use clap::Subcommand;
+use p256::elliptic_curve::sec1::ToEncodedPoint;
+use p256::pkcs8::{EncodePrivateKey, LineEnding};
+use p256::SecretKey;
+use std::io::Read;
+use std::path::PathBuf;
+use std::sync::Arc;
+use web_push::HyperWebPushClient;
+
+type CliResult = Result<(), Box<dyn std::error::Error>>;
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Run the server. This is the default.
+    Serve,
+    /// Generate a new P-256 VAPID private key.
+    GenKeys {
+        /// File to write the PEM key to. Defaults to the configured private key path.
+        #[arg(short, long)]
+        out: Option<PathBuf>,
+        /// Overwrite an existing key. Clients subscribed with the old key will stop receiving alerts.
+        #[arg(long)]
+        force: bool,
+    },
+    /// Inspect and manage registrations.
+    #[command(subcommand)]
+    Registrations(RegistrationsCommand),
+    /// Push a test notification to a registered endpoint.
+    SendTest {
+        /// Push endpoint of the registration.
+        endpoint: String,
+    },
+    /// Export or import the registration db.
+    #[command(subcommand)]
+    Db(DbCommand),
+}
+
+#[derive(Subcommand, Debug)]
+pub enum RegistrationsCommand {
+    /// List every registration.
+    List,
+    /// Print a registration as JSON.
+    Show {
+        /// Push endpoint of the registration.
+        endpoint: String,
+    },
+    /// Remove a registration.
+    Remove {
+        /// Push endpoint of the registration.
+        endpoint: String,
+    },
+    /// Remove all expired registrations, without sending them an expiry notice.
+    PurgeExpired,
+    /// Print the latest deliveries to a webhook, newest first. Deliveries are kept after a webhook is removed.
+    Deliveries {
+        /// Endpoint of the registration, which is its webhook url.
+        endpoint: String,
+        /// Number of deliveries to print.
+        #[arg(short, long, default_value_t = 20)]
+        limit: i64,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum DbCommand {
+    /// Write every registration to a JSON dump.
+    Export {
+        /// File to write to. Defaults to stdout.
+        #[arg(short, long)]
+        out: Option<PathBuf>,
+    },
+    /// Load registrations from a JSON dump made by `db export`.
+    Import {
+        /// File to read from. Defaults to stdin.
+        file: Option<PathBuf>,
+        /// Replace registrations that already exist, rather than skipping them.
+        #[arg(long)]
+        replace: bool,
+    },
+    /// Look for corrupt and orphaned rows, and list quarantined registrations. Changes nothing.
+    Check,
+}
+
+/// Runs a management command.
+pub async fn run(command: Command, config: Config) -> CliResult {
+    match command {
+        Command::Serve => unreachable!("serve is handled by main"),
+        Command::GenKeys { out, force } => gen_keys(out.unwrap_or(config.private_key), force),
+        Command::Registrations(command) => {
+            let subs = RegistrationRepository::open(&config).await?;
+            registrations(command, &subs).await
+        }
+        Command::SendTest { endpoint } => send_test(&endpoint, config).await,
+        // Loading quarantines corrupt registrations, so check the store without loading it
+        Command::Db(DbCommand::Check) => check(&*store::open(&config).await?).await,
+        Command::Db(command) => {
+            let subs = RegistrationRepository::open(&config).await?;
+            db(command, &subs, &config).await
+        }
+    }
+}
+
+/// Writes a new PKCS#8 PEM private key to `path`, printing its public key.
+fn gen_keys(path: PathBuf, force: bool) -> CliResult {
+    if path.exists() && !force {
+        return Err(format!(
+            "{} already exists, pass --force to replace it",
+            path.display()
+        )
+        .into());
+    }
+
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    let key = SecretKey::random(&mut rand::rngs::OsRng);
+    std::fs::write(&path, key.to_pkcs8_pem(LineEnding::LF)?.as_bytes())?;
+
+    let public_key = key.public_key().to_encoded_point(false);
+    println!("Wrote private key to {}", path.display());
+    println!(
+        "Public key: {}",
+        base64::encode_config(public_key.as_bytes(), base64::URL_SAFE_NO_PAD)
+    );
+
+    Ok(())
+}
+
+async fn registrations(command: RegistrationsCommand, subs: &RegistrationRepository) -> CliResult {
+    match command {
+        RegistrationsCommand::List => {
+            let now = Utc::now();
+
+            for reg in subs.cache.iter() {
+                let expires = reg
+                    .expires_at
+                    .map(|e| e.to_rfc3339())
+                    .unwrap_or_else(|| "never".to_string());
+                let state = if reg.is_expired(now) {
+                    "expired"
+                } else if reg.snoozed_until.is_some_and(|s| s > now) {
+                    "snoozed"
+                } else {
+                    "active"
+                };
+
+                println!(
+                    "{}\t{}\t{} rides\t{}\texpires {}",
+                    reg.key(),
+                    reg.config.0,
+                    reg.config.1.len(),
+                    state,
+                    expires
+                );
+            }
+
+            eprintln!("{} registrations", subs.get_current_user_count());
+        }
+        RegistrationsCommand::Show { endpoint } => {
+            let reg = subs
+                .cache
+                .get(&endpoint)
+                .ok_or_else(|| format!("{} is not registered", endpoint))?;
+
+            println!("{}", serde_json::to_string_pretty(reg.value())?);
+        }
+        RegistrationsCommand::Remove { endpoint } => {
+            if !subs.remove_registration(&endpoint).await? {
+                return Err(format!("{} is not registered", endpoint).into());
+            }
+
+            println!("Removed {}", endpoint);
+        }
+        RegistrationsCommand::PurgeExpired => {
+            let expired = subs.expired_endpoints(Utc::now());
+
+            for endpoint in &expired {
+                subs.remove_registration(endpoint).await?;
+            }
+
+            println!("Removed {} expired registrations", expired.len());
+        }
+        RegistrationsCommand::Deliveries { endpoint, limit } => {
+            let deliveries = subs.deliveries(&endpoint, limit).await?;
+
+            for delivery in &deliveries {
+                println!(
+                    "{}\t{}\t{}",
+                    delivery.delivered_at.to_rfc3339(),
+                    delivery.payload,
+                    delivery.error.as_deref().unwrap_or("delivered")
+                );
+            }
+
+            eprintln!("{} deliveries", deliveries.len());
+        }
+    }
+
+    Ok(())
+}
+
+/// Sends a test notification to `endpoint` over its channel. Pushes are signed with the key it subscribed with.
+async fn send_test(endpoint: &str, config: Config) -> CliResult {
+    let keys = crate::load_keys(&config)?;
+    let subs = RegistrationRepository::open(&config).await?;
+
+    let reg = subs
+        .cache
+        .get(endpoint)
+        .map(|r| r.clone())
+        .ok_or_else(|| format!("{} is not registered", endpoint))?;
+
+    let keys = Arc::new(keys);
+    let channels = Channels::new(Box::new(HyperWebPushClient::new()), keys.clone(), &config)?;
+    let metrics = Metrics::new();
+    let app = Application::new(
+        subs,
+        queue_times::client::CachedClient::new(
+            metrics.measure_client(queue_times::api::ApiClient::new()),
+        ),
+        channels,
+        keys,
+        config,
+        metrics,
+    );
+
+    app.send(&reg, &PushPayload::Test).await?;
+    println!("Sent test notification to {}", endpoint);
+
+    Ok(())
+}
+
+async fn db(command: DbCommand, subs: &RegistrationRepository, config: &Config) -> CliResult {
+    match command {
+        DbCommand::Export { out } => {
+            let dump = serde_json::to_string_pretty(&subs.export())?;
+
+            match out {
+                Some(path) => std::fs::write(path, dump)?,
+                None => println!("{}", dump),
+            }
+
+            eprintln!("Exported {} registrations", subs.get_current_user_count());
+        }
+        DbCommand::Import { file, replace } => {
+            let json = match file {
+                Some(path) => std::fs::read_to_string(path)?,
+                None => {
+                    let mut json = String::new();
+                    std::io::stdin().read_to_string(&mut json)?;
+                    json
+                }
+            };
+            let dumps: Vec<RegistrationDump> = serde_json::from_str(&json)?;
+
+            let total = dumps.len();
+            // Dumps can be edited by hand, so their channels are checked like new registrations
+            let dumps = dumps
+                .into_iter()
+                .filter(|dump| {
+                    let reg = &dump.registration;
+                    match channel::check_address(&reg.channel, config) {
+                        Ok(()) => true,
+                        Err(why) => {
+                            log::warn!("Skipping registration {}: {}", reg.sub.endpoint, why);
+                            false
+                        }
+                    }
+                })
+                .collect();
+            let imported = subs.import(dumps, replace).await?;
+            println!("Imported {} of {} registrations", imported, total);
+        }
+        DbCommand::Check => unreachable!("check does not load the db"),
+    }
+
+    Ok(())
+}
+
+/// Prints every problem found in the store. Fails if any need fixing.
+async fn check(store: &dyn RegistrationStore) -> CliResult {
+    let report = store.check().await?;
+
+    for (title, problems) in [
+        (
+            "Corrupt, will be quarantined when next loaded",
+            &report.corrupt,
+        ),
+        ("Orphaned rows", &report.orphans),
+        ("Quarantined", &report.quarantined),
+    ] {
+        if problems.is_empty() {
+            continue;
+        }
+
+        println!("{}:", title);
+        for problem in problems {
+            println!("\t{}\t{}", problem.endpoint, problem.reason);
+        }
+    }
+
+    if !report.is_clean() {
+        return Err(format!(
+            "found {} corrupt registrations and {} orphaned rows",
+            report.corrupt.len(),
+            report.orphans.len()
+        )
+        .into());
+    }
+
+    println!("No problems found");
+    Ok(())
+}
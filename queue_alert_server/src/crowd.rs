@@ -0,0 +1,87 @@
+//! Park crowd reports, for `/crowd`.
+//!
+//! A report is a park's [`ParkCrowd`], along with how the waits posted now compare to the typical waits of the same
+//! rides at this hour. Only rides with a posted wait and a typical wait are compared, so a new server reports no
+//! comparison until it has some history.
+
+use queue_times::model::{ParkCrowd, RideStatus, RideTime};
+use serde::Serialize;
+
+/// How busy a park is, and how that compares to usual.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CrowdReport {
+    #[serde(flatten)]
+    pub crowd: ParkCrowd,
+    /// Mean typical wait at this hour of the rides compared. `None` if no rides could be compared.
+    pub typical_wait: Option<f64>,
+    /// Minutes the rides compared are waiting longer than usual on average, negative if shorter.
+    pub vs_typical: Option<f64>,
+}
+
+/// Reports on the crowd at a park with `rides`. `typical` gets the usual wait of a ride at this hour, if it is known.
+pub fn report(rides: &[RideTime], typical: impl Fn(&str) -> Option<f64>) -> CrowdReport {
+    let compared: Vec<(f64, f64)> = rides
+        .iter()
+        .filter_map(|ride| match ride.status {
+            RideStatus::Wait(wait) => typical(&ride.name).map(|usual| (wait as f64, usual)),
+            _ => None,
+        })
+        .collect();
+
+    let count = compared.len() as f64;
+    let (waits, usual) = compared
+        .iter()
+        .fold((0.0, 0.0), |(waits, usual), (wait, typical)| {
+            (waits + wait, usual + typical)
+        });
+
+    let mut crowd = ParkCrowd::new(rides);
+    crowd.average_wait = crowd.average_wait.map(round);
+    crowd.open_share = (crowd.open_share * 100.0).round() / 100.0;
+
+    CrowdReport {
+        crowd,
+        typical_wait: (!compared.is_empty()).then(|| round(usual / count)),
+        vs_typical: (!compared.is_empty()).then(|| round((waits - usual) / count)),
+    }
+}
+
+/// Rounds to one decimal place.
+fn round(value: f64) -> f64 {
+    (value * 10.0).round() / 10.0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use queue_times::model::CrowdLevel;
+
+    #[test]
+    fn test_report() {
+        let rides = [
+            RideTime::new("Maverick", RideStatus::Wait(20)),
+            RideTime::new("Millennium Force", RideStatus::Wait(10)),
+            RideTime::new("Steel Vengeance", RideStatus::Wait(5)),
+            RideTime::new("Top Thrill 2", RideStatus::Closed),
+        ];
+        let typical = |name: &str| match name {
+            "Maverick" => Some(45.0),
+            "Millennium Force" => Some(30.0),
+            "Top Thrill 2" => Some(60.0),
+            _ => None,
+        };
+
+        let report = report(&rides, typical);
+        assert_eq!(report.crowd.average_wait, Some(11.7));
+        assert_eq!(report.crowd.open_share, 0.75);
+        assert_eq!(report.crowd.level, Some(CrowdLevel::Low));
+        // Only rides posting a wait with history are compared
+        assert_eq!(report.typical_wait, Some(37.5));
+        assert_eq!(report.vs_typical, Some(-22.5));
+
+        let unknown = super::report(&rides, |_| None);
+        assert_eq!(unknown.typical_wait, None);
+        assert_eq!(unknown.vs_typical, None);
+    }
+}
@@ -1,9 +1,27 @@
 use thiserror::Error;
 
 #[derive(Error, Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
     #[error(transparent)]
     DBError(#[from] sqlx::Error),
     #[error(transparent)]
-    SerializationErr(#[from] serde_json::Error)
+    SerializationErr(#[from] serde_json::Error),
+    #[error("could not read config file {0}: {1}")]
+    ConfigRead(std::path::PathBuf, std::io::Error),
+    #[error(transparent)]
+    ConfigParse(#[from] toml::de::Error),
+    #[error("db is at schema version {0}, but this server only knows up to {1}")]
+    SchemaTooNew(i64, i64),
+    #[error("invalid smtp config: {0}")]
+    Smtp(String),
+    #[cfg(not(feature = "postgres"))]
+    #[error("postgres_url is set, but this server was built without the postgres feature")]
+    PostgresDisabled,
+    #[cfg(feature = "mqtt")]
+    #[error("invalid mqtt config: {0}")]
+    Mqtt(String),
+    #[cfg(not(feature = "mqtt"))]
+    #[error("mqtt_url is set, but this server was built without the mqtt feature")]
+    MqttDisabled
 }
\ No newline at end of file
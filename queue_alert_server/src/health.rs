@@ -0,0 +1,182 @@
+//! Readiness checks, served at `/readyz`.
+
+use crate::app::Application;
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+
+/// Result of a single readiness check.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Check {
+    pub ok: bool,
+    /// Why the check failed, or what it found.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl Check {
+    fn ok() -> Self {
+        Self {
+            ok: true,
+            detail: None,
+        }
+    }
+
+    fn ok_with(detail: String) -> Self {
+        Self {
+            ok: true,
+            detail: Some(detail),
+        }
+    }
+
+    fn failed(detail: String) -> Self {
+        Self {
+            ok: false,
+            detail: Some(detail),
+        }
+    }
+}
+
+/// Whether the server can do its job, with the result of each check.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Readiness {
+    /// True if every check passed.
+    pub ready: bool,
+    /// The registration store can be reached.
+    pub db: Check,
+    /// Wait times were refreshed recently.
+    pub cache: Check,
+    /// The push loop has ticked recently.
+    pub push_loop: Check,
+    /// A VAPID key is loaded to sign pushes with.
+    pub vapid_key: Check,
+}
+
+/// Runs every readiness check against `app`.
+pub async fn check(app: &Application) -> Readiness {
+    let now = Utc::now();
+
+    let db = match app.subs.ping().await {
+        Ok(_) => Check::ok(),
+        Err(why) => Check::failed(format!("db is unreachable: {}", why)),
+    };
+
+    let cache = cache_check(
+        app.queue_client
+            .last_updated()
+            .await
+            .map(|t| t.with_timezone(&Utc)),
+        now,
+        app.config.max_cache_age(),
+    );
+
+    // Missing a few ticks is fine, as a tick can take a while with many registrations
+    let push_loop = push_loop_check(
+        app.last_tick(),
+        app.started_at,
+        now,
+        Duration::seconds(3 * app.config.push_interval_secs as i64),
+    );
+
+    let vapid_key = if app.keys.current_public().is_empty() {
+        Check::failed("no VAPID key is loaded".to_string())
+    } else {
+        Check::ok()
+    };
+
+    Readiness {
+        ready: db.ok && cache.ok && push_loop.ok && vapid_key.ok,
+        db,
+        cache,
+        push_loop,
+        vapid_key,
+    }
+}
+
+/// Checks that wait times were last refreshed no more than `max_age` ago.
+fn cache_check(
+    last_updated: Option<DateTime<Utc>>,
+    now: DateTime<Utc>,
+    max_age: Duration,
+) -> Check {
+    match last_updated {
+        None => Check::failed("wait times have not been fetched yet".to_string()),
+        Some(at) if now - at > max_age => {
+            Check::failed(format!("wait times are {}s old", (now - at).num_seconds()))
+        }
+        Some(at) => Check::ok_with(format!("wait times are {}s old", (now - at).num_seconds())),
+    }
+}
+
+/// Checks that the push loop ticked no more than `max_gap` ago. Before its first tick, the gap is counted from when the
+/// server started.
+fn push_loop_check(
+    last_tick: Option<DateTime<Utc>>,
+    started_at: DateTime<Utc>,
+    now: DateTime<Utc>,
+    max_gap: Duration,
+) -> Check {
+    match last_tick {
+        Some(at) if now - at > max_gap => Check::failed(format!(
+            "push loop last ticked {}s ago",
+            (now - at).num_seconds()
+        )),
+        Some(at) => Check::ok_with(format!(
+            "push loop last ticked {}s ago",
+            (now - at).num_seconds()
+        )),
+        None if now - started_at > max_gap => {
+            Check::failed("push loop has not ticked since starting".to_string())
+        }
+        None => Check::ok_with("push loop has not ticked yet".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_cache_check() {
+        let now = Utc::now();
+        let max_age = Duration::minutes(15);
+
+        assert!(!cache_check(None, now, max_age).ok);
+        assert!(cache_check(Some(now - Duration::minutes(5)), now, max_age).ok);
+        assert_eq!(
+            cache_check(Some(now - Duration::minutes(20)), now, max_age),
+            Check::failed("wait times are 1200s old".to_string())
+        );
+    }
+
+    #[test]
+    fn test_push_loop_check() {
+        let now = Utc::now();
+        let max_gap = Duration::minutes(3);
+
+        // Just started
+        assert!(push_loop_check(None, now - Duration::seconds(10), now, max_gap).ok);
+        // Never ticked
+        assert!(!push_loop_check(None, now - Duration::minutes(10), now, max_gap).ok);
+        // Ticking
+        assert!(
+            push_loop_check(
+                Some(now - Duration::minutes(1)),
+                now - Duration::hours(1),
+                now,
+                max_gap
+            )
+            .ok
+        );
+        // Stalled
+        assert!(
+            !push_loop_check(
+                Some(now - Duration::minutes(5)),
+                now - Duration::hours(1),
+                now,
+                max_gap
+            )
+            .ok
+        );
+    }
+}
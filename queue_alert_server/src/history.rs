@@ -0,0 +1,113 @@
+//! Typical wait times, learned from the wait time cache while the server runs.
+//!
+//! After each refresh of the cache, the posted wait of every ride is recorded against the hour of the day it was seen
+//! in. History is only kept in memory, so a new server has none until it has seen each hour.
+
+use chrono::{DateTime, Timelike, Utc};
+use dashmap::DashMap;
+use queue_times::model::{RideStatus, RideTime};
+
+/// Waits seen at an hour after which older waits count for less, so typical waits follow recent days.
+pub const HISTORY_SAMPLES: u32 = 60;
+
+/// Mean of the waits seen for a ride at some hour.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+struct Typical {
+    mean: f64,
+    samples: u32,
+}
+
+/// Typical wait of each ride, by the hour of the day.
+///
+/// Hours are in UTC, which is the same local hour at any one park apart from around daylight saving changes.
+#[derive(Debug, Default)]
+pub struct WaitHistory {
+    /// Typical wait by park, ride and hour
+    waits: DashMap<(String, String, u32), Typical>,
+}
+
+impl WaitHistory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records the posted waits of `rides` at `park`, seen at `at`. Rides without a posted wait are skipped.
+    pub fn record(&self, park: &str, rides: &[RideTime], at: DateTime<Utc>) {
+        for ride in rides {
+            let RideStatus::Wait(wait) = ride.status else {
+                continue;
+            };
+
+            let mut typical = self
+                .waits
+                .entry((park.to_string(), ride.name.clone(), at.hour()))
+                .or_default();
+            typical.samples = (typical.samples + 1).min(HISTORY_SAMPLES);
+            typical.mean += (wait as f64 - typical.mean) / typical.samples as f64;
+        }
+    }
+
+    /// Gets the typical wait in minutes of a ride at the hour of `at`, if it has been seen then.
+    pub fn typical(&self, park: &str, ride_name: &str, at: DateTime<Utc>) -> Option<f64> {
+        self.waits
+            .get(&(park.to_string(), ride_name.to_string(), at.hour()))
+            .map(|typical| typical.mean)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::{Duration, TimeZone};
+
+    #[test]
+    fn test_typical() {
+        let history = WaitHistory::new();
+        let at = Utc.with_ymd_and_hms(2023, 7, 1, 16, 0, 0).unwrap();
+
+        history.record(
+            "Cedar Point",
+            &[
+                RideTime::new("Maverick", RideStatus::Wait(20)),
+                RideTime::new("Steel Vengeance", RideStatus::Open),
+            ],
+            at,
+        );
+        history.record(
+            "Cedar Point",
+            &[RideTime::new("Maverick", RideStatus::Wait(40))],
+            at + Duration::minutes(30),
+        );
+
+        assert_eq!(history.typical("Cedar Point", "Maverick", at), Some(30.0));
+        assert_eq!(history.typical("Cedar Point", "Steel Vengeance", at), None);
+        assert_eq!(
+            history.typical("Cedar Point", "Maverick", at + Duration::hours(1)),
+            None
+        );
+        assert_eq!(history.typical("Kings Island", "Maverick", at), None);
+    }
+
+    #[test]
+    fn test_old_waits_fade() {
+        let history = WaitHistory::new();
+        let at = Utc.with_ymd_and_hms(2023, 7, 1, 16, 0, 0).unwrap();
+
+        for _ in 0..HISTORY_SAMPLES {
+            history.record(
+                "Cedar Point",
+                &[RideTime::new("Maverick", RideStatus::Wait(60))],
+                at,
+            );
+        }
+        for _ in 0..HISTORY_SAMPLES * 3 {
+            history.record(
+                "Cedar Point",
+                &[RideTime::new("Maverick", RideStatus::Wait(10))],
+                at,
+            );
+        }
+
+        assert!(history.typical("Cedar Point", "Maverick", at).unwrap() < 15.0);
+    }
+}
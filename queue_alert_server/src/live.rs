@@ -0,0 +1,294 @@
+//! Live wait times, streamed as Server-Sent Events from `/parks/{id}/stream`.
+//!
+//! On connect a stream sends the park's full, sorted ride list as a `rides` event. After each refresh of the wait time
+//! cache it sends a `delta` event, holding the rides that are new or changed status and the names of any that were
+//! removed. Each event's id is the time of the refresh it is from, in unix milliseconds. A client that reconnects with
+//! that id as `Last-Event-ID` has missed nothing if the cache has not refreshed since, so is not sent the full list
+//! again. Otherwise it is sent the full list to replace what it has. A comment is sent every `stream_heartbeat_secs`
+//! so proxies do not close idle streams.
+
+use crate::app::Application;
+use actix_web::web::Bytes;
+use chrono::{DateTime, Local};
+use queue_times::client::QueueTimesClient;
+use queue_times::model::RideTime;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use url::Url;
+
+/// Events that can be queued for a client before the stream waits for it to catch up.
+const STREAM_BUFFER: usize = 8;
+
+/// Changes to a park's rides between two refreshes.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Delta {
+    /// Rides that are new, or whose status changed.
+    pub changed: Vec<RideTime>,
+    /// Names of rides that are no longer listed.
+    pub removed: Vec<String>,
+}
+
+/// Finds the url of the park with queue times id `id`, which is part of its url.
+pub fn park_url(parks: &HashMap<String, Url>, id: u32) -> Option<Url> {
+    let id = id.to_string();
+
+    parks
+        .values()
+        .find(|url| {
+            url.path_segments().is_some_and(|segments| {
+                segments
+                    .collect::<Vec<_>>()
+                    .windows(2)
+                    .any(|pair| pair[0] == "parks" && pair[1] == id)
+            })
+        })
+        .cloned()
+}
+
+/// Finds what changed going from `old` to `new`.
+pub fn delta(old: &[RideTime], new: &[RideTime]) -> Delta {
+    let old_status: HashMap<_, _> = old.iter().map(|r| (&r.name, r.status)).collect();
+    let new_names: Vec<_> = new.iter().map(|r| &r.name).collect();
+
+    Delta {
+        changed: new
+            .iter()
+            .filter(|r| old_status.get(&r.name) != Some(&r.status))
+            .cloned()
+            .collect(),
+        removed: old
+            .iter()
+            .filter(|r| !new_names.contains(&&r.name))
+            .map(|r| r.name.clone())
+            .collect(),
+    }
+}
+
+/// Streams the rides at `park_url` until the client goes away, or the server shuts down. `last_event_id` is the
+/// `Last-Event-ID` the client sent, if it is reconnecting.
+pub fn stream(
+    app: Arc<Application>,
+    park_url: Url,
+    last_event_id: Option<String>,
+) -> ReceiverStream<Bytes> {
+    let (tx, rx) = mpsc::channel(STREAM_BUFFER);
+    tokio::spawn(run(app, park_url, last_event_id, tx));
+    ReceiverStream::new(rx)
+}
+
+/// What woke a stream up.
+enum Wake {
+    Refreshed,
+    Heartbeat,
+    Stop,
+}
+
+/// Sends events to `tx` until it closes or the server shuts down.
+async fn run(
+    app: Arc<Application>,
+    park_url: Url,
+    last_event_id: Option<String>,
+    tx: mpsc::Sender<Bytes>,
+) {
+    let mut refreshed = app.queue_client.subscribe();
+    let mut shutdown = app.subscribe_shutdown();
+    let period = app.config.stream_heartbeat();
+    let mut heartbeat = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+
+    let id = event_id(*refreshed.borrow_and_update());
+    let mut rides = match park_rides(&app, &park_url).await {
+        Ok(rides) => rides,
+        Err(why) => {
+            log::warn!("Couldn't get rides to stream: {}", why);
+            return;
+        }
+    };
+
+    let resumed = id.is_some() && last_event_id == id.map(|id| id.to_string());
+    if !resumed && tx.send(event("rides", id, &rides)).await.is_err() {
+        return;
+    }
+
+    loop {
+        let wake = tokio::select! {
+            changed = refreshed.changed() => if changed.is_ok() { Wake::Refreshed } else { Wake::Stop },
+            _ = heartbeat.tick() => Wake::Heartbeat,
+            _ = shutdown.wait_for(|down| *down) => Wake::Stop,
+            _ = tx.closed() => Wake::Stop,
+        };
+
+        let message = match wake {
+            Wake::Refreshed => {
+                let id = event_id(*refreshed.borrow_and_update());
+                let new = match park_rides(&app, &park_url).await {
+                    Ok(rides) => rides,
+                    Err(why) => {
+                        log::debug!("Couldn't get rides to stream: {}", why);
+                        continue;
+                    }
+                };
+
+                let message = event("delta", id, &delta(&rides, &new));
+                rides = new;
+                message
+            }
+            Wake::Heartbeat => Bytes::from_static(b": heartbeat\n\n"),
+            Wake::Stop => return,
+        };
+
+        if tx.send(message).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Gets the rides at `park_url`, sorted like `/parkWaitTimes`.
+async fn park_rides(
+    app: &Application,
+    park_url: &Url,
+) -> queue_times::error::Result<Vec<RideTime>> {
+    let mut rides = app.queue_client.get_ride_times(park_url.clone()).await?;
+    rides.sort();
+    Ok(rides)
+}
+
+/// Id of events from the refresh at `refreshed`. Events before the first refresh have none.
+fn event_id(refreshed: Option<DateTime<Local>>) -> Option<i64> {
+    refreshed.map(|time| time.timestamp_millis())
+}
+
+/// Formats an event named `name`, with `data` as JSON.
+fn event(name: &str, id: Option<i64>, data: &impl Serialize) -> Bytes {
+    let data = serde_json::to_string(data).unwrap();
+
+    Bytes::from(match id {
+        Some(id) => format!("id: {}\nevent: {}\ndata: {}\n\n", id, name, data),
+        None => format!("event: {}\ndata: {}\n\n", name, data),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::app::test::{stand_in_app, stand_in_upstream};
+    use crate::config::Config;
+    use crate::store::sqlite::test::TempDb;
+    use queue_times::model::RideStatus;
+    use tokio_stream::StreamExt;
+
+    #[test]
+    fn test_park_url() {
+        let cedar_point = Url::parse("https://queue-times.com/en-US/parks/1/queue_times").unwrap();
+        let parks = HashMap::from([
+            ("Cedar Point".to_string(), cedar_point.clone()),
+            (
+                "Kings Island".to_string(),
+                Url::parse("https://queue-times.com/en-US/parks/11/queue_times").unwrap(),
+            ),
+        ]);
+
+        assert_eq!(park_url(&parks, 1), Some(cedar_point));
+        assert_eq!(park_url(&parks, 2), None);
+    }
+
+    #[test]
+    fn test_delta() {
+        let old = [
+            RideTime::new("Maverick", RideStatus::Wait(20)),
+            RideTime::new("Millennium Force", RideStatus::Open),
+            RideTime::new("Top Thrill Dragster", RideStatus::Closed),
+        ];
+        let new = [
+            RideTime::new("Maverick", RideStatus::Wait(35)),
+            RideTime::new("Millennium Force", RideStatus::Open),
+            RideTime::new("Top Thrill 2", RideStatus::Open),
+        ];
+
+        assert_eq!(
+            delta(&old, &new),
+            Delta {
+                changed: vec![
+                    RideTime::new("Maverick", RideStatus::Wait(35)),
+                    RideTime::new("Top Thrill 2", RideStatus::Open)
+                ],
+                removed: vec!["Top Thrill Dragster".to_string()],
+            }
+        );
+        assert_eq!(delta(&new, &new), Delta::default());
+    }
+
+    #[test]
+    fn test_event() {
+        assert_eq!(
+            event("delta", Some(12), &Delta::default()),
+            "id: 12\nevent: delta\ndata: {\"changed\":[],\"removed\":[]}\n\n"
+        );
+        assert_eq!(event("rides", None, &[0; 0]), "event: rides\ndata: []\n\n");
+    }
+
+    /// Reads the next event from `stream`, skipping heartbeats.
+    async fn next_event(stream: &mut ReceiverStream<Bytes>) -> String {
+        loop {
+            let message = tokio::time::timeout(std::time::Duration::from_secs(10), stream.next())
+                .await
+                .unwrap()
+                .unwrap();
+            if !message.starts_with(b":") {
+                return String::from_utf8(message.to_vec()).unwrap();
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stream() {
+        let file = TempDb::new("live_stream");
+        let app = Arc::new(
+            stand_in_app(
+                &file,
+                Config {
+                    upstream_url: stand_in_upstream(),
+                    stream_heartbeat_secs: 1,
+                    ..Default::default()
+                },
+            )
+            .await,
+        );
+        let parks = app.queue_client.get_park_urls().await.unwrap();
+        let url = park_url(&parks, 1).unwrap();
+
+        let mut events = stream(app.clone(), url.clone(), None);
+        assert_eq!(
+            next_event(&mut events).await,
+            "event: rides\ndata: [{\"name\":\"Steel Vengeance\",\"status\":{\"Wait\":10}}]\n\n"
+        );
+
+        // Fetching the rides started the first refresh, which streams what changed
+        let delta = next_event(&mut events).await;
+        assert!(delta.starts_with("id: "));
+        assert!(delta.ends_with("event: delta\ndata: {\"changed\":[],\"removed\":[]}\n\n"));
+
+        // Reconnecting with the latest id skips the full list, leaving nothing to send until the next refresh
+        let id = delta.lines().next().unwrap().trim_start_matches("id: ");
+        let mut resumed = stream(app.clone(), url.clone(), Some(id.to_string()));
+        assert_eq!(resumed.next().await.unwrap(), ": heartbeat\n\n");
+
+        // Reconnecting with an older id gets the full list again
+        let mut behind = stream(app.clone(), url, Some("0".to_string()));
+        assert!(next_event(&mut behind)
+            .await
+            .starts_with(&format!("id: {}\nevent: rides\n", id)));
+
+        // Streams end when the server shuts down, after any heartbeats still queued
+        app.begin_shutdown();
+        tokio::time::timeout(std::time::Duration::from_secs(10), async {
+            while let Some(message) = events.next().await {
+                assert_eq!(message, ": heartbeat\n\n");
+            }
+        })
+        .await
+        .unwrap();
+    }
+}
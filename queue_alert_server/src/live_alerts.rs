@@ -0,0 +1,252 @@
+//! Live alerts over a WebSocket at `/alerts/live`, for clients that cannot use web push but have the app open.
+//!
+//! Clients send their config as JSON text, in the same `[park, rides]` form as a registration's `config`, and may send
+//! a new one at any time to replace it. The server replies with a `watching` event, then checks the config right away
+//! and after each refresh of the wait time cache, the same way pushed alerts are checked. Rides that should alert are
+//! sent in an `alert` event. Alert state, such as if a `once` ride has alerted, only lasts as long as the connection.
+//! Messages that are not a valid config get an `error` event. The server pings every `stream_heartbeat_secs` so
+//! proxies do not close idle connections, and closes connections when it shuts down.
+
+use crate::alerts::{self, Rules, Snapshot, State};
+use crate::app::Application;
+use crate::models::RideConfig;
+use actix_ws::{CloseCode, Message, MessageStream, ProtocolError, Session};
+use chrono::{DateTime, Utc};
+use queue_times::client::QueueTimesClient;
+use queue_times::model::RideTime;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+use url::Url;
+
+/// Events sent to clients, as JSON text.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+enum Event<'a> {
+    /// A config was accepted, and its rides will be alerted on.
+    Watching { park: &'a str },
+    /// Rides that have met their alert condition.
+    Alert { rides: Vec<&'a RideTime> },
+    /// A message from the client was not a valid config.
+    Error { message: String },
+}
+
+/// A connections config, along with its alert state.
+#[derive(Debug)]
+struct Watch {
+    park: String,
+    url: Url,
+    rides: Vec<RideConfig>,
+    state: State,
+}
+
+impl Watch {
+    /// Parses a config sent by a client, checking its park exists in `parks` and its rides are valid the same way a
+    /// registration's are.
+    fn parse(text: &str, parks: &HashMap<String, Url>) -> Result<Self, String> {
+        let (park, rides): (String, Vec<RideConfig>) =
+            serde_json::from_str(text).map_err(|why| format!("Invalid config: {}", why))?;
+
+        let Some(url) = parks.get(&park).cloned() else {
+            return Err(format!("Unknown park {}", park));
+        };
+        for rc in &rides {
+            rc.validate()?;
+        }
+
+        Ok(Self {
+            park,
+            url,
+            rides,
+            state: State::default(),
+        })
+    }
+
+    /// Checks this config against `rides` at `now`, giving the rides that should alert. This updates alert state the
+    /// same way pushed alerts do.
+    fn check<'a>(&mut self, rides: &'a [RideTime], now: DateTime<Utc>) -> Vec<&'a RideTime> {
+        let events = alerts::evaluate(
+            Rules::rides(&self.rides),
+            Snapshot { rides, at: now },
+            &self.state,
+        );
+        self.state.apply(&events, now);
+
+        alerts::alerting(&events)
+    }
+}
+
+/// Sends live alerts over `session` until the client goes away, or the server shuts down.
+pub async fn run(app: Arc<Application>, mut session: Session, mut messages: MessageStream) {
+    let mut refreshed = app.queue_client.subscribe();
+    let mut shutdown = app.subscribe_shutdown();
+    let period = app.config.stream_heartbeat();
+    let mut heartbeat = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+    let mut watch: Option<Watch> = None;
+
+    loop {
+        let wake = tokio::select! {
+            message = messages.recv() => Wake::Message(message),
+            changed = refreshed.changed() => if changed.is_ok() { Wake::Refreshed } else { Wake::Stop },
+            _ = heartbeat.tick() => Wake::Heartbeat,
+            _ = shutdown.wait_for(|down| *down) => Wake::Stop,
+        };
+
+        let open = match wake {
+            Wake::Message(Some(Ok(Message::Text(text)))) => match configure(&app, &text).await {
+                Ok(new) => {
+                    let watch = watch.insert(new);
+                    send(&mut session, &Event::Watching { park: &watch.park }).await
+                        && check(&app, &mut session, watch).await
+                }
+                Err(message) => send(&mut session, &Event::Error { message }).await,
+            },
+            Wake::Message(Some(Ok(Message::Ping(bytes)))) => session.pong(&bytes).await.is_ok(),
+            Wake::Message(Some(Ok(Message::Close(reason)))) => {
+                let _ = session.close(reason).await;
+                return;
+            }
+            Wake::Message(Some(Ok(_))) => true,
+            Wake::Message(Some(Err(why))) => {
+                log::debug!("Closing live alerts after protocol error: {}", why);
+                let _ = session.close(None).await;
+                return;
+            }
+            Wake::Message(None) => return,
+            Wake::Refreshed => match watch.as_mut() {
+                Some(watch) => check(&app, &mut session, watch).await,
+                None => true,
+            },
+            Wake::Heartbeat => session.ping(b"").await.is_ok(),
+            Wake::Stop => {
+                let _ = session.close(Some(CloseCode::Restart.into())).await;
+                return;
+            }
+        };
+
+        if !open {
+            return;
+        }
+    }
+}
+
+/// What woke a connection up.
+enum Wake {
+    Message(Option<Result<Message, ProtocolError>>),
+    Refreshed,
+    Heartbeat,
+    Stop,
+}
+
+/// Parses a config sent by a client, giving an error message to send back if it is invalid.
+async fn configure(app: &Application, text: &str) -> Result<Watch, String> {
+    let parks = app.queue_client.get_park_urls().await.map_err(|why| {
+        log::error!("While getting parks: {}", why);
+        "Couldn't get parks, try again later".to_string()
+    })?;
+
+    Watch::parse(text, &parks)
+}
+
+/// Checks `watch` against the current wait times, alerting the client if any rides should. Returns false if the client
+/// has gone away.
+async fn check(app: &Application, session: &mut Session, watch: &mut Watch) -> bool {
+    let rides = match app.queue_client.get_ride_times(watch.url.clone()).await {
+        Ok(rides) => rides,
+        Err(why) => {
+            log::debug!("Couldn't get rides for live alerts: {}", why);
+            return true;
+        }
+    };
+
+    let rides = watch.check(&rides, Utc::now());
+    if rides.is_empty() {
+        return true;
+    }
+
+    send(session, &Event::Alert { rides }).await
+}
+
+/// Sends `event` as JSON text. Returns false if the client has gone away.
+async fn send(session: &mut Session, event: &Event<'_>) -> bool {
+    session
+        .text(serde_json::to_string(event).unwrap())
+        .await
+        .is_ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::models::{AlertMode, RideStatus};
+
+    fn parks() -> HashMap<String, Url> {
+        HashMap::from([(
+            "Cedar Point".to_string(),
+            Url::parse("https://queue-times.com/en-US/parks/1/queue_times").unwrap(),
+        )])
+    }
+
+    #[test]
+    fn test_parse() {
+        let watch = Watch::parse(
+            r#"["Cedar Point",[{"rideName":"Maverick","alertOn":"Open","mode":"once"}]]"#,
+            &parks(),
+        )
+        .unwrap();
+        assert_eq!(watch.park, "Cedar Point");
+        assert_eq!(watch.rides[0].mode, AlertMode::Once);
+
+        assert_eq!(
+            Watch::parse(r#"["Kings Island",[]]"#, &parks()).unwrap_err(),
+            "Unknown park Kings Island"
+        );
+        assert!(Watch::parse("{}", &parks())
+            .unwrap_err()
+            .starts_with("Invalid config: "));
+        assert_eq!(
+            Watch::parse(
+                r#"["Cedar Point",[{"rideName":"Maverick","alertOn":"Open","window":{"start":"09:00:00","end":"17:00:00","timezone":"America/New_York","startDate":"2024-06-02","endDate":"2024-06-01"}}]]"#,
+                &parks(),
+            )
+            .unwrap_err(),
+            "Window ends on 2024-06-01 before it starts on 2024-06-02"
+        );
+    }
+
+    #[test]
+    fn test_check() {
+        use queue_times::model::RideStatus as Status;
+
+        let mut watch = Watch::parse("[\"Cedar Point\",[]]", &parks()).unwrap();
+        watch.rides = vec![
+            RideConfig {
+                ride_name: "Maverick".to_string(),
+                alert_on: RideStatus::Open,
+                mode: AlertMode::Once,
+                ..Default::default()
+            },
+            RideConfig {
+                ride_name: "Steel Vengeance".to_string(),
+                alert_on: RideStatus::Wait(30),
+                mode: AlertMode::OnChange,
+                ..Default::default()
+            },
+        ];
+        let now = Utc::now();
+
+        let rides = [
+            RideTime::new("Maverick", Status::Wait(20)),
+            RideTime::new("Steel Vengeance", Status::Wait(45)),
+        ];
+        assert_eq!(watch.check(&rides, now), vec![&rides[0]]);
+
+        // Once alerts have disarmed, and on change alerts alert when their condition becomes met
+        let rides = [
+            RideTime::new("Maverick", Status::Wait(20)),
+            RideTime::new("Steel Vengeance", Status::Wait(25)),
+        ];
+        assert_eq!(watch.check(&rides, now), vec![&rides[1]]);
+        assert!(watch.check(&rides, now).is_empty());
+    }
+}
@@ -3,128 +3,321 @@
  */
 
 use crate::app::Application;
+use crate::channel::Channels;
+use crate::cli::Command;
+use crate::config::{Args, Config};
+use crate::metrics::Metrics;
+use crate::models::VapidKeys;
 use crate::registration::RegistrationRepository;
 use actix_files::Files;
 use actix_web::middleware::Logger;
 use actix_web::web::Data;
 use actix_web::*;
+use clap::Parser;
 use iis::get_port;
 use simplelog::{ConfigBuilder, LevelFilter};
 use std::io::Read;
+use std::path::Path;
 use std::sync::Arc;
 use web_push::{
     HyperWebPushClient, PartialVapidSignatureBuilder, VapidSignatureBuilder,
 };
 
+mod alerts;
 mod app;
+mod channel;
+mod cli;
+mod config;
+mod crowd;
 mod error;
+mod health;
+mod history;
+mod live;
+mod live_alerts;
+mod metrics;
+mod migrations;
 mod models;
+#[cfg(feature = "mqtt")]
+mod mqtt;
+mod recommend;
 mod registration;
 mod routes;
+mod store;
+mod token;
 
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
-    let config = ConfigBuilder::default()
+    let mut args = Args::parse();
+    let conf = match Config::load(&args) {
+        Ok(conf) => conf,
+        Err(why) => {
+            eprintln!("Couldn't load config: {}", why);
+            std::process::exit(1);
+        }
+    };
+
+    let log_config = ConfigBuilder::default()
         .add_filter_ignore_str("html5ever")
         .add_filter_ignore_str("selectors::matching")
         .add_filter_ignore_str("reqwest")
         .add_filter_ignore_str("hyper")
+        .add_filter_ignore_str("rumqttc")
         .build();
-    simplelog::SimpleLogger::init(
-        if cfg!(feature = "prod") {
-            LevelFilter::Info
-        } else {
-            LevelFilter::Debug
-        },
-        config,
-    )
-    .unwrap();
 
+    match args.command.take().unwrap_or(Command::Serve) {
+        Command::Serve if args.migrate_only => {
+            simplelog::SimpleLogger::init(conf.log_level, log_config).unwrap();
+
+            match store::migrate(&conf).await {
+                Ok((from, to)) => {
+                    log::info!("Migrated db from version {} to {}", from, to);
+                    Ok(())
+                }
+                Err(why) => {
+                    log::error!("Couldn't migrate db: {}", why);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Command::Serve => {
+            simplelog::SimpleLogger::init(conf.log_level, log_config).unwrap();
+            serve(conf).await
+        }
+        command => {
+            // Commands print their output to stdout, so only log problems, and to stderr
+            simplelog::WriteLogger::init(
+                conf.log_level.min(LevelFilter::Warn),
+                log_config,
+                std::io::stderr(),
+            )
+            .unwrap();
+
+            if let Err(why) = cli::run(command, conf).await {
+                eprintln!("Error: {}", why);
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Runs the web server and push loops.
+async fn serve(conf: Config) -> std::io::Result<()> {
     //Get port for listening. IIS has its own port.
     let port: String;
     if cfg!(feature = "host_iis") {
         port = get_port();
         log::info!("Got port {} from IIS", port)
     } else {
-        port = "8080".to_string();
-        log::info!(
-            "Not configured to use any server, defaulting to port: {}",
-            port
-        )
+        port = conf.port.to_string();
+        log::info!("Not hosted in IIS, using configured port: {}", port)
     }
 
     //Load keys
-    let keys = load_private_key();
-    if let Err(why) = keys {
-        log::error!("Couldn't load private key. Make sure to have a PEM pk in the file: './private_key.pem'. Generate with: `openssl ecparam -genkey -name prime256v1 -out private_key.pem`");
-        return std::io::Result::Err(why);
-    }
-    let keys = keys.unwrap();
+    let keys = load_keys(&conf)?;
+
+    let metrics = Metrics::new();
 
     //Load db
-    let subs = RegistrationRepository::init().await.unwrap();
+    let store = match store::open(&conf).await {
+        Ok(store) => store,
+        Err(why) => {
+            log::error!("Couldn't load db: {}", why);
+            std::process::exit(1);
+        }
+    };
+    let subs = match RegistrationRepository::new(metrics.measure_store(store)).await {
+        Ok(subs) => subs,
+        Err(why) => {
+            log::error!("Couldn't load db: {}", why);
+            std::process::exit(1);
+        }
+    };
+    //Registrations from before keys were recorded subscribed with the current key
+    if let Err(why) = subs.set_missing_vapid_keys(keys.current_public()).await {
+        log::error!("Couldn't record keys of old registrations: {}", why);
+        std::process::exit(1);
+    }
     //Shared caching queue times client
-    let queue_client = queue_times::client::CachedClient::new(queue_times::api::ApiClient::new());
-    //Client for sending push notifications
-    let push_client = HyperWebPushClient::new();
+    let queue_client = queue_times::client::CachedClient::with_ttl(
+        metrics.measure_client(queue_times::api::ApiClient::with_base_url(
+            conf.upstream_url.clone(),
+        )),
+        conf.cache_ttl(),
+    );
+    //Channels for sending notifications
+    let keys = Arc::new(keys);
+    let channels = match Channels::new(Box::new(HyperWebPushClient::new()), keys.clone(), &conf) {
+        Ok(channels) => channels,
+        Err(why) => {
+            log::error!("Couldn't set up notification channels: {}", why);
+            std::process::exit(1);
+        }
+    };
+
+    let bind_addr = format!("{}:{}", conf.host, port);
+    let static_dir = conf.static_dir.clone();
+    let cors_origins = conf.cors_origins.clone();
+    let shutdown_timeout = conf.shutdown_timeout();
 
     let app = Arc::new(Application::new(
         subs,
         queue_client,
-        Box::new(push_client),
+        channels,
         keys,
+        conf,
+        metrics,
     ));
-    let tokio_app = app.clone();
+    let mut loops = app.spawn_loops();
+    loops.extend(spawn_mqtt(&app));
+    let server_app = app.clone();
 
-    // Start task that checks client configs and sends push notifications on a timer
-    tokio::spawn(async move { tokio_app.push_loop().await });
-
-    HttpServer::new(move || {
-        //We don't need no security 😎
-        let cors = actix_cors::Cors::permissive();
+    let server = HttpServer::new(move || {
+        //Allow anyone unless origins are configured
+        let cors = if cors_origins.is_empty() {
+            actix_cors::Cors::permissive()
+        } else {
+            cors_origins
+                .iter()
+                .fold(actix_cors::Cors::default(), |cors, origin| {
+                    cors.allowed_origin(origin)
+                })
+                .allow_any_method()
+                .allow_any_header()
+        };
 
         App::new()
             .wrap(cors)
             .wrap(Logger::new("%{r}a %U %s"))
-            .app_data(Data::new(app.clone()))
+            .app_data(Data::new(server_app.clone()))
             //Begin endpoints
             .service(routes::registration::vapid_public_key)
             .service(routes::registration::register)
             .service(routes::registration::unregister)
+            .service(routes::registration::lookup)
+            .service(routes::registration::snooze)
+            .service(routes::registration::resume)
             .service(routes::registration::get_current_user_count)
             .service(routes::queue::get_all_parks)
             .service(routes::queue::get_park_wait_times)
-            .service(Files::new("/", "./www").index_file("index.html")) //Must be last, serves static site
+            .service(routes::queue::stream_park_wait_times)
+            .service(routes::queue::live_alerts)
+            .service(routes::queue::recommend)
+            .service(routes::queue::crowd)
+            .service(routes::metrics::get_metrics)
+            .service(routes::health::healthz)
+            .service(routes::health::readyz)
+            .service(Files::new("/", &static_dir).index_file("index.html")) //Must be last, serves static site
     })
-    .bind(format!("0.0.0.0:{}", port))?
-    .run()
-    .await
+    // Signals are handled below, so the push loops stop along with the server
+    .disable_signals()
+    .shutdown_timeout(shutdown_timeout.as_secs())
+    .bind(bind_addr)?
+    .run();
+    let server_handle = server.handle();
+    let mut server = tokio::spawn(server);
+
+    tokio::select! {
+        res = &mut server => {
+            // Only returns early if the server failed
+            app.shutdown(loops, shutdown_timeout).await;
+            return res?;
+        }
+        _ = shutdown_signal() => {}
+    }
+
+    log::info!("Shutting down");
+    // End live streams first, as the server waits for open responses to finish
+    app.begin_shutdown();
+    // Stop accepting requests, and let those in progress finish
+    server_handle.stop(true).await;
+    app.shutdown(loops, shutdown_timeout).await;
+    log::info!("Shut down");
+
+    server.await?
 }
 
-/// Loads a PEM private key from a local file './private_key.pem', and generates a public key
-/// from it. Both keys are base64URL encoded. A partial VAPID object is also returned.
+/// Starts publishing wait times to MQTT, if a broker is configured. Exits if it cannot be.
+fn spawn_mqtt(app: &Arc<Application>) -> Option<tokio::task::JoinHandle<()>> {
+    app.config.mqtt_url.as_ref()?;
+
+    #[cfg(feature = "mqtt")]
+    match mqtt::spawn(app.clone()) {
+        Ok(publisher) => Some(publisher),
+        Err(why) => {
+            log::error!("Couldn't set up MQTT: {}", why);
+            std::process::exit(1);
+        }
+    }
+
+    #[cfg(not(feature = "mqtt"))]
+    {
+        log::error!("{}", error::Error::MqttDisabled);
+        std::process::exit(1);
+    }
+}
+
+/// Waits for SIGTERM, or Ctrl-C.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        let mut term = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Couldn't listen for SIGTERM");
+
+        tokio::select! {
+            _ = term.recv() => {}
+            _ = tokio::signal::ctrl_c() => {}
+        }
+    }
+
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c()
+        .await
+        .expect("Couldn't listen for Ctrl-C");
+}
+
+/// Loads the current and previous VAPID keys set in the config.
+fn load_keys(conf: &Config) -> std::io::Result<VapidKeys> {
+    let current = load_private_key(&conf.private_key).inspect_err(|_| {
+        log::error!("Couldn't load private key. Make sure to have a PEM pk in the file: '{}'. Generate one with: `queue_alert_server gen-keys`", conf.private_key.display());
+    })?;
+
+    let previous = conf
+        .previous_keys
+        .iter()
+        .map(|path| {
+            load_private_key(path).inspect_err(|_| {
+                log::error!("Couldn't load previous key '{}'", path.display());
+            })
+        })
+        .collect::<std::io::Result<Vec<_>>>()?;
+
+    log::info!("Using pub key: {}", current.0 .0);
+
+    Ok(VapidKeys { current, previous })
+}
+
+/// Loads a PEM private key from `path`, and generates a public key
+/// from it. The public key is base64URL encoded. A partial VAPID object is also returned.
 ///
 /// # Generation
-/// `openssl ecparam -genkey -name prime256v1 -out private_key.pem`
-fn load_private_key() -> std::io::Result<(
-    models::PrivateKey,
-    models::PublicKey,
-    PartialVapidSignatureBuilder,
-)> {
-    let mut file = std::fs::File::open("./secrets/private_key.pem")?;
+/// `queue_alert_server gen-keys`, or `openssl ecparam -genkey -name prime256v1 -out private_key.pem`
+fn load_private_key(
+    path: &Path,
+) -> std::io::Result<(models::PublicKey, PartialVapidSignatureBuilder)> {
+    let mut file = std::fs::File::open(path)?;
     let mut str = String::new();
 
     file.read_to_string(&mut str)?;
 
-    let sig = VapidSignatureBuilder::from_pem_no_sub(str.as_bytes()).unwrap();
+    let sig = VapidSignatureBuilder::from_pem_no_sub(str.as_bytes())
+        .map_err(|why| std::io::Error::new(std::io::ErrorKind::InvalidData, why))?;
 
     let key_bytes = sig.get_public_key();
 
     let final_pub = base64::encode_config(key_bytes, base64::URL_SAFE_NO_PAD);
 
-    log::info!("Using pub key: {}", final_pub);
-
-    Ok((models::PrivateKey(str), models::PublicKey(final_pub), sig))
+    Ok((models::PublicKey(final_pub), sig))
 }
 
 #[cfg(test)]
@@ -154,7 +347,7 @@ mod test {
 
         println!("encoded: {}", content);
 
-        assert!(content.as_bytes().len() < 4000);
+        assert!(content.len() < 4000);
         let un_based = base64::decode(&content).unwrap();
 
         let mut decoder = GzDecoder::new(&*un_based);
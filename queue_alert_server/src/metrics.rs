@@ -0,0 +1,432 @@
+//! Prometheus metrics, served at `/metrics`.
+//!
+//! Most metrics are recorded as the server runs. Counts that the server already keeps, such as registrations and
+//! cache hits, are read when scraped instead.
+
+use crate::channel::SendError;
+use crate::error::Error;
+use crate::models::Registration;
+use crate::store::{CheckReport, Delivery, Loaded, RegistrationStore};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use prometheus::{
+    exponential_buckets, Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter,
+    IntCounterVec, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use queue_times::client::{CacheStats, QueueTimesClient};
+use queue_times::model::RideTime;
+use std::collections::HashMap;
+use std::future::Future;
+use url::Url;
+
+/// Prefix of every metric name.
+const NAMESPACE: &str = "queue_alert";
+
+/// Metrics recorded while the server runs.
+pub struct Metrics {
+    registry: Registry,
+    /// Push notifications we tried to send
+    push_attempts: IntCounter,
+    /// Push notifications accepted by the push service
+    push_successes: IntCounter,
+    /// Push notifications that failed, by kind of error
+    push_failures: IntCounterVec,
+    /// How long each push loop tick takes
+    pub tick_duration: Histogram,
+    /// How long each call to the queue times site takes, by call
+    upstream_duration: HistogramVec,
+    /// Calls to the queue times site that failed, by call
+    upstream_errors: IntCounterVec,
+    /// How long each query to the registration store takes, by query
+    db_duration: HistogramVec,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new_custom(Some(NAMESPACE.to_string()), None).unwrap();
+
+        let push_attempts =
+            IntCounter::new("push_attempts_total", "Push notifications sent").unwrap();
+        let push_successes = IntCounter::new(
+            "push_successes_total",
+            "Push notifications accepted by the push service",
+        )
+        .unwrap();
+        let push_failures = IntCounterVec::new(
+            Opts::new("push_failures_total", "Push notifications that failed"),
+            &["error"],
+        )
+        .unwrap();
+        let tick_duration = Histogram::with_opts(
+            HistogramOpts::new(
+                "push_tick_duration_seconds",
+                "Time taken to check and alert every registration",
+            )
+            .buckets(exponential_buckets(0.01, 2.0, 14).unwrap()),
+        )
+        .unwrap();
+        let upstream_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "upstream_request_duration_seconds",
+                "Time taken by calls to the queue times site",
+            ),
+            &["call"],
+        )
+        .unwrap();
+        let upstream_errors = IntCounterVec::new(
+            Opts::new(
+                "upstream_errors_total",
+                "Calls to the queue times site that failed",
+            ),
+            &["call"],
+        )
+        .unwrap();
+        let db_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "db_query_duration_seconds",
+                "Time taken by queries to the registration store",
+            ),
+            &["query"],
+        )
+        .unwrap();
+
+        registry.register(Box::new(push_attempts.clone())).unwrap();
+        registry.register(Box::new(push_successes.clone())).unwrap();
+        registry.register(Box::new(push_failures.clone())).unwrap();
+        registry.register(Box::new(tick_duration.clone())).unwrap();
+        registry
+            .register(Box::new(upstream_duration.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(upstream_errors.clone()))
+            .unwrap();
+        registry.register(Box::new(db_duration.clone())).unwrap();
+
+        Self {
+            registry,
+            push_attempts,
+            push_successes,
+            push_failures,
+            tick_duration,
+            upstream_duration,
+            upstream_errors,
+            db_duration,
+        }
+    }
+
+    /// Wraps a queue times client so that its calls are measured.
+    pub fn measure_client<T: QueueTimesClient>(&self, client: T) -> MeasuredClient<T> {
+        MeasuredClient {
+            client,
+            duration: self.upstream_duration.clone(),
+            errors: self.upstream_errors.clone(),
+        }
+    }
+
+    /// Wraps a registration store so that its queries are measured.
+    pub fn measure_store(&self, store: Box<dyn RegistrationStore>) -> Box<dyn RegistrationStore> {
+        Box::new(MeasuredStore {
+            store,
+            duration: self.db_duration.clone(),
+        })
+    }
+
+    /// Records the result of sending a notification.
+    pub fn record_push(&self, res: &Result<(), SendError>) {
+        self.push_attempts.inc();
+
+        match res {
+            Ok(_) => self.push_successes.inc(),
+            Err(why) => self
+                .push_failures
+                .with_label_values(&[why.short_description()])
+                .inc(),
+        }
+    }
+
+    /// Renders every metric in the Prometheus text format, including the passed counts read at scrape time.
+    pub fn render(&self, users_by_park: &HashMap<String, usize>, cache: CacheStats) -> String {
+        let scraped = Registry::new_custom(Some(NAMESPACE.to_string()), None).unwrap();
+
+        let users = IntGaugeVec::new(
+            Opts::new("registrations", "Registered users, by park"),
+            &["park"],
+        )
+        .unwrap();
+        for (park, count) in users_by_park {
+            users.with_label_values(&[park]).set(*count as i64);
+        }
+
+        let hits = IntCounter::new(
+            "cache_hits_total",
+            "Queue times calls answered from the cache",
+        )
+        .unwrap();
+        hits.inc_by(cache.hits);
+        let misses = IntCounter::new(
+            "cache_misses_total",
+            "Queue times calls that went to the queue times site",
+        )
+        .unwrap();
+        misses.inc_by(cache.misses);
+
+        scraped.register(Box::new(users)).unwrap();
+        scraped.register(Box::new(hits)).unwrap();
+        scraped.register(Box::new(misses)).unwrap();
+
+        let mut families = self.registry.gather();
+        families.extend(scraped.gather());
+
+        let mut out = Vec::new();
+        TextEncoder::new().encode(&families, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Queue times client that measures the calls made by the client it wraps.
+pub struct MeasuredClient<T> {
+    client: T,
+    duration: HistogramVec,
+    errors: IntCounterVec,
+}
+
+impl<T> MeasuredClient<T> {
+    /// Runs a call, recording how long it took and if it failed.
+    async fn measure<R>(
+        &self,
+        call: &str,
+        fut: impl Future<Output = queue_times::error::Result<R>>,
+    ) -> queue_times::error::Result<R> {
+        let timer = self.duration.with_label_values(&[call]).start_timer();
+        let res = fut.await;
+        timer.observe_duration();
+
+        if res.is_err() {
+            self.errors.with_label_values(&[call]).inc();
+        }
+        res
+    }
+}
+
+#[async_trait]
+impl<T: QueueTimesClient + Send + Sync> QueueTimesClient for MeasuredClient<T> {
+    async fn get_park_urls(&self) -> queue_times::error::Result<HashMap<String, Url>> {
+        self.measure("get_park_urls", self.client.get_park_urls())
+            .await
+    }
+
+    async fn get_ride_times(&self, park_url: Url) -> queue_times::error::Result<Vec<RideTime>> {
+        self.measure("get_ride_times", self.client.get_ride_times(park_url))
+            .await
+    }
+}
+
+/// Registration store that measures the queries made by the store it wraps.
+struct MeasuredStore {
+    store: Box<dyn RegistrationStore>,
+    duration: HistogramVec,
+}
+
+impl MeasuredStore {
+    /// Runs a query, recording how long it took.
+    async fn measure<R>(&self, query: &str, fut: impl Future<Output = R>) -> R {
+        let _timer = self.duration.with_label_values(&[query]).start_timer();
+        fut.await
+    }
+}
+
+#[async_trait]
+impl RegistrationStore for MeasuredStore {
+    async fn load_all(&self, sink: &mut (dyn FnMut(Registration) + Send)) -> Result<Loaded, Error> {
+        self.measure("load_all", self.store.load_all(sink)).await
+    }
+
+    async fn load(&self, endpoint: &str) -> Result<Option<Registration>, Error> {
+        self.measure("load", self.store.load(endpoint)).await
+    }
+
+    async fn check(&self) -> Result<CheckReport, Error> {
+        self.measure("check", self.store.check()).await
+    }
+
+    async fn ping(&self) -> Result<(), Error> {
+        self.measure("ping", self.store.ping()).await
+    }
+
+    async fn insert(&self, reg: &Registration) -> Result<(), Error> {
+        self.measure("insert", self.store.insert(reg)).await
+    }
+
+    async fn update(&self, reg: &Registration) -> Result<(), Error> {
+        self.measure("update", self.store.update(reg)).await
+    }
+
+    async fn remove(&self, endpoint: &str) -> Result<bool, Error> {
+        self.measure("remove", self.store.remove(endpoint)).await
+    }
+
+    async fn set_token_hash(&self, endpoint: &str, hash: &str) -> Result<(), Error> {
+        self.measure("set_token_hash", self.store.set_token_hash(endpoint, hash))
+            .await
+    }
+
+    async fn set_missing_vapid_keys(&self, key: &str) -> Result<(), Error> {
+        self.measure(
+            "set_missing_vapid_keys",
+            self.store.set_missing_vapid_keys(key),
+        )
+        .await
+    }
+
+    async fn snooze(
+        &self,
+        endpoint: &str,
+        ride_name: Option<&str>,
+        until: Option<DateTime<Utc>>,
+    ) -> Result<(), Error> {
+        self.measure("snooze", self.store.snooze(endpoint, ride_name, until))
+            .await
+    }
+
+    async fn mark_alerted(
+        &self,
+        alerted: &[(String, Vec<String>)],
+        at: DateTime<Utc>,
+    ) -> Result<(), Error> {
+        self.measure("mark_alerted", self.store.mark_alerted(alerted, at))
+            .await
+    }
+
+    async fn save_matched(&self, matched: &[(String, String, bool)]) -> Result<(), Error> {
+        self.measure("save_matched", self.store.save_matched(matched))
+            .await
+    }
+
+    async fn mark_groups_alerted(
+        &self,
+        alerted: &[(String, Vec<String>)],
+        at: DateTime<Utc>,
+    ) -> Result<(), Error> {
+        self.measure(
+            "mark_groups_alerted",
+            self.store.mark_groups_alerted(alerted, at),
+        )
+        .await
+    }
+
+    async fn save_groups_matched(&self, matched: &[(String, String, bool)]) -> Result<(), Error> {
+        self.measure(
+            "save_groups_matched",
+            self.store.save_groups_matched(matched),
+        )
+        .await
+    }
+
+    async fn mark_crowd_alerted(&self, alerted: &[String], at: DateTime<Utc>) -> Result<(), Error> {
+        self.measure(
+            "mark_crowd_alerted",
+            self.store.mark_crowd_alerted(alerted, at),
+        )
+        .await
+    }
+
+    async fn save_crowd_matched(&self, matched: &[(String, bool)]) -> Result<(), Error> {
+        self.measure("save_crowd_matched", self.store.save_crowd_matched(matched))
+            .await
+    }
+
+    async fn log_delivery(&self, delivery: &Delivery) -> Result<(), Error> {
+        self.measure("log_delivery", self.store.log_delivery(delivery))
+            .await
+    }
+
+    async fn deliveries(&self, endpoint: &str, limit: i64) -> Result<Vec<Delivery>, Error> {
+        self.measure("deliveries", self.store.deliveries(endpoint, limit))
+            .await
+    }
+
+    async fn prune_deliveries(&self, before: DateTime<Utc>) -> Result<u64, Error> {
+        self.measure("prune_deliveries", self.store.prune_deliveries(before))
+            .await
+    }
+
+    async fn close(&self) {
+        self.store.close().await
+    }
+
+    fn is_shared(&self) -> bool {
+        self.store.is_shared()
+    }
+
+    async fn try_lead(&self) -> Result<bool, Error> {
+        self.measure("try_lead", self.store.try_lead()).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::registration::RegistrationRepository;
+    use crate::store::sqlite::test::TempDb;
+    use crate::store::sqlite::SqliteStore;
+    use crate::store::test::registration;
+    use web_push::WebPushError;
+
+    /// Fails every call, without touching the network.
+    struct DownClient;
+
+    #[async_trait]
+    impl QueueTimesClient for DownClient {
+        async fn get_park_urls(&self) -> queue_times::error::Result<HashMap<String, Url>> {
+            Err("down".into())
+        }
+
+        async fn get_ride_times(
+            &self,
+            _park_url: Url,
+        ) -> queue_times::error::Result<Vec<RideTime>> {
+            Err("down".into())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_render() {
+        let metrics = Metrics::new();
+
+        metrics.record_push(&Ok(()));
+        metrics.record_push(&Err(WebPushError::EndpointNotValid.into()));
+        metrics.record_push(&Err(WebPushError::PayloadTooLarge.into()));
+
+        let client = metrics.measure_client(DownClient);
+        assert!(client.get_park_urls().await.is_err());
+
+        let file = TempDb::new("metrics");
+        let store = metrics.measure_store(Box::new(SqliteStore::open(&file.0).await.unwrap()));
+        let subs = RegistrationRepository::new(store).await.unwrap();
+        subs.add_registration(registration("https://push.example.com/a"))
+            .await
+            .unwrap();
+
+        let text = metrics.render(&subs.count_by_park(), CacheStats { hits: 3, misses: 1 });
+
+        assert!(text.contains("queue_alert_push_attempts_total 3"));
+        assert!(text.contains("queue_alert_push_successes_total 1"));
+        assert!(text.contains("queue_alert_push_failures_total{error=\"endpoint_not_valid\"} 1"));
+        assert!(text.contains("queue_alert_push_failures_total{error=\"payload_too_large\"} 1"));
+        assert!(text.contains("queue_alert_upstream_errors_total{call=\"get_park_urls\"} 1"));
+        assert!(text.contains(
+            "queue_alert_upstream_request_duration_seconds_count{call=\"get_park_urls\"} 1"
+        ));
+        assert!(text.contains("queue_alert_db_query_duration_seconds_count{query=\"load_all\"} 1"));
+        assert!(text.contains("queue_alert_db_query_duration_seconds_count{query=\"insert\"} 1"));
+        assert!(text.contains("queue_alert_registrations{park=\"Cedar Point\"} 1"));
+        assert!(text.contains("queue_alert_cache_hits_total 3"));
+        assert!(text.contains("queue_alert_cache_misses_total 1"));
+    }
+}
@@ -0,0 +1,378 @@
+//! Versioned schema migrations.
+//!
+//! Each migration is applied in its own transaction, along with a row in SCHEMA_VERSION recording it. Migrations must
+//! never be edited once released, schema changes go in a new migration instead.
+
+use crate::error::Error;
+use sqlx::sqlite::SqliteRow;
+use sqlx::{query, Executor, Row, SqlitePool};
+
+/// A single schema change.
+pub struct Migration {
+    pub version: i64,
+    pub name: &'static str,
+    sql: &'static str,
+}
+
+/// All migrations, in the order they are applied.
+pub static MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "initial",
+        sql: include_str!("../sql/migrations/0001_initial.sql"),
+    },
+    Migration {
+        version: 2,
+        name: "windows",
+        sql: include_str!("../sql/migrations/0002_windows.sql"),
+    },
+    Migration {
+        version: 3,
+        name: "expiry",
+        sql: include_str!("../sql/migrations/0003_expiry.sql"),
+    },
+    Migration {
+        version: 4,
+        name: "snooze",
+        sql: include_str!("../sql/migrations/0004_snooze.sql"),
+    },
+    Migration {
+        version: 5,
+        name: "alert_modes",
+        sql: include_str!("../sql/migrations/0005_alert_modes.sql"),
+    },
+    Migration {
+        version: 6,
+        name: "tokens",
+        sql: include_str!("../sql/migrations/0006_tokens.sql"),
+    },
+    Migration {
+        version: 7,
+        name: "vapid_keys",
+        sql: include_str!("../sql/migrations/0007_vapid_keys.sql"),
+    },
+    Migration {
+        version: 8,
+        name: "quarantine",
+        sql: include_str!("../sql/migrations/0008_quarantine.sql"),
+    },
+    Migration {
+        version: 9,
+        name: "matched",
+        sql: include_str!("../sql/migrations/0009_matched.sql"),
+    },
+    Migration {
+        version: 10,
+        name: "channels",
+        sql: include_str!("../sql/migrations/0010_channels.sql"),
+    },
+    Migration {
+        version: 11,
+        name: "webhooks",
+        sql: include_str!("../sql/migrations/0011_webhooks.sql"),
+    },
+    Migration {
+        version: 12,
+        name: "groups",
+        sql: include_str!("../sql/migrations/0012_groups.sql"),
+    },
+    Migration {
+        version: 13,
+        name: "crowd",
+        sql: include_str!("../sql/migrations/0013_crowd.sql"),
+    },
+];
+
+/// Version of the newest migration.
+pub fn latest_version() -> i64 {
+    MIGRATIONS.last().map_or(0, |m| m.version)
+}
+
+/// Brings the db up to the latest schema. Returns the version the db was at before migrating.
+///
+/// # Errors
+/// Errors if the db was migrated by a newer server, or if a migration fails. Failed migrations are rolled back.
+pub async fn run(db: &SqlitePool) -> Result<i64, Error> {
+    db.execute(
+        "CREATE TABLE IF NOT EXISTS SCHEMA_VERSION
+        (
+            version    INTEGER NOT NULL,
+            name       TEXT    NOT NULL,
+            applied_at TEXT    NOT NULL,
+            PRIMARY KEY (version)
+        )",
+    )
+    .await?;
+
+    let mut version = current_version(db).await?;
+    if version == 0 {
+        version = legacy_version(db).await?;
+
+        if version > 0 {
+            log::info!(
+                "Found a db from before versioned migrations, at version {}",
+                version
+            );
+            let mut trans = db.begin().await?;
+            for migration in MIGRATIONS.iter().take_while(|m| m.version <= version) {
+                record(&mut *trans, migration).await?;
+            }
+            trans.commit().await?;
+        }
+    }
+
+    if version > latest_version() {
+        return Err(Error::SchemaTooNew(version, latest_version()));
+    }
+
+    let from = version;
+    for migration in MIGRATIONS.iter().filter(|m| m.version > from) {
+        log::info!(
+            "Applying migration {} {}",
+            migration.version,
+            migration.name
+        );
+
+        let mut trans = db.begin().await?;
+        trans.execute(migration.sql).await?;
+        record(&mut *trans, migration).await?;
+        trans.commit().await?;
+    }
+
+    Ok(from)
+}
+
+/// Gets the newest applied migration, or 0 if none have been.
+pub async fn current_version(db: &SqlitePool) -> Result<i64, Error> {
+    Ok(
+        query("SELECT COALESCE(MAX(version), 0) FROM SCHEMA_VERSION")
+            .map(|r: SqliteRow| r.get(0))
+            .fetch_one(db)
+            .await?,
+    )
+}
+
+/// Works out which migrations a db from before versioning already has. Only the baseline schema of migration 1 was
+/// released before then, so this is 1 if its tables exist, or 0 if the db is new.
+async fn legacy_version(db: &SqlitePool) -> Result<i64, Error> {
+    let exists: bool = query(
+        "SELECT COUNT(*) > 0 FROM sqlite_master WHERE type = 'table' AND name = 'REGISTRATIONS'",
+    )
+    .map(|r: SqliteRow| r.get(0))
+    .fetch_one(db)
+    .await?;
+
+    Ok(if exists { 1 } else { 0 })
+}
+
+/// Records that a migration was applied.
+async fn record<'e, E>(executor: E, migration: &Migration) -> Result<(), Error>
+where
+    E: Executor<'e, Database = sqlx::Sqlite>,
+{
+    query("INSERT INTO SCHEMA_VERSION (version, name, applied_at) VALUES (?, ?, datetime())")
+        .bind(migration.version)
+        .bind(migration.name)
+        .execute(executor)
+        .await?;
+
+    Ok(())
+}
+
+/// Migrations for the Postgres store.
+///
+/// These are separate from the SQLite migrations, as the SQL differs. Postgres support was added after versioning, so
+/// there are no legacy dbs to detect.
+#[cfg(feature = "postgres")]
+pub mod postgres {
+    use super::Migration;
+    use crate::error::Error;
+    use sqlx::{query, query_scalar, Connection, Executor, PgConnection, PgPool};
+
+    /// Advisory lock held while migrating, so servers starting together do not race.
+    const MIGRATION_LOCK: i64 = 0x5141_0001;
+
+    /// All Postgres migrations, in the order they are applied.
+    pub static MIGRATIONS: &[Migration] = &[
+        Migration {
+            version: 1,
+            name: "initial",
+            sql: include_str!("../sql/postgres/0001_initial.sql"),
+        },
+        Migration {
+            version: 2,
+            name: "quarantine",
+            sql: include_str!("../sql/postgres/0002_quarantine.sql"),
+        },
+        Migration {
+            version: 3,
+            name: "matched",
+            sql: include_str!("../sql/postgres/0003_matched.sql"),
+        },
+        Migration {
+            version: 4,
+            name: "channels",
+            sql: include_str!("../sql/postgres/0004_channels.sql"),
+        },
+        Migration {
+            version: 5,
+            name: "webhooks",
+            sql: include_str!("../sql/postgres/0005_webhooks.sql"),
+        },
+        Migration {
+            version: 6,
+            name: "groups",
+            sql: include_str!("../sql/postgres/0006_groups.sql"),
+        },
+        Migration {
+            version: 7,
+            name: "crowd",
+            sql: include_str!("../sql/postgres/0007_crowd.sql"),
+        },
+    ];
+
+    /// Version of the newest migration.
+    pub fn latest_version() -> i64 {
+        MIGRATIONS.last().map_or(0, |m| m.version)
+    }
+
+    /// Brings the db up to the latest schema. Returns the version the db was at before migrating.
+    ///
+    /// # Errors
+    /// Errors if the db was migrated by a newer server, or if a migration fails. Failed migrations are rolled back.
+    pub async fn run(db: &PgPool) -> Result<i64, Error> {
+        let mut conn = db.acquire().await?;
+
+        query("SELECT pg_advisory_lock($1)")
+            .bind(MIGRATION_LOCK)
+            .execute(&mut *conn)
+            .await?;
+        let res = run_locked(&mut conn).await;
+        query("SELECT pg_advisory_unlock($1)")
+            .bind(MIGRATION_LOCK)
+            .execute(&mut *conn)
+            .await?;
+
+        res
+    }
+
+    async fn run_locked(conn: &mut PgConnection) -> Result<i64, Error> {
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS SCHEMA_VERSION
+            (
+                version    BIGINT      NOT NULL,
+                name       TEXT        NOT NULL,
+                applied_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+                PRIMARY KEY (version)
+            )",
+        )
+        .await?;
+
+        let from = current_version(&mut *conn).await?;
+        if from > latest_version() {
+            return Err(Error::SchemaTooNew(from, latest_version()));
+        }
+
+        for migration in MIGRATIONS.iter().filter(|m| m.version > from) {
+            log::info!(
+                "Applying postgres migration {} {}",
+                migration.version,
+                migration.name
+            );
+
+            let mut trans = conn.begin().await?;
+            trans.execute(migration.sql).await?;
+            query("INSERT INTO SCHEMA_VERSION (version, name) VALUES ($1, $2)")
+                .bind(migration.version)
+                .bind(migration.name)
+                .execute(&mut *trans)
+                .await?;
+            trans.commit().await?;
+        }
+
+        Ok(from)
+    }
+
+    /// Gets the newest applied migration, or 0 if none have been.
+    pub async fn current_version(conn: &mut PgConnection) -> Result<i64, Error> {
+        Ok(
+            query_scalar("SELECT COALESCE(MAX(version), 0) FROM SCHEMA_VERSION")
+                .fetch_one(conn)
+                .await?,
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::registration::RegistrationRepository;
+    use crate::store::sqlite::test::TempDb;
+    use crate::store::sqlite::SqliteStore;
+    use crate::store::DEFAULT_EXPIRY_DAYS;
+    use chrono::{Duration, Utc};
+
+    #[test]
+    fn test_versions_are_ordered() {
+        for (i, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(migration.version, i as i64 + 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_new_db() {
+        let file = TempDb::new("new");
+        let db = file.pool().await;
+
+        assert_eq!(run(&db).await.unwrap(), 0);
+        assert_eq!(current_version(&db).await.unwrap(), latest_version());
+
+        // Running again does nothing
+        assert_eq!(run(&db).await.unwrap(), latest_version());
+    }
+
+    #[tokio::test]
+    async fn test_upgrade_baseline_fixture() {
+        let file = TempDb::new("baseline");
+        let db = file.pool().await;
+        db.execute(include_str!("../sql/fixtures/baseline.sql"))
+            .await
+            .unwrap();
+
+        assert_eq!(run(&db).await.unwrap(), 1);
+        assert_eq!(current_version(&db).await.unwrap(), latest_version());
+        db.close().await;
+
+        // Existing registrations survive, with defaults for everything added since
+        let store = SqliteStore::open(&file.0).await.unwrap();
+        let subs = RegistrationRepository::new(Box::new(store)).await.unwrap();
+        assert_eq!(subs.get_current_user_count(), 2);
+
+        let reg = subs.cache.get("https://push.example.com/a").unwrap();
+        assert_eq!(reg.config.0, "Cedar Point");
+        assert_eq!(reg.config.1.len(), 2);
+        // Expiry starts from the upgrade, not from when they registered
+        let expires_at = reg.expires_at.unwrap();
+        assert!(
+            expires_at > Utc::now() + Duration::days(DEFAULT_EXPIRY_DAYS) - Duration::minutes(1)
+        );
+        assert!(expires_at <= Utc::now() + Duration::days(DEFAULT_EXPIRY_DAYS));
+        assert!(subs.expired_endpoints(Utc::now()).is_empty());
+        assert!(reg.token_hash.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_newer_db_is_rejected() {
+        let file = TempDb::new("newer");
+        let db = file.pool().await;
+        run(&db).await.unwrap();
+
+        query("INSERT INTO SCHEMA_VERSION VALUES (?, 'future', datetime())")
+            .bind(latest_version() + 1)
+            .execute(&db)
+            .await
+            .unwrap();
+
+        assert!(matches!(run(&db).await, Err(Error::SchemaTooNew(..))));
+    }
+}
@@ -2,18 +2,43 @@
  * Copyright (c) 2021. Andrew Ealovega
  */
 
+use chrono::{DateTime, Datelike, Duration, NaiveDate, NaiveTime, Utc, Weekday};
+use chrono_tz::Tz;
+use lettre::Address;
+use queue_times::model::{CrowdLevel, ParkCrowd, RideTime};
 use serde::{Deserialize, Serialize};
+use url::Url;
 use web_push::{PartialVapidSignatureBuilder, SubscriptionInfo};
 
-/// EC base64 encoded private key.
-#[derive(Clone)]
-pub struct PrivateKey(pub String);
-
 /// EC base64 encoded public key.
 #[derive(Clone)]
 pub struct PublicKey(pub String);
 
-pub type Keys = (PrivateKey, PublicKey, PartialVapidSignatureBuilder);
+pub type Keys = (PublicKey, PartialVapidSignatureBuilder);
+
+/// Every VAPID key the server can sign pushes with.
+///
+/// Subscriptions are bound to the key they were made with, so retired keys are kept until their clients resubscribe.
+pub struct VapidKeys {
+    /// Key that new subscriptions are made with.
+    pub current: Keys,
+    /// Retired keys, still used to sign pushes to the clients that subscribed with them.
+    pub previous: Vec<Keys>,
+}
+
+impl VapidKeys {
+    /// Base64URL public key of the current key.
+    pub fn current_public(&self) -> &str {
+        &self.current.0 .0
+    }
+
+    /// Gets the keys with the passed base64URL public key, if we have them.
+    pub fn get(&self, public: &str) -> Option<&Keys> {
+        std::iter::once(&self.current)
+            .chain(self.previous.iter())
+            .find(|k| k.0 .0 == public)
+    }
+}
 
 /// Current operating status of a ride. Defaults to `Closed`.
 #[derive(Copy, Clone, Debug, Serialize, Deserialize, Ord, PartialOrd, Eq, PartialEq, Hash, Default)]
@@ -28,19 +53,700 @@ pub enum RideStatus {
     Closed
 }
 
+/// A period of time during which alerts are evaluated.
+///
+/// Times are wall clock times in `timezone`, which should be the park's local timezone.
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq, Hash)]
+#[serde(rename_all = "camelCase")]
+pub struct ActiveWindow {
+    /// Time of day alerts start, inclusive.
+    pub start: NaiveTime,
+    /// Time of day alerts stop, exclusive. If this is before `start`, the window runs past midnight. If it equals
+    /// `start`, the window lasts all day.
+    pub end: NaiveTime,
+    /// IANA timezone the window is in, eg. `America/New_York`.
+    pub timezone: Tz,
+    /// Days of the week the window applies to. Applies every day if empty.
+    #[serde(default)]
+    pub days: Vec<Weekday>,
+    /// First day the window applies, inclusive.
+    #[serde(default)]
+    pub start_date: Option<NaiveDate>,
+    /// Last day the window applies, inclusive.
+    #[serde(default)]
+    pub end_date: Option<NaiveDate>,
+}
+
+impl ActiveWindow {
+    /// Checks if `now` falls inside this window.
+    pub fn is_active(&self, now: DateTime<Utc>) -> bool {
+        let local = now.with_timezone(&self.timezone);
+        let time = local.time();
+        let mut day = local.date_naive();
+
+        if self.start == self.end {
+            // All day
+        } else if self.start < self.end {
+            if time < self.start || time >= self.end {
+                return false;
+            }
+        } else if time < self.end {
+            // Early morning part of a window that started the day before
+            day -= Duration::days(1);
+        } else if time < self.start {
+            return false;
+        }
+
+        if !self.days.is_empty() && !self.days.contains(&day.weekday()) {
+            return false;
+        }
+
+        self.start_date.is_none_or(|d| day >= d) && self.end_date.is_none_or(|d| day <= d)
+    }
+
+    /// Checks that this window is ever active.
+    ///
+    /// # Errors
+    /// Returns why the window is invalid.
+    pub fn validate(&self) -> Result<(), String> {
+        match (self.start_date, self.end_date) {
+            (Some(start), Some(end)) if start > end => Err(format!(
+                "Window ends on {} before it starts on {}",
+                end, start
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// How often a ride alerts once its condition is met. Defaults to `Repeat`.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, Eq, PartialEq, Hash, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertMode {
+    /// Alert the first time the condition is met, then disarm.
+    Once,
+    /// Alert every time the condition is checked and met.
+    #[default]
+    Repeat,
+    /// Alert each time the condition goes from unmet to met.
+    OnChange,
+}
+
 /// Clients alert config.
-#[derive(Clone, Debug, Serialize, Deserialize, Ord, PartialOrd, Eq, PartialEq, Default, Hash)]
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq, Default, Hash)]
 #[serde(rename_all = "camelCase")]
 pub struct RideConfig {
     pub ride_name: String,
     pub alert_on: RideStatus,
+    #[serde(default)]
+    pub mode: AlertMode,
+    /// Only alert on this ride during this window, if set.
+    #[serde(default)]
+    pub window: Option<ActiveWindow>,
+    /// Alerts for this ride are paused until this time. Set by the server.
+    #[serde(default, skip_deserializing)]
+    pub snoozed_until: Option<DateTime<Utc>>,
+    /// Last time an alert for this ride was delivered. Set by the server.
+    #[serde(default, skip_deserializing)]
+    pub last_alerted: Option<DateTime<Utc>>,
+    /// If the alert condition was met the last time it was checked. Kept in memory, and saved on shutdown.
+    #[serde(skip)]
+    pub matched: bool,
+}
+
+impl RideConfig {
+    /// Checks if this ride should be evaluated at `now`.
+    pub fn is_active(&self, now: DateTime<Utc>) -> bool {
+        self.snoozed_until.is_none_or(|s| s <= now)
+            && self.window.as_ref().is_none_or(|w| w.is_active(now))
+    }
+
+    /// Checks that this ride's window is ever active.
+    ///
+    /// # Errors
+    /// Returns why the window is invalid.
+    pub fn validate(&self) -> Result<(), String> {
+        self.window.as_ref().map_or(Ok(()), ActiveWindow::validate)
+    }
+}
+
+/// How many rides in a group must meet their condition for the group to alert. Defaults to `Any`.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, Eq, PartialEq, Hash, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum GroupRequire {
+    /// At least one ride.
+    #[default]
+    Any,
+    /// Every ride.
+    All,
+    /// At least this many rides.
+    AtLeast(u16),
+}
+
+/// A ride in a group, and the condition it must meet.
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq, Hash)]
+#[serde(rename_all = "camelCase")]
+pub struct GroupRide {
+    pub ride_name: String,
+    pub alert_on: RideStatus,
+}
+
+/// Alerts when enough rides in a group meet their conditions at once, eg. when any coaster in a list is under 20
+/// minutes, or when two rides are both open.
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq, Default, Hash)]
+#[serde(rename_all = "camelCase")]
+pub struct GroupConfig {
+    /// Names the group in alerts. Unique within a registration.
+    pub name: String,
+    pub rides: Vec<GroupRide>,
+    #[serde(default)]
+    pub require: GroupRequire,
+    #[serde(default)]
+    pub mode: AlertMode,
+    /// Last time an alert for this group was delivered. Set by the server.
+    #[serde(default, skip_deserializing)]
+    pub last_alerted: Option<DateTime<Utc>>,
+    /// If the group's rule was met the last time it was checked. Kept in memory, and saved on shutdown.
+    #[serde(skip)]
+    pub matched: bool,
+}
+
+impl GroupConfig {
+    /// Checks that this group could ever alert, and that no ride is in it twice.
+    ///
+    /// # Errors
+    /// Returns why the group is invalid.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.name.is_empty() {
+            return Err("Groups must have a name".to_string());
+        }
+        if self.rides.is_empty() {
+            return Err(format!("Group {} has no rides", self.name));
+        }
+        for (i, ride) in self.rides.iter().enumerate() {
+            if self.rides[..i].iter().any(|r| r.ride_name == ride.ride_name) {
+                return Err(format!("Group {} has {} twice", self.name, ride.ride_name));
+            }
+        }
+        if let GroupRequire::AtLeast(count) = self.require {
+            if count == 0 || count as usize > self.rides.len() {
+                return Err(format!(
+                    "Group {} requires {} of its {} rides",
+                    self.name,
+                    count,
+                    self.rides.len()
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Alerts when the crowd at the park drops below a level, eg. when it is no longer `Moderate` or busier.
+///
+/// The crowd level is that of [`ParkCrowd::level`], so a park with no posted waits is never below any level.
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq, Default, Hash)]
+#[serde(rename_all = "camelCase")]
+pub struct CrowdAlert {
+    pub below: CrowdLevel,
+    #[serde(default)]
+    pub mode: AlertMode,
+    /// Last time a crowd alert was delivered. Set by the server.
+    #[serde(default, skip_deserializing)]
+    pub last_alerted: Option<DateTime<Utc>>,
+    /// If the crowd was below `below` the last time it was checked. Kept in memory, and saved on shutdown.
+    #[serde(skip)]
+    pub matched: bool,
+}
+
+/// Where a registration's alerts are delivered. Defaults to `WebPush`.
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq, Default)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum Channel {
+    /// Push to the browser that made the registration.
+    #[default]
+    WebPush,
+    /// POST the payload as JSON to a url.
+    Webhook { url: Url },
+    /// Publish a plain text message to an ntfy style topic url, eg. `https://ntfy.sh/my-topic`.
+    Ntfy { url: Url },
+    /// Email a plain text message over SMTP.
+    Email { to: Address },
+}
+
+impl Channel {
+    /// Short name of the channel, for logs and errors.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Channel::WebPush => "web push",
+            Channel::Webhook { .. } => "webhook",
+            Channel::Ntfy { .. } => "ntfy",
+            Channel::Email { .. } => "email",
+        }
+    }
+
+    /// Where this channel delivers to, which is used as the endpoint of registrations on it. `None` for Web Push, as
+    /// its registrations use their push endpoint.
+    pub fn address(&self) -> Option<String> {
+        match self {
+            Channel::WebPush => None,
+            Channel::Webhook { url } | Channel::Ntfy { url } => Some(url.to_string()),
+            Channel::Email { to } => Some(format!("mailto:{}", to)),
+        }
+    }
+}
+
+/// Placeholder subscription of registrations that are not on Web Push. The endpoint is filled in with their address.
+fn no_subscription() -> SubscriptionInfo {
+    SubscriptionInfo::new("", "", "")
 }
 
 /// A clients registration.
-#[derive(serde::Deserialize, serde::Serialize)]
+#[derive(serde::Deserialize, serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
 pub struct Registration {
-    /// Push API endpoint info.
+    /// Push API endpoint info. Registrations on other channels only use its endpoint, which is their address.
+    #[serde(default = "no_subscription")]
     pub sub: SubscriptionInfo,
+    /// Where alerts are delivered.
+    #[serde(default)]
+    pub channel: Channel,
     /// Users config. Tuple of (park, Rides to wait on).
     pub config: (String, Vec<RideConfig>),
-}
\ No newline at end of file
+    /// Rules over several rides in the park, alerting when enough of them meet their conditions.
+    #[serde(default)]
+    pub groups: Vec<GroupConfig>,
+    /// Alert when the park's crowd drops below a level, if set.
+    #[serde(default)]
+    pub crowd: Option<CrowdAlert>,
+    /// Only alert on any ride during this window, if set.
+    #[serde(default)]
+    pub window: Option<ActiveWindow>,
+    /// Time this registration is removed. The server picks a default if unset.
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+    /// All alerts are paused until this time. Set by the server.
+    #[serde(default, skip_deserializing)]
+    pub snoozed_until: Option<DateTime<Utc>>,
+    /// Base64URL VAPID public key the client subscribed with. Set to the current key if the client does not send it.
+    #[serde(default)]
+    pub vapid_key: Option<String>,
+    /// Hash of the token proving ownership of this registration. `None` for registrations made before tokens
+    /// existed, until one is delivered to them.
+    #[serde(skip)]
+    pub token_hash: Option<String>,
+    /// Secret that webhook deliveries are signed with. Set by the server for webhook registrations.
+    #[serde(skip)]
+    pub signing_secret: Option<String>,
+}
+
+impl Registration {
+    /// Checks if this registration should be evaluated at `now`.
+    pub fn is_active(&self, now: DateTime<Utc>) -> bool {
+        !self.is_expired(now)
+            && self.snoozed_until.is_none_or(|s| s <= now)
+            && self.window.as_ref().is_none_or(|w| w.is_active(now))
+    }
+
+    /// Checks if this registration has expired at `now`.
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at.is_some_and(|e| e <= now)
+    }
+
+    /// Checks that every window is ever active, that every group and the crowd alert could alert, and that group names
+    /// are unique.
+    ///
+    /// # Errors
+    /// Returns why a rule is invalid.
+    pub fn validate_rules(&self) -> Result<(), String> {
+        for rc in &self.config.1 {
+            rc.validate()?;
+        }
+        if let Some(window) = &self.window {
+            window.validate()?;
+        }
+
+        if self
+            .crowd
+            .as_ref()
+            .is_some_and(|crowd| crowd.below == CrowdLevel::Low)
+        {
+            return Err("Crowds can't drop below Low".to_string());
+        }
+
+        for (i, group) in self.groups.iter().enumerate() {
+            group.validate()?;
+
+            if self.groups[..i].iter().any(|g| g.name == group.name) {
+                return Err(format!("Group {} is named twice", group.name));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A button on an alert notification, which the service worker handles by posting to a route.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PushAction {
+    /// Identifies the action to the service worker.
+    pub action: &'static str,
+    /// Button text.
+    pub title: &'static str,
+    /// Route posted to when the button is pressed.
+    pub route: &'static str,
+    /// Minutes to snooze for. Snoozes until expiry if unset.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub minutes: Option<u32>,
+    /// If true, the action only applies to the ride the notification is for.
+    pub per_ride: bool,
+}
+
+/// Actions sent with every alert.
+pub const ALERT_ACTIONS: [PushAction; 2] = [
+    PushAction {
+        action: "snooze",
+        title: "Snooze 30 min",
+        route: "/snooze",
+        minutes: Some(30),
+        per_ride: false,
+    },
+    PushAction {
+        action: "stopRide",
+        title: "Stop alerts for this ride",
+        route: "/snooze",
+        minutes: None,
+        per_ride: true,
+    },
+];
+
+/// A group that met its rule, with the rides in it that met their conditions.
+#[derive(Serialize, Clone, Debug, Eq, PartialEq)]
+pub struct GroupAlert<'a> {
+    pub name: &'a str,
+    pub rides: Vec<&'a RideTime>,
+}
+
+/// Content of a push notification. This is sent as gzipped and base64 encoded JSON.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum PushPayload<'a> {
+    /// Rides that have met their alert condition, groups that met their rule, and the park's crowd if it dropped below
+    /// the level alerted on, with the actions to offer on their notifications.
+    ///
+    /// Registrations without a token are sent one here, which they must keep to manage their registration.
+    Alert {
+        rides: Vec<&'a RideTime>,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        groups: Vec<GroupAlert<'a>>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        crowd: Option<&'a ParkCrowd>,
+        actions: &'a [PushAction],
+        #[serde(skip_serializing_if = "Option::is_none")]
+        token: Option<&'a str>,
+    },
+    /// The registration has expired, and will no longer receive alerts.
+    Expired,
+    /// Sent by an operator to check that pushes reach a client.
+    Test,
+    /// The client subscribed with a retired VAPID key, and should resubscribe with `key`.
+    Resubscribe { key: &'a str },
+}
+
+impl PushPayload<'_> {
+    /// Type of the payload, as sent in its `type` field.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            PushPayload::Alert { .. } => "alert",
+            PushPayload::Expired => "expired",
+            PushPayload::Test => "test",
+            PushPayload::Resubscribe { .. } => "resubscribe",
+        }
+    }
+
+    /// Notification title, for channels that send plain text.
+    pub fn title(&self) -> &'static str {
+        match self {
+            PushPayload::Alert { .. } => "Ride Alert",
+            PushPayload::Expired => "Alerts Ended",
+            PushPayload::Test => "Test Notification",
+            PushPayload::Resubscribe { .. } => "Resubscribe",
+        }
+    }
+
+    /// Notification body, worded like the service worker's notifications, for channels that send plain text.
+    pub fn text(&self) -> String {
+        match self {
+            PushPayload::Alert {
+                rides,
+                groups,
+                crowd,
+                token,
+                ..
+            } => {
+                let mut lines = rides
+                    .iter()
+                    .map(|ride| match ride.status {
+                        queue_times::model::RideStatus::Wait(wait) => {
+                            format!("{}'s wait is {} minutes!", ride.name, wait)
+                        }
+                        queue_times::model::RideStatus::Open => format!("{} is Open!", ride.name),
+                        queue_times::model::RideStatus::Closed => {
+                            format!("{} is Closed!", ride.name)
+                        }
+                    })
+                    .collect::<Vec<_>>();
+
+                for group in groups {
+                    let rides = group
+                        .rides
+                        .iter()
+                        .map(|ride| match ride.status {
+                            queue_times::model::RideStatus::Wait(wait) => {
+                                format!("{} ({} min)", ride.name, wait)
+                            }
+                            queue_times::model::RideStatus::Open => format!("{} (Open)", ride.name),
+                            queue_times::model::RideStatus::Closed => {
+                                format!("{} (Closed)", ride.name)
+                            }
+                        })
+                        .collect::<Vec<_>>();
+                    lines.push(format!("{}: {}", group.name, rides.join(", ")));
+                }
+
+                if let Some(crowd) = crowd {
+                    lines.push(format!(
+                        "Crowds are {}, with an average wait of {:.0} minutes",
+                        crowd.level.unwrap_or_default(),
+                        crowd.average_wait.unwrap_or_default()
+                    ));
+                }
+
+                if let Some(token) = token {
+                    lines.push(format!(
+                        "Keep this token to change or remove your alerts: {}",
+                        token
+                    ));
+                }
+
+                lines.join("\n")
+            }
+            PushPayload::Expired => {
+                "Your ride alerts have expired. Open Queue Alert to set up new ones.".to_string()
+            }
+            PushPayload::Test => "Queue Alert can send you notifications.".to_string(),
+            PushPayload::Resubscribe { key } => format!("Resubscribe with VAPID key {}", key),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn window(start: (u32, u32), end: (u32, u32)) -> ActiveWindow {
+        ActiveWindow {
+            start: NaiveTime::from_hms_opt(start.0, start.1, 0).unwrap(),
+            end: NaiveTime::from_hms_opt(end.0, end.1, 0).unwrap(),
+            timezone: chrono_tz::America::New_York,
+            days: vec![],
+            start_date: None,
+            end_date: None,
+        }
+    }
+
+    /// Builds a UTC time from a local time in New York.
+    fn ny(y: i32, m: u32, d: u32, h: u32, min: u32) -> DateTime<Utc> {
+        chrono_tz::America::New_York
+            .with_ymd_and_hms(y, m, d, h, min, 0)
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    #[test]
+    fn test_window_same_day() {
+        let w = window((10, 0), (20, 0));
+
+        assert!(!w.is_active(ny(2023, 7, 1, 7, 0)));
+        assert!(w.is_active(ny(2023, 7, 1, 10, 0)));
+        assert!(w.is_active(ny(2023, 7, 1, 19, 59)));
+        assert!(!w.is_active(ny(2023, 7, 1, 20, 0)));
+    }
+
+    #[test]
+    fn test_window_past_midnight() {
+        let mut w = window((18, 0), (1, 0));
+        // 2023-07-01 is a Saturday
+        w.days = vec![Weekday::Sat];
+
+        assert!(w.is_active(ny(2023, 7, 1, 23, 0)));
+        // Still saturday's window
+        assert!(w.is_active(ny(2023, 7, 2, 0, 30)));
+        assert!(!w.is_active(ny(2023, 7, 2, 1, 30)));
+        assert!(!w.is_active(ny(2023, 7, 2, 19, 0)));
+        // Friday's window would run into saturday morning
+        assert!(!w.is_active(ny(2023, 7, 1, 0, 30)));
+    }
+
+    #[test]
+    fn test_window_dates() {
+        let mut w = window((0, 0), (23, 59));
+        w.start_date = NaiveDate::from_ymd_opt(2023, 7, 1);
+        w.end_date = NaiveDate::from_ymd_opt(2023, 7, 2);
+
+        assert!(!w.is_active(ny(2023, 6, 30, 12, 0)));
+        assert!(w.is_active(ny(2023, 7, 1, 12, 0)));
+        assert!(w.is_active(ny(2023, 7, 2, 12, 0)));
+        assert!(!w.is_active(ny(2023, 7, 3, 12, 0)));
+        assert_eq!(w.validate(), Ok(()));
+
+        // Ending before it starts would never be active
+        w.start_date = NaiveDate::from_ymd_opt(2023, 7, 3);
+        assert_eq!(
+            w.validate(),
+            Err("Window ends on 2023-07-02 before it starts on 2023-07-03".to_string())
+        );
+        let mut reg = crate::store::test::registration("https://push.example.com/a");
+        reg.config.1[1].window = Some(w.clone());
+        assert!(reg.validate_rules().is_err());
+        reg.config.1[1].window = None;
+        reg.window = Some(w);
+        assert!(reg.validate_rules().is_err());
+    }
+
+    #[test]
+    fn test_window_all_day() {
+        let mut w = window((9, 0), (9, 0));
+        w.days = vec![Weekday::Sat];
+
+        // 2023-07-01 is a Saturday
+        assert!(w.is_active(ny(2023, 7, 1, 0, 0)));
+        assert!(w.is_active(ny(2023, 7, 1, 8, 59)));
+        assert!(w.is_active(ny(2023, 7, 1, 9, 0)));
+        assert!(w.is_active(ny(2023, 7, 1, 23, 59)));
+        assert!(!w.is_active(ny(2023, 7, 2, 0, 0)));
+    }
+
+    #[test]
+    fn test_registration_expiry() {
+        let reg = Registration {
+            sub: SubscriptionInfo::new("https://example.com", "p256dh", "auth"),
+            channel: Channel::WebPush,
+            config: ("Cedar Point".to_string(), vec![]),
+            groups: vec![],
+            crowd: None,
+            window: None,
+            expires_at: Some(ny(2023, 7, 1, 22, 0)),
+            snoozed_until: None,
+            vapid_key: None,
+            token_hash: None,
+            signing_secret: None,
+        };
+
+        assert!(!reg.is_expired(ny(2023, 7, 1, 21, 59)));
+        assert!(reg.is_active(ny(2023, 7, 1, 21, 59)));
+        assert!(reg.is_expired(ny(2023, 7, 1, 22, 0)));
+        assert!(!reg.is_active(ny(2023, 7, 1, 22, 0)));
+    }
+
+    #[test]
+    fn test_snooze() {
+        let mut ride = RideConfig {
+            ride_name: "Maverick".to_string(),
+            alert_on: RideStatus::Open,
+            ..Default::default()
+        };
+        ride.snoozed_until = Some(ny(2023, 7, 1, 12, 30));
+
+        assert!(!ride.is_active(ny(2023, 7, 1, 12, 0)));
+        assert!(ride.is_active(ny(2023, 7, 1, 12, 30)));
+    }
+
+    #[test]
+    fn test_group_json() {
+        let group: GroupConfig = serde_json::from_str(
+            r#"{"name":"Coasters","require":{"atLeast":2},"rides":[
+                {"rideName":"Maverick","alertOn":{"wait":20}},
+                {"rideName":"Millennium Force","alertOn":"Open"}
+            ]}"#,
+        )
+        .unwrap();
+
+        assert_eq!(group.require, GroupRequire::AtLeast(2));
+        assert_eq!(group.rides[0].alert_on, RideStatus::Wait(20));
+        assert_eq!(group.mode, AlertMode::Repeat);
+        assert_eq!(
+            serde_json::from_str::<GroupRequire>(r#""all""#).unwrap(),
+            GroupRequire::All
+        );
+    }
+
+    #[test]
+    fn test_validate_rules() {
+        let ride = |name: &str| GroupRide {
+            ride_name: name.to_string(),
+            alert_on: RideStatus::Open,
+        };
+        let mut group = GroupConfig {
+            name: "Coasters".to_string(),
+            rides: vec![ride("Maverick"), ride("Millennium Force")],
+            require: GroupRequire::AtLeast(2),
+            ..Default::default()
+        };
+        assert_eq!(group.validate(), Ok(()));
+
+        group.require = GroupRequire::AtLeast(3);
+        assert!(group.validate().is_err());
+        group.require = GroupRequire::AtLeast(0);
+        assert!(group.validate().is_err());
+        group.require = GroupRequire::Any;
+        group.rides.push(ride("Maverick"));
+        assert_eq!(
+            group.validate(),
+            Err("Group Coasters has Maverick twice".to_string())
+        );
+        group.require = GroupRequire::All;
+        group.rides.clear();
+        assert!(group.validate().is_err());
+
+        group.rides.push(ride("Maverick"));
+        let mut reg = crate::store::test::registration("https://push.example.com/a");
+        reg.groups = vec![group.clone(), group];
+        assert_eq!(
+            reg.validate_rules(),
+            Err("Group Coasters is named twice".to_string())
+        );
+        reg.groups.pop();
+        assert_eq!(reg.validate_rules(), Ok(()));
+
+        reg.crowd = Some(CrowdAlert {
+            below: CrowdLevel::Low,
+            ..Default::default()
+        });
+        assert!(reg.validate_rules().is_err());
+        reg.crowd = Some(CrowdAlert {
+            below: CrowdLevel::Moderate,
+            ..Default::default()
+        });
+        assert_eq!(reg.validate_rules(), Ok(()));
+    }
+
+    #[test]
+    fn test_vapid_keys_get() {
+        use crate::channel::web_push::test::keys;
+        let keys = VapidKeys {
+            current: keys(),
+            previous: vec![keys(), keys()],
+        };
+
+        let current = keys.get(keys.current_public()).unwrap();
+        assert_eq!(current.0 .0, keys.current.0 .0);
+        for previous in &keys.previous {
+            assert_eq!(keys.get(&previous.0 .0).unwrap().0 .0, previous.0 .0);
+        }
+        assert!(keys.get("retired").is_none());
+    }
+}
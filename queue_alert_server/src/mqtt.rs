@@ -0,0 +1,499 @@
+//! Publishes live wait times to an MQTT broker, for home automation.
+//!
+//! After each refresh of the wait time cache, every ride's wait and status are published retained to
+//! `<prefix>/<park>/<ride>/wait` and `<prefix>/<park>/<ride>/status`. Park and ride names are slugged to lowercase
+//! letters, digits and underscores, eg. `queue_alert/cedar_point/millennium_force/wait`. Waits are in minutes, or
+//! `None` when no wait is posted, which Home Assistant reads as unknown. Statuses are `open` or `closed`.
+//!
+//! Home Assistant discovery configs are also published, so each park shows up as a device with a wait and status
+//! sensor per ride. `<prefix>/availability` is `online` while connected, and the broker sets it to `offline` otherwise.
+
+use crate::app::{Application, QueueClient};
+use crate::config::Config;
+use crate::error::Error;
+use queue_times::client::QueueTimesClient;
+use queue_times::model::{RideStatus, RideTime};
+use rumqttc::{
+    AsyncClient, Event, EventLoop, LastWill, MqttOptions, Outgoing, Packet, QoS, TlsConfiguration,
+    Transport,
+};
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+/// Availability payload while connected.
+const ONLINE: &str = "online";
+/// Availability payload once disconnected.
+const OFFLINE: &str = "offline";
+/// Wait before reconnecting after the connection to the broker fails.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+/// Requests that can be queued for the broker before publishing waits.
+const QUEUE_CAPACITY: usize = 100;
+
+/// Connects to the broker set in the config of `app`, and publishes wait times after each refresh until the app shuts
+/// down.
+pub fn spawn(app: Arc<Application>) -> Result<JoinHandle<()>, Error> {
+    let (publisher, events) = MqttPublisher::new(&app.config)?;
+    Ok(tokio::spawn(publisher.run(app, events)))
+}
+
+/// Publishes wait times to a broker.
+pub struct MqttPublisher {
+    client: AsyncClient,
+    /// Topic that wait times are published under
+    topic_prefix: String,
+    /// Topic that discovery configs are published under, if they are published
+    discovery_prefix: Option<String>,
+    /// Parks to publish, or every park if empty
+    parks: Vec<String>,
+    /// Payload last published to each topic, so unchanged ones are not sent again
+    published: HashMap<String, String>,
+    /// Set when the client reconnects, so everything is sent again in case the broker lost it
+    reconnected: Arc<AtomicBool>,
+}
+
+impl MqttPublisher {
+    /// Sets up a client for the broker at `mqtt_url`. Nothing is sent until the returned event loop is polled.
+    pub fn new(config: &Config) -> Result<(Self, EventLoop), Error> {
+        let url = config
+            .mqtt_url
+            .as_ref()
+            .ok_or_else(|| Error::Mqtt("mqtt_url is not set".to_string()))?;
+        let (transport, default_port) = match url.scheme() {
+            "mqtt" | "tcp" => (Transport::Tcp, 1883),
+            "mqtts" | "ssl" => (Transport::Tls(TlsConfiguration::Native), 8883),
+            scheme => return Err(Error::Mqtt(format!("unsupported scheme {}", scheme))),
+        };
+        let host = url
+            .host_str()
+            .ok_or_else(|| Error::Mqtt("mqtt_url has no host".to_string()))?;
+        let topic_prefix = config.mqtt_topic_prefix.trim_end_matches('/').to_string();
+
+        let mut options = MqttOptions::new(
+            &config.mqtt_client_id,
+            host,
+            url.port().unwrap_or(default_port),
+        );
+        options
+            .set_transport(transport)
+            .set_keep_alive(Duration::from_secs(30))
+            .set_last_will(LastWill::new(
+                availability_topic(&topic_prefix),
+                OFFLINE,
+                QoS::AtLeastOnce,
+                true,
+            ));
+        if !url.username().is_empty() {
+            options.set_credentials(url.username(), url.password().unwrap_or_default());
+        }
+
+        let (client, events) = AsyncClient::new(options, QUEUE_CAPACITY);
+
+        Ok((
+            Self {
+                client,
+                topic_prefix,
+                discovery_prefix: config.mqtt_discovery.then(|| {
+                    config
+                        .mqtt_discovery_prefix
+                        .trim_end_matches('/')
+                        .to_string()
+                }),
+                parks: config.mqtt_parks.clone(),
+                published: HashMap::new(),
+                reconnected: Arc::new(AtomicBool::new(false)),
+            },
+            events,
+        ))
+    }
+
+    /// Publishes after each refresh of the wait time cache, until the app shuts down. `events` drives the connection.
+    async fn run(mut self, app: Arc<Application>, events: EventLoop) {
+        let mut connection = tokio::spawn(poll(
+            events,
+            self.client.clone(),
+            availability_topic(&self.topic_prefix),
+            self.reconnected.clone(),
+        ));
+        let mut refreshed = app.queue_client.subscribe();
+        let mut shutdown = app.subscribe_shutdown();
+
+        // The cache may have filled before we started watching it
+        let filled = refreshed.borrow_and_update().is_some();
+        if filled {
+            self.publish(&app.queue_client).await;
+        }
+
+        loop {
+            let changed = tokio::select! {
+                changed = refreshed.changed() => changed.is_ok(),
+                _ = shutdown.wait_for(|down| *down) => false,
+            };
+            if !changed {
+                break;
+            }
+
+            self.publish(&app.queue_client).await;
+        }
+
+        // The broker only sends our last will if the connection drops, so say we are going
+        let _ = self
+            .client
+            .publish(
+                availability_topic(&self.topic_prefix),
+                QoS::AtLeastOnce,
+                true,
+                OFFLINE,
+            )
+            .await;
+        let _ = self.client.disconnect().await;
+
+        if tokio::time::timeout(RECONNECT_DELAY, &mut connection)
+            .await
+            .is_err()
+        {
+            log::warn!("Couldn't disconnect from MQTT broker in time");
+            connection.abort();
+        }
+    }
+
+    /// Publishes the cached wait times of every park.
+    async fn publish(&mut self, queue_client: &QueueClient) {
+        let parks = match queue_client.get_park_urls().await {
+            Ok(parks) => parks,
+            Err(why) => {
+                log::warn!("Couldn't get parks to publish to MQTT: {}", why);
+                return;
+            }
+        };
+
+        if self.reconnected.swap(false, Ordering::SeqCst) {
+            self.published.clear();
+        }
+
+        for (park, url) in parks {
+            if !self.parks.is_empty() && !self.parks.contains(&park) {
+                continue;
+            }
+
+            match queue_client.get_ride_times(url).await {
+                Ok(rides) => {
+                    let messages = self.messages(&park, &rides);
+                    self.send(messages).await;
+                }
+                Err(why) => log::warn!("Couldn't get {} rides to publish to MQTT: {}", park, why),
+            }
+        }
+    }
+
+    /// Publishes each message retained, skipping any that are unchanged since they were last sent.
+    async fn send(&mut self, messages: Vec<(String, String)>) {
+        for (topic, payload) in messages {
+            if self.published.get(&topic) == Some(&payload) {
+                continue;
+            }
+
+            if let Err(why) = self
+                .client
+                .publish(&topic, QoS::AtLeastOnce, true, payload.as_bytes())
+                .await
+            {
+                log::warn!("Couldn't publish {} to MQTT: {}", topic, why);
+                continue;
+            }
+            self.published.insert(topic, payload);
+        }
+    }
+
+    /// Builds the topics and payloads describing each ride at `park`.
+    fn messages(&self, park: &str, rides: &[RideTime]) -> Vec<(String, String)> {
+        let park_slug = slug(park);
+        let mut messages = Vec::new();
+
+        for ride in rides {
+            let ride_slug = slug(&ride.name);
+            let base = format!("{}/{}/{}", self.topic_prefix, park_slug, ride_slug);
+
+            let (wait, status) = match ride.status {
+                RideStatus::Wait(minutes) => (minutes.to_string(), "open"),
+                RideStatus::Open => ("None".to_string(), "open"),
+                RideStatus::Closed => ("None".to_string(), "closed"),
+            };
+            messages.push((format!("{}/wait", base), wait));
+            messages.push((format!("{}/status", base), status.to_string()));
+
+            if let Some(discovery_prefix) = &self.discovery_prefix {
+                let node = format!("queue_alert_{}", park_slug);
+                let device = json!({
+                    "identifiers": [node],
+                    "name": park,
+                    "manufacturer": "Queue Alert",
+                    "model": "Queue times",
+                });
+
+                messages.push((
+                    format!(
+                        "{}/sensor/{}/{}_wait/config",
+                        discovery_prefix, node, ride_slug
+                    ),
+                    json!({
+                        "name": format!("{} wait", ride.name),
+                        "unique_id": format!("{}_{}_wait", node, ride_slug),
+                        "state_topic": format!("{}/wait", base),
+                        "availability_topic": availability_topic(&self.topic_prefix),
+                        "unit_of_measurement": "min",
+                        "state_class": "measurement",
+                        "icon": "mdi:timer-sand",
+                        "device": device,
+                    })
+                    .to_string(),
+                ));
+                messages.push((
+                    format!(
+                        "{}/sensor/{}/{}_status/config",
+                        discovery_prefix, node, ride_slug
+                    ),
+                    json!({
+                        "name": format!("{} status", ride.name),
+                        "unique_id": format!("{}_{}_status", node, ride_slug),
+                        "state_topic": format!("{}/status", base),
+                        "availability_topic": availability_topic(&self.topic_prefix),
+                        "device_class": "enum",
+                        "options": ["open", "closed"],
+                        "icon": "mdi:roller-coaster",
+                        "device": device,
+                    })
+                    .to_string(),
+                ));
+            }
+        }
+
+        messages
+    }
+}
+
+/// Drives the connection to the broker, reconnecting if it drops, until the client disconnects.
+async fn poll(
+    mut events: EventLoop,
+    client: AsyncClient,
+    availability: String,
+    reconnected: Arc<AtomicBool>,
+) {
+    let mut disconnecting = false;
+
+    loop {
+        match events.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                log::info!("Connected to MQTT broker");
+                reconnected.store(true, Ordering::SeqCst);
+
+                // Replaces the last will left by any earlier connection
+                if let Err(why) = client.try_publish(&availability, QoS::AtLeastOnce, true, ONLINE)
+                {
+                    log::warn!("Couldn't publish MQTT availability: {}", why);
+                }
+            }
+            // Keep reading until the broker hangs up, as closing with acks unread can reset the connection before the
+            // broker reads what we sent
+            Ok(Event::Outgoing(Outgoing::Disconnect)) => disconnecting = true,
+            Ok(_) => {}
+            Err(_) if disconnecting => break,
+            Err(why) => {
+                log::warn!(
+                    "MQTT connection failed, retrying in {:?}: {}",
+                    RECONNECT_DELAY,
+                    why
+                );
+                tokio::time::sleep(RECONNECT_DELAY).await;
+            }
+        }
+    }
+}
+
+/// Topic holding whether the server is connected.
+fn availability_topic(topic_prefix: &str) -> String {
+    format!("{}/availability", topic_prefix)
+}
+
+/// Turns a park or ride name into a topic level of lowercase letters, digits and underscores.
+fn slug(name: &str) -> String {
+    let mut slug = String::with_capacity(name.len());
+
+    for c in name.chars().filter(|c| !matches!(c, '\'' | '’')) {
+        if c.is_ascii_alphanumeric() {
+            slug.push(c.to_ascii_lowercase());
+        } else if !slug.is_empty() && !slug.ends_with('_') {
+            slug.push('_');
+        }
+    }
+
+    slug.trim_end_matches('_').to_string()
+}
+
+#[cfg(test)]
+mod test {
+    //! The broker test runs against the MQTT broker at `QA_TEST_MQTT_URL`, eg. `mqtt://localhost:1883`. It is ignored
+    //! unless asked for with `--ignored`, and fails if that is unset.
+
+    use super::*;
+
+    fn publisher(config: Config) -> (MqttPublisher, EventLoop) {
+        MqttPublisher::new(&Config {
+            mqtt_url: Some("mqtt://localhost".parse().unwrap()),
+            ..config
+        })
+        .unwrap()
+    }
+
+    fn rides() -> Vec<RideTime> {
+        vec![
+            RideTime {
+                name: "Maverick".to_string(),
+                status: RideStatus::Wait(20),
+            },
+            RideTime {
+                name: "Snoopy's Express Railroad".to_string(),
+                status: RideStatus::Closed,
+            },
+        ]
+    }
+
+    #[test]
+    fn test_slug() {
+        assert_eq!(slug("Cedar Point"), "cedar_point");
+        assert_eq!(
+            slug("Snoopy's Express Railroad"),
+            "snoopys_express_railroad"
+        );
+        assert_eq!(slug("Rock, Spin, and Turn"), "rock_spin_and_turn");
+        assert_eq!(slug("4x4's"), "4x4s");
+        assert_eq!(slug(" Wild/Mild #1 "), "wild_mild_1");
+    }
+
+    #[test]
+    fn test_messages() {
+        let (publisher, _) = publisher(Config {
+            mqtt_discovery: false,
+            ..Default::default()
+        });
+
+        assert_eq!(
+            publisher.messages("Cedar Point", &rides()),
+            vec![
+                (
+                    "queue_alert/cedar_point/maverick/wait".to_string(),
+                    "20".to_string()
+                ),
+                (
+                    "queue_alert/cedar_point/maverick/status".to_string(),
+                    "open".to_string()
+                ),
+                (
+                    "queue_alert/cedar_point/snoopys_express_railroad/wait".to_string(),
+                    "None".to_string()
+                ),
+                (
+                    "queue_alert/cedar_point/snoopys_express_railroad/status".to_string(),
+                    "closed".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_discovery() {
+        let (publisher, _) = publisher(Config::default());
+        let messages = publisher.messages("Cedar Point", &rides());
+
+        let (topic, payload) = &messages[2];
+        assert_eq!(
+            topic,
+            "homeassistant/sensor/queue_alert_cedar_point/maverick_wait/config"
+        );
+        let config: serde_json::Value = serde_json::from_str(payload).unwrap();
+        assert_eq!(config["name"], "Maverick wait");
+        assert_eq!(
+            config["state_topic"],
+            "queue_alert/cedar_point/maverick/wait"
+        );
+        assert_eq!(config["availability_topic"], "queue_alert/availability");
+        assert_eq!(config["unit_of_measurement"], "min");
+        assert_eq!(config["device"]["name"], "Cedar Point");
+
+        let (topic, payload) = &messages[3];
+        assert_eq!(
+            topic,
+            "homeassistant/sensor/queue_alert_cedar_point/maverick_status/config"
+        );
+        let config: serde_json::Value = serde_json::from_str(payload).unwrap();
+        assert_eq!(
+            config["state_topic"],
+            "queue_alert/cedar_point/maverick/status"
+        );
+        assert_eq!(
+            config["device"]["identifiers"],
+            json!(["queue_alert_cedar_point"])
+        );
+    }
+
+    #[tokio::test]
+    #[ignore = "needs QA_TEST_MQTT_URL"]
+    async fn test_broker() {
+        let url = std::env::var("QA_TEST_MQTT_URL")
+            .expect("QA_TEST_MQTT_URL should be set to run the broker test");
+        let config = Config {
+            mqtt_url: Some(url.parse().unwrap()),
+            mqtt_client_id: "queue_alert_test".to_string(),
+            mqtt_topic_prefix: "queue_alert_test".to_string(),
+            mqtt_discovery: false,
+            ..Default::default()
+        };
+
+        let (mut publisher, events) = MqttPublisher::new(&config).unwrap();
+        let connection = tokio::spawn(poll(
+            events,
+            publisher.client.clone(),
+            availability_topic(&publisher.topic_prefix),
+            publisher.reconnected.clone(),
+        ));
+        let messages = publisher.messages("Cedar Point", &rides());
+        publisher.send(messages.clone()).await;
+        publisher.client.disconnect().await.unwrap();
+        connection.await.unwrap();
+
+        // Everything was retained, so a later subscriber sees it
+        let (subscriber, mut events) = AsyncClient::new(
+            MqttOptions::new(
+                "queue_alert_test_subscriber",
+                config.mqtt_url.as_ref().unwrap().host_str().unwrap(),
+                config.mqtt_url.as_ref().unwrap().port().unwrap_or(1883),
+            ),
+            10,
+        );
+        subscriber
+            .subscribe("queue_alert_test/cedar_point/#", QoS::AtLeastOnce)
+            .await
+            .unwrap();
+
+        let mut received = HashMap::new();
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while received.len() < messages.len() {
+                if let Event::Incoming(Packet::Publish(publish)) = events.poll().await.unwrap() {
+                    received.insert(
+                        publish.topic,
+                        String::from_utf8(publish.payload.to_vec()).unwrap(),
+                    );
+                }
+            }
+        })
+        .await
+        .unwrap();
+
+        for (topic, payload) in messages {
+            assert_eq!(received.get(&topic), Some(&payload));
+        }
+    }
+}
@@ -0,0 +1,188 @@
+//! Recommendations of what to ride next, for `/recommend`.
+//!
+//! Every open ride is scored, starting from [`BASE_SCORE`] less its wait in minutes. Favourites and rides close to the
+//! rider score higher, and rides whose wait is shorter than usual for the hour score higher by half the difference, up
+//! to [`MAX_TYPICAL_BONUS`] either way. Each ride's score comes with the reasons for it.
+
+use queue_times::model::{RideStatus, RideTime};
+use serde::Serialize;
+
+/// Score of an open ride with no wait, before anything else is counted.
+pub const BASE_SCORE: f64 = 100.0;
+/// Added to the score of a favourite ride.
+pub const FAVOURITE_BONUS: f64 = 30.0;
+/// Added to the score of a ride close to the rider.
+pub const NEAR_BONUS: f64 = 15.0;
+/// Most a wait being shorter or longer than usual can change a score.
+pub const MAX_TYPICAL_BONUS: f64 = 25.0;
+/// Difference from the usual wait, in minutes, worth giving as a reason.
+const TYPICAL_REASON_MINUTES: f64 = 5.0;
+
+/// What a rider has said about the rides they want.
+#[derive(Debug, Default, Clone)]
+pub struct Preferences {
+    /// Names of the rides the rider likes best.
+    pub favourites: Vec<String>,
+    /// Names of the rides in the area the rider is in, or close to it.
+    pub near: Vec<String>,
+}
+
+/// A ride worth riding, and why.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Recommendation<'a> {
+    #[serde(flatten)]
+    pub ride: &'a RideTime,
+    pub score: f64,
+    pub reasons: Vec<String>,
+}
+
+/// Ranks the open rides in `rides`, best first. `typical` gets the usual wait of a ride at this hour, if it is known.
+pub fn recommend<'a>(
+    rides: &'a [RideTime],
+    prefs: &Preferences,
+    typical: impl Fn(&str) -> Option<f64>,
+) -> Vec<Recommendation<'a>> {
+    let mut recommendations: Vec<_> = rides
+        .iter()
+        .filter_map(|ride| {
+            let mut reasons = Vec::new();
+            let mut score = BASE_SCORE;
+
+            match ride.status {
+                RideStatus::Closed => return None,
+                RideStatus::Open => reasons.push("Open with no posted wait".to_string()),
+                RideStatus::Wait(wait) => {
+                    score -= wait as f64;
+                    reasons.push(format!("{} minute wait", wait));
+
+                    if let Some(usual) = typical(&ride.name) {
+                        let shorter = usual - wait as f64;
+                        score += (shorter / 2.0).clamp(-MAX_TYPICAL_BONUS, MAX_TYPICAL_BONUS);
+
+                        if shorter >= TYPICAL_REASON_MINUTES {
+                            reasons.push(format!(
+                                "{:.0} minutes shorter than usual at this hour",
+                                shorter
+                            ));
+                        } else if shorter <= -TYPICAL_REASON_MINUTES {
+                            reasons.push(format!(
+                                "{:.0} minutes longer than usual at this hour",
+                                -shorter
+                            ));
+                        }
+                    }
+                }
+            }
+
+            if prefs.favourites.contains(&ride.name) {
+                score += FAVOURITE_BONUS;
+                reasons.push("One of your favourites".to_string());
+            }
+            if prefs.near.contains(&ride.name) {
+                score += NEAR_BONUS;
+                reasons.push("Close by".to_string());
+            }
+
+            Some(Recommendation {
+                ride,
+                score: (score * 10.0).round() / 10.0,
+                reasons,
+            })
+        })
+        .collect();
+
+    recommendations.sort_by(|a, b| {
+        b.score
+            .total_cmp(&a.score)
+            .then_with(|| a.ride.name.cmp(&b.ride.name))
+    });
+    recommendations
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn names<'a>(recommendations: &'a [Recommendation]) -> Vec<&'a str> {
+        recommendations
+            .iter()
+            .map(|r| r.ride.name.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn test_shorter_waits_first() {
+        let rides = [
+            RideTime::new("Maverick", RideStatus::Wait(45)),
+            RideTime::new("Millennium Force", RideStatus::Wait(10)),
+            RideTime::new("Top Thrill 2", RideStatus::Closed),
+            RideTime::new("Valravn", RideStatus::Open),
+        ];
+
+        let recommendations = recommend(&rides, &Preferences::default(), |_| None);
+        assert_eq!(
+            names(&recommendations),
+            ["Valravn", "Millennium Force", "Maverick"]
+        );
+        assert_eq!(recommendations[1].score, 90.0);
+        assert_eq!(recommendations[1].reasons, ["10 minute wait"]);
+    }
+
+    #[test]
+    fn test_preferences() {
+        let rides = [
+            RideTime::new("Maverick", RideStatus::Wait(45)),
+            RideTime::new("Millennium Force", RideStatus::Wait(10)),
+            RideTime::new("Steel Vengeance", RideStatus::Wait(30)),
+        ];
+        let prefs = Preferences {
+            favourites: vec!["Maverick".to_string()],
+            near: vec!["Steel Vengeance".to_string()],
+        };
+
+        let recommendations = recommend(&rides, &prefs, |_| None);
+        assert_eq!(
+            names(&recommendations),
+            ["Millennium Force", "Maverick", "Steel Vengeance"]
+        );
+        assert_eq!(recommendations[1].score, 85.0);
+        assert_eq!(
+            recommendations[1].reasons,
+            ["45 minute wait", "One of your favourites"]
+        );
+        assert_eq!(recommendations[2].reasons, ["30 minute wait", "Close by"]);
+    }
+
+    #[test]
+    fn test_typical_waits() {
+        let rides = [
+            RideTime::new("Maverick", RideStatus::Wait(30)),
+            RideTime::new("Millennium Force", RideStatus::Wait(20)),
+            RideTime::new("Steel Vengeance", RideStatus::Wait(25)),
+        ];
+        let typical = |name: &str| match name {
+            "Maverick" => Some(90.0),
+            "Millennium Force" => Some(35.0),
+            "Steel Vengeance" => Some(27.0),
+            _ => None,
+        };
+
+        let recommendations = recommend(&rides, &Preferences::default(), typical);
+        assert_eq!(
+            names(&recommendations),
+            ["Maverick", "Millennium Force", "Steel Vengeance"]
+        );
+        // Much shorter waits than usual are capped
+        assert_eq!(recommendations[0].score, 95.0);
+        assert_eq!(
+            recommendations[0].reasons,
+            [
+                "30 minute wait",
+                "60 minutes shorter than usual at this hour"
+            ]
+        );
+        assert_eq!(recommendations[1].score, 87.5);
+        // Small differences are counted, but not worth mentioning
+        assert_eq!(recommendations[2].reasons, ["25 minute wait"]);
+    }
+}
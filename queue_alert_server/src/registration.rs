@@ -1,94 +1,314 @@
 //! User registration management
 
+use crate::config::Config;
 use crate::error::Error;
-use crate::models::{Registration, RideConfig, RideStatus};
+use crate::models::{AlertMode, Channel, CrowdAlert, GroupConfig, Registration};
+use crate::store::{self, default_expiry, Delivery, RegistrationStore};
+use crate::token;
+use chrono::{DateTime, Utc};
 use dashmap::DashMap;
-use sqlx::sqlite::{SqliteConnectOptions, SqliteRow};
-use sqlx::{query, Executor, Row, Sqlite, SqlitePool, Transaction};
-use std::path::PathBuf;
-use web_push::SubscriptionInfo;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// A registration along with the server side state that clients cannot set, used for backups.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RegistrationDump {
+    pub registration: Registration,
+    pub token_hash: Option<String>,
+    #[serde(default)]
+    pub signing_secret: Option<String>,
+    pub snoozed_until: Option<DateTime<Utc>>,
+    pub rides: Vec<RideDump>,
+    #[serde(default)]
+    pub groups: Vec<GroupDump>,
+    #[serde(default)]
+    pub crowd_last_alerted: Option<DateTime<Utc>>,
+}
+
+/// Server side state of a single ride alert, used for backups.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RideDump {
+    pub ride_name: String,
+    pub snoozed_until: Option<DateTime<Utc>>,
+    pub last_alerted: Option<DateTime<Utc>>,
+}
+
+/// Server side state of a single group alert, used for backups.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct GroupDump {
+    pub name: String,
+    pub last_alerted: Option<DateTime<Utc>>,
+}
+
+impl From<&Registration> for RegistrationDump {
+    fn from(reg: &Registration) -> Self {
+        Self {
+            registration: reg.clone(),
+            token_hash: reg.token_hash.clone(),
+            signing_secret: reg.signing_secret.clone(),
+            snoozed_until: reg.snoozed_until,
+            rides: reg
+                .config
+                .1
+                .iter()
+                .map(|r| RideDump {
+                    ride_name: r.ride_name.clone(),
+                    snoozed_until: r.snoozed_until,
+                    last_alerted: r.last_alerted,
+                })
+                .collect(),
+            groups: reg
+                .groups
+                .iter()
+                .map(|g| GroupDump {
+                    name: g.name.clone(),
+                    last_alerted: g.last_alerted,
+                })
+                .collect(),
+            crowd_last_alerted: reg.crowd.as_ref().and_then(|c| c.last_alerted),
+        }
+    }
+}
+
+impl From<RegistrationDump> for Registration {
+    fn from(dump: RegistrationDump) -> Self {
+        let mut reg = dump.registration;
+        reg.token_hash = dump.token_hash;
+        reg.signing_secret = dump.signing_secret;
+        reg.snoozed_until = dump.snoozed_until;
+
+        for ride in dump.rides {
+            if let Some(conf) = reg
+                .config
+                .1
+                .iter_mut()
+                .find(|r| r.ride_name == ride.ride_name)
+            {
+                conf.snoozed_until = ride.snoozed_until;
+                conf.last_alerted = ride.last_alerted;
+            }
+        }
+
+        for group in dump.groups {
+            if let Some(conf) = reg.groups.iter_mut().find(|g| g.name == group.name) {
+                conf.last_alerted = group.last_alerted;
+            }
+        }
+
+        if let Some(crowd) = reg.crowd.as_mut() {
+            crowd.last_alerted = dump.crowd_last_alerted;
+        }
+
+        reg
+    }
+}
 
-/// Handles user registration, persisting to disk.
+/// Handles user registration, persisting to a [`RegistrationStore`].
 ///
-/// This struct implements a lockless write back cache, so reads will not touch the disk. It is assumed
-/// that no other application is writing to the db while we are running, so we assume we are always
-/// consistent with the db.
+/// This struct implements a lockless write back cache, so reads will not touch the store. If the store is not shared,
+/// it is assumed that no other application is writing to it while we are running, so we assume we are always
+/// consistent with it. Shared stores are kept consistent with [`Self::reload`] and [`Self::sync`] instead.
 pub struct RegistrationRepository {
     /// Cache of endpoint to registration data
     pub cache: DashMap<String, Registration>,
-    db: SqlitePool,
+    store: Box<dyn RegistrationStore>,
+    /// If this server was sending alerts when last checked
+    leading: AtomicBool,
 }
 
-impl<'a> RegistrationRepository {
-    /// loads the database, caching the current results if it exists or creating a new db otherwise.
-    pub async fn init() -> Result<Self, Error> {
-        let db = SqlitePool::connect_with(
-            SqliteConnectOptions::new()
-                .create_if_missing(true)
-                .filename(PathBuf::from("registrations.sqlite")),
-        )
-        .await?;
+impl RegistrationRepository {
+    /// Opens the store set in `config`, caching its registrations.
+    ///
+    /// The store is migrated to the latest schema first.
+    pub async fn open(config: &Config) -> Result<Self, Error> {
+        Self::new(store::open(config).await?).await
+    }
 
-        // Run our 'migrations script'
-        query(include_str!("../sql/init.sql")).execute(&db).await?;
+    /// Caches the registrations in `store`.
+    ///
+    /// Registrations that cannot be read are quarantined and skipped, rather than stopping the server from starting.
+    pub async fn new(store: Box<dyn RegistrationStore>) -> Result<Self, Error> {
+        let cache = DashMap::new();
+        let loaded = store
+            .load_all(&mut |reg| {
+                cache.insert(reg.sub.endpoint.clone(), reg);
+            })
+            .await?;
 
-        let cache = Self::cache_db(&db).await?;
+        log::info!("Loaded {} registrations from the db", loaded.loaded);
+        if loaded.quarantined > 0 {
+            log::warn!(
+                "Quarantined {} registrations that could not be read, see `db check`",
+                loaded.quarantined
+            );
+        }
 
-        Ok(Self { cache, db })
+        Ok(Self {
+            cache,
+            store,
+            leading: AtomicBool::new(false),
+        })
     }
 
-    /// Loads db into memory
-    async fn cache_db(db: &SqlitePool) -> Result<DashMap<String, Registration>, Error> {
-        let all_sub: Vec<SubscriptionInfo> = query("SELECT * FROM REGISTRATIONS")
-            .map(|r: SqliteRow| serde_json::from_str(r.get("subscription_info")).unwrap())
-            .fetch_all(db)
+    /// Reloads every registration from a shared store, picking up changes made by other servers.
+    ///
+    /// Does nothing if the store is not shared.
+    pub async fn reload(&self) -> Result<(), Error> {
+        if !self.store.is_shared() {
+            return Ok(());
+        }
+
+        let mut endpoints = HashSet::new();
+        self.store
+            .load_all(&mut |reg| {
+                endpoints.insert(reg.sub.endpoint.clone());
+                self.cache_loaded(reg);
+            })
             .await?;
 
-        log::info!("Loading {} registrations from the db", all_sub.len());
+        self.cache
+            .retain(|endpoint, _| endpoints.contains(endpoint));
 
-        let cache = DashMap::new();
+        Ok(())
+    }
 
-        // Un-normalize all subs
-        for sub in all_sub {
-            let park: String = query("SELECT park FROM CONFIGS WHERE endpoint = ?")
-                .bind(sub.endpoint.clone())
-                .map(|r: SqliteRow| r.get("park"))
-                .fetch_one(db)
-                .await?;
-
-            let config = query("SELECT ridename, alerton, wait FROM RIDEALERTS WHERE endpoint = ?")
-                .bind(sub.endpoint.clone())
-                .map(|r: SqliteRow| {
-                    let name: String = r.get("ridename");
-                    let alerton: &str = r.get("alerton");
-
-                    let alerton = match alerton {
-                        "open" => RideStatus::Open,
-                        "closed" => RideStatus::Closed,
-                        "wait" => {
-                            let wait: u16 = r.get("wait");
-                            RideStatus::Wait(wait)
-                        }
-                        // Db enforces this
-                        _ => unreachable!(),
-                    };
-
-                    RideConfig {
-                        alert_on: alerton,
-                        ride_name: name,
-                    }
+    /// Reloads a single registration from a shared store, so requests see changes made by other servers.
+    ///
+    /// Does nothing if the store is not shared.
+    pub async fn sync(&self, endpoint: &str) -> Result<(), Error> {
+        if !self.store.is_shared() {
+            return Ok(());
+        }
+
+        match self.store.load(endpoint).await? {
+            Some(reg) => self.cache_loaded(reg),
+            None => {
+                self.cache.remove(endpoint);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Caches a registration loaded from the store, keeping any state that is only kept in memory.
+    fn cache_loaded(&self, mut reg: Registration) {
+        if let Some(old) = self.cache.get(&reg.sub.endpoint) {
+            for ride in reg.config.1.iter_mut() {
+                if let Some(old_ride) = old.config.1.iter().find(|r| {
+                    r.ride_name == ride.ride_name
+                        && r.alert_on == ride.alert_on
+                        && r.mode == ride.mode
+                }) {
+                    ride.matched = old_ride.matched;
+                }
+            }
+
+            for group in reg.groups.iter_mut() {
+                if let Some(old_group) = old.groups.iter().find(|g| same_rule(g, group)) {
+                    group.matched = old_group.matched;
+                }
+            }
+
+            if let (Some(crowd), Some(old_crowd)) = (reg.crowd.as_mut(), old.crowd.as_ref()) {
+                if same_crowd(old_crowd, crowd) {
+                    crowd.matched = old_crowd.matched;
+                }
+            }
+        }
+
+        self.cache.insert(reg.sub.endpoint.clone(), reg);
+    }
+
+    /// Checks if this server should send alerts, refreshing the cache first if the store is shared.
+    ///
+    /// Only one server sharing a store sends alerts, so clients are not alerted once per server.
+    pub async fn lead(&self) -> bool {
+        if let Err(why) = self.reload().await {
+            log::error!("Couldn't reload registrations: {}", why);
+        }
+
+        let lead = match self.store.try_lead().await {
+            Ok(lead) => lead,
+            Err(why) => {
+                log::error!("Couldn't check if this server sends alerts: {}", why);
+                false
+            }
+        };
+
+        self.leading.store(lead, Ordering::SeqCst);
+        lead
+    }
+
+    /// Saves the state that is otherwise only kept in memory, then closes the store.
+    ///
+    /// Only the server sending alerts knows if on change alerts matched, so other servers sharing the store save
+    /// nothing.
+    pub async fn close(&self) -> Result<(), Error> {
+        if !self.store.is_shared() || self.leading.load(Ordering::SeqCst) {
+            let matched: Vec<_> = self
+                .cache
+                .iter()
+                .flat_map(|reg| {
+                    reg.config
+                        .1
+                        .iter()
+                        .filter(|ride| ride.mode == AlertMode::OnChange)
+                        .map(|ride| {
+                            (
+                                reg.sub.endpoint.clone(),
+                                ride.ride_name.clone(),
+                                ride.matched,
+                            )
+                        })
+                        .collect::<Vec<_>>()
                 })
-                .fetch_all(db)
-                .await?;
+                .collect();
+
+            self.store.save_matched(&matched).await?;
+
+            let groups_matched: Vec<_> = self
+                .cache
+                .iter()
+                .flat_map(|reg| {
+                    reg.groups
+                        .iter()
+                        .filter(|group| group.mode == AlertMode::OnChange)
+                        .map(|group| (reg.sub.endpoint.clone(), group.name.clone(), group.matched))
+                        .collect::<Vec<_>>()
+                })
+                .collect();
+
+            self.store.save_groups_matched(&groups_matched).await?;
+
+            let crowds_matched: Vec<_> = self
+                .cache
+                .iter()
+                .filter_map(|reg| {
+                    reg.crowd
+                        .as_ref()
+                        .filter(|crowd| crowd.mode == AlertMode::OnChange)
+                        .map(|crowd| (reg.sub.endpoint.clone(), crowd.matched))
+                })
+                .collect();
 
-            let reg = Registration {
-                sub,
-                config: (park, config),
-            };
-            cache.insert(reg.sub.endpoint.clone(), reg);
+            self.store.save_crowd_matched(&crowds_matched).await?;
         }
 
-        Ok(cache)
+        self.store.close().await;
+        Ok(())
+    }
+
+    /// Gets the endpoints of all registrations that have expired at `now`.
+    pub fn expired_endpoints(&self, now: DateTime<Utc>) -> Vec<String> {
+        self.cache
+            .iter()
+            .filter(|r| r.is_expired(now))
+            .map(|r| r.key().clone())
+            .collect()
     }
 
     /// Gets the current number of connected users.
@@ -96,38 +316,43 @@ impl<'a> RegistrationRepository {
         self.cache.len()
     }
 
+    /// Checks that the store can be reached.
+    pub async fn ping(&self) -> Result<(), Error> {
+        self.store.ping().await
+    }
+
+    /// Gets the number of registrations for each park.
+    pub fn count_by_park(&self) -> HashMap<String, usize> {
+        let mut counts = HashMap::new();
+        for reg in self.cache.iter() {
+            *counts.entry(reg.config.0.clone()).or_default() += 1;
+        }
+        counts
+    }
+
     /// Checks if a given endpoint is already registered.
     pub fn endpoint_is_registered(&self, endpoint: &str) -> bool {
         self.cache.contains_key(endpoint)
     }
 
     /// Add a new user registration. Reg must not already be in db.
-    pub async fn add_registration(&self, reg: Registration) -> Result<(), Error> {
-        //First add user to db
-        let mut trans = self.db.begin().await?;
-
-        // Add subscription info
-        trans
-            .execute(
-                query("INSERT INTO REGISTRATIONS VALUES (?, ?, date())")
-                    .bind(reg.sub.endpoint.clone())
-                    .bind(serde_json::to_string(&reg.sub)?),
-            )
-            .await?;
+    ///
+    /// Returns the token that proves ownership of the new registration.
+    pub async fn add_registration(&self, mut reg: Registration) -> Result<String, Error> {
+        reg.expires_at = Some(store::expiry(reg.expires_at, Utc::now()));
 
-        // Add park info
-        trans
-            .execute(
-                query("INSERT INTO CONFIGS VALUES (?, ?)")
-                    .bind(reg.sub.endpoint.clone())
-                    .bind(reg.config.0.clone()),
-            )
-            .await?;
+        let token = token::generate();
+        reg.token_hash = Some(token::hash(&token));
 
-        // Add config
-        Self::add_config_to_transaction(&reg, &mut trans).await?;
+        self.insert_registration(reg).await?;
 
-        trans.commit().await?;
+        Ok(token)
+    }
+
+    /// Adds a registration exactly as passed, including its server side state. Reg must not already be in db.
+    async fn insert_registration(&self, reg: Registration) -> Result<(), Error> {
+        //First add user to db
+        self.store.insert(&reg).await?;
 
         //Update cache
         self.cache.insert(reg.sub.endpoint.clone(), reg);
@@ -136,30 +361,49 @@ impl<'a> RegistrationRepository {
     }
 
     /// Update an existing registration. User must already be in DB.
-    pub async fn update_registration(&self, reg: Registration) -> Result<(), Error> {
-        //First update db
-        let mut trans = self.db.begin().await?;
-
-        // Add park info
-        trans
-            .execute(
-                query("UPDATE CONFIGS SET park = ? WHERE endpoint = ?")
-                    .bind(reg.config.0.clone())
-                    .bind(reg.sub.endpoint.clone()),
-            )
-            .await?;
-
-        // Just remove the old config, then insert the new one
-        trans
-            .execute(
-                query("DELETE FROM RIDEALERTS WHERE endpoint = ?").bind(reg.sub.endpoint.clone()),
-            )
-            .await?;
-
-        // Add config
-        Self::add_config_to_transaction(&reg, &mut trans).await?;
+    pub async fn update_registration(&self, mut reg: Registration) -> Result<(), Error> {
+        // Updating a config restarts its expiry
+        reg.expires_at = Some(store::expiry(reg.expires_at, Utc::now()));
+
+        // Keep snoozes, alert history, tokens and secrets, as these are not part of the clients config
+        if let Some(old) = self.cache.get(&reg.sub.endpoint) {
+            reg.snoozed_until = old.snoozed_until;
+            reg.token_hash = old.token_hash.clone();
+            reg.signing_secret = old.signing_secret.clone();
+            if reg.vapid_key.is_none() {
+                reg.vapid_key = old.vapid_key.clone();
+            }
+
+            for ride in reg.config.1.iter_mut() {
+                if let Some(old_ride) = old.config.1.iter().find(|r| r.ride_name == ride.ride_name)
+                {
+                    ride.snoozed_until = old_ride.snoozed_until;
+
+                    // Changing the alert re-arms it
+                    if old_ride.alert_on == ride.alert_on && old_ride.mode == ride.mode {
+                        ride.last_alerted = old_ride.last_alerted;
+                        ride.matched = old_ride.matched;
+                    }
+                }
+            }
+
+            for group in reg.groups.iter_mut() {
+                if let Some(old_group) = old.groups.iter().find(|g| same_rule(g, group)) {
+                    group.last_alerted = old_group.last_alerted;
+                    group.matched = old_group.matched;
+                }
+            }
+
+            if let (Some(crowd), Some(old_crowd)) = (reg.crowd.as_mut(), old.crowd.as_ref()) {
+                if same_crowd(old_crowd, crowd) {
+                    crowd.last_alerted = old_crowd.last_alerted;
+                    crowd.matched = old_crowd.matched;
+                }
+            }
+        }
 
-        trans.commit().await?;
+        //First update db
+        self.store.update(&reg).await?;
 
         //Update cache
         self.cache.insert(reg.sub.endpoint.clone(), reg);
@@ -167,59 +411,400 @@ impl<'a> RegistrationRepository {
         Ok(())
     }
 
-    /// Adds a new registration or updates an existing one.
-    pub async fn add_or_update_registration(&self, reg: Registration) -> Result<(), Error> {
+    /// Adds a new registration or updates an existing one. Returns the new registrations token if one was added.
+    pub async fn add_or_update_registration(
+        &self,
+        reg: Registration,
+    ) -> Result<Option<String>, Error> {
         if self.endpoint_is_registered(&reg.sub.endpoint) {
             log::info!("Updating existing registration {}", reg.sub.endpoint);
-            self.update_registration(reg).await
+            self.update_registration(reg).await.map(|_| None)
         } else {
             log::info!("Adding new registration {}", reg.sub.endpoint);
-            self.add_registration(reg).await
+            self.add_registration(reg).await.map(Some)
+        }
+    }
+
+    /// Dumps every registration, including the state needed to restore it with [`Self::import`].
+    pub fn export(&self) -> Vec<RegistrationDump> {
+        self.cache.iter().map(|r| r.value().into()).collect()
+    }
+
+    /// Restores registrations from [`Self::export`]. Existing registrations are replaced if `replace` is set,
+    /// else they are skipped.
+    ///
+    /// Returns the number of registrations imported.
+    pub async fn import(
+        &self,
+        dumps: Vec<RegistrationDump>,
+        replace: bool,
+    ) -> Result<usize, Error> {
+        let mut imported = 0;
+
+        for dump in dumps {
+            let mut reg: Registration = dump.into();
+            // Dumps from before expiry existed have none, so start it from the import
+            reg.expires_at = reg.expires_at.or_else(|| Some(default_expiry(Utc::now())));
+
+            if self.endpoint_is_registered(&reg.sub.endpoint) {
+                if !replace {
+                    log::warn!("Skipping existing registration {}", reg.sub.endpoint);
+                    continue;
+                }
+                self.remove_registration(&reg.sub.endpoint).await?;
+            }
+
+            self.insert_registration(reg).await?;
+            imported += 1;
+        }
+
+        Ok(imported)
+    }
+
+    /// Checks if `token` proves ownership of the registration at `endpoint`.
+    ///
+    /// This is always true if the endpoint is not registered. Registrations made before tokens existed are owned by
+    /// no one until their token is delivered in an alert, as anyone who knows their endpoint could claim them.
+    pub fn owns(&self, endpoint: &str, token: Option<&str>) -> bool {
+        match self.cache.get(endpoint) {
+            Some(reg) => match (&reg.token_hash, token) {
+                (Some(hash), Some(token)) => token::verify(token, hash),
+                _ => false,
+            },
+            None => true,
         }
     }
 
+    /// Sets the token hash of a registration made before tokens existed, once its token has been delivered.
+    pub async fn set_token_hash(&self, endpoint: &str, hash: String) -> Result<(), Error> {
+        self.store.set_token_hash(endpoint, &hash).await?;
+
+        if let Some(mut reg) = self.cache.get_mut(endpoint) {
+            reg.token_hash = Some(hash);
+        }
+
+        Ok(())
+    }
+
+    /// Records `key` as the VAPID key of every Web Push registration that does not know which key it subscribed with.
+    ///
+    /// Registrations from before keys were recorded were all made with the key in use when they are first loaded.
+    pub async fn set_missing_vapid_keys(&self, key: &str) -> Result<(), Error> {
+        self.store.set_missing_vapid_keys(key).await?;
+
+        for mut reg in self.cache.iter_mut() {
+            if reg.vapid_key.is_none() && reg.channel == Channel::WebPush {
+                reg.vapid_key = Some(key.to_string());
+            }
+        }
+
+        Ok(())
+    }
+
     /// Removes registration, if it exists. Returns bool indicating if registration existed.
     pub async fn remove_registration(&self, endpoint: &str) -> Result<bool, Error> {
-        if self.endpoint_is_registered(endpoint) {
-            log::info!("Unregistering {}", endpoint);
-            query("DELETE FROM REGISTRATIONS WHERE endpoint = ?")
-                .bind(endpoint.clone())
-                .execute(&self.db)
-                .await?;
-
-            self.cache.remove(endpoint);
+        let existed = self.store.remove(endpoint).await?;
+        self.cache.remove(endpoint);
 
-            Ok(true)
+        if existed {
+            log::info!("Unregistered {}", endpoint);
         } else {
             log::info!("Attempted to unregister non-existent registration");
-            Ok(false)
         }
+
+        Ok(existed)
+    }
+
+    /// Snoozes a registration until `until`, or resumes it if `None`. If `ride_name` is set, only that ride is snoozed.
+    ///
+    /// Returns false if the registration or ride does not exist.
+    pub async fn snooze(
+        &self,
+        endpoint: &str,
+        ride_name: Option<&str>,
+        until: Option<DateTime<Utc>>,
+    ) -> Result<bool, Error> {
+        let exists = self.cache.get(endpoint).is_some_and(|reg| {
+            ride_name.is_none_or(|name| reg.config.1.iter().any(|r| r.ride_name == name))
+        });
+        if !exists {
+            return Ok(false);
+        }
+
+        self.store.snooze(endpoint, ride_name, until).await?;
+
+        //Update cache
+        if let Some(mut reg) = self.cache.get_mut(endpoint) {
+            match ride_name {
+                None => reg.snoozed_until = until,
+                Some(ride_name) => {
+                    if let Some(ride) = reg.config.1.iter_mut().find(|r| r.ride_name == ride_name) {
+                        ride.snoozed_until = until;
+                    }
+                }
+            }
+        }
+
+        Ok(true)
     }
 
-    /// Adds all the rides in a users config into the RIDEALERTS table in a transaction.
-    async fn add_config_to_transaction(
-        reg: &Registration,
-        trans: &mut Transaction<'a, Sqlite>,
+    /// Records that alerts were delivered at `at`. `alerted` is a list of endpoints and the rides each was alerted for.
+    ///
+    /// This disarms any `Once` alerts in the list.
+    pub async fn mark_alerted(
+        &self,
+        alerted: &[(String, Vec<String>)],
+        at: DateTime<Utc>,
     ) -> Result<(), Error> {
-        for ride in reg.config.1.iter() {
-            trans
-                .execute(
-                    query("INSERT INTO RIDEALERTS VALUES (?, ?, ?, ?)")
-                        .bind(reg.sub.endpoint.clone())
-                        .bind(ride.ride_name.clone())
-                        .bind(match ride.alert_on {
-                            RideStatus::Wait(_) => "wait",
-                            RideStatus::Open => "open",
-                            RideStatus::Closed => "closed",
-                        })
-                        .bind(if let RideStatus::Wait(time) = ride.alert_on {
-                            Some(time)
-                        } else {
-                            None
-                        }),
-                )
-                .await?;
+        self.store.mark_alerted(alerted, at).await?;
+
+        //Update cache
+        for (endpoint, rides) in alerted {
+            if let Some(mut reg) = self.cache.get_mut(endpoint) {
+                for ride in reg
+                    .config
+                    .1
+                    .iter_mut()
+                    .filter(|r| rides.contains(&r.ride_name))
+                {
+                    ride.last_alerted = Some(at);
+                }
+            }
         }
+
         Ok(())
     }
+
+    /// Records if a rides alert condition was met when last checked. This is only saved to the store by [`Self::close`].
+    pub fn set_matched(&self, endpoint: &str, ride_name: &str, matched: bool) {
+        if let Some(mut reg) = self.cache.get_mut(endpoint) {
+            if let Some(ride) = reg.config.1.iter_mut().find(|r| r.ride_name == ride_name) {
+                ride.matched = matched;
+            }
+        }
+    }
+
+    /// Records that group alerts were delivered at `at`. `alerted` is a list of endpoints and the groups each was alerted
+    /// for.
+    ///
+    /// This disarms any `Once` groups in the list.
+    pub async fn mark_groups_alerted(
+        &self,
+        alerted: &[(String, Vec<String>)],
+        at: DateTime<Utc>,
+    ) -> Result<(), Error> {
+        self.store.mark_groups_alerted(alerted, at).await?;
+
+        //Update cache
+        for (endpoint, groups) in alerted {
+            if let Some(mut reg) = self.cache.get_mut(endpoint) {
+                for group in reg.groups.iter_mut().filter(|g| groups.contains(&g.name)) {
+                    group.last_alerted = Some(at);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Records if a groups rule was met when last checked. This is only saved to the store by [`Self::close`].
+    pub fn set_group_matched(&self, endpoint: &str, name: &str, matched: bool) {
+        if let Some(mut reg) = self.cache.get_mut(endpoint) {
+            if let Some(group) = reg.groups.iter_mut().find(|g| g.name == name) {
+                group.matched = matched;
+            }
+        }
+    }
+
+    /// Records that crowd alerts were delivered to each of `alerted` at `at`.
+    ///
+    /// This disarms any `Once` crowd alerts in the list.
+    pub async fn mark_crowd_alerted(
+        &self,
+        alerted: &[String],
+        at: DateTime<Utc>,
+    ) -> Result<(), Error> {
+        self.store.mark_crowd_alerted(alerted, at).await?;
+
+        //Update cache
+        for endpoint in alerted {
+            if let Some(mut reg) = self.cache.get_mut(endpoint) {
+                if let Some(crowd) = reg.crowd.as_mut() {
+                    crowd.last_alerted = Some(at);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Records if a crowd alert's condition was met when last checked. This is only saved to the store by
+    /// [`Self::close`].
+    pub fn set_crowd_matched(&self, endpoint: &str, matched: bool) {
+        if let Some(mut reg) = self.cache.get_mut(endpoint) {
+            if let Some(crowd) = reg.crowd.as_mut() {
+                crowd.matched = matched;
+            }
+        }
+    }
+
+    /// Adds a webhook delivery to the delivery log.
+    pub async fn log_delivery(&self, delivery: &Delivery) -> Result<(), Error> {
+        self.store.log_delivery(delivery).await
+    }
+
+    /// Gets the latest `limit` deliveries to an endpoint, newest first.
+    pub async fn deliveries(&self, endpoint: &str, limit: i64) -> Result<Vec<Delivery>, Error> {
+        self.store.deliveries(endpoint, limit).await
+    }
+
+    /// Removes deliveries made before `before` from the delivery log. Returns how many were removed.
+    pub async fn prune_deliveries(&self, before: DateTime<Utc>) -> Result<u64, Error> {
+        self.store.prune_deliveries(before).await
+    }
+}
+
+/// Checks if two groups have the same rule, so alert state can carry over from one to the other. Changing a group
+/// re-arms it.
+fn same_rule(a: &GroupConfig, b: &GroupConfig) -> bool {
+    a.name == b.name && a.rides == b.rides && a.require == b.require && a.mode == b.mode
+}
+
+/// Checks if two crowd alerts have the same rule, so alert state can carry over from one to the other.
+fn same_crowd(a: &CrowdAlert, b: &CrowdAlert) -> bool {
+    a.below == b.below && a.mode == b.mode
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::models::{AlertMode, GroupRide, RideConfig, RideStatus};
+    use crate::store::sqlite::test::TempDb;
+    use crate::store::sqlite::SqliteStore;
+    use crate::store::test::registration;
+    use queue_times::model::CrowdLevel;
+    use web_push::SubscriptionInfo;
+
+    #[test]
+    fn test_dump_keeps_server_state() {
+        let at = Utc::now();
+        let reg = Registration {
+            sub: SubscriptionInfo::new("https://example.com", "p256dh", "auth"),
+            channel: Channel::WebPush,
+            config: (
+                "Cedar Point".to_string(),
+                vec![RideConfig {
+                    ride_name: "Maverick".to_string(),
+                    alert_on: RideStatus::Open,
+                    mode: AlertMode::Once,
+                    snoozed_until: Some(at),
+                    last_alerted: Some(at),
+                    ..Default::default()
+                }],
+            ),
+            groups: vec![GroupConfig {
+                name: "Coasters".to_string(),
+                rides: vec![GroupRide {
+                    ride_name: "Maverick".to_string(),
+                    alert_on: RideStatus::Open,
+                }],
+                last_alerted: Some(at),
+                ..Default::default()
+            }],
+            crowd: Some(CrowdAlert {
+                below: CrowdLevel::Moderate,
+                last_alerted: Some(at),
+                ..Default::default()
+            }),
+            window: None,
+            expires_at: Some(at),
+            snoozed_until: Some(at),
+            vapid_key: Some("key".to_string()),
+            token_hash: Some("hash".to_string()),
+            signing_secret: None,
+        };
+
+        let json = serde_json::to_string(&RegistrationDump::from(&reg)).unwrap();
+        let restored: Registration = serde_json::from_str::<RegistrationDump>(&json)
+            .unwrap()
+            .into();
+
+        assert_eq!(restored.token_hash, reg.token_hash);
+        assert_eq!(restored.vapid_key, reg.vapid_key);
+        assert_eq!(restored.snoozed_until, reg.snoozed_until);
+        assert_eq!(restored.config.1, reg.config.1);
+        assert_eq!(restored.groups, reg.groups);
+        assert_eq!(restored.crowd, reg.crowd);
+    }
+
+    #[tokio::test]
+    async fn test_close_saves_matched() {
+        let file = TempDb::new("close");
+        let subs = RegistrationRepository::new(Box::new(SqliteStore::open(&file.0).await.unwrap()))
+            .await
+            .unwrap();
+
+        let mut reg = registration("https://push.example.com/a");
+        reg.config.1[1].mode = AlertMode::OnChange;
+        subs.add_registration(reg).await.unwrap();
+        subs.set_matched("https://push.example.com/a", "Steel Vengeance", true);
+        subs.set_group_matched("https://push.example.com/a", "Coasters", true);
+        subs.set_crowd_matched("https://push.example.com/a", true);
+
+        subs.close().await.unwrap();
+        assert!(subs.ping().await.is_err());
+
+        // On change alerts that matched before shutdown don't alert again once restarted
+        let subs = RegistrationRepository::new(Box::new(SqliteStore::open(&file.0).await.unwrap()))
+            .await
+            .unwrap();
+        let reg = subs.cache.get("https://push.example.com/a").unwrap();
+        assert!(reg.config.1[1].matched);
+        assert!(!reg.config.1[0].matched);
+        assert!(reg.groups[0].matched);
+        assert!(reg.crowd.as_ref().unwrap().matched);
+    }
+
+    #[tokio::test]
+    async fn test_expiry_is_capped() {
+        let file = TempDb::new("expiry");
+        let subs = RegistrationRepository::new(Box::new(SqliteStore::open(&file.0).await.unwrap()))
+            .await
+            .unwrap();
+        let cap = Utc::now() + chrono::Duration::days(store::MAX_EXPIRY_DAYS);
+
+        let mut reg = registration("https://push.example.com/a");
+        reg.expires_at = Some(Utc::now() + chrono::Duration::days(3650));
+        subs.add_registration(reg.clone()).await.unwrap();
+        assert!(
+            subs.cache
+                .get("https://push.example.com/a")
+                .unwrap()
+                .expires_at
+                <= Some(cap + chrono::Duration::minutes(1))
+        );
+
+        // Updates can't push it out either
+        subs.update_registration(reg).await.unwrap();
+        assert!(
+            subs.cache
+                .get("https://push.example.com/a")
+                .unwrap()
+                .expires_at
+                <= Some(cap + chrono::Duration::minutes(1))
+        );
+
+        // Nearer expiries are kept as asked
+        let mut reg = registration("https://push.example.com/b");
+        let soon = Utc::now() + chrono::Duration::hours(5);
+        reg.expires_at = Some(soon);
+        subs.add_registration(reg).await.unwrap();
+        assert_eq!(
+            subs.cache
+                .get("https://push.example.com/b")
+                .unwrap()
+                .expires_at,
+            Some(soon)
+        );
+    }
 }
@@ -4,7 +4,7 @@
 
 use crate::models::Registration;
 use actix_web::web;
-use actix_web::{get, post, HttpResponse, Responder, Result};
+use actix_web::{get, post, HttpRequest, HttpResponse, Responder, Result};
 use std::sync::Arc;
 use web_push::*;
 
@@ -12,6 +12,26 @@ use web_push::*;
 pub mod registration {
     use super::*;
     use crate::app::Application;
+    use crate::channel;
+    use crate::models::Channel;
+    use crate::token;
+    use actix_web::http::header;
+    use chrono::{Duration, Utc};
+    use queue_times::client::QueueTimesClient;
+
+    /// Gets the registration token sent as `Authorization: Bearer <token>`, if any.
+    fn bearer_token(req: &HttpRequest) -> Option<&str> {
+        req.headers()
+            .get(header::AUTHORIZATION)?
+            .to_str()
+            .ok()?
+            .strip_prefix("Bearer ")
+    }
+
+    /// Response for requests without a valid token for their registration.
+    fn forbidden() -> HttpResponse {
+        HttpResponse::Forbidden().body("Missing or invalid token for this subscription")
+    }
 
     /// Returns the current number of subscribed users.
     #[get("/userCount")]
@@ -26,35 +46,121 @@ pub mod registration {
     #[get("/vapidPublicKey")]
     pub async fn vapid_public_key(app: web::Data<Arc<Application>>) -> impl Responder {
         let app = app.into_inner();
-        let public = app.keys.1.clone();
-
-        public.0
+        app.keys.current_public().to_string()
     }
 
     /// Adds the subscription to the vec of clients to push. Updates registration if already registered, as config can change.
+    ///
+    /// Clients should send the VAPID key they subscribed with as `vapidKey`, so pushes are signed with the right key.
+    ///
+    /// Registrations can set `channel` to get alerts somewhere other than Web Push. These do not need a `sub`, and their
+    /// endpoint is the address of their channel, eg. `mailto:rider@example.com` for email.
+    ///
+    /// New registrations respond with JSON `{token}`, which must be sent as a bearer token to update or remove the
+    /// registration later. New webhooks are also sent `signingSecret`, which their deliveries are signed with.
     #[post("/register")]
     pub async fn register(
+        req: HttpRequest,
         subscription: web::Json<Registration>,
         app: web::Data<Arc<Application>>,
     ) -> impl Responder {
-        let subscription = subscription.into_inner();
+        let mut subscription = subscription.into_inner();
+
+        if let Err(why) = subscription.validate_rules() {
+            return HttpResponse::BadRequest().body(why);
+        }
+
+        // Registrations are counted by park in metrics, so only parks that exist are accepted
+        match app.queue_client.get_park_urls().await {
+            Ok(parks) if !parks.contains_key(&subscription.config.0) => {
+                return HttpResponse::BadRequest().body("No park with that name.");
+            }
+            Ok(_) => {}
+            Err(err) => return HttpResponse::InternalServerError().body(format!("{}", err)),
+        }
+
+        match subscription.channel.address() {
+            Some(address) => {
+                if app.channels.get(&subscription.channel).is_none() {
+                    return HttpResponse::BadRequest().body(format!(
+                        "{} alerts are not enabled on this server",
+                        subscription.channel.name()
+                    ));
+                }
+                if let Err(why) = channel::check_address(&subscription.channel, &app.config) {
+                    return HttpResponse::BadRequest().body(why);
+                }
+
+                subscription.sub = SubscriptionInfo::new(address, String::new(), String::new());
+                subscription.vapid_key = None;
+            }
+            None if subscription.sub.endpoint.is_empty() => {
+                return HttpResponse::BadRequest().body("Missing push subscription");
+            }
+            None => {}
+        }
+
+        // Other servers sharing the store may have changed it
+        if app.subs.sync(&subscription.sub.endpoint).await.is_err() {
+            return HttpResponse::InternalServerError().finish();
+        }
+
+        if !app
+            .subs
+            .owns(&subscription.sub.endpoint, bearer_token(&req))
+        {
+            return forbidden();
+        }
+
+        match &subscription.vapid_key {
+            Some(key) if app.keys.get(key).is_none() => {
+                return HttpResponse::BadRequest().body("Subscribed with an unknown VAPID key");
+            }
+            // Clients that don't say which key they used subscribed with the one we are serving
+            None if subscription.channel == Channel::WebPush
+                && !app.subs.endpoint_is_registered(&subscription.sub.endpoint) =>
+            {
+                subscription.vapid_key = Some(app.keys.current_public().to_string());
+            }
+            _ => {}
+        }
+
+        // New webhooks get a secret to check that deliveries came from us
+        let secret = (matches!(subscription.channel, Channel::Webhook { .. })
+            && !app.subs.endpoint_is_registered(&subscription.sub.endpoint))
+        .then(token::generate);
+        subscription.signing_secret = secret.clone();
 
         match app.subs.add_or_update_registration(subscription).await {
-            Ok(_) => HttpResponse::Ok(),
-            Err(_) => HttpResponse::InternalServerError(),
+            Ok(Some(token)) => match secret {
+                Some(secret) => HttpResponse::Ok()
+                    .json(serde_json::json!({ "token": token, "signingSecret": secret })),
+                None => HttpResponse::Ok().json(serde_json::json!({ "token": token })),
+            },
+            Ok(None) => HttpResponse::Ok().finish(),
+            Err(_) => HttpResponse::InternalServerError().finish(),
         }
-        .finish()
     }
 
     /// Removes the subscription from the server, stopping push notifications. Clients still need to unsub from the
     /// push service on their end.
     #[post("/unregister")]
     pub async fn unregister(
+        req: HttpRequest,
         subscription: web::Json<SubscriptionInfo>,
         app: web::Data<Arc<Application>>,
     ) -> impl Responder {
         let subscription = subscription.into_inner();
 
+        // Other servers sharing the store may have changed it
+        if app.subs.sync(&subscription.endpoint).await.is_err() {
+            return HttpResponse::InternalServerError().finish();
+        }
+
+        if !app.subs.owns(&subscription.endpoint, bearer_token(&req)) {
+            return forbidden();
+        }
+
         match app.subs.remove_registration(&subscription.endpoint).await {
             Ok(true) => HttpResponse::Ok().finish(),
             Ok(false) => {
@@ -63,6 +169,141 @@ pub mod registration {
             Err(_) => HttpResponse::InternalServerError().finish(),
         }
     }
+
+    /// Body of a `/registration/lookup` request. A full `SubscriptionInfo` is also accepted.
+    #[derive(serde::Deserialize)]
+    pub struct LookupRequest {
+        /// Endpoint of the registration to look up.
+        pub endpoint: String,
+    }
+
+    /// Responds with the servers copy of a registration, including its config, when each ride last alerted and
+    /// when it expires. Clients can use this to reconcile their config with the server. The subscription's keys are
+    /// left out, as the client already has them.
+    #[post("/registration/lookup")]
+    pub async fn lookup(
+        http_req: HttpRequest,
+        req: web::Json<LookupRequest>,
+        app: web::Data<Arc<Application>>,
+    ) -> impl Responder {
+        // Other servers sharing the store may have changed it
+        if app.subs.sync(&req.endpoint).await.is_err() {
+            return HttpResponse::InternalServerError().finish();
+        }
+
+        if !app.subs.owns(&req.endpoint, bearer_token(&http_req)) {
+            return forbidden();
+        }
+
+        match app.subs.cache.get(&req.endpoint) {
+            Some(reg) => {
+                let mut body = serde_json::to_value(&*reg).unwrap();
+                body.as_object_mut().unwrap().remove("sub");
+                HttpResponse::Ok().json(body)
+            }
+            None => {
+                HttpResponse::NotFound().body("Subscription was not registered with queue alert")
+            }
+        }
+    }
+
+    /// Body of a `/snooze` request.
+    #[derive(serde::Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct SnoozeRequest {
+        /// Endpoint of the registration to snooze.
+        pub endpoint: String,
+        /// Minutes to snooze for. Snoozes until the registration expires if unset.
+        #[serde(default)]
+        pub minutes: Option<u32>,
+        /// Only snooze this ride, if set.
+        #[serde(default)]
+        pub ride_name: Option<String>,
+    }
+
+    /// Pauses alerts for a registration, or a single ride in it.
+    #[post("/snooze")]
+    pub async fn snooze(
+        http_req: HttpRequest,
+        req: web::Json<SnoozeRequest>,
+        app: web::Data<Arc<Application>>,
+    ) -> impl Responder {
+        let req = req.into_inner();
+
+        // Other servers sharing the store may have changed it
+        if app.subs.sync(&req.endpoint).await.is_err() {
+            return HttpResponse::InternalServerError().finish();
+        }
+
+        if !app.subs.owns(&req.endpoint, bearer_token(&http_req)) {
+            return forbidden();
+        }
+
+        let until = match req.minutes {
+            Some(minutes) => Some(Utc::now() + Duration::minutes(minutes.into())),
+            None => app
+                .subs
+                .cache
+                .get(&req.endpoint)
+                .and_then(|reg| reg.expires_at),
+        };
+        if until.is_none() {
+            return HttpResponse::BadRequest()
+                .body("Subscription was not registered with queue alert");
+        }
+
+        match app
+            .subs
+            .snooze(&req.endpoint, req.ride_name.as_deref(), until)
+            .await
+        {
+            Ok(true) => HttpResponse::Ok().finish(),
+            Ok(false) => HttpResponse::BadRequest()
+                .body("Subscription or ride was not registered with queue alert"),
+            Err(_) => HttpResponse::InternalServerError().finish(),
+        }
+    }
+
+    /// Body of a `/resume` request.
+    #[derive(serde::Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct ResumeRequest {
+        /// Endpoint of the registration to resume.
+        pub endpoint: String,
+        /// Only resume this ride, if set.
+        #[serde(default)]
+        pub ride_name: Option<String>,
+    }
+
+    /// Resumes alerts for a snoozed registration, or a single ride in it.
+    #[post("/resume")]
+    pub async fn resume(
+        http_req: HttpRequest,
+        req: web::Json<ResumeRequest>,
+        app: web::Data<Arc<Application>>,
+    ) -> impl Responder {
+        let req = req.into_inner();
+
+        // Other servers sharing the store may have changed it
+        if app.subs.sync(&req.endpoint).await.is_err() {
+            return HttpResponse::InternalServerError().finish();
+        }
+
+        if !app.subs.owns(&req.endpoint, bearer_token(&http_req)) {
+            return forbidden();
+        }
+
+        match app
+            .subs
+            .snooze(&req.endpoint, req.ride_name.as_deref(), None)
+            .await
+        {
+            Ok(true) => HttpResponse::Ok().finish(),
+            Ok(false) => HttpResponse::BadRequest()
+                .body("Subscription or ride was not registered with queue alert"),
+            Err(_) => HttpResponse::InternalServerError().finish(),
+        }
+    }
 }
 
 pub mod queue {
@@ -119,7 +360,7 @@ pub mod queue {
                         return HttpResponse::BadRequest().body("Non queue-times url passed.");
                     }
                     Some(domain) => {
-                        if domain != "queue-times.com" {
+                        if Some(domain) != app.config.upstream_url.domain() {
                             return HttpResponse::BadRequest().body("Non queue-times url passed.");
                         }
                     }
@@ -141,4 +382,357 @@ pub mod queue {
             Err(err) => HttpResponse::InternalServerError().body(format!("{}", err)),
         }
     }
+
+    /// Streams live wait times for the park with queue times id `id`, as Server-Sent Events. See [`crate::live`] for
+    /// the events sent.
+    ///
+    /// # Example
+    /// `GET /parks/1/stream`
+    #[get("/parks/{id}/stream")]
+    pub async fn stream_park_wait_times(
+        req: HttpRequest,
+        app: web::Data<Arc<Application>>,
+        id: web::Path<u32>,
+    ) -> impl Responder {
+        use tokio_stream::StreamExt;
+
+        let app = app.get_ref().clone();
+
+        let url = match app.queue_client.get_park_urls().await {
+            Ok(parks) => crate::live::park_url(&parks, id.into_inner()),
+            Err(err) => return HttpResponse::InternalServerError().body(format!("{}", err)),
+        };
+        let Some(url) = url else {
+            return HttpResponse::NotFound().body("No park with that id.");
+        };
+
+        let last_event_id = req
+            .headers()
+            .get("Last-Event-ID")
+            .and_then(|id| id.to_str().ok())
+            .map(str::to_string);
+
+        HttpResponse::Ok()
+            .content_type("text/event-stream")
+            .insert_header((actix_web::http::header::CACHE_CONTROL, "no-cache"))
+            // Stops nginx from buffering events
+            .insert_header(("X-Accel-Buffering", "no"))
+            .streaming(crate::live::stream(app, url, last_event_id).map(Ok::<_, actix_web::Error>))
+    }
+
+    /// Used for extracting `/recommend` queries. Lists of rides are comma separated.
+    #[derive(serde::Deserialize)]
+    pub struct RecommendQuery {
+        pub park: String,
+        #[serde(default)]
+        pub favourites: String,
+        #[serde(default)]
+        pub near: String,
+    }
+
+    /// Responds with a JSON list of the open rides at a park, ranked by what to ride next. See [`crate::recommend`]
+    /// for how rides are scored. Responds 404 if no park has that name.
+    ///
+    /// # Example
+    /// `GET /recommend?park=Cedar Point&favourites=Maverick,Steel Vengeance&near=Millennium Force`
+    #[get("/recommend")]
+    pub async fn recommend(
+        app: web::Data<Arc<Application>>,
+        query: web::Query<RecommendQuery>,
+    ) -> impl Responder {
+        use crate::recommend::{recommend, Preferences};
+
+        let query = query.into_inner();
+        let url = match app.queue_client.get_park_urls().await {
+            Ok(mut parks) => parks.remove(&query.park),
+            Err(err) => return HttpResponse::InternalServerError().body(format!("{}", err)),
+        };
+        let Some(url) = url else {
+            return HttpResponse::NotFound().body("No park with that name.");
+        };
+
+        let rides = match app.queue_client.get_ride_times(url).await {
+            Ok(rides) => rides,
+            Err(err) => return HttpResponse::InternalServerError().body(format!("{}", err)),
+        };
+
+        let list = |names: &str| {
+            names
+                .split(',')
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .map(str::to_string)
+                .collect()
+        };
+        let prefs = Preferences {
+            favourites: list(&query.favourites),
+            near: list(&query.near),
+        };
+
+        let now = chrono::Utc::now();
+        HttpResponse::Ok().json(recommend(&rides, &prefs, |ride| {
+            app.history.typical(&query.park, ride, now)
+        }))
+    }
+
+    /// Used for extracting `/crowd` queries.
+    #[derive(serde::Deserialize)]
+    pub struct CrowdQuery {
+        pub park: String,
+    }
+
+    /// Responds with JSON describing how busy a park is, and how that compares to usual for the hour. See
+    /// [`crate::crowd`] for what is reported. Responds 404 if no park has that name.
+    ///
+    /// # Example
+    /// `GET /crowd?park=Cedar Point`
+    #[get("/crowd")]
+    pub async fn crowd(
+        app: web::Data<Arc<Application>>,
+        query: web::Query<CrowdQuery>,
+    ) -> impl Responder {
+        let query = query.into_inner();
+        let url = match app.queue_client.get_park_urls().await {
+            Ok(mut parks) => parks.remove(&query.park),
+            Err(err) => return HttpResponse::InternalServerError().body(format!("{}", err)),
+        };
+        let Some(url) = url else {
+            return HttpResponse::NotFound().body("No park with that name.");
+        };
+
+        let rides = match app.queue_client.get_ride_times(url).await {
+            Ok(rides) => rides,
+            Err(err) => return HttpResponse::InternalServerError().body(format!("{}", err)),
+        };
+
+        let now = chrono::Utc::now();
+        HttpResponse::Ok().json(crate::crowd::report(&rides, |ride| {
+            app.history.typical(&query.park, ride, now)
+        }))
+    }
+
+    /// Opens a WebSocket that alerts on the config the client sends over it, for clients that can't use web push. See
+    /// [`crate::live_alerts`] for the messages sent.
+    ///
+    /// # Example
+    /// `GET /alerts/live`, then send `["Cedar Point",[{"rideName":"Maverick","alertOn":"Open"}]]`
+    #[get("/alerts/live")]
+    pub async fn live_alerts(
+        req: HttpRequest,
+        body: web::Payload,
+        app: web::Data<Arc<Application>>,
+    ) -> Result<HttpResponse> {
+        let (res, session, messages) = actix_ws::handle(&req, body)?;
+
+        actix_web::rt::spawn(crate::live_alerts::run(
+            app.get_ref().clone(),
+            session,
+            messages,
+        ));
+
+        Ok(res)
+    }
+}
+
+pub mod metrics {
+    use super::*;
+    use crate::app::Application;
+
+    /// Responds with server metrics in the Prometheus text format.
+    #[get("/metrics")]
+    pub async fn get_metrics(app: web::Data<Arc<Application>>) -> impl Responder {
+        let app = app.into_inner();
+
+        HttpResponse::Ok()
+            .content_type(prometheus::TEXT_FORMAT)
+            .body(
+                app.metrics
+                    .render(&app.subs.count_by_park(), app.queue_client.cache_stats()),
+            )
+    }
+}
+
+/// Routes for load balancers and process supervisors.
+pub mod health {
+    use super::*;
+    use crate::app::Application;
+
+    /// Responds with 200 if the server is running.
+    #[get("/healthz")]
+    pub async fn healthz() -> impl Responder {
+        HttpResponse::Ok().json(serde_json::json!({ "status": "ok" }))
+    }
+
+    /// Responds with the result of each readiness check as JSON, with 503 if any failed.
+    #[get("/readyz")]
+    pub async fn readyz(app: web::Data<Arc<Application>>) -> impl Responder {
+        let readiness = crate::health::check(&app.into_inner()).await;
+
+        if readiness.ready {
+            HttpResponse::Ok().json(readiness)
+        } else {
+            HttpResponse::ServiceUnavailable().json(readiness)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::app::test::{stand_in_app, stand_in_upstream};
+    use crate::config::Config;
+    use crate::store::sqlite::test::TempDb;
+    use crate::store::test::registration;
+    use actix_web::http::{header, StatusCode};
+    use actix_web::{test, App};
+
+    #[actix_web::test]
+    async fn test_lookup() {
+        let file = TempDb::new("lookup");
+        let app = Arc::new(stand_in_app(&file, Config::default()).await);
+        let token = app
+            .subs
+            .add_registration(registration("https://push.example.com/a"))
+            .await
+            .unwrap();
+        let service = test::init_service(
+            App::new()
+                .app_data(web::Data::new(app))
+                .service(registration::lookup),
+        )
+        .await;
+
+        let lookup = |endpoint: &str, token: &str| {
+            test::TestRequest::post()
+                .uri("/registration/lookup")
+                .insert_header((header::AUTHORIZATION, format!("Bearer {}", token)))
+                .set_json(serde_json::json!({ "endpoint": endpoint }))
+                .to_request()
+        };
+
+        let res = test::call_service(&service, lookup("https://push.example.com/b", &token)).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        let res = test::call_service(&service, lookup("https://push.example.com/a", "wrong")).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        let res = test::call_service(&service, lookup("https://push.example.com/a", &token)).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body: serde_json::Value = test::read_body_json(res).await;
+        let body = body.as_object().unwrap();
+        // The client's push keys are not sent back
+        assert!(!body.contains_key("sub"));
+        assert_eq!(body["config"][0], "Cedar Point");
+        assert_eq!(body["config"][1][0]["rideName"], "Maverick");
+        assert_eq!(body["vapidKey"], "key");
+        assert!(body.contains_key("expiresAt"));
+        assert!(!body.contains_key("expires_at"));
+    }
+
+    #[actix_web::test]
+    async fn test_register_unknown_park() {
+        let file = TempDb::new("register_park");
+        let config = Config {
+            upstream_url: stand_in_upstream(),
+            ..Default::default()
+        };
+        let app = Arc::new(stand_in_app(&file, config).await);
+        let service = test::init_service(
+            App::new()
+                .app_data(web::Data::new(app.clone()))
+                .service(registration::register),
+        )
+        .await;
+
+        let register = |park: &str| {
+            let mut reg = serde_json::to_value(registration("https://push.example.com/a")).unwrap();
+            reg["config"][0] = park.into();
+            reg["vapidKey"] = app.keys.current_public().into();
+            test::TestRequest::post()
+                .uri("/register")
+                .set_json(reg)
+                .to_request()
+        };
+
+        let res = test::call_service(&service, register("Nowhere Land")).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(app.subs.get_current_user_count(), 0);
+
+        let res = test::call_service(&service, register("Cedar Point")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(app.subs.count_by_park()["Cedar Point"], 1);
+    }
+
+    #[actix_web::test]
+    async fn test_legacy_registration_is_not_claimable() {
+        let file = TempDb::new("legacy_owner");
+        let config = Config {
+            upstream_url: stand_in_upstream(),
+            ..Default::default()
+        };
+        let app = Arc::new(stand_in_app(&file, config).await);
+
+        // A registration from before tokens existed, that has not been sent one yet
+        let mut legacy = registration("https://push.example.com/a");
+        legacy.token_hash = None;
+        app.subs
+            .import(vec![(&legacy).into()], false)
+            .await
+            .unwrap();
+        assert!(app
+            .subs
+            .cache
+            .get("https://push.example.com/a")
+            .unwrap()
+            .token_hash
+            .is_none());
+
+        let service = test::init_service(
+            App::new()
+                .app_data(web::Data::new(app.clone()))
+                .service(registration::register)
+                .service(registration::unregister)
+                .service(registration::lookup)
+                .service(registration::snooze)
+                .service(registration::resume),
+        )
+        .await;
+
+        // Someone who only knows the endpoint can't take it over, with or without a made up token
+        let mut hijack = serde_json::to_value(registration("https://push.example.com/a")).unwrap();
+        hijack["sub"]["keys"]["p256dh"] = "attacker".into();
+        hijack["vapidKey"] = app.keys.current_public().into();
+        let endpoint = serde_json::json!({ "endpoint": "https://push.example.com/a" });
+        let sub = serde_json::json!({ "endpoint": "https://push.example.com/a", "keys": { "p256dh": "attacker", "auth": "auth" } });
+
+        for token in [None, Some("made up")] {
+            for (uri, body) in [
+                ("/register", &hijack),
+                ("/unregister", &sub),
+                ("/registration/lookup", &endpoint),
+                ("/snooze", &endpoint),
+                ("/resume", &endpoint),
+            ] {
+                let mut req = test::TestRequest::post().uri(uri).set_json(body);
+                if let Some(token) = token {
+                    req = req.insert_header((header::AUTHORIZATION, format!("Bearer {}", token)));
+                }
+
+                let res = test::call_service(&service, req.to_request()).await;
+                assert_eq!(
+                    res.status(),
+                    StatusCode::FORBIDDEN,
+                    "{} with {:?}",
+                    uri,
+                    token
+                );
+            }
+        }
+
+        // The registration is untouched, and no token was handed out
+        let reg = app.subs.cache.get("https://push.example.com/a").unwrap();
+        assert_eq!(reg.sub.keys.p256dh, "p256dh");
+        assert!(reg.snoozed_until.is_none());
+        assert!(reg.token_hash.is_none());
+    }
 }
@@ -0,0 +1,957 @@
+//! Persistent storage backends for registrations.
+//!
+//! [`RegistrationRepository`](crate::registration::RegistrationRepository) caches registrations in memory, and writes
+//! every change through to a [`RegistrationStore`]. SQLite is always available, Postgres is behind the `postgres`
+//! feature.
+
+use crate::config::Config;
+use crate::error::Error;
+use crate::migrations;
+use crate::models::{
+    ActiveWindow, AlertMode, Channel, CrowdAlert, GroupConfig, GroupRequire, GroupRide,
+    Registration, RideConfig, RideStatus,
+};
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, NaiveDate, NaiveTime, Utc};
+use queue_times::model::CrowdLevel;
+use std::collections::VecDeque;
+use web_push::SubscriptionInfo;
+
+#[cfg(feature = "postgres")]
+pub mod postgres;
+pub mod sqlite;
+
+/// Days a registration lasts if the client does not set an expiry.
+pub const DEFAULT_EXPIRY_DAYS: i64 = 3;
+
+/// Most days a client can set a registration to last, so every registration is eventually cleaned up.
+pub const MAX_EXPIRY_DAYS: i64 = 14;
+
+/// Counts of registrations read from a store.
+pub struct Loaded {
+    /// Number of registrations passed to the sink.
+    pub loaded: usize,
+    /// Number of registrations that could not be read, and were quarantined.
+    pub quarantined: usize,
+}
+
+/// Something wrong with the stored rows of a registration.
+#[derive(Debug, Clone)]
+pub struct Problem {
+    pub endpoint: String,
+    pub reason: String,
+}
+
+/// A notification delivered, or not, to a webhook. Kept for debugging.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Delivery {
+    pub endpoint: String,
+    pub delivered_at: DateTime<Utc>,
+    /// Type of payload delivered, eg. `alert`.
+    pub payload: String,
+    /// Why delivery failed. Unset if it succeeded.
+    pub error: Option<String>,
+}
+
+/// Results of [`RegistrationStore::check`].
+#[derive(Debug, Default)]
+pub struct CheckReport {
+    /// Registrations that cannot be read, and will be quarantined when next loaded.
+    pub corrupt: Vec<Problem>,
+    /// Rows that do not belong to any registration. These are never loaded, so are harmless but wasted.
+    pub orphans: Vec<Problem>,
+    /// Registrations that have already been quarantined.
+    pub quarantined: Vec<Problem>,
+}
+
+impl CheckReport {
+    /// True if nothing needs fixing. Quarantined registrations have already been dealt with, so don't count.
+    pub fn is_clean(&self) -> bool {
+        self.corrupt.is_empty() && self.orphans.is_empty()
+    }
+}
+
+/// Storage for registrations, including the server side state of each.
+///
+/// Registrations that cannot be read are moved to a QUARANTINE table when loaded, so that one bad row does not stop
+/// the server from starting. Their rows are kept there as JSON, along with why they were quarantined.
+#[async_trait]
+pub trait RegistrationStore: Send + Sync {
+    /// Loads every registration, passing each to `sink` as it is read. Any that cannot be read are quarantined.
+    async fn load_all(&self, sink: &mut (dyn FnMut(Registration) + Send)) -> Result<Loaded, Error>;
+
+    /// Loads a single registration, if it exists. It is quarantined if it cannot be read.
+    async fn load(&self, endpoint: &str) -> Result<Option<Registration>, Error>;
+
+    /// Finds corrupt and orphaned rows, without changing anything.
+    async fn check(&self) -> Result<CheckReport, Error>;
+
+    /// Checks that the store can be reached.
+    async fn ping(&self) -> Result<(), Error>;
+
+    /// Adds a new registration exactly as passed. The endpoint must not already be stored.
+    async fn insert(&self, reg: &Registration) -> Result<(), Error>;
+
+    /// Replaces the config, windows, expiry and key of an existing registration.
+    async fn update(&self, reg: &Registration) -> Result<(), Error>;
+
+    /// Removes a registration. Returns false if it was not stored.
+    async fn remove(&self, endpoint: &str) -> Result<bool, Error>;
+
+    /// Sets the hash of the token proving ownership of a registration.
+    async fn set_token_hash(&self, endpoint: &str, hash: &str) -> Result<(), Error>;
+
+    /// Sets the VAPID key of every Web Push registration that does not have one.
+    async fn set_missing_vapid_keys(&self, key: &str) -> Result<(), Error>;
+
+    /// Snoozes a registration, or one of its rides if `ride_name` is set, until `until`. `None` resumes it.
+    async fn snooze(
+        &self,
+        endpoint: &str,
+        ride_name: Option<&str>,
+        until: Option<DateTime<Utc>>,
+    ) -> Result<(), Error>;
+
+    /// Records that each endpoint was alerted for its listed rides at `at`.
+    async fn mark_alerted(
+        &self,
+        alerted: &[(String, Vec<String>)],
+        at: DateTime<Utc>,
+    ) -> Result<(), Error>;
+
+    /// Records if the alert condition of each endpoint and ride was met when last checked.
+    async fn save_matched(&self, matched: &[(String, String, bool)]) -> Result<(), Error>;
+
+    /// Records that each endpoint was alerted for its listed groups at `at`.
+    async fn mark_groups_alerted(
+        &self,
+        alerted: &[(String, Vec<String>)],
+        at: DateTime<Utc>,
+    ) -> Result<(), Error>;
+
+    /// Records if the rule of each endpoint and group was met when last checked.
+    async fn save_groups_matched(&self, matched: &[(String, String, bool)]) -> Result<(), Error>;
+
+    /// Records that each endpoint was alerted about its park's crowd at `at`.
+    async fn mark_crowd_alerted(&self, alerted: &[String], at: DateTime<Utc>) -> Result<(), Error>;
+
+    /// Records if the crowd alert of each endpoint was met when last checked.
+    async fn save_crowd_matched(&self, matched: &[(String, bool)]) -> Result<(), Error>;
+
+    /// Adds a webhook delivery to the delivery log.
+    async fn log_delivery(&self, delivery: &Delivery) -> Result<(), Error>;
+
+    /// Gets the latest `limit` deliveries to an endpoint, newest first.
+    async fn deliveries(&self, endpoint: &str, limit: i64) -> Result<Vec<Delivery>, Error>;
+
+    /// Removes deliveries made before `before` from the delivery log. Returns how many were removed.
+    async fn prune_deliveries(&self, before: DateTime<Utc>) -> Result<u64, Error>;
+
+    /// Closes the store, waiting for queries in progress to finish.
+    async fn close(&self);
+
+    /// True if other servers may write to this store, so cached registrations can go stale.
+    fn is_shared(&self) -> bool {
+        false
+    }
+
+    /// Checks if this server should be the one sending alerts, which only one server sharing a store may do.
+    async fn try_lead(&self) -> Result<bool, Error> {
+        Ok(true)
+    }
+}
+
+/// Opens the store set in `config`, migrating it to the latest schema. Postgres is used if a url is set, else SQLite.
+pub async fn open(config: &Config) -> Result<Box<dyn RegistrationStore>, Error> {
+    match &config.postgres_url {
+        #[cfg(feature = "postgres")]
+        Some(url) => Ok(Box::new(postgres::PostgresStore::open(url).await?)),
+        #[cfg(not(feature = "postgres"))]
+        Some(_) => Err(Error::PostgresDisabled),
+        None => Ok(Box::new(sqlite::SqliteStore::open(&config.database).await?)),
+    }
+}
+
+/// Migrates the store set in `config` to the latest schema without loading it.
+///
+/// Returns the versions it was migrated from and to.
+pub async fn migrate(config: &Config) -> Result<(i64, i64), Error> {
+    match &config.postgres_url {
+        #[cfg(feature = "postgres")]
+        Some(url) => Ok((
+            postgres::PostgresStore::migrate(url).await?,
+            migrations::postgres::latest_version(),
+        )),
+        #[cfg(not(feature = "postgres"))]
+        Some(_) => Err(Error::PostgresDisabled),
+        None => Ok((
+            sqlite::SqliteStore::migrate(&config.database).await?,
+            migrations::latest_version(),
+        )),
+    }
+}
+
+/// Gets the expiry of a registration updated at `now` without an explicit expiry.
+pub fn default_expiry(now: DateTime<Utc>) -> DateTime<Utc> {
+    now + Duration::days(DEFAULT_EXPIRY_DAYS)
+}
+
+/// Gets the expiry of a registration updated at `now`, which asked for `requested`. Defaults if unset, and is capped
+/// at [`MAX_EXPIRY_DAYS`].
+pub fn expiry(requested: Option<DateTime<Utc>>, now: DateTime<Utc>) -> DateTime<Utc> {
+    requested.map_or_else(
+        || default_expiry(now),
+        |requested| requested.min(now + Duration::days(MAX_EXPIRY_DAYS)),
+    )
+}
+
+/// Splits an alert condition into its `alerton` and `wait` columns.
+pub(crate) fn alert_on_columns(alert_on: RideStatus) -> (&'static str, Option<i32>) {
+    match alert_on {
+        RideStatus::Wait(time) => ("wait", Some(time as i32)),
+        RideStatus::Open => ("open", None),
+        RideStatus::Closed => ("closed", None),
+    }
+}
+
+/// Splits how many rides a group requires into its `require` and `count` columns.
+pub(crate) fn require_columns(require: GroupRequire) -> (&'static str, Option<i32>) {
+    match require {
+        GroupRequire::Any => ("any", None),
+        GroupRequire::All => ("all", None),
+        GroupRequire::AtLeast(count) => ("at_least", Some(count as i32)),
+    }
+}
+
+/// Gets the `mode` column of an alert mode.
+pub(crate) fn mode_column(mode: AlertMode) -> &'static str {
+    match mode {
+        AlertMode::Once => "once",
+        AlertMode::Repeat => "repeat",
+        AlertMode::OnChange => "on_change",
+    }
+}
+
+/// Gets the `below` column of a crowd level.
+pub(crate) fn below_column(below: CrowdLevel) -> &'static str {
+    match below {
+        CrowdLevel::Low => "low",
+        CrowdLevel::Moderate => "moderate",
+        CrowdLevel::High => "high",
+        CrowdLevel::VeryHigh => "very_high",
+    }
+}
+
+/// Formats a windows days for storage.
+pub(crate) fn window_days(window: &ActiveWindow) -> String {
+    window
+        .days
+        .iter()
+        .map(|d| d.to_string())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Formats a registrations channel for storage. Web Push is stored as null, as every registration used it before
+/// other channels existed.
+pub(crate) fn channel_column(channel: &Channel) -> Result<Option<String>, serde_json::Error> {
+    match channel {
+        Channel::WebPush => Ok(None),
+        channel => serde_json::to_string(channel).map(Some),
+    }
+}
+
+/// A row of REGISTRATIONS.
+pub(crate) struct RegistrationRow {
+    pub endpoint: String,
+    pub subscription_info: String,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub snoozed_until: Option<DateTime<Utc>>,
+    pub token_hash: Option<String>,
+    pub vapid_key: Option<String>,
+    pub channel: Option<String>,
+    pub signing_secret: Option<String>,
+}
+
+/// A row of RIDEALERTS.
+pub(crate) struct RideRow {
+    pub ride_name: String,
+    pub alert_on: String,
+    pub wait: Option<i32>,
+    pub snoozed_until: Option<DateTime<Utc>>,
+    pub mode: String,
+    pub last_alerted: Option<DateTime<Utc>>,
+    pub matched: bool,
+    /// The rides RIDEWINDOWS row, if it has one.
+    pub window: Option<WindowRow>,
+}
+
+/// A row of RIDEGROUPS.
+pub(crate) struct GroupRow {
+    pub name: String,
+    pub require: String,
+    pub count: Option<i32>,
+    pub mode: String,
+    pub last_alerted: Option<DateTime<Utc>>,
+    pub matched: bool,
+    /// The groups GROUPRIDES rows.
+    pub rides: Vec<GroupRideRow>,
+}
+
+/// A row of GROUPRIDES.
+pub(crate) struct GroupRideRow {
+    pub ride_name: String,
+    pub alert_on: String,
+    pub wait: Option<i32>,
+}
+
+/// A row of CROWDALERTS.
+pub(crate) struct CrowdRow {
+    pub below: String,
+    pub mode: String,
+    pub last_alerted: Option<DateTime<Utc>>,
+    pub matched: bool,
+}
+
+/// A row of CONFIGWINDOWS or RIDEWINDOWS.
+pub(crate) struct WindowRow {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+    pub timezone: String,
+    pub days: String,
+    pub start_date: Option<NaiveDate>,
+    pub end_date: Option<NaiveDate>,
+}
+
+/// The rows making up a single registration, as read from a store. Rows that could not be read at all are recorded as
+/// errors, so one bad column fails only its own registration.
+pub(crate) struct StoredRows {
+    pub registration: RegistrationRow,
+    pub park: Option<String>,
+    pub window: Option<Result<WindowRow, sqlx::Error>>,
+    pub rides: Vec<Result<RideRow, sqlx::Error>>,
+    pub groups: Vec<Result<GroupRow, sqlx::Error>>,
+    pub crowd: Option<Result<CrowdRow, sqlx::Error>>,
+}
+
+/// Rows of a table a registration has many of, such as its rides, read in the same endpoint order as registrations so
+/// each registration's rows can be taken as it is read. Only the rows read ahead of the current registration are held.
+///
+/// Endpoints are compared byte by byte, so stores must sort them that way.
+pub(crate) struct ChildRows<T> {
+    /// Rows read but not yet taken, in endpoint order.
+    rows: VecDeque<(String, T)>,
+    /// Set once every row has been read.
+    done: bool,
+}
+
+impl<T> ChildRows<T> {
+    pub fn new() -> Self {
+        Self {
+            rows: VecDeque::new(),
+            done: false,
+        }
+    }
+
+    /// True if more rows must be read before the rows of `endpoint` can be taken.
+    pub fn needs_more(&self, endpoint: &str) -> bool {
+        !self.done && self.rows.back().is_none_or(|(e, _)| e.as_str() <= endpoint)
+    }
+
+    /// Adds the next rows read. An empty batch means every row has been read.
+    pub fn extend(&mut self, batch: Vec<(String, T)>) {
+        self.done = batch.is_empty();
+        self.rows.extend(batch);
+    }
+
+    /// Takes the rows of `endpoint`, once [`Self::needs_more`] is false. Rows before it belong to no registration, so
+    /// are dropped.
+    pub fn take(&mut self, endpoint: &str) -> Vec<T> {
+        while self
+            .rows
+            .front()
+            .is_some_and(|(e, _)| e.as_str() < endpoint)
+        {
+            self.rows.pop_front();
+        }
+
+        let mut taken = Vec::new();
+        while self.rows.front().is_some_and(|(e, _)| e == endpoint) {
+            taken.extend(self.rows.pop_front().map(|(_, row)| row));
+        }
+        taken
+    }
+}
+
+/// Receives the rows of each registration as a store reads them. Registrations whose own row cannot be read are
+/// passed as a problem instead.
+pub(crate) type RowSink<'a> = dyn FnMut(Result<StoredRows, Problem>) + Send + 'a;
+
+impl StoredRows {
+    /// Builds the registration these rows store.
+    ///
+    /// # Errors
+    /// Returns why the rows do not make a valid registration, which should then be quarantined.
+    pub fn assemble(self) -> Result<Registration, String> {
+        let row = self.registration;
+
+        let sub: SubscriptionInfo = serde_json::from_str(&row.subscription_info)
+            .map_err(|e| format!("invalid subscription info: {}", e))?;
+        if sub.endpoint != row.endpoint {
+            return Err(format!(
+                "subscription is for a different endpoint {}",
+                sub.endpoint
+            ));
+        }
+
+        let channel = row
+            .channel
+            .map(|c| serde_json::from_str(&c))
+            .transpose()
+            .map_err(|e| format!("invalid channel: {}", e))?
+            .unwrap_or_default();
+
+        let park = self.park.ok_or("no config")?;
+        let window = self
+            .window
+            .map(|w| w.map_err(|e| e.to_string()).and_then(WindowRow::assemble))
+            .transpose()?;
+
+        let rides = self
+            .rides
+            .into_iter()
+            .map(|ride| {
+                let ride = ride.map_err(|e| format!("invalid ride alert: {}", e))?;
+
+                Ok(RideConfig {
+                    alert_on: parse_alert_on(&ride.alert_on, ride.wait, &ride.ride_name)?,
+                    mode: parse_mode(&ride.mode, &ride.ride_name)?,
+                    window: ride.window.map(WindowRow::assemble).transpose()?,
+                    snoozed_until: ride.snoozed_until,
+                    last_alerted: ride.last_alerted,
+                    matched: ride.matched,
+                    ride_name: ride.ride_name,
+                })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+
+        let groups = self
+            .groups
+            .into_iter()
+            .map(|group| {
+                let group = group.map_err(|e| format!("invalid group: {}", e))?;
+
+                Ok(GroupConfig {
+                    require: match (group.require.as_str(), group.count) {
+                        ("any", _) => GroupRequire::Any,
+                        ("all", _) => GroupRequire::All,
+                        ("at_least", Some(count)) => {
+                            GroupRequire::AtLeast(count.try_into().map_err(|_| {
+                                format!("invalid count {} for group {}", count, group.name)
+                            })?)
+                        }
+                        (require, count) => {
+                            return Err(format!(
+                                "invalid require {} {:?} for group {}",
+                                require, count, group.name
+                            ))
+                        }
+                    },
+                    mode: parse_mode(&group.mode, &group.name)?,
+                    rides: group
+                        .rides
+                        .into_iter()
+                        .map(|ride| {
+                            Ok(GroupRide {
+                                alert_on: parse_alert_on(
+                                    &ride.alert_on,
+                                    ride.wait,
+                                    &ride.ride_name,
+                                )?,
+                                ride_name: ride.ride_name,
+                            })
+                        })
+                        .collect::<Result<_, String>>()?,
+                    last_alerted: group.last_alerted,
+                    matched: group.matched,
+                    name: group.name,
+                })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+
+        let crowd = self
+            .crowd
+            .map(|crowd| {
+                let crowd = crowd.map_err(|e| format!("invalid crowd alert: {}", e))?;
+
+                Ok(CrowdAlert {
+                    below: match crowd.below.as_str() {
+                        "moderate" => CrowdLevel::Moderate,
+                        "high" => CrowdLevel::High,
+                        "very_high" => CrowdLevel::VeryHigh,
+                        below => return Err(format!("invalid crowd level {}", below)),
+                    },
+                    mode: parse_mode(&crowd.mode, "crowd alert")?,
+                    last_alerted: crowd.last_alerted,
+                    matched: crowd.matched,
+                })
+            })
+            .transpose()?;
+
+        Ok(Registration {
+            sub,
+            channel,
+            config: (park, rides),
+            groups,
+            crowd,
+            window,
+            expires_at: row.expires_at,
+            snoozed_until: row.snoozed_until,
+            vapid_key: row.vapid_key,
+            token_hash: row.token_hash,
+            signing_secret: row.signing_secret,
+        })
+    }
+}
+
+/// Reads the `alerton` and `wait` columns of a ride named `ride_name`.
+fn parse_alert_on(
+    alert_on: &str,
+    wait: Option<i32>,
+    ride_name: &str,
+) -> Result<RideStatus, String> {
+    match (alert_on, wait) {
+        ("open", _) => Ok(RideStatus::Open),
+        ("closed", _) => Ok(RideStatus::Closed),
+        ("wait", Some(wait)) => {
+            Ok(RideStatus::Wait(wait.try_into().map_err(|_| {
+                format!("invalid wait {} for {}", wait, ride_name)
+            })?))
+        }
+        (alert_on, wait) => Err(format!(
+            "invalid alert {} {:?} for {}",
+            alert_on, wait, ride_name
+        )),
+    }
+}
+
+/// Reads the `mode` column of the ride, group or alert named `name`.
+fn parse_mode(mode: &str, name: &str) -> Result<AlertMode, String> {
+    match mode {
+        "once" => Ok(AlertMode::Once),
+        "repeat" => Ok(AlertMode::Repeat),
+        "on_change" => Ok(AlertMode::OnChange),
+        mode => Err(format!("invalid mode {} for {}", mode, name)),
+    }
+}
+
+/// Adds a row of RIDEGROUPS joined with one of its GROUPRIDES to `groups`, which stores read ordered by group. Rows of
+/// the same group as the last are merged into it.
+pub(crate) fn push_group_row(
+    groups: &mut Vec<Result<GroupRow, sqlx::Error>>,
+    row: Result<(GroupRow, Option<GroupRideRow>), sqlx::Error>,
+) {
+    let (group, ride) = match row {
+        Ok(row) => row,
+        Err(why) => return groups.push(Err(why)),
+    };
+
+    match groups.last_mut() {
+        Some(Ok(last)) if last.name == group.name => last.rides.extend(ride),
+        _ => groups.push(Ok(GroupRow {
+            rides: ride.into_iter().collect(),
+            ..group
+        })),
+    }
+}
+
+impl WindowRow {
+    fn assemble(self) -> Result<ActiveWindow, String> {
+        Ok(ActiveWindow {
+            start: self.start,
+            end: self.end,
+            timezone: self
+                .timezone
+                .parse()
+                .map_err(|_| format!("invalid timezone {}", self.timezone))?,
+            days: self
+                .days
+                .split(',')
+                .filter(|d| !d.is_empty())
+                .map(|d| d.parse())
+                .collect::<Result<_, _>>()
+                .map_err(|_| format!("invalid window days {}", self.days))?,
+            start_date: self.start_date,
+            end_date: self.end_date,
+        })
+    }
+}
+
+/// Assembles the rows of a registration read from a store.
+///
+/// # Errors
+/// Returns why the registration cannot be read, if it can't.
+pub(crate) fn assemble(rows: Result<StoredRows, Problem>) -> Result<Registration, Problem> {
+    rows.and_then(|rows| {
+        let endpoint = rows.registration.endpoint.clone();
+        rows.assemble()
+            .map_err(|reason| Problem { endpoint, reason })
+    })
+}
+
+/// Queries that find rows which do not belong to a registration, and what each finds. Each selects the endpoint of the
+/// orphaned rows, and is the same in every backend.
+///
+/// Registrations missing their own rows are found when loading instead.
+pub(crate) const ORPHAN_CHECKS: &[(&str, &str)] = &[
+    (
+        "SELECT endpoint FROM CONFIGS WHERE endpoint NOT IN (SELECT endpoint FROM REGISTRATIONS)",
+        "config without a registration",
+    ),
+    (
+        "SELECT DISTINCT endpoint FROM RIDEALERTS WHERE endpoint NOT IN (SELECT endpoint FROM CONFIGS)",
+        "ride alerts without a config",
+    ),
+    (
+        "SELECT endpoint FROM CONFIGWINDOWS WHERE endpoint NOT IN (SELECT endpoint FROM CONFIGS)",
+        "window without a config",
+    ),
+    (
+        "SELECT DISTINCT endpoint FROM RIDEWINDOWS WHERE (endpoint, ridename) NOT IN (SELECT endpoint, ridename FROM RIDEALERTS)",
+        "ride window without a ride alert",
+    ),
+    (
+        "SELECT DISTINCT endpoint FROM RIDEGROUPS WHERE endpoint NOT IN (SELECT endpoint FROM CONFIGS)",
+        "group without a config",
+    ),
+    (
+        "SELECT DISTINCT endpoint FROM GROUPRIDES WHERE (endpoint, groupname) NOT IN (SELECT endpoint, name FROM RIDEGROUPS)",
+        "group ride without a group",
+    ),
+    (
+        "SELECT endpoint FROM CROWDALERTS WHERE endpoint NOT IN (SELECT endpoint FROM CONFIGS)",
+        "crowd alert without a config",
+    ),
+];
+
+#[cfg(test)]
+pub(crate) mod test {
+    //! Tests every store must pass.
+
+    use super::*;
+    use crate::models::RideConfig;
+    use chrono::{SubsecRound, Weekday};
+    use url::Url;
+    use web_push::SubscriptionInfo;
+
+    pub fn registration(endpoint: &str) -> Registration {
+        Registration {
+            sub: SubscriptionInfo::new(endpoint, "p256dh", "auth"),
+            channel: Channel::WebPush,
+            config: (
+                "Cedar Point".to_string(),
+                vec![
+                    RideConfig {
+                        ride_name: "Maverick".to_string(),
+                        alert_on: RideStatus::Wait(30),
+                        mode: AlertMode::Once,
+                        window: Some(ActiveWindow {
+                            start: NaiveTime::from_hms_opt(10, 0, 0).unwrap(),
+                            end: NaiveTime::from_hms_opt(1, 0, 0).unwrap(),
+                            timezone: chrono_tz::America::New_York,
+                            days: vec![Weekday::Sat, Weekday::Sun],
+                            start_date: None,
+                            end_date: None,
+                        }),
+                        ..Default::default()
+                    },
+                    RideConfig {
+                        ride_name: "Steel Vengeance".to_string(),
+                        alert_on: RideStatus::Open,
+                        ..Default::default()
+                    },
+                ],
+            ),
+            groups: vec![GroupConfig {
+                name: "Coasters".to_string(),
+                rides: vec![
+                    GroupRide {
+                        ride_name: "Millennium Force".to_string(),
+                        alert_on: RideStatus::Wait(20),
+                    },
+                    GroupRide {
+                        ride_name: "Magnum XL-200".to_string(),
+                        alert_on: RideStatus::Open,
+                    },
+                ],
+                require: GroupRequire::AtLeast(2),
+                mode: AlertMode::OnChange,
+                ..Default::default()
+            }],
+            crowd: Some(CrowdAlert {
+                below: CrowdLevel::Moderate,
+                mode: AlertMode::OnChange,
+                ..Default::default()
+            }),
+            window: None,
+            // Stores may not keep sub-second precision
+            expires_at: Some(Utc::now().trunc_subsecs(0) + Duration::days(1)),
+            snoozed_until: None,
+            vapid_key: Some("key".to_string()),
+            token_hash: Some("hash".to_string()),
+            signing_secret: None,
+        }
+    }
+
+    #[test]
+    fn test_child_rows() {
+        let row = |endpoint: &str, n| (endpoint.to_string(), n);
+        let mut rows = ChildRows::new();
+        assert!(rows.needs_more("b"));
+
+        // Rows of "a" have no registration, and more rows of "b" may follow
+        rows.extend(vec![row("a", 1), row("b", 2)]);
+        assert!(rows.needs_more("b"));
+        rows.extend(vec![row("b", 3), row("d", 4)]);
+        assert!(!rows.needs_more("b"));
+        assert_eq!(rows.take("b"), vec![2, 3]);
+
+        // Registrations without rows get none
+        assert!(!rows.needs_more("c"));
+        assert!(rows.take("c").is_empty());
+
+        rows.extend(Vec::new());
+        assert!(!rows.needs_more("d"));
+        assert_eq!(rows.take("d"), vec![4]);
+        assert!(!rows.needs_more("e"));
+    }
+
+    /// Gets [`registration`] as a webhook to `url`, as registering one does.
+    pub fn webhook_registration(url: &Url) -> Registration {
+        let mut reg = registration(url.as_str());
+        reg.channel = Channel::Webhook { url: url.clone() };
+        reg.vapid_key = None;
+        reg
+    }
+
+    /// Loads every registration in `store`.
+    pub async fn load_all(store: &dyn RegistrationStore) -> (Vec<Registration>, Loaded) {
+        let mut registrations = Vec::new();
+        let loaded = store
+            .load_all(&mut |reg| registrations.push(reg))
+            .await
+            .unwrap();
+
+        (registrations, loaded)
+    }
+
+    /// Checks that registrations and their server side state round trip through `store`, which must be empty.
+    pub async fn conformance(store: &dyn RegistrationStore) {
+        let reg = registration("https://push.example.com/a");
+        store.insert(&reg).await.unwrap();
+        store
+            .insert(&registration("https://push.example.com/b"))
+            .await
+            .unwrap();
+
+        assert_eq!(load_all(store).await.0.len(), 2);
+        let loaded = store.load(&reg.sub.endpoint).await.unwrap().unwrap();
+        assert_eq!(loaded.config, reg.config);
+        assert_eq!(loaded.groups, reg.groups);
+        assert_eq!(loaded.crowd, reg.crowd);
+        assert_eq!(loaded.expires_at, reg.expires_at);
+        assert_eq!(loaded.token_hash, reg.token_hash);
+        assert_eq!(loaded.vapid_key, reg.vapid_key);
+        assert!(store
+            .load("https://push.example.com/c")
+            .await
+            .unwrap()
+            .is_none());
+
+        // Updates replace the config
+        let mut updated = reg.clone();
+        updated.config.0 = "Kings Island".to_string();
+        updated.config.1.truncate(1);
+        updated.config.1[0].alert_on = RideStatus::Closed;
+        updated.groups[0].rides.reverse();
+        updated.groups[0].require = GroupRequire::All;
+        updated.crowd = None;
+        updated.vapid_key = Some("new key".to_string());
+        store.update(&updated).await.unwrap();
+        let loaded = store.load(&reg.sub.endpoint).await.unwrap().unwrap();
+        assert_eq!(loaded.config, updated.config);
+        assert_eq!(loaded.groups, updated.groups);
+        assert_eq!(loaded.crowd, None);
+        assert_eq!(loaded.vapid_key, updated.vapid_key);
+        updated.crowd = reg.crowd.clone();
+        store.update(&updated).await.unwrap();
+
+        // Server side state
+        let at = Utc::now().trunc_subsecs(0);
+        store
+            .snooze(&reg.sub.endpoint, None, Some(at))
+            .await
+            .unwrap();
+        store
+            .snooze(&reg.sub.endpoint, Some("Maverick"), Some(at))
+            .await
+            .unwrap();
+        store
+            .mark_alerted(
+                &[(reg.sub.endpoint.clone(), vec!["Maverick".to_string()])],
+                at,
+            )
+            .await
+            .unwrap();
+        store
+            .set_token_hash(&reg.sub.endpoint, "new hash")
+            .await
+            .unwrap();
+        store
+            .save_matched(&[(reg.sub.endpoint.clone(), "Maverick".to_string(), true)])
+            .await
+            .unwrap();
+        store
+            .mark_groups_alerted(
+                &[(reg.sub.endpoint.clone(), vec!["Coasters".to_string()])],
+                at,
+            )
+            .await
+            .unwrap();
+        store
+            .save_groups_matched(&[(reg.sub.endpoint.clone(), "Coasters".to_string(), true)])
+            .await
+            .unwrap();
+        store
+            .mark_crowd_alerted(std::slice::from_ref(&reg.sub.endpoint), at)
+            .await
+            .unwrap();
+        store
+            .save_crowd_matched(&[(reg.sub.endpoint.clone(), true)])
+            .await
+            .unwrap();
+        let loaded = store.load(&reg.sub.endpoint).await.unwrap().unwrap();
+        assert_eq!(loaded.snoozed_until, Some(at));
+        assert_eq!(loaded.config.1[0].snoozed_until, Some(at));
+        assert_eq!(loaded.config.1[0].last_alerted, Some(at));
+        assert!(loaded.config.1[0].matched);
+        assert_eq!(loaded.groups[0].last_alerted, Some(at));
+        assert!(loaded.groups[0].matched);
+        let crowd = loaded.crowd.unwrap();
+        assert_eq!(crowd.last_alerted, Some(at));
+        assert!(crowd.matched);
+        assert_eq!(loaded.token_hash.as_deref(), Some("new hash"));
+
+        let mut keyless = registration("https://push.example.com/c");
+        keyless.vapid_key = None;
+        store.insert(&keyless).await.unwrap();
+        store.set_missing_vapid_keys("current").await.unwrap();
+        let loaded = store.load(&keyless.sub.endpoint).await.unwrap().unwrap();
+        assert_eq!(loaded.vapid_key.as_deref(), Some("current"));
+
+        // Other channels keep their address, and have no key
+        let mut emailed = registration("mailto:rider@example.com");
+        emailed.channel = Channel::Email {
+            to: "rider@example.com".parse().unwrap(),
+        };
+        emailed.vapid_key = None;
+        store.insert(&emailed).await.unwrap();
+        store.set_missing_vapid_keys("current").await.unwrap();
+        let loaded = store.load(&emailed.sub.endpoint).await.unwrap().unwrap();
+        assert_eq!(loaded.channel, emailed.channel);
+        assert!(loaded.vapid_key.is_none());
+
+        let mut hooked = webhook_registration(&"https://hooks.example.com/a".parse().unwrap());
+        hooked.signing_secret = Some("secret".to_string());
+        store.insert(&hooked).await.unwrap();
+        let loaded = store.load(&hooked.sub.endpoint).await.unwrap().unwrap();
+        assert_eq!(loaded.signing_secret, hooked.signing_secret);
+
+        // Deliveries are newest first, and outlive their registration
+        let delivery = |at: DateTime<Utc>, error: Option<&str>| Delivery {
+            endpoint: hooked.sub.endpoint.clone(),
+            delivered_at: at,
+            payload: "alert".to_string(),
+            error: error.map(str::to_string),
+        };
+        let old = delivery(at - Duration::days(10), None);
+        let failed = delivery(at, Some("rejected with status 500"));
+        store.log_delivery(&old).await.unwrap();
+        store.log_delivery(&failed).await.unwrap();
+        assert!(store.remove(&hooked.sub.endpoint).await.unwrap());
+        assert_eq!(
+            store.deliveries(&hooked.sub.endpoint, 10).await.unwrap(),
+            vec![failed.clone(), old]
+        );
+        assert_eq!(
+            store
+                .prune_deliveries(at - Duration::days(7))
+                .await
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            store.deliveries(&hooked.sub.endpoint, 10).await.unwrap(),
+            vec![failed]
+        );
+
+        // Removing cascades to the config
+        assert!(store.remove(&reg.sub.endpoint).await.unwrap());
+        assert!(!store.remove(&reg.sub.endpoint).await.unwrap());
+        assert!(store.load(&reg.sub.endpoint).await.unwrap().is_none());
+        store.insert(&reg).await.unwrap();
+        let loaded = store.load(&reg.sub.endpoint).await.unwrap().unwrap();
+        assert_eq!(loaded.config, reg.config);
+        assert_eq!(loaded.groups, reg.groups);
+    }
+
+    /// Endpoints of the registrations [`CORRUPT`] breaks, and one it leaves alone.
+    pub const CORRUPTED: &[&str] = &[
+        "https://push.example.com/a",
+        "https://push.example.com/b",
+        "https://push.example.com/c",
+    ];
+    pub const INTACT: &str = "https://push.example.com/d";
+
+    /// Statements that corrupt the registrations in [`CORRUPTED`], in ways any backend can store with foreign keys on.
+    pub const CORRUPT: &[&str] = &[
+        "UPDATE REGISTRATIONS SET subscription_info = 'not json' WHERE endpoint = 'https://push.example.com/a'",
+        "DELETE FROM CONFIGS WHERE endpoint = 'https://push.example.com/b'",
+        "UPDATE RIDEWINDOWS SET timezone = 'Mars/Olympus_Mons' WHERE endpoint = 'https://push.example.com/c'",
+    ];
+
+    /// Adds a config with no registration. Backends must disable foreign keys to run this.
+    pub const ORPHAN: &str =
+        "INSERT INTO CONFIGS VALUES ('https://push.example.com/gone', 'Cedar Point')";
+
+    /// Adds the registrations that [`CORRUPT`] acts on.
+    pub async fn insert_corruptible(store: &dyn RegistrationStore) {
+        for endpoint in CORRUPTED.iter().chain([&INTACT]) {
+            store.insert(&registration(endpoint)).await.unwrap();
+        }
+    }
+
+    /// Checks that `store` finds and quarantines what [`CORRUPT`] and [`ORPHAN`] did.
+    pub async fn quarantine(store: &dyn RegistrationStore) {
+        let report = store.check().await.unwrap();
+        let mut corrupt: Vec<_> = report.corrupt.iter().map(|p| p.endpoint.as_str()).collect();
+        corrupt.sort();
+        assert_eq!(corrupt, CORRUPTED);
+        assert_eq!(report.orphans.len(), 1);
+        assert_eq!(report.orphans[0].endpoint, "https://push.example.com/gone");
+        assert!(report.quarantined.is_empty());
+        assert!(!report.is_clean());
+
+        // Loading skips and quarantines the corrupt registrations
+        let (registrations, loaded) = load_all(store).await;
+        assert_eq!(loaded.quarantined, CORRUPTED.len());
+        assert_eq!(loaded.loaded, 1);
+        assert_eq!(registrations[0].sub.endpoint, INTACT);
+
+        let report = store.check().await.unwrap();
+        assert!(report.corrupt.is_empty());
+        assert_eq!(report.orphans.len(), 1);
+        assert_eq!(report.quarantined.len(), CORRUPTED.len());
+        assert!(store.load(CORRUPTED[0]).await.unwrap().is_none());
+    }
+}
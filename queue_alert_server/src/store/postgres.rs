@@ -0,0 +1,964 @@
+//! Postgres registration storage, which several servers can share.
+
+use super::{
+    alert_on_columns, assemble, below_column, channel_column, mode_column, push_group_row,
+    require_columns, window_days, CheckReport, ChildRows, CrowdRow, Delivery, GroupRideRow,
+    GroupRow, Loaded, Problem, RegistrationRow, RegistrationStore, RideRow, RowSink, StoredRows,
+    WindowRow, ORPHAN_CHECKS,
+};
+use crate::error::Error;
+use crate::migrations;
+use crate::models::Registration;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use sqlx::postgres::PgRow;
+use sqlx::{
+    query, query_scalar, Connection, Executor, PgConnection, PgPool, Postgres, Row, Transaction,
+};
+use tokio::sync::Mutex;
+
+/// Rows fetched from a cursor at a time when loading registrations.
+const FETCH_SIZE: usize = 1000;
+
+/// Advisory lock held by the server that sends alerts.
+const LEADER_LOCK: i64 = 0x5141_0002;
+
+/// Stores registrations in a Postgres db.
+pub struct PostgresStore {
+    db: PgPool,
+    /// Connection holding the leader lock, if this server has it. Postgres releases the lock if this disconnects.
+    leader: Mutex<Option<PgConnection>>,
+}
+
+impl PostgresStore {
+    /// Connects to the db at `url`, migrating it to the latest schema.
+    pub async fn open(url: &str) -> Result<Self, Error> {
+        let db = PgPool::connect(url).await?;
+        migrations::postgres::run(&db).await?;
+
+        Ok(Self {
+            db,
+            leader: Mutex::new(None),
+        })
+    }
+
+    /// Migrates the db at `url` to the latest schema without loading it. Returns the version it was at before.
+    pub async fn migrate(url: &str) -> Result<i64, Error> {
+        let db = PgPool::connect(url).await?;
+        let from = migrations::postgres::run(&db).await?;
+        db.close().await;
+
+        Ok(from)
+    }
+
+    /// Reads the rows of every registration, or only the one at `endpoint` if set, passing each to `on_rows`.
+    ///
+    /// This takes three queries however many registrations there are, read from a single snapshot so registrations
+    /// being changed are read consistently. Each query is a cursor sorted by endpoint, fetched from in batches side by
+    /// side, so each registration is passed on as soon as its rows are read.
+    async fn read_rows(
+        &self,
+        endpoint: Option<&str>,
+        on_rows: &mut RowSink<'_>,
+    ) -> Result<(), Error> {
+        let mut trans = self.db.begin().await?;
+        trans
+            .execute("SET TRANSACTION ISOLATION LEVEL REPEATABLE READ READ ONLY")
+            .await?;
+
+        // Endpoints are sorted byte by byte, as ChildRows compares them
+        query(
+            "DECLARE rides NO SCROLL CURSOR FOR
+            SELECT a.endpoint, a.ridename, a.alerton, a.wait, a.snoozed_until, a.mode, a.last_alerted, a.matched,
+                w.ridename AS window_ridename, w.start_time, w.end_time, w.timezone, w.days, w.start_date, w.end_date
+            FROM RIDEALERTS a LEFT JOIN RIDEWINDOWS w ON w.endpoint = a.endpoint AND w.ridename = a.ridename
+            WHERE $1::TEXT IS NULL OR a.endpoint = $1
+            ORDER BY a.endpoint COLLATE \"C\", a.position",
+        )
+        .bind(endpoint)
+        .execute(&mut *trans)
+        .await?;
+        query(
+            "DECLARE groups NO SCROLL CURSOR FOR
+            SELECT g.endpoint, g.name, g.require, g.count, g.mode, g.last_alerted, g.matched,
+                r.ridename, r.alerton, r.wait
+            FROM RIDEGROUPS g LEFT JOIN GROUPRIDES r ON r.endpoint = g.endpoint AND r.groupname = g.name
+            WHERE $1::TEXT IS NULL OR g.endpoint = $1
+            ORDER BY g.endpoint COLLATE \"C\", g.position, r.position",
+        )
+        .bind(endpoint)
+        .execute(&mut *trans)
+        .await?;
+        query(
+            "DECLARE registrations NO SCROLL CURSOR FOR
+            SELECT r.endpoint, r.subscription_info, r.expires_at, r.snoozed_until, r.token_hash,
+                r.vapid_key, r.channel, r.signing_secret, c.park,
+                w.endpoint AS window_endpoint, w.start_time, w.end_time, w.timezone, w.days, w.start_date, w.end_date,
+                ca.endpoint AS crowd_endpoint, ca.below AS crowd_below, ca.mode AS crowd_mode,
+                ca.last_alerted AS crowd_last_alerted, ca.matched AS crowd_matched
+            FROM REGISTRATIONS r
+                LEFT JOIN CONFIGS c ON c.endpoint = r.endpoint
+                LEFT JOIN CONFIGWINDOWS w ON w.endpoint = r.endpoint
+                LEFT JOIN CROWDALERTS ca ON ca.endpoint = r.endpoint
+            WHERE $1::TEXT IS NULL OR r.endpoint = $1
+            ORDER BY r.endpoint COLLATE \"C\"",
+        )
+        .bind(endpoint)
+        .execute(&mut *trans)
+        .await?;
+
+        let mut rides = ChildRows::new();
+        let mut groups = ChildRows::new();
+        loop {
+            let batch = Self::fetch(&mut trans, "registrations").await?;
+            if batch.is_empty() {
+                break;
+            }
+
+            for r in batch {
+                let endpoint: String = r.try_get("endpoint")?;
+
+                Self::read_children(&mut trans, &mut rides, "rides", &endpoint, Self::ride_row)
+                    .await?;
+                Self::read_children(
+                    &mut trans,
+                    &mut groups,
+                    "groups",
+                    &endpoint,
+                    Self::group_row,
+                )
+                .await?;
+
+                let rides = rides.take(&endpoint);
+                let mut registration_groups = Vec::new();
+                for row in groups.take(&endpoint) {
+                    push_group_row(&mut registration_groups, row);
+                }
+
+                on_rows(match Self::registration_row(&r) {
+                    Ok(registration) => Ok(StoredRows {
+                        registration,
+                        park: r.try_get("park")?,
+                        window: Self::joined_window(&r, "window_endpoint"),
+                        rides,
+                        groups: registration_groups,
+                        crowd: Self::joined_crowd(&r),
+                    }),
+                    Err(why) => Err(Problem {
+                        endpoint,
+                        reason: format!("invalid registration: {}", why),
+                    }),
+                });
+            }
+        }
+
+        trans.commit().await?;
+
+        Ok(())
+    }
+
+    /// Fetches the next batch of rows from a cursor declared by [`Self::read_rows`]. Empty once every row is fetched.
+    async fn fetch(conn: &mut PgConnection, cursor: &str) -> Result<Vec<PgRow>, Error> {
+        Ok(query(&format!("FETCH {} FROM {}", FETCH_SIZE, cursor))
+            .fetch_all(conn)
+            .await?)
+    }
+
+    /// Fetches from `cursor` into `children` until it holds every row of `endpoint`, reading each row with `read`.
+    async fn read_children<T>(
+        conn: &mut PgConnection,
+        children: &mut ChildRows<T>,
+        cursor: &str,
+        endpoint: &str,
+        read: fn(&PgRow) -> T,
+    ) -> Result<(), Error> {
+        while children.needs_more(endpoint) {
+            let batch = Self::fetch(&mut *conn, cursor)
+                .await?
+                .iter()
+                .map(|r| Ok((r.try_get("endpoint")?, read(r))))
+                .collect::<Result<_, sqlx::Error>>()?;
+            children.extend(batch);
+        }
+
+        Ok(())
+    }
+
+    fn registration_row(r: &PgRow) -> Result<RegistrationRow, sqlx::Error> {
+        Ok(RegistrationRow {
+            endpoint: r.try_get("endpoint")?,
+            subscription_info: r.try_get("subscription_info")?,
+            expires_at: r.try_get("expires_at")?,
+            snoozed_until: r.try_get("snoozed_until")?,
+            token_hash: r.try_get("token_hash")?,
+            vapid_key: r.try_get("vapid_key")?,
+            channel: r.try_get("channel")?,
+            signing_secret: r.try_get("signing_secret")?,
+        })
+    }
+
+    fn ride_row(r: &PgRow) -> Result<RideRow, sqlx::Error> {
+        Ok(RideRow {
+            ride_name: r.try_get("ridename")?,
+            alert_on: r.try_get("alerton")?,
+            wait: r.try_get("wait")?,
+            snoozed_until: r.try_get("snoozed_until")?,
+            mode: r.try_get("mode")?,
+            last_alerted: r.try_get("last_alerted")?,
+            matched: r.try_get("matched")?,
+            window: Self::joined_window(r, "window_ridename").transpose()?,
+        })
+    }
+
+    /// Reads a group, and the group ride joined onto it if the join found one.
+    fn group_row(r: &PgRow) -> Result<(GroupRow, Option<GroupRideRow>), sqlx::Error> {
+        let ride = match r.try_get::<Option<String>, _>("ridename")? {
+            Some(ride_name) => Some(GroupRideRow {
+                ride_name,
+                alert_on: r.try_get("alerton")?,
+                wait: r.try_get("wait")?,
+            }),
+            None => None,
+        };
+
+        Ok((
+            GroupRow {
+                name: r.try_get("name")?,
+                require: r.try_get("require")?,
+                count: r.try_get("count")?,
+                mode: r.try_get("mode")?,
+                last_alerted: r.try_get("last_alerted")?,
+                matched: r.try_get("matched")?,
+                rides: Vec::new(),
+            },
+            ride,
+        ))
+    }
+
+    /// Reads the crowd alert joined onto a registration, if it has one.
+    fn joined_crowd(r: &PgRow) -> Option<Result<CrowdRow, sqlx::Error>> {
+        match r.try_get::<Option<String>, _>("crowd_endpoint") {
+            Ok(Some(_)) => Some(Self::crowd_row(r)),
+            Ok(None) => None,
+            Err(why) => Some(Err(why)),
+        }
+    }
+
+    fn crowd_row(r: &PgRow) -> Result<CrowdRow, sqlx::Error> {
+        Ok(CrowdRow {
+            below: r.try_get("crowd_below")?,
+            mode: r.try_get("crowd_mode")?,
+            last_alerted: r.try_get("crowd_last_alerted")?,
+            matched: r.try_get("crowd_matched")?,
+        })
+    }
+
+    /// Reads a window joined onto a row, if `marker` shows the join found one.
+    fn joined_window(r: &PgRow, marker: &str) -> Option<Result<WindowRow, sqlx::Error>> {
+        match r.try_get::<Option<String>, _>(marker) {
+            Ok(Some(_)) => Some(Self::window_row(r)),
+            Ok(None) => None,
+            Err(why) => Some(Err(why)),
+        }
+    }
+
+    /// Reads the columns of either CONFIGWINDOWS or RIDEWINDOWS.
+    fn window_row(r: &PgRow) -> Result<WindowRow, sqlx::Error> {
+        Ok(WindowRow {
+            start: r.try_get("start_time")?,
+            end: r.try_get("end_time")?,
+            timezone: r.try_get("timezone")?,
+            days: r.try_get("days")?,
+            start_date: r.try_get("start_date")?,
+            end_date: r.try_get("end_date")?,
+        })
+    }
+
+    /// Loads every registration, or only the one at `endpoint` if set, into `sink`. Registrations that cannot be read
+    /// are quarantined once all are read.
+    async fn load_where(
+        &self,
+        endpoint: Option<&str>,
+        sink: &mut (dyn FnMut(Registration) + Send),
+    ) -> Result<Loaded, Error> {
+        let mut loaded = 0;
+        let mut corrupt = Vec::new();
+
+        self.read_rows(endpoint, &mut |rows| match assemble(rows) {
+            Ok(reg) => {
+                loaded += 1;
+                sink(reg);
+            }
+            Err(problem) => corrupt.push(problem),
+        })
+        .await?;
+
+        for problem in &corrupt {
+            self.quarantine(problem).await?;
+        }
+
+        Ok(Loaded {
+            loaded,
+            quarantined: corrupt.len(),
+        })
+    }
+
+    /// Moves a registration that cannot be read out of the way, keeping its rows as JSON.
+    async fn quarantine(&self, problem: &Problem) -> Result<(), Error> {
+        log::warn!(
+            "Quarantining registration {}: {}",
+            problem.endpoint,
+            problem.reason
+        );
+
+        let mut trans = self.db.begin().await?;
+
+        trans
+            .execute(
+                query(
+                    "INSERT INTO QUARANTINE (endpoint, reason, data)
+                    SELECT r.endpoint, $2, json_build_object(
+                        'registration', row_to_json(r),
+                        'park', (SELECT park FROM CONFIGS c WHERE c.endpoint = r.endpoint),
+                        'rides', (SELECT json_agg(a) FROM RIDEALERTS a WHERE a.endpoint = r.endpoint),
+                        'groups', (SELECT json_agg(g) FROM RIDEGROUPS g WHERE g.endpoint = r.endpoint),
+                        'group_rides', (SELECT json_agg(gr) FROM GROUPRIDES gr WHERE gr.endpoint = r.endpoint),
+                        'crowd', (SELECT row_to_json(ca) FROM CROWDALERTS ca WHERE ca.endpoint = r.endpoint)
+                    )::TEXT
+                    FROM REGISTRATIONS r WHERE r.endpoint = $1",
+                )
+                .bind(&problem.endpoint)
+                .bind(&problem.reason),
+            )
+            .await?;
+        trans
+            .execute(query("DELETE FROM REGISTRATIONS WHERE endpoint = $1").bind(&problem.endpoint))
+            .await?;
+
+        trans.commit().await?;
+
+        Ok(())
+    }
+
+    /// Adds all the rides in a users config into the RIDEALERTS table in a transaction, along with any windows, groups
+    /// and crowd alert.
+    async fn add_config_to_transaction(
+        reg: &Registration,
+        trans: &mut Transaction<'_, Postgres>,
+    ) -> Result<(), Error> {
+        if let Some(window) = &reg.window {
+            trans
+                .execute(
+                    query("INSERT INTO CONFIGWINDOWS VALUES ($1, $2, $3, $4, $5, $6, $7)")
+                        .bind(&reg.sub.endpoint)
+                        .bind(window.start)
+                        .bind(window.end)
+                        .bind(window.timezone.name())
+                        .bind(window_days(window))
+                        .bind(window.start_date)
+                        .bind(window.end_date),
+                )
+                .await?;
+        }
+
+        for (position, ride) in reg.config.1.iter().enumerate() {
+            let (alert_on, wait) = alert_on_columns(ride.alert_on);
+
+            trans
+                .execute(
+                    query("INSERT INTO RIDEALERTS (endpoint, ridename, position, alerton, wait, snoozed_until, mode, last_alerted, matched) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)")
+                        .bind(&reg.sub.endpoint)
+                        .bind(&ride.ride_name)
+                        .bind(position as i32)
+                        .bind(alert_on)
+                        .bind(wait)
+                        .bind(ride.snoozed_until)
+                        .bind(mode_column(ride.mode))
+                        .bind(ride.last_alerted)
+                        .bind(ride.matched),
+                )
+                .await?;
+
+            if let Some(window) = &ride.window {
+                trans
+                    .execute(
+                        query("INSERT INTO RIDEWINDOWS VALUES ($1, $2, $3, $4, $5, $6, $7, $8)")
+                            .bind(&reg.sub.endpoint)
+                            .bind(&ride.ride_name)
+                            .bind(window.start)
+                            .bind(window.end)
+                            .bind(window.timezone.name())
+                            .bind(window_days(window))
+                            .bind(window.start_date)
+                            .bind(window.end_date),
+                    )
+                    .await?;
+            }
+        }
+
+        for (position, group) in reg.groups.iter().enumerate() {
+            let (require, count) = require_columns(group.require);
+
+            trans
+                .execute(
+                    query("INSERT INTO RIDEGROUPS (endpoint, name, position, require, count, mode, last_alerted, matched) VALUES ($1, $2, $3, $4, $5, $6, $7, $8)")
+                        .bind(&reg.sub.endpoint)
+                        .bind(&group.name)
+                        .bind(position as i32)
+                        .bind(require)
+                        .bind(count)
+                        .bind(mode_column(group.mode))
+                        .bind(group.last_alerted)
+                        .bind(group.matched),
+                )
+                .await?;
+
+            for (position, ride) in group.rides.iter().enumerate() {
+                let (alert_on, wait) = alert_on_columns(ride.alert_on);
+
+                trans
+                    .execute(
+                        query("INSERT INTO GROUPRIDES VALUES ($1, $2, $3, $4, $5, $6)")
+                            .bind(&reg.sub.endpoint)
+                            .bind(&group.name)
+                            .bind(&ride.ride_name)
+                            .bind(position as i32)
+                            .bind(alert_on)
+                            .bind(wait),
+                    )
+                    .await?;
+            }
+        }
+
+        if let Some(crowd) = &reg.crowd {
+            trans
+                .execute(
+                    query("INSERT INTO CROWDALERTS (endpoint, below, mode, last_alerted, matched) VALUES ($1, $2, $3, $4, $5)")
+                        .bind(&reg.sub.endpoint)
+                        .bind(below_column(crowd.below))
+                        .bind(mode_column(crowd.mode))
+                        .bind(crowd.last_alerted)
+                        .bind(crowd.matched),
+                )
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl RegistrationStore for PostgresStore {
+    async fn load_all(&self, sink: &mut (dyn FnMut(Registration) + Send)) -> Result<Loaded, Error> {
+        self.load_where(None, sink).await
+    }
+
+    async fn load(&self, endpoint: &str) -> Result<Option<Registration>, Error> {
+        let mut found = None;
+        self.load_where(Some(endpoint), &mut |reg| found = Some(reg))
+            .await?;
+
+        Ok(found)
+    }
+
+    async fn check(&self) -> Result<CheckReport, Error> {
+        let mut corrupt = Vec::new();
+        self.read_rows(None, &mut |rows| {
+            if let Err(problem) = assemble(rows) {
+                corrupt.push(problem);
+            }
+        })
+        .await?;
+
+        let mut orphans = Vec::new();
+        for (sql, reason) in ORPHAN_CHECKS {
+            for endpoint in query_scalar::<_, String>(sql).fetch_all(&self.db).await? {
+                orphans.push(Problem {
+                    endpoint,
+                    reason: reason.to_string(),
+                });
+            }
+        }
+
+        let quarantined = query("SELECT endpoint, reason FROM QUARANTINE ORDER BY quarantined_at")
+            .map(|r: PgRow| Problem {
+                endpoint: r.get("endpoint"),
+                reason: r.get("reason"),
+            })
+            .fetch_all(&self.db)
+            .await?;
+
+        Ok(CheckReport {
+            corrupt,
+            orphans,
+            quarantined,
+        })
+    }
+
+    async fn ping(&self) -> Result<(), Error> {
+        self.db.execute("SELECT 1").await?;
+        Ok(())
+    }
+
+    async fn insert(&self, reg: &Registration) -> Result<(), Error> {
+        let mut trans = self.db.begin().await?;
+
+        // Add subscription info
+        trans
+            .execute(
+                query("INSERT INTO REGISTRATIONS (endpoint, subscription_info, expires_at, token_hash, vapid_key, channel, signing_secret) VALUES ($1, $2, $3, $4, $5, $6, $7)")
+                    .bind(&reg.sub.endpoint)
+                    .bind(serde_json::to_string(&reg.sub)?)
+                    .bind(reg.expires_at)
+                    .bind(&reg.token_hash)
+                    .bind(&reg.vapid_key)
+                    .bind(channel_column(&reg.channel)?)
+                    .bind(&reg.signing_secret),
+            )
+            .await?;
+
+        // Add park info
+        trans
+            .execute(
+                query("INSERT INTO CONFIGS VALUES ($1, $2)")
+                    .bind(&reg.sub.endpoint)
+                    .bind(&reg.config.0),
+            )
+            .await?;
+
+        // Add config
+        Self::add_config_to_transaction(reg, &mut trans).await?;
+
+        trans.commit().await?;
+
+        Ok(())
+    }
+
+    async fn update(&self, reg: &Registration) -> Result<(), Error> {
+        let mut trans = self.db.begin().await?;
+
+        trans
+            .execute(
+                query(
+                    "UPDATE REGISTRATIONS SET expires_at = $1, vapid_key = $2, channel = $3 WHERE endpoint = $4",
+                )
+                .bind(reg.expires_at)
+                .bind(&reg.vapid_key)
+                .bind(channel_column(&reg.channel)?)
+                .bind(&reg.sub.endpoint),
+            )
+            .await?;
+
+        // Add park info
+        trans
+            .execute(
+                query("UPDATE CONFIGS SET park = $1 WHERE endpoint = $2")
+                    .bind(&reg.config.0)
+                    .bind(&reg.sub.endpoint),
+            )
+            .await?;
+
+        // Just remove the old config, then insert the new one
+        trans
+            .execute(query("DELETE FROM RIDEALERTS WHERE endpoint = $1").bind(&reg.sub.endpoint))
+            .await?;
+        trans
+            .execute(query("DELETE FROM CONFIGWINDOWS WHERE endpoint = $1").bind(&reg.sub.endpoint))
+            .await?;
+        trans
+            .execute(query("DELETE FROM RIDEGROUPS WHERE endpoint = $1").bind(&reg.sub.endpoint))
+            .await?;
+        trans
+            .execute(query("DELETE FROM CROWDALERTS WHERE endpoint = $1").bind(&reg.sub.endpoint))
+            .await?;
+
+        // Add config
+        Self::add_config_to_transaction(reg, &mut trans).await?;
+
+        trans.commit().await?;
+
+        Ok(())
+    }
+
+    async fn remove(&self, endpoint: &str) -> Result<bool, Error> {
+        let res = query("DELETE FROM REGISTRATIONS WHERE endpoint = $1")
+            .bind(endpoint)
+            .execute(&self.db)
+            .await?;
+
+        Ok(res.rows_affected() > 0)
+    }
+
+    async fn set_token_hash(&self, endpoint: &str, hash: &str) -> Result<(), Error> {
+        query("UPDATE REGISTRATIONS SET token_hash = $1 WHERE endpoint = $2")
+            .bind(hash)
+            .bind(endpoint)
+            .execute(&self.db)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn set_missing_vapid_keys(&self, key: &str) -> Result<(), Error> {
+        query(
+            "UPDATE REGISTRATIONS SET vapid_key = $1 WHERE vapid_key IS NULL AND channel IS NULL",
+        )
+        .bind(key)
+        .execute(&self.db)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn snooze(
+        &self,
+        endpoint: &str,
+        ride_name: Option<&str>,
+        until: Option<DateTime<Utc>>,
+    ) -> Result<(), Error> {
+        match ride_name {
+            None => {
+                query("UPDATE REGISTRATIONS SET snoozed_until = $1 WHERE endpoint = $2")
+                    .bind(until)
+                    .bind(endpoint)
+                    .execute(&self.db)
+                    .await?;
+            }
+            Some(ride_name) => {
+                query(
+                    "UPDATE RIDEALERTS SET snoozed_until = $1 WHERE endpoint = $2 AND ridename = $3",
+                )
+                .bind(until)
+                .bind(endpoint)
+                .bind(ride_name)
+                .execute(&self.db)
+                .await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn mark_alerted(
+        &self,
+        alerted: &[(String, Vec<String>)],
+        at: DateTime<Utc>,
+    ) -> Result<(), Error> {
+        let mut trans = self.db.begin().await?;
+
+        for (endpoint, rides) in alerted {
+            trans
+                .execute(
+                    query("UPDATE RIDEALERTS SET last_alerted = $1 WHERE endpoint = $2 AND ridename = ANY($3)")
+                        .bind(at)
+                        .bind(endpoint)
+                        .bind(rides),
+                )
+                .await?;
+        }
+
+        trans.commit().await?;
+
+        Ok(())
+    }
+
+    async fn save_matched(&self, matched: &[(String, String, bool)]) -> Result<(), Error> {
+        let mut trans = self.db.begin().await?;
+
+        for (endpoint, ride, matched) in matched {
+            trans
+                .execute(
+                    query(
+                        "UPDATE RIDEALERTS SET matched = $1 WHERE endpoint = $2 AND ridename = $3",
+                    )
+                    .bind(matched)
+                    .bind(endpoint)
+                    .bind(ride),
+                )
+                .await?;
+        }
+
+        trans.commit().await?;
+
+        Ok(())
+    }
+
+    async fn mark_groups_alerted(
+        &self,
+        alerted: &[(String, Vec<String>)],
+        at: DateTime<Utc>,
+    ) -> Result<(), Error> {
+        let mut trans = self.db.begin().await?;
+
+        for (endpoint, groups) in alerted {
+            trans
+                .execute(
+                    query("UPDATE RIDEGROUPS SET last_alerted = $1 WHERE endpoint = $2 AND name = ANY($3)")
+                        .bind(at)
+                        .bind(endpoint)
+                        .bind(groups),
+                )
+                .await?;
+        }
+
+        trans.commit().await?;
+
+        Ok(())
+    }
+
+    async fn save_groups_matched(&self, matched: &[(String, String, bool)]) -> Result<(), Error> {
+        let mut trans = self.db.begin().await?;
+
+        for (endpoint, group, matched) in matched {
+            trans
+                .execute(
+                    query("UPDATE RIDEGROUPS SET matched = $1 WHERE endpoint = $2 AND name = $3")
+                        .bind(matched)
+                        .bind(endpoint)
+                        .bind(group),
+                )
+                .await?;
+        }
+
+        trans.commit().await?;
+
+        Ok(())
+    }
+
+    async fn mark_crowd_alerted(&self, alerted: &[String], at: DateTime<Utc>) -> Result<(), Error> {
+        query("UPDATE CROWDALERTS SET last_alerted = $1 WHERE endpoint = ANY($2)")
+            .bind(at)
+            .bind(alerted)
+            .execute(&self.db)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn save_crowd_matched(&self, matched: &[(String, bool)]) -> Result<(), Error> {
+        let mut trans = self.db.begin().await?;
+
+        for (endpoint, matched) in matched {
+            trans
+                .execute(
+                    query("UPDATE CROWDALERTS SET matched = $1 WHERE endpoint = $2")
+                        .bind(matched)
+                        .bind(endpoint),
+                )
+                .await?;
+        }
+
+        trans.commit().await?;
+
+        Ok(())
+    }
+
+    async fn log_delivery(&self, delivery: &Delivery) -> Result<(), Error> {
+        query(
+            "INSERT INTO WEBHOOKDELIVERIES (endpoint, delivered_at, payload, error) VALUES ($1, $2, $3, $4)",
+        )
+        .bind(&delivery.endpoint)
+        .bind(delivery.delivered_at)
+        .bind(&delivery.payload)
+        .bind(&delivery.error)
+        .execute(&self.db)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn deliveries(&self, endpoint: &str, limit: i64) -> Result<Vec<Delivery>, Error> {
+        let rows = query(
+            "SELECT endpoint, delivered_at, payload, error FROM WEBHOOKDELIVERIES WHERE endpoint = $1
+            ORDER BY id DESC LIMIT $2",
+        )
+        .bind(endpoint)
+        .bind(limit)
+        .fetch_all(&self.db)
+        .await?;
+
+        rows.iter()
+            .map(|r| {
+                Ok(Delivery {
+                    endpoint: r.try_get("endpoint")?,
+                    delivered_at: r.try_get("delivered_at")?,
+                    payload: r.try_get("payload")?,
+                    error: r.try_get("error")?,
+                })
+            })
+            .collect()
+    }
+
+    async fn prune_deliveries(&self, before: DateTime<Utc>) -> Result<u64, Error> {
+        let res = query("DELETE FROM WEBHOOKDELIVERIES WHERE delivered_at < $1")
+            .bind(before)
+            .execute(&self.db)
+            .await?;
+
+        Ok(res.rows_affected())
+    }
+
+    async fn close(&self) {
+        // Let another server take over sending alerts straight away
+        if let Some(conn) = self.leader.lock().await.take() {
+            if let Err(why) = conn.close().await {
+                log::warn!(
+                    "Couldn't close the connection holding the leader lock: {}",
+                    why
+                );
+            }
+        }
+        self.db.close().await;
+    }
+
+    fn is_shared(&self) -> bool {
+        true
+    }
+
+    async fn try_lead(&self) -> Result<bool, Error> {
+        let mut leader = self.leader.lock().await;
+
+        // Keep leading as long as the session holding the lock is alive
+        if let Some(conn) = leader.as_mut() {
+            if query("SELECT 1").execute(&mut *conn).await.is_ok() {
+                return Ok(true);
+            }
+            log::warn!("Lost the connection holding the leader lock");
+            *leader = None;
+        }
+
+        let mut conn = self.db.acquire().await?.detach();
+        let locked: bool = query_scalar("SELECT pg_try_advisory_lock($1)")
+            .bind(LEADER_LOCK)
+            .fetch_one(&mut conn)
+            .await?;
+
+        if locked {
+            log::info!("This server is now sending alerts");
+            *leader = Some(conn);
+        }
+
+        Ok(locked)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    //! These run against the Postgres server at `QA_TEST_POSTGRES_URL`, in a new database per test. They are ignored
+    //! unless asked for, eg. `QA_TEST_POSTGRES_URL=postgres://postgres@localhost/postgres cargo test --features postgres
+    //! postgres -- --ignored`, and fail if it is not set.
+
+    use super::super::test as store_test;
+    use super::*;
+    use url::Url;
+
+    /// A database that is dropped by [`TempPg::drop`].
+    struct TempPg {
+        admin: PgPool,
+        name: String,
+        url: String,
+    }
+
+    impl TempPg {
+        async fn new(name: &str) -> Self {
+            let admin_url = std::env::var("QA_TEST_POSTGRES_URL")
+                .expect("QA_TEST_POSTGRES_URL should be set to run Postgres tests");
+
+            let name = format!("queue_alert_{}_{}", name, std::process::id());
+            let admin = PgPool::connect(&admin_url).await.unwrap();
+            admin
+                .execute(format!("DROP DATABASE IF EXISTS {} WITH (FORCE)", name).as_str())
+                .await
+                .unwrap();
+            admin
+                .execute(format!("CREATE DATABASE {}", name).as_str())
+                .await
+                .unwrap();
+
+            let mut url = Url::parse(&admin_url).unwrap();
+            url.set_path(&name);
+
+            Self {
+                admin,
+                name,
+                url: url.to_string(),
+            }
+        }
+
+        async fn drop(self) {
+            self.admin
+                .execute(format!("DROP DATABASE {} WITH (FORCE)", self.name).as_str())
+                .await
+                .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs QA_TEST_POSTGRES_URL"]
+    async fn test_conformance() {
+        let db = TempPg::new("store").await;
+        let store = PostgresStore::open(&db.url).await.unwrap();
+
+        store_test::conformance(&store).await;
+
+        // Migrating again does nothing
+        assert_eq!(
+            PostgresStore::migrate(&db.url).await.unwrap(),
+            migrations::postgres::latest_version()
+        );
+
+        db.drop().await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs QA_TEST_POSTGRES_URL"]
+    async fn test_quarantine() {
+        let db = TempPg::new("quarantine").await;
+        let store = PostgresStore::open(&db.url).await.unwrap();
+        store_test::insert_corruptible(&store).await;
+
+        let mut conn = store.db.acquire().await.unwrap();
+        for sql in store_test::CORRUPT {
+            conn.execute(*sql).await.unwrap();
+        }
+        // Skips foreign key triggers for this session
+        conn.execute("SET session_replication_role = replica")
+            .await
+            .unwrap();
+        conn.execute(store_test::ORPHAN).await.unwrap();
+        conn.execute("SET session_replication_role = DEFAULT")
+            .await
+            .unwrap();
+        drop(conn);
+
+        store_test::quarantine(&store).await;
+
+        let data: String = query_scalar("SELECT data FROM QUARANTINE WHERE endpoint = $1")
+            .bind(store_test::CORRUPTED[0])
+            .fetch_one(&store.db)
+            .await
+            .unwrap();
+        let data: serde_json::Value = serde_json::from_str(&data).unwrap();
+        assert_eq!(data["registration"]["subscription_info"], "not json");
+        assert_eq!(data["rides"][0]["ridename"], "Maverick");
+
+        db.drop().await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs QA_TEST_POSTGRES_URL"]
+    async fn test_one_leader() {
+        let db = TempPg::new("leader").await;
+        let first = PostgresStore::open(&db.url).await.unwrap();
+        let second = PostgresStore::open(&db.url).await.unwrap();
+
+        assert!(first.try_lead().await.unwrap());
+        assert!(first.try_lead().await.unwrap());
+        assert!(!second.try_lead().await.unwrap());
+
+        // The lock is released once the leader goes away
+        drop(first);
+        assert!(second.try_lead().await.unwrap());
+
+        db.drop().await;
+    }
+}
@@ -0,0 +1,900 @@
+//! SQLite registration storage, for a single server.
+
+use super::{
+    alert_on_columns, assemble, below_column, channel_column, mode_column, push_group_row,
+    require_columns, window_days, CheckReport, ChildRows, CrowdRow, Delivery, GroupRideRow,
+    GroupRow, Loaded, Problem, RegistrationRow, RegistrationStore, RideRow, RowSink, StoredRows,
+    WindowRow, ORPHAN_CHECKS,
+};
+use crate::error::Error;
+use crate::migrations;
+use crate::models::Registration;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use sqlx::sqlite::{SqliteConnectOptions, SqliteRow};
+use sqlx::{query, query_scalar, Executor, Row, Sqlite, SqlitePool, Transaction};
+use std::path::Path;
+use tokio_stream::{Stream, StreamExt};
+
+/// Stores registrations in a SQLite db file.
+pub struct SqliteStore {
+    db: SqlitePool,
+}
+
+impl SqliteStore {
+    /// Opens the database at `path`, creating it if it does not exist. The db is migrated to the latest schema.
+    pub async fn open(path: &Path) -> Result<Self, Error> {
+        let db = Self::connect(path).await?;
+        migrations::run(&db).await?;
+
+        Ok(Self { db })
+    }
+
+    /// Migrates the database at `path` to the latest schema without loading it. Returns the version it was at before.
+    pub async fn migrate(path: &Path) -> Result<i64, Error> {
+        let db = Self::connect(path).await?;
+        let from = migrations::run(&db).await?;
+        db.close().await;
+
+        Ok(from)
+    }
+
+    /// Opens the database at `path`, creating it if it does not exist.
+    async fn connect(path: &Path) -> Result<SqlitePool, Error> {
+        Ok(SqlitePool::connect_with(
+            SqliteConnectOptions::new()
+                .create_if_missing(true)
+                .filename(path),
+        )
+        .await?)
+    }
+
+    /// Reads the rows of every registration, or only the one at `endpoint` if set, passing each to `on_rows`.
+    ///
+    /// This takes three queries however many registrations there are. Registrations are streamed in with their
+    /// windows and crowd alerts, alongside their rides and groups, all sorted by endpoint so each registration is
+    /// passed on as soon as its rows are read.
+    async fn read_rows(
+        &self,
+        endpoint: Option<&str>,
+        on_rows: &mut RowSink<'_>,
+    ) -> Result<(), Error> {
+        let mut ride_rows = query(
+            "SELECT a.endpoint, a.ridename, a.alerton, a.wait, a.snoozed_until, a.mode, a.last_alerted, a.matched,
+                w.ridename AS window_ridename, w.start_time, w.end_time, w.timezone, w.days, w.start_date, w.end_date
+            FROM RIDEALERTS a LEFT JOIN RIDEWINDOWS w ON w.endpoint = a.endpoint AND w.ridename = a.ridename
+            WHERE ?1 IS NULL OR a.endpoint = ?1
+            ORDER BY a.endpoint, a.rowid",
+        )
+        .bind(endpoint)
+        .fetch(&self.db);
+        let mut rides = ChildRows::new();
+
+        let mut group_rows = query(
+            "SELECT g.endpoint, g.name, g.require, g.count, g.mode, g.last_alerted, g.matched,
+                r.ridename, r.alerton, r.wait
+            FROM RIDEGROUPS g LEFT JOIN GROUPRIDES r ON r.endpoint = g.endpoint AND r.groupname = g.name
+            WHERE ?1 IS NULL OR g.endpoint = ?1
+            ORDER BY g.endpoint, g.rowid, r.rowid",
+        )
+        .bind(endpoint)
+        .fetch(&self.db);
+        let mut groups = ChildRows::new();
+
+        let mut registration_rows = query(
+            "SELECT r.endpoint, r.subscription_info, r.expires_at, r.snoozed_until, r.token_hash,
+                r.vapid_key, r.channel, r.signing_secret, c.park,
+                w.endpoint AS window_endpoint, w.start_time, w.end_time, w.timezone, w.days, w.start_date, w.end_date,
+                ca.endpoint AS crowd_endpoint, ca.below AS crowd_below, ca.mode AS crowd_mode,
+                ca.last_alerted AS crowd_last_alerted, ca.matched AS crowd_matched
+            FROM REGISTRATIONS r
+                LEFT JOIN CONFIGS c ON c.endpoint = r.endpoint
+                LEFT JOIN CONFIGWINDOWS w ON w.endpoint = r.endpoint
+                LEFT JOIN CROWDALERTS ca ON ca.endpoint = r.endpoint
+            WHERE ?1 IS NULL OR r.endpoint = ?1
+            ORDER BY r.endpoint",
+        )
+        .bind(endpoint)
+        .fetch(&self.db);
+
+        while let Some(r) = registration_rows.next().await {
+            let r = r?;
+            let endpoint: String = r.try_get("endpoint")?;
+
+            Self::read_children(&mut rides, &mut ride_rows, &endpoint, Self::ride_row).await?;
+            Self::read_children(&mut groups, &mut group_rows, &endpoint, Self::group_row).await?;
+
+            let rides = rides.take(&endpoint);
+            let mut registration_groups = Vec::new();
+            for row in groups.take(&endpoint) {
+                push_group_row(&mut registration_groups, row);
+            }
+
+            on_rows(match Self::registration_row(&r) {
+                Ok(registration) => Ok(StoredRows {
+                    registration,
+                    park: r.try_get("park")?,
+                    window: Self::joined_window(&r, "window_endpoint"),
+                    rides,
+                    groups: registration_groups,
+                    crowd: Self::joined_crowd(&r),
+                }),
+                Err(why) => Err(Problem {
+                    endpoint,
+                    reason: format!("invalid registration: {}", why),
+                }),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Reads from `stream` into `children` until it holds every row of `endpoint`, reading each row with `read`.
+    async fn read_children<T>(
+        children: &mut ChildRows<T>,
+        stream: &mut (impl Stream<Item = Result<SqliteRow, sqlx::Error>> + Unpin),
+        endpoint: &str,
+        read: fn(&SqliteRow) -> T,
+    ) -> Result<(), Error> {
+        while children.needs_more(endpoint) {
+            let row = match stream.next().await {
+                Some(row) => {
+                    let row = row?;
+                    vec![(row.try_get("endpoint")?, read(&row))]
+                }
+                None => Vec::new(),
+            };
+            children.extend(row);
+        }
+
+        Ok(())
+    }
+
+    fn registration_row(r: &SqliteRow) -> Result<RegistrationRow, sqlx::Error> {
+        Ok(RegistrationRow {
+            endpoint: r.try_get("endpoint")?,
+            subscription_info: r.try_get("subscription_info")?,
+            expires_at: r.try_get("expires_at")?,
+            snoozed_until: r.try_get("snoozed_until")?,
+            token_hash: r.try_get("token_hash")?,
+            vapid_key: r.try_get("vapid_key")?,
+            channel: r.try_get("channel")?,
+            signing_secret: r.try_get("signing_secret")?,
+        })
+    }
+
+    fn ride_row(r: &SqliteRow) -> Result<RideRow, sqlx::Error> {
+        Ok(RideRow {
+            ride_name: r.try_get("ridename")?,
+            alert_on: r.try_get("alerton")?,
+            wait: r.try_get("wait")?,
+            snoozed_until: r.try_get("snoozed_until")?,
+            mode: r.try_get("mode")?,
+            last_alerted: r.try_get("last_alerted")?,
+            matched: r.try_get("matched")?,
+            window: Self::joined_window(r, "window_ridename").transpose()?,
+        })
+    }
+
+    /// Reads a group, and the group ride joined onto it if the join found one.
+    fn group_row(r: &SqliteRow) -> Result<(GroupRow, Option<GroupRideRow>), sqlx::Error> {
+        let ride = match r.try_get::<Option<String>, _>("ridename")? {
+            Some(ride_name) => Some(GroupRideRow {
+                ride_name,
+                alert_on: r.try_get("alerton")?,
+                wait: r.try_get("wait")?,
+            }),
+            None => None,
+        };
+
+        Ok((
+            GroupRow {
+                name: r.try_get("name")?,
+                require: r.try_get("require")?,
+                count: r.try_get("count")?,
+                mode: r.try_get("mode")?,
+                last_alerted: r.try_get("last_alerted")?,
+                matched: r.try_get("matched")?,
+                rides: Vec::new(),
+            },
+            ride,
+        ))
+    }
+
+    /// Reads the crowd alert joined onto a registration, if it has one.
+    fn joined_crowd(r: &SqliteRow) -> Option<Result<CrowdRow, sqlx::Error>> {
+        match r.try_get::<Option<String>, _>("crowd_endpoint") {
+            Ok(Some(_)) => Some(Self::crowd_row(r)),
+            Ok(None) => None,
+            Err(why) => Some(Err(why)),
+        }
+    }
+
+    fn crowd_row(r: &SqliteRow) -> Result<CrowdRow, sqlx::Error> {
+        Ok(CrowdRow {
+            below: r.try_get("crowd_below")?,
+            mode: r.try_get("crowd_mode")?,
+            last_alerted: r.try_get("crowd_last_alerted")?,
+            matched: r.try_get("crowd_matched")?,
+        })
+    }
+
+    /// Reads a window joined onto a row, if `marker` shows the join found one.
+    fn joined_window(r: &SqliteRow, marker: &str) -> Option<Result<WindowRow, sqlx::Error>> {
+        match r.try_get::<Option<String>, _>(marker) {
+            Ok(Some(_)) => Some(Self::window_row(r)),
+            Ok(None) => None,
+            Err(why) => Some(Err(why)),
+        }
+    }
+
+    /// Reads the columns of either CONFIGWINDOWS or RIDEWINDOWS.
+    fn window_row(r: &SqliteRow) -> Result<WindowRow, sqlx::Error> {
+        Ok(WindowRow {
+            start: r.try_get("start_time")?,
+            end: r.try_get("end_time")?,
+            timezone: r.try_get("timezone")?,
+            days: r.try_get("days")?,
+            start_date: r.try_get("start_date")?,
+            end_date: r.try_get("end_date")?,
+        })
+    }
+
+    /// Loads every registration, or only the one at `endpoint` if set, into `sink`. Registrations that cannot be read
+    /// are quarantined once all are read, as SQLite cannot write while reading.
+    async fn load_where(
+        &self,
+        endpoint: Option<&str>,
+        sink: &mut (dyn FnMut(Registration) + Send),
+    ) -> Result<Loaded, Error> {
+        let mut loaded = 0;
+        let mut corrupt = Vec::new();
+
+        self.read_rows(endpoint, &mut |rows| match assemble(rows) {
+            Ok(reg) => {
+                loaded += 1;
+                sink(reg);
+            }
+            Err(problem) => corrupt.push(problem),
+        })
+        .await?;
+
+        for problem in &corrupt {
+            self.quarantine(problem).await?;
+        }
+
+        Ok(Loaded {
+            loaded,
+            quarantined: corrupt.len(),
+        })
+    }
+
+    /// Moves a registration that cannot be read out of the way, keeping its rows as JSON.
+    async fn quarantine(&self, problem: &Problem) -> Result<(), Error> {
+        log::warn!(
+            "Quarantining registration {}: {}",
+            problem.endpoint,
+            problem.reason
+        );
+
+        let mut trans = self.db.begin().await?;
+
+        trans
+            .execute(
+                query(
+                    "INSERT INTO QUARANTINE (endpoint, reason, data, quarantined_at)
+                    SELECT r.endpoint, ?2, json_object(
+                        'registration', json_object(
+                            'subscription_info', r.subscription_info, 'created_at', r.created_at,
+                            'expires_at', r.expires_at, 'snoozed_until', r.snoozed_until,
+                            'token_hash', r.token_hash, 'vapid_key', r.vapid_key, 'channel', r.channel,
+                            'signing_secret', r.signing_secret
+                        ),
+                        'park', (SELECT park FROM CONFIGS c WHERE c.endpoint = r.endpoint),
+                        'rides', json((SELECT json_group_array(json_object(
+                            'ridename', ridename, 'alerton', alerton, 'wait', wait, 'mode', mode,
+                            'snoozed_until', snoozed_until, 'last_alerted', last_alerted, 'matched', matched
+                        )) FROM RIDEALERTS a WHERE a.endpoint = r.endpoint)),
+                        'groups', json((SELECT json_group_array(json_object(
+                            'name', name, 'require', require, 'count', count, 'mode', mode,
+                            'last_alerted', last_alerted, 'matched', matched,
+                            'rides', json((SELECT json_group_array(json_object(
+                                'ridename', ridename, 'alerton', alerton, 'wait', wait
+                            )) FROM GROUPRIDES gr WHERE gr.endpoint = g.endpoint AND gr.groupname = g.name))
+                        )) FROM RIDEGROUPS g WHERE g.endpoint = r.endpoint)),
+                        'crowd', (SELECT json_object(
+                            'below', below, 'mode', mode, 'last_alerted', last_alerted, 'matched', matched
+                        ) FROM CROWDALERTS ca WHERE ca.endpoint = r.endpoint)
+                    ), datetime()
+                    FROM REGISTRATIONS r WHERE r.endpoint = ?1",
+                )
+                .bind(&problem.endpoint)
+                .bind(&problem.reason),
+            )
+            .await?;
+        trans
+            .execute(query("DELETE FROM REGISTRATIONS WHERE endpoint = ?").bind(&problem.endpoint))
+            .await?;
+
+        trans.commit().await?;
+
+        Ok(())
+    }
+
+    /// Adds all the rides in a users config into the RIDEALERTS table in a transaction, along with any windows, groups
+    /// and crowd alert.
+    async fn add_config_to_transaction(
+        reg: &Registration,
+        trans: &mut Transaction<'_, Sqlite>,
+    ) -> Result<(), Error> {
+        if let Some(window) = &reg.window {
+            trans
+                .execute(
+                    query("INSERT INTO CONFIGWINDOWS VALUES (?, ?, ?, ?, ?, ?, ?)")
+                        .bind(reg.sub.endpoint.clone())
+                        .bind(window.start)
+                        .bind(window.end)
+                        .bind(window.timezone.name())
+                        .bind(window_days(window))
+                        .bind(window.start_date)
+                        .bind(window.end_date),
+                )
+                .await?;
+        }
+
+        for ride in reg.config.1.iter() {
+            let (alert_on, wait) = alert_on_columns(ride.alert_on);
+
+            trans
+                .execute(
+                    query("INSERT INTO RIDEALERTS (endpoint, ridename, alerton, wait, snoozed_until, mode, last_alerted, matched) VALUES (?, ?, ?, ?, ?, ?, ?, ?)")
+                        .bind(reg.sub.endpoint.clone())
+                        .bind(ride.ride_name.clone())
+                        .bind(alert_on)
+                        .bind(wait)
+                        .bind(ride.snoozed_until)
+                        .bind(mode_column(ride.mode))
+                        .bind(ride.last_alerted)
+                        .bind(ride.matched),
+                )
+                .await?;
+
+            if let Some(window) = &ride.window {
+                trans
+                    .execute(
+                        query("INSERT INTO RIDEWINDOWS VALUES (?, ?, ?, ?, ?, ?, ?, ?)")
+                            .bind(reg.sub.endpoint.clone())
+                            .bind(ride.ride_name.clone())
+                            .bind(window.start)
+                            .bind(window.end)
+                            .bind(window.timezone.name())
+                            .bind(window_days(window))
+                            .bind(window.start_date)
+                            .bind(window.end_date),
+                    )
+                    .await?;
+            }
+        }
+
+        for group in reg.groups.iter() {
+            let (require, count) = require_columns(group.require);
+
+            trans
+                .execute(
+                    query("INSERT INTO RIDEGROUPS (endpoint, name, require, count, mode, last_alerted, matched) VALUES (?, ?, ?, ?, ?, ?, ?)")
+                        .bind(reg.sub.endpoint.clone())
+                        .bind(group.name.clone())
+                        .bind(require)
+                        .bind(count)
+                        .bind(mode_column(group.mode))
+                        .bind(group.last_alerted)
+                        .bind(group.matched),
+                )
+                .await?;
+
+            for ride in group.rides.iter() {
+                let (alert_on, wait) = alert_on_columns(ride.alert_on);
+
+                trans
+                    .execute(
+                        query("INSERT INTO GROUPRIDES VALUES (?, ?, ?, ?, ?)")
+                            .bind(reg.sub.endpoint.clone())
+                            .bind(group.name.clone())
+                            .bind(ride.ride_name.clone())
+                            .bind(alert_on)
+                            .bind(wait),
+                    )
+                    .await?;
+            }
+        }
+
+        if let Some(crowd) = &reg.crowd {
+            trans
+                .execute(
+                    query("INSERT INTO CROWDALERTS (endpoint, below, mode, last_alerted, matched) VALUES (?, ?, ?, ?, ?)")
+                        .bind(reg.sub.endpoint.clone())
+                        .bind(below_column(crowd.below))
+                        .bind(mode_column(crowd.mode))
+                        .bind(crowd.last_alerted)
+                        .bind(crowd.matched),
+                )
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl RegistrationStore for SqliteStore {
+    async fn load_all(&self, sink: &mut (dyn FnMut(Registration) + Send)) -> Result<Loaded, Error> {
+        self.load_where(None, sink).await
+    }
+
+    async fn load(&self, endpoint: &str) -> Result<Option<Registration>, Error> {
+        let mut found = None;
+        self.load_where(Some(endpoint), &mut |reg| found = Some(reg))
+            .await?;
+
+        Ok(found)
+    }
+
+    async fn check(&self) -> Result<CheckReport, Error> {
+        let mut corrupt = Vec::new();
+        self.read_rows(None, &mut |rows| {
+            if let Err(problem) = assemble(rows) {
+                corrupt.push(problem);
+            }
+        })
+        .await?;
+
+        let mut orphans = Vec::new();
+        for (sql, reason) in ORPHAN_CHECKS {
+            for endpoint in query_scalar::<_, String>(sql).fetch_all(&self.db).await? {
+                orphans.push(Problem {
+                    endpoint,
+                    reason: reason.to_string(),
+                });
+            }
+        }
+
+        let quarantined = query("SELECT endpoint, reason FROM QUARANTINE ORDER BY quarantined_at")
+            .map(|r: SqliteRow| Problem {
+                endpoint: r.get("endpoint"),
+                reason: r.get("reason"),
+            })
+            .fetch_all(&self.db)
+            .await?;
+
+        Ok(CheckReport {
+            corrupt,
+            orphans,
+            quarantined,
+        })
+    }
+
+    async fn ping(&self) -> Result<(), Error> {
+        self.db.execute("SELECT 1").await?;
+        Ok(())
+    }
+
+    async fn insert(&self, reg: &Registration) -> Result<(), Error> {
+        let mut trans = self.db.begin().await?;
+
+        // Add subscription info
+        trans
+            .execute(
+                query("INSERT INTO REGISTRATIONS (endpoint, subscription_info, created_at, expires_at, token_hash, vapid_key, channel, signing_secret) VALUES (?, ?, date(), ?, ?, ?, ?, ?)")
+                    .bind(reg.sub.endpoint.clone())
+                    .bind(serde_json::to_string(&reg.sub)?)
+                    .bind(reg.expires_at)
+                    .bind(reg.token_hash.clone())
+                    .bind(reg.vapid_key.clone())
+                    .bind(channel_column(&reg.channel)?)
+                    .bind(reg.signing_secret.clone()),
+            )
+            .await?;
+
+        // Add park info
+        trans
+            .execute(
+                query("INSERT INTO CONFIGS VALUES (?, ?)")
+                    .bind(reg.sub.endpoint.clone())
+                    .bind(reg.config.0.clone()),
+            )
+            .await?;
+
+        // Add config
+        Self::add_config_to_transaction(reg, &mut trans).await?;
+
+        trans.commit().await?;
+
+        Ok(())
+    }
+
+    async fn update(&self, reg: &Registration) -> Result<(), Error> {
+        let mut trans = self.db.begin().await?;
+
+        trans
+            .execute(
+                query("UPDATE REGISTRATIONS SET expires_at = ?, vapid_key = ?, channel = ? WHERE endpoint = ?")
+                    .bind(reg.expires_at)
+                    .bind(reg.vapid_key.clone())
+                    .bind(channel_column(&reg.channel)?)
+                    .bind(reg.sub.endpoint.clone()),
+            )
+            .await?;
+
+        // Add park info
+        trans
+            .execute(
+                query("UPDATE CONFIGS SET park = ? WHERE endpoint = ?")
+                    .bind(reg.config.0.clone())
+                    .bind(reg.sub.endpoint.clone()),
+            )
+            .await?;
+
+        // Just remove the old config, then insert the new one
+        trans
+            .execute(
+                query("DELETE FROM RIDEALERTS WHERE endpoint = ?").bind(reg.sub.endpoint.clone()),
+            )
+            .await?;
+        trans
+            .execute(
+                query("DELETE FROM CONFIGWINDOWS WHERE endpoint = ?")
+                    .bind(reg.sub.endpoint.clone()),
+            )
+            .await?;
+        trans
+            .execute(
+                query("DELETE FROM RIDEGROUPS WHERE endpoint = ?").bind(reg.sub.endpoint.clone()),
+            )
+            .await?;
+        trans
+            .execute(
+                query("DELETE FROM CROWDALERTS WHERE endpoint = ?").bind(reg.sub.endpoint.clone()),
+            )
+            .await?;
+
+        // Add config
+        Self::add_config_to_transaction(reg, &mut trans).await?;
+
+        trans.commit().await?;
+
+        Ok(())
+    }
+
+    async fn remove(&self, endpoint: &str) -> Result<bool, Error> {
+        let res = query("DELETE FROM REGISTRATIONS WHERE endpoint = ?")
+            .bind(endpoint)
+            .execute(&self.db)
+            .await?;
+
+        Ok(res.rows_affected() > 0)
+    }
+
+    async fn set_token_hash(&self, endpoint: &str, hash: &str) -> Result<(), Error> {
+        query("UPDATE REGISTRATIONS SET token_hash = ? WHERE endpoint = ?")
+            .bind(hash)
+            .bind(endpoint)
+            .execute(&self.db)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn set_missing_vapid_keys(&self, key: &str) -> Result<(), Error> {
+        query("UPDATE REGISTRATIONS SET vapid_key = ? WHERE vapid_key IS NULL AND channel IS NULL")
+            .bind(key)
+            .execute(&self.db)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn snooze(
+        &self,
+        endpoint: &str,
+        ride_name: Option<&str>,
+        until: Option<DateTime<Utc>>,
+    ) -> Result<(), Error> {
+        match ride_name {
+            None => {
+                query("UPDATE REGISTRATIONS SET snoozed_until = ? WHERE endpoint = ?")
+                    .bind(until)
+                    .bind(endpoint)
+                    .execute(&self.db)
+                    .await?;
+            }
+            Some(ride_name) => {
+                query(
+                    "UPDATE RIDEALERTS SET snoozed_until = ? WHERE endpoint = ? AND ridename = ?",
+                )
+                .bind(until)
+                .bind(endpoint)
+                .bind(ride_name)
+                .execute(&self.db)
+                .await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn mark_alerted(
+        &self,
+        alerted: &[(String, Vec<String>)],
+        at: DateTime<Utc>,
+    ) -> Result<(), Error> {
+        let mut trans = self.db.begin().await?;
+
+        for (endpoint, rides) in alerted {
+            for ride in rides {
+                trans
+                    .execute(
+                        query("UPDATE RIDEALERTS SET last_alerted = ? WHERE endpoint = ? AND ridename = ?")
+                            .bind(at)
+                            .bind(endpoint)
+                            .bind(ride),
+                    )
+                    .await?;
+            }
+        }
+
+        trans.commit().await?;
+
+        Ok(())
+    }
+
+    async fn save_matched(&self, matched: &[(String, String, bool)]) -> Result<(), Error> {
+        let mut trans = self.db.begin().await?;
+
+        for (endpoint, ride, matched) in matched {
+            trans
+                .execute(
+                    query("UPDATE RIDEALERTS SET matched = ? WHERE endpoint = ? AND ridename = ?")
+                        .bind(matched)
+                        .bind(endpoint)
+                        .bind(ride),
+                )
+                .await?;
+        }
+
+        trans.commit().await?;
+
+        Ok(())
+    }
+
+    async fn mark_groups_alerted(
+        &self,
+        alerted: &[(String, Vec<String>)],
+        at: DateTime<Utc>,
+    ) -> Result<(), Error> {
+        let mut trans = self.db.begin().await?;
+
+        for (endpoint, groups) in alerted {
+            for group in groups {
+                trans
+                    .execute(
+                        query("UPDATE RIDEGROUPS SET last_alerted = ? WHERE endpoint = ? AND name = ?")
+                            .bind(at)
+                            .bind(endpoint)
+                            .bind(group),
+                    )
+                    .await?;
+            }
+        }
+
+        trans.commit().await?;
+
+        Ok(())
+    }
+
+    async fn save_groups_matched(&self, matched: &[(String, String, bool)]) -> Result<(), Error> {
+        let mut trans = self.db.begin().await?;
+
+        for (endpoint, group, matched) in matched {
+            trans
+                .execute(
+                    query("UPDATE RIDEGROUPS SET matched = ? WHERE endpoint = ? AND name = ?")
+                        .bind(matched)
+                        .bind(endpoint)
+                        .bind(group),
+                )
+                .await?;
+        }
+
+        trans.commit().await?;
+
+        Ok(())
+    }
+
+    async fn mark_crowd_alerted(&self, alerted: &[String], at: DateTime<Utc>) -> Result<(), Error> {
+        let mut trans = self.db.begin().await?;
+
+        for endpoint in alerted {
+            trans
+                .execute(
+                    query("UPDATE CROWDALERTS SET last_alerted = ? WHERE endpoint = ?")
+                        .bind(at)
+                        .bind(endpoint),
+                )
+                .await?;
+        }
+
+        trans.commit().await?;
+
+        Ok(())
+    }
+
+    async fn save_crowd_matched(&self, matched: &[(String, bool)]) -> Result<(), Error> {
+        let mut trans = self.db.begin().await?;
+
+        for (endpoint, matched) in matched {
+            trans
+                .execute(
+                    query("UPDATE CROWDALERTS SET matched = ? WHERE endpoint = ?")
+                        .bind(matched)
+                        .bind(endpoint),
+                )
+                .await?;
+        }
+
+        trans.commit().await?;
+
+        Ok(())
+    }
+
+    async fn log_delivery(&self, delivery: &Delivery) -> Result<(), Error> {
+        query(
+            "INSERT INTO WEBHOOKDELIVERIES (endpoint, delivered_at, payload, error) VALUES (?, ?, ?, ?)",
+        )
+        .bind(&delivery.endpoint)
+        .bind(delivery.delivered_at)
+        .bind(&delivery.payload)
+        .bind(&delivery.error)
+        .execute(&self.db)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn deliveries(&self, endpoint: &str, limit: i64) -> Result<Vec<Delivery>, Error> {
+        let rows = query(
+            "SELECT endpoint, delivered_at, payload, error FROM WEBHOOKDELIVERIES WHERE endpoint = ?
+            ORDER BY id DESC LIMIT ?",
+        )
+        .bind(endpoint)
+        .bind(limit)
+        .fetch_all(&self.db)
+        .await?;
+
+        rows.iter()
+            .map(|r| {
+                Ok(Delivery {
+                    endpoint: r.try_get("endpoint")?,
+                    delivered_at: r.try_get("delivered_at")?,
+                    payload: r.try_get("payload")?,
+                    error: r.try_get("error")?,
+                })
+            })
+            .collect()
+    }
+
+    async fn prune_deliveries(&self, before: DateTime<Utc>) -> Result<u64, Error> {
+        let res = query("DELETE FROM WEBHOOKDELIVERIES WHERE delivered_at < ?")
+            .bind(before)
+            .execute(&self.db)
+            .await?;
+
+        Ok(res.rows_affected())
+    }
+
+    async fn close(&self) {
+        self.db.close().await;
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::super::test as store_test;
+    use super::*;
+    use crate::registration::RegistrationRepository;
+    use std::path::PathBuf;
+
+    /// A db file that is deleted on drop.
+    pub struct TempDb(pub PathBuf);
+
+    impl TempDb {
+        pub fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "queue_alert_{}_{}.sqlite",
+                name,
+                std::process::id()
+            ));
+            let _ = std::fs::remove_file(&path);
+            Self(path)
+        }
+
+        pub async fn pool(&self) -> SqlitePool {
+            SqliteStore::connect(&self.0).await.unwrap()
+        }
+    }
+
+    impl Drop for TempDb {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_conformance() {
+        let file = TempDb::new("store");
+        let store = SqliteStore::open(&file.0).await.unwrap();
+
+        store_test::conformance(&store).await;
+    }
+
+    #[tokio::test]
+    async fn test_quarantine() {
+        let file = TempDb::new("quarantine");
+        let store = SqliteStore::open(&file.0).await.unwrap();
+        store_test::insert_corruptible(&store).await;
+
+        for sql in store_test::CORRUPT {
+            store.db.execute(*sql).await.unwrap();
+        }
+        let db = SqlitePool::connect_with(
+            SqliteConnectOptions::new()
+                .filename(&file.0)
+                .foreign_keys(false),
+        )
+        .await
+        .unwrap();
+        db.execute(store_test::ORPHAN).await.unwrap();
+
+        store_test::quarantine(&store).await;
+
+        // The rows are kept
+        let data: String = query_scalar("SELECT data FROM QUARANTINE WHERE endpoint = ?")
+            .bind(store_test::CORRUPTED[0])
+            .fetch_one(&db)
+            .await
+            .unwrap();
+        let data: serde_json::Value = serde_json::from_str(&data).unwrap();
+        assert_eq!(data["registration"]["subscription_info"], "not json");
+        assert_eq!(data["park"], "Cedar Point");
+        assert_eq!(data["rides"][0]["ridename"], "Maverick");
+    }
+
+    /// Loads 50k registrations, failing if it takes long enough to slow startup. Debug builds get more time, so run
+    /// with `cargo test --release test_load_50k -- --nocapture` to check the real timing.
+    #[tokio::test]
+    async fn test_load_50k() {
+        let file = TempDb::new("bench");
+        let store = SqliteStore::open(&file.0).await.unwrap();
+        store
+            .db
+            .execute(include_str!("../../sql/fixtures/synthetic.sql"))
+            .await
+            .unwrap();
+
+        let start = std::time::Instant::now();
+        let subs = RegistrationRepository::new(Box::new(store)).await.unwrap();
+        let elapsed = start.elapsed();
+
+        println!(
+            "Loaded {} registrations in {:?}",
+            subs.get_current_user_count(),
+            elapsed
+        );
+        assert_eq!(subs.get_current_user_count(), 50_000);
+
+        let limit = if cfg!(debug_assertions) {
+            std::time::Duration::from_secs(30)
+        } else {
+            std::time::Duration::from_secs(5)
+        };
+        assert!(elapsed < limit, "took {:?}, over {:?}", elapsed, limit);
+    }
+}
@@ -0,0 +1,37 @@
+//! Secret tokens that prove ownership of a registration.
+//!
+//! Clients receive a token when they first register, and must send it as a bearer token to change or remove
+//! their registration. Only a hash of the token is stored.
+
+use sha2::{Digest, Sha256};
+
+/// Generates a new random token.
+pub fn generate() -> String {
+    let bytes: [u8; 32] = rand::random();
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+/// Hashes a token for storage.
+pub fn hash(token: &str) -> String {
+    base64::encode_config(Sha256::digest(token.as_bytes()), base64::URL_SAFE_NO_PAD)
+}
+
+/// Checks if `token` hashes to `hash`.
+pub fn verify(token: &str, hash: &str) -> bool {
+    self::hash(token) == hash
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_token_verifies() {
+        let token = generate();
+        let hashed = hash(&token);
+
+        assert_ne!(token, hashed);
+        assert!(verify(&token, &hashed));
+        assert!(!verify(&generate(), &hashed));
+    }
+}
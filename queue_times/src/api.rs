@@ -10,12 +10,20 @@ use url::Url;
 /// Client that uses the official Queue Times API instead of scraping.
 pub struct ApiClient {
     reqwest_client: reqwest::Client,
+    /// Root of the queue times site to query, normally [`BASE_URL`].
+    base_url: Url,
 }
 
 impl ApiClient {
     pub fn new() -> Self {
+        Self::with_base_url(Url::parse(BASE_URL).unwrap())
+    }
+
+    /// Creates a client that queries a site other than [`BASE_URL`], such as a mirror or proxy.
+    pub fn with_base_url(base_url: Url) -> Self {
         Self {
             reqwest_client: reqwest::Client::new(),
+            base_url,
         }
     }
 }
@@ -33,7 +41,7 @@ impl QueueTimesClient for ApiClient {
 
         let parks_json = self
             .reqwest_client
-            .get("https://queue-times.com/en-US/parks.json")
+            .get(self.base_url.join("en-US/parks.json").unwrap())
             .send()
             .await?
             .json::<serde_json::Value>()
@@ -51,8 +59,7 @@ impl QueueTimesClient for ApiClient {
 
                 park_map.insert(
                     name.to_string(),
-                    Url::parse(BASE_URL)
-                        .unwrap()
+                    self.base_url
                         .join("en-US/parks/")
                         .unwrap()
                         .join(&format!("{}/", id))
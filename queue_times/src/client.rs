@@ -25,12 +25,12 @@
 
 use std::collections::HashMap;
 use std::sync::Arc;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 
 use async_trait::async_trait;
 use chrono::{Duration, Local};
 use reqwest::Client as reqClient;
-use tokio::sync::RwLock;
+use tokio::sync::{watch, RwLock};
 use url::Url;
 
 use crate::error::*;
@@ -162,10 +162,25 @@ pub struct CachedClient<T>
     /// Cache of park name to URL to rides page. Never needs to be updated.
     parks_cache: RwLock<HashMap<String, Url>>,
     //use RwLock over dashmap to avoid clone when returning
-    /// Last update to cache, update every 5 minutes.
+    /// Last update to cache, update every `ttl`.
     last_updated: Arc<RwLock<chrono::DateTime<Local>>>,
+    /// How long cached ride times are served before refreshing.
+    ttl: Duration,
     /// True if cache is currently updating in background.
     currently_updating_cache: Arc<AtomicBool>,
+    /// Calls answered from the cache.
+    hits: AtomicU64,
+    /// Calls that had to go to the wrapped client.
+    misses: AtomicU64,
+    /// Sent the time of each finished cache update.
+    refreshed: Arc<watch::Sender<Option<chrono::DateTime<Local>>>>,
+}
+
+/// Counts of how often a [`CachedClient`] could answer from its cache.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
 }
 
 impl<T> CachedClient<T>
@@ -174,12 +189,47 @@ impl<T> CachedClient<T>
 {
     /// Wraps the passed client with a cache.
     pub fn new(client: T) -> Self {
+        Self::with_ttl(client, Duration::minutes(5))
+    }
+
+    /// Wraps the passed client with a cache that refreshes every `ttl`, rather than the default five minutes.
+    pub fn with_ttl(client: T, ttl: Duration) -> Self {
         CachedClient {
             client: Arc::new(client),
             ride_cache: Arc::new(dashmap::DashMap::new()),
             parks_cache: RwLock::new(HashMap::new()),
-            last_updated: Arc::new(RwLock::new(Local::now() - Duration::minutes(6))),
+            last_updated: Arc::new(RwLock::new(Local::now() - ttl - Duration::minutes(1))),
             currently_updating_cache: Arc::new(Default::default()),
+            ttl,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            refreshed: Arc::new(watch::Sender::new(None)),
+        }
+    }
+
+    /// When cached ride times were last refreshed, or `None` if they have not been fetched yet.
+    pub async fn last_updated(&self) -> Option<chrono::DateTime<Local>> {
+        if self.ride_cache.is_empty() {
+            None
+        } else {
+            Some(*self.last_updated.read().await)
+        }
+    }
+
+    /// Watches for cache updates. The receiver sees the time of each update once every park has been refreshed,
+    /// and holds `None` until the first.
+    ///
+    /// The cache only updates when called, so something must keep calling [`QueueTimesClient::get_ride_times`] for
+    /// updates to be seen.
+    pub fn subscribe(&self) -> watch::Receiver<Option<chrono::DateTime<Local>>> {
+        self.refreshed.subscribe()
+    }
+
+    /// How many calls have been answered from the cache, and how many went to the wrapped client.
+    pub fn cache_stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
         }
     }
 }
@@ -192,6 +242,7 @@ impl<T> QueueTimesClient for CachedClient<T>
     async fn get_park_urls(&self) -> Result<HashMap<String, Url>> {
         //Fill cache if never been used
         if self.parks_cache.read().await.is_empty() {
+            self.misses.fetch_add(1, Ordering::Relaxed);
             let parks = self.client.get_park_urls().await?;
 
             //Update cache
@@ -200,6 +251,7 @@ impl<T> QueueTimesClient for CachedClient<T>
 
             Ok(parks)
         } else {
+            self.hits.fetch_add(1, Ordering::Relaxed);
             let lock = self.parks_cache.read().await;
 
             Ok(lock.clone())
@@ -211,17 +263,19 @@ impl<T> QueueTimesClient for CachedClient<T>
             let time_lock = self.last_updated.read().await;
 
             //Return cache if website hasn't updated yet
-            if (Local::now() - *time_lock) < chrono::Duration::minutes(5) {
+            if (Local::now() - *time_lock) < self.ttl {
                 let rides = self
                     .ride_cache
                     .get(&park_url)
                     .ok_or_else(|| Error::from(ErrorKind::BadUrl(park_url)))?;
 
+                self.hits.fetch_add(1, Ordering::Relaxed);
                 return Ok(rides.value().clone());
             }
         }
 
         //Cache must be updated
+        self.misses.fetch_add(1, Ordering::Relaxed);
 
         //Clone Arcs
         let mut parks = self.get_park_urls().await?;
@@ -229,6 +283,7 @@ impl<T> QueueTimesClient for CachedClient<T>
         let ride_cache = self.ride_cache.clone();
         let last_updated = self.last_updated.clone();
         let currently_updating = self.currently_updating_cache.clone();
+        let refreshed = self.refreshed.clone();
 
         //Check if we're already updating to avoid downloading more than once
         if !currently_updating.load(Ordering::SeqCst) {
@@ -248,6 +303,7 @@ impl<T> QueueTimesClient for CachedClient<T>
                 }
                 let mut time_lock = last_updated.write().await;
                 *time_lock = Local::now();
+                refreshed.send_replace(Some(*time_lock));
             });
         }
 
@@ -343,4 +399,62 @@ mod tests {
             .unwrap();
         assert_eq!(mille_wait, og_mille_wait);
     }
+
+    /// Serves one park with no rides, without touching the network.
+    struct StubClient;
+
+    #[async_trait]
+    impl QueueTimesClient for StubClient {
+        async fn get_park_urls(&self) -> Result<HashMap<String, Url>> {
+            Ok(HashMap::from([(
+                "Stub Park".to_string(),
+                Url::parse("https://stub.example.com/park").unwrap(),
+            )]))
+        }
+
+        async fn get_ride_times(&self, _park_url: Url) -> Result<Vec<RideTime>> {
+            Ok(Vec::new())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cache_stats() {
+        let client = CachedClient::new(StubClient);
+        assert_eq!(client.cache_stats(), CacheStats::default());
+
+        let parks = client.get_park_urls().await.unwrap();
+        let url = parks.get("Stub Park").unwrap().to_owned();
+        assert_eq!(client.cache_stats(), CacheStats { hits: 0, misses: 1 });
+
+        // Ride times miss until the background refresh finishes
+        client.get_ride_times(url.clone()).await.unwrap();
+        assert_eq!(client.cache_stats().misses, 2);
+
+        while client.currently_updating_cache.load(Ordering::SeqCst) {
+            tokio::task::yield_now().await;
+        }
+
+        let before = client.cache_stats();
+        client.get_ride_times(url).await.unwrap();
+        assert_eq!(
+            client.cache_stats(),
+            CacheStats {
+                hits: before.hits + 1,
+                misses: before.misses
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_subscribe() {
+        let client = CachedClient::new(StubClient);
+        let mut refreshed = client.subscribe();
+        assert_eq!(*refreshed.borrow(), None);
+
+        let parks = client.get_park_urls().await.unwrap();
+        client.get_ride_times(parks.get("Stub Park").unwrap().to_owned()).await.unwrap();
+
+        refreshed.changed().await.unwrap();
+        assert_eq!(*refreshed.borrow(), client.last_updated().await);
+    }
 }
@@ -1,9 +1,10 @@
 /*
  * Copyright (c) 2021. Andrew Ealovega
  */
-//! Contains models used in parsing
+//! Contains models used in parsing, and summaries built from them
 
 use serde::{Deserialize, Serialize};
+use std::fmt;
 
 /// Current operating status of a ride. Defaults to `Closed`.
 #[derive(Copy, Clone, Debug, Serialize, Deserialize, Ord, PartialOrd, Eq, PartialEq, Hash, Default)]
@@ -23,3 +24,177 @@ pub struct RideTime {
     pub name: String,
     pub status: RideStatus,
 }
+
+impl RideTime {
+    /// Creates the status of the ride called `name`.
+    pub fn new(name: impl Into<String>, status: RideStatus) -> Self {
+        Self {
+            name: name.into(),
+            status,
+        }
+    }
+}
+
+/// How busy a park is, going by the average posted wait of its open rides. Ordered from quietest to busiest.
+#[derive(
+    Copy, Clone, Debug, Serialize, Deserialize, Ord, PartialOrd, Eq, PartialEq, Hash, Default,
+)]
+pub enum CrowdLevel {
+    /// Average wait under [`CrowdLevel::MODERATE_WAIT`] minutes.
+    #[default]
+    Low,
+    /// Average wait under [`CrowdLevel::HIGH_WAIT`] minutes.
+    Moderate,
+    /// Average wait under [`CrowdLevel::VERY_HIGH_WAIT`] minutes.
+    High,
+    VeryHigh,
+}
+
+impl CrowdLevel {
+    /// Average wait in minutes from which a park is at least `Moderate`.
+    pub const MODERATE_WAIT: f64 = 15.0;
+    /// Average wait in minutes from which a park is at least `High`.
+    pub const HIGH_WAIT: f64 = 30.0;
+    /// Average wait in minutes from which a park is `VeryHigh`.
+    pub const VERY_HIGH_WAIT: f64 = 45.0;
+
+    /// Gets the level of a park whose open rides average a wait of `average_wait` minutes.
+    pub fn of_wait(average_wait: f64) -> Self {
+        if average_wait < Self::MODERATE_WAIT {
+            CrowdLevel::Low
+        } else if average_wait < Self::HIGH_WAIT {
+            CrowdLevel::Moderate
+        } else if average_wait < Self::VERY_HIGH_WAIT {
+            CrowdLevel::High
+        } else {
+            CrowdLevel::VeryHigh
+        }
+    }
+}
+
+impl fmt::Display for CrowdLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CrowdLevel::Low => "low",
+            CrowdLevel::Moderate => "moderate",
+            CrowdLevel::High => "high",
+            CrowdLevel::VeryHigh => "very high",
+        })
+    }
+}
+
+/// How busy a park is right now, summarised from the current status of its rides.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ParkCrowd {
+    /// Number of rides in the park.
+    pub rides: usize,
+    /// Number of rides that are open, with or without a posted wait.
+    pub open: usize,
+    /// Share of rides that are open, from 0 to 1. 0 if the park has no rides.
+    pub open_share: f64,
+    /// Mean posted wait in minutes. `None` if no ride posts a wait.
+    pub average_wait: Option<f64>,
+    /// Median posted wait in minutes. `None` if no ride posts a wait.
+    pub median_wait: Option<f64>,
+    /// Level of the average wait. `None` if no ride posts a wait, such as when the park is closed.
+    pub level: Option<CrowdLevel>,
+}
+
+impl ParkCrowd {
+    /// Summarises a park with the statuses in `rides`. Only rides with a posted wait count towards the waits.
+    pub fn new(rides: &[RideTime]) -> Self {
+        let open = rides
+            .iter()
+            .filter(|r| r.status != RideStatus::Closed)
+            .count();
+        let mut waits: Vec<u16> = rides
+            .iter()
+            .filter_map(|r| match r.status {
+                RideStatus::Wait(wait) => Some(wait),
+                _ => None,
+            })
+            .collect();
+        waits.sort_unstable();
+
+        let average_wait = if waits.is_empty() {
+            None
+        } else {
+            Some(waits.iter().map(|&w| w as f64).sum::<f64>() / waits.len() as f64)
+        };
+        let median_wait = match waits.len() {
+            0 => None,
+            len if len % 2 == 0 => Some((waits[len / 2 - 1] as f64 + waits[len / 2] as f64) / 2.0),
+            len => Some(waits[len / 2] as f64),
+        };
+
+        Self {
+            rides: rides.len(),
+            open,
+            open_share: if rides.is_empty() {
+                0.0
+            } else {
+                open as f64 / rides.len() as f64
+            },
+            average_wait,
+            median_wait,
+            level: average_wait.map(CrowdLevel::of_wait),
+        }
+    }
+}
+
+impl From<&[RideTime]> for ParkCrowd {
+    fn from(rides: &[RideTime]) -> Self {
+        Self::new(rides)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_park_crowd() {
+        let rides = vec![
+            RideTime::new("Maverick", RideStatus::Wait(45)),
+            RideTime::new("Millennium Force", RideStatus::Wait(10)),
+            RideTime::new("Steel Vengeance", RideStatus::Wait(20)),
+            RideTime::new("Valravn", RideStatus::Wait(30)),
+            RideTime::new("Magnum XL-200", RideStatus::Open),
+            RideTime::new("Top Thrill 2", RideStatus::Closed),
+        ];
+
+        let crowd = ParkCrowd::new(&rides);
+        assert_eq!(crowd.rides, 6);
+        assert_eq!(crowd.open, 5);
+        assert_eq!(crowd.average_wait, Some(26.25));
+        assert_eq!(crowd.median_wait, Some(25.0));
+        assert_eq!(crowd.level, Some(CrowdLevel::Moderate));
+        assert_eq!(ParkCrowd::new(&rides[1..3]).median_wait, Some(15.0));
+        assert_eq!(
+            ParkCrowd::new(&rides[..1]).level,
+            Some(CrowdLevel::VeryHigh)
+        );
+
+        // A closed park has no crowd to speak of
+        let closed = ParkCrowd::new(&rides[5..]);
+        assert_eq!(closed.open_share, 0.0);
+        assert_eq!(closed.level, None);
+        assert_eq!(ParkCrowd::new(&[]), ParkCrowd::default());
+    }
+
+    #[test]
+    fn test_crowd_levels() {
+        assert_eq!(CrowdLevel::of_wait(0.0), CrowdLevel::Low);
+        assert_eq!(
+            CrowdLevel::of_wait(CrowdLevel::MODERATE_WAIT),
+            CrowdLevel::Moderate
+        );
+        assert_eq!(CrowdLevel::of_wait(29.9), CrowdLevel::Moderate);
+        assert_eq!(
+            CrowdLevel::of_wait(CrowdLevel::VERY_HIGH_WAIT),
+            CrowdLevel::VeryHigh
+        );
+        assert!(CrowdLevel::Low < CrowdLevel::Moderate);
+    }
+}